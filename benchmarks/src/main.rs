@@ -10,8 +10,10 @@ extern crate splay;
 mod bst;
 
 
-use bench_teardown::{DataMaster, TreapMaster, PlainSet, PlainMap, PlainSetSingle, FilteredPlainSet, FilteredIntervalSet, BTreeSetMaster, SplayMaster, IntervalSet, IntervalMap, BSTMaster};
-use bench_teardown::{bench_refill_teardown_cycle, bench_refill, imptree_single_elem_range_n, btree_single_delete_n};
+use bench_teardown::{DataMaster, TreapMaster, PlainSet, VebSet, BlockSet, PlainMap, PlainSetSingle, FilteredPlainSet, FilteredIntervalSet, BTreeSetMaster, SplayMaster, IntervalSet, IntervalMap, BSTMaster};
+use bench_teardown::{bench_refill_teardown_cycle, bench_refill, imptree_single_elem_range_n, btree_single_delete_n, layout_find_n,
+                      block_find_n, split_map_teardown_n};
+use teardown_tree::{Bfs, Veb};
 
 use std::time::Duration;
 
@@ -101,6 +103,8 @@ fn bench_refill_impl<M: DataMaster>(_: usize, spec: &[u64]) -> (String, Vec<u64>
 fn main() {
     bench_table("Refill", &[
         BenchJob::new(&bench_refill_impl::<PlainSet>,            &[170000000,   80000000,   12000000,   1100000,    65000,  2400,   230]),
+        BenchJob::new(&bench_refill_impl::<VebSet>,              &[170000000,   80000000,   12000000,   1100000,    65000,  2400,   230]),
+        BenchJob::new(&bench_refill_impl::<BlockSet>,            &[170000000,   80000000,   12000000,   1100000,    65000,  2400,   230]),
         BenchJob::new(&bench_refill_impl::<PlainMap>,            &[170000000,   80000000,   12000000,   1100000,    65000,  2400,   230]),
         BenchJob::new(&bench_refill_impl::<IntervalSet>,         &[150000000,   70000000,   11000000,   1000000,    60000,  2200,   210]),
         BenchJob::new(&bench_refill_impl::<IntervalMap>,         &[150000000,   70000000,   11000000,   1000000,    60000,  2200,   210]),
@@ -113,6 +117,8 @@ fn main() {
 
     bench_table("Full refill/teardown in bulks of 10 items", &[
         BenchJob::new(&bench_teardown_full_impl::<PlainSet>,            &[40000000, 3100000,    300000, 12000,  1100,   70, 7]),
+        BenchJob::new(&bench_teardown_full_impl::<VebSet>,              &[40000000, 3100000,    300000, 12000,  1100,   70, 7]),
+        BenchJob::new(&bench_teardown_full_impl::<BlockSet>,            &[40000000, 3100000,    300000, 12000,  1100,   70, 7]),
         BenchJob::new(&bench_teardown_full_impl::<PlainMap>,            &[40000000, 3100000,    300000, 12000,  1100,   70, 7]),
         BenchJob::new(&bench_teardown_full_impl::<FilteredPlainSet>,    &[24000000, 2000000,    170000, 10000,  1000,   70, 7]),
         BenchJob::new(&bench_teardown_full_impl::<PlainSetSingle>,      &[32000000, 2000000,    120000,  5000,   350,   30, 3]),
@@ -128,6 +134,8 @@ fn main() {
 
     bench_table("Full refill/teardown in bulks of 100 items", &[
         BenchJob::new(&bench_teardown_full_impl::<PlainSet>,            &[0, 8000000, 700000, 70000,  4500,   400, 32]),
+        BenchJob::new(&bench_teardown_full_impl::<VebSet>,              &[0, 8000000, 700000, 70000,  4500,   400, 32]),
+        BenchJob::new(&bench_teardown_full_impl::<BlockSet>,            &[0, 8000000, 700000, 70000,  4500,   400, 32]),
         BenchJob::new(&bench_teardown_full_impl::<PlainMap>,            &[0, 8000000, 700000, 70000,  4500,   400, 32]),
         BenchJob::new(&bench_teardown_full_impl::<FilteredPlainSet>,    &[0, 3000000, 270000, 25000,  2000,   180, 28]),
         BenchJob::new(&bench_teardown_full_impl::<PlainSetSingle>,      &[0, 2200000, 150000,  6000,   500,    50,  5]),
@@ -143,6 +151,8 @@ fn main() {
 
     bench_table("Full refill/teardown in bulks of 1000 items", &[
         BenchJob::new(&bench_teardown_full_impl::<PlainSet>,            &[0, 0, 800000, 80000,  8000,   700,    70]),
+        BenchJob::new(&bench_teardown_full_impl::<VebSet>,              &[0, 0, 800000, 80000,  8000,   700,    70]),
+        BenchJob::new(&bench_teardown_full_impl::<BlockSet>,            &[0, 0, 800000, 80000,  8000,   700,    70]),
         BenchJob::new(&bench_teardown_full_impl::<PlainMap>,            &[0, 0, 800000, 80000,  8000,   700,    70]),
         BenchJob::new(&bench_teardown_full_impl::<FilteredPlainSet>,    &[0, 0, 300000, 25000,  2500,   250,    25]),
         BenchJob::new(&bench_teardown_full_impl::<PlainSetSingle>,      &[0, 0,  80000,  6000,   500,    60,     6]),
@@ -169,6 +179,20 @@ fn main() {
     btree_single_delete_n(100000, 100,  80);
     btree_single_delete_n(1000000, 100, 30);
 
+    layout_find_n::<Bfs>(10000, 1000, 1000);
+    layout_find_n::<Veb>(10000, 1000, 1000);
+    layout_find_n::<Bfs>(1000000, 1000, 300);
+    layout_find_n::<Veb>(1000000, 1000, 300);
+    layout_find_n::<Bfs>(30000000, 1000, 100);
+    layout_find_n::<Veb>(30000000, 1000, 100);
+
+    block_find_n(10000, 1000, 1000);
+    block_find_n(1000000, 1000, 300);
+    block_find_n(30000000, 1000, 100);
+
+    split_map_teardown_n(10000, 100, 1000);
+    split_map_teardown_n(100000, 100, 100);
+    split_map_teardown_n(1000000, 100, 10);
 
 }
 
@@ -185,6 +209,7 @@ mod bench_teardown {
 
     use treap::TreapMap;
     use teardown_tree::{IntervalTeardownSet, IntervalTeardownMap, KeyInterval, Refill, TeardownSet, TeardownMap, ItemFilter};
+    use teardown_tree::{Layout, Veb, BlockTeardownSet, SplitTeardownMap};
     use teardown_tree::util::make_teardown_seq;
     use teardown_tree::sink::{UncheckedVecRefSink, SinkAdapter};
     use super::{nanos, black_box};
@@ -274,6 +299,107 @@ mod bench_teardown {
         println!("average time to delete {} random elements from TeardownTree using delete_range(), {} elements: {}cy, total: {}ms", rm_items, n, avg_cycles, elapsed_nanos/1000000)
     }
 
+    /// Compares the memory layouts on lookups (`contains()` and short `query_range()`s) of random
+    /// keys, which is where the cache misses of the deep levels show up once the tree is larger
+    /// than the cache.
+    pub fn layout_find_n<L: Layout>(n: usize, queries: usize, iters: u64) {
+        let mut rng = XorShiftRng::from_seed([1,2,3,4]);
+        let set: TeardownSet<usize, L> = TeardownSet::with_sorted_layout((0..n).collect());
+        let keys: Vec<_> = (0..queries).map(|_| rng.gen_range(0, n)).collect();
+        let mut output = Vec::with_capacity(10);
+        let (mut find_cycles, mut query_cycles) = (0, 0);
+
+        let start = time::Instant::now();
+        for _ in 0..iters {
+            let mut ts: Timestamp = new_timestamp();
+            for key in keys.iter() {
+                black_box(set.contains(key));
+            }
+            find_cycles += next_elapsed(&mut ts);
+
+            for &key in keys.iter() {
+                output.truncate(0);
+                set.query_range(key..key+10, &mut output);
+                black_box(&output);
+            }
+            query_cycles += next_elapsed(&mut ts);
+        }
+        let elapsed_nanos = nanos(start.elapsed());
+
+        let descr = if L::is_identity() { "BFS" } else { "vEB" };
+        let per_query = iters * queries as u64;
+        println!("average time to find/query a random key in TeardownSet with {} layout, {} elements: {}cy/{}cy, total: {}ms",
+                 descr, n, find_cycles/per_query, query_cycles/per_query, elapsed_nanos/1000000)
+    }
+
+    /// The same as `layout_find_n()`, for `BlockTeardownSet<u64>`.
+    pub fn block_find_n(n: usize, queries: usize, iters: u64) {
+        let mut rng = XorShiftRng::from_seed([1,2,3,4]);
+        let set = BlockTeardownSet::with_sorted((0..n as u64).collect());
+        let keys: Vec<u64> = (0..queries).map(|_| rng.gen_range(0, n as u64)).collect();
+        let mut output = Vec::with_capacity(10);
+        let (mut find_cycles, mut query_cycles) = (0, 0);
+
+        let start = time::Instant::now();
+        for _ in 0..iters {
+            let mut ts: Timestamp = new_timestamp();
+            for key in keys.iter() {
+                black_box(set.contains(key));
+            }
+            find_cycles += next_elapsed(&mut ts);
+
+            for &key in keys.iter() {
+                output.truncate(0);
+                set.query_range(key..key+10, &mut output);
+                black_box(&output);
+            }
+            query_cycles += next_elapsed(&mut ts);
+        }
+        let elapsed_nanos = nanos(start.elapsed());
+
+        let per_query = iters * queries as u64;
+        println!("average time to find/query a random key in BlockTeardownSet, {} elements: {}cy/{}cy, total: {}ms",
+                 n, find_cycles/per_query, query_cycles/per_query, elapsed_nanos/1000000)
+    }
+
+    /// Compares `TeardownMap` and `SplitTeardownMap` with 200-byte values on an incremental refill
+    /// followed by a full teardown in bulks of `bulk` items.
+    pub fn split_map_teardown_n(n: usize, bulk: usize, iters: u64) {
+        type Val = [u64; 25];
+        let items: Vec<(usize, Val)> = (0..n).map(|x| (x, [x as u64; 25])).collect();
+        let ranges: Vec<_> = make_teardown_seq(n, bulk, &mut XorShiftRng::from_seed([1,2,3,4]));
+        let mut output = Vec::with_capacity(bulk);
+
+        let master = TeardownMap::new(items.clone());
+        let mut copy = master.clone();
+        let mut map_cycles = 0;
+        for _ in 0..iters {
+            let mut ts: Timestamp = new_timestamp();
+            copy.refill_incremental(&master);
+            for range in ranges.iter() {
+                output.truncate(0);
+                copy.delete_range(range.clone(), UncheckedVecRefSink::new(&mut output));
+            }
+            map_cycles += next_elapsed(&mut ts);
+        }
+
+        let master = SplitTeardownMap::new(items);
+        let mut copy = master.clone();
+        let mut split_cycles = 0;
+        for _ in 0..iters {
+            let mut ts: Timestamp = new_timestamp();
+            copy.refill_incremental(&master);
+            for range in ranges.iter() {
+                output.truncate(0);
+                copy.delete_range(range.clone(), UncheckedVecRefSink::new(&mut output));
+            }
+            split_cycles += next_elapsed(&mut ts);
+        }
+
+        println!("average time to refill/tear down a map with 200-byte values, {} elements in bulks of {}: TeardownMap {}cy, SplitTeardownMap {}cy",
+                 n, bulk, map_cycles/iters, split_cycles/iters)
+    }
+
     #[inline(never)]
     pub fn bench_refill<M: DataMaster>(n: usize, iters: u64) -> u64 {
         let elems: Vec<_> = (0..n).collect();
@@ -429,6 +555,128 @@ mod bench_teardown {
     }
 
 
+    //----- TeardownSet::delete_range() with the vEB layout -----------------------------------------
+    #[derive(Clone, Debug)]
+    pub struct VebSet(TeardownSet<usize, Veb>);
+
+    impl DataMaster for VebSet {
+        type Cpy = VebSet;
+
+        fn build(elems: Vec<usize>) -> Self {
+            VebSet(TeardownSet::with_layout(elems))
+        }
+
+        fn cpy(&self) -> Self {
+            self.clone()
+        }
+
+        fn size(&self) -> usize {
+            self.0.size()
+        }
+
+        fn descr_cycle() -> String {
+            "TeardownSet<Veb>::delete_range()".to_string()
+        }
+
+        fn descr_refill() -> String {
+            "TeardownSet<Veb>".to_string()
+        }
+    }
+
+    impl DataCopy for VebSet {
+        type Master = VebSet;
+        type T = usize;
+
+        fn delete_range(&mut self, range: Range<usize>, output: &mut Vec<usize>) {
+            self.0.delete_range(range, UncheckedVecRefSink::new(output));
+        }
+
+        #[inline(never)]
+        fn refill(&mut self, master: &Self::Master) {
+            self.0.refill(&master.0)
+        }
+
+        fn size(&self) -> usize {
+            self.0.size()
+        }
+
+        fn clear(&mut self) {
+            self.0.clear();
+        }
+
+        fn as_vec(&self) -> Vec<Self::T> {
+            self.0.iter().cloned().collect()
+        }
+    }
+
+    impl Display for VebSet {
+        fn fmt(&self, fmt: &mut Formatter) -> Result {
+            Display::fmt(&self.0, fmt)
+        }
+    }
+
+
+    //----- BlockTeardownSet::delete_range() -------------------------------------------------------
+    #[derive(Clone, Debug)]
+    pub struct BlockSet(BlockTeardownSet<u64>);
+
+    impl DataMaster for BlockSet {
+        type Cpy = BlockSet;
+
+        fn build(elems: Vec<usize>) -> Self {
+            BlockSet(BlockTeardownSet::new(elems.into_iter().map(|x| x as u64).collect()))
+        }
+
+        fn cpy(&self) -> Self {
+            self.clone()
+        }
+
+        fn size(&self) -> usize {
+            self.0.size()
+        }
+
+        fn descr_cycle() -> String {
+            "BlockTeardownSet::delete_range()".to_string()
+        }
+
+        fn descr_refill() -> String {
+            "BlockTeardownSet".to_string()
+        }
+    }
+
+    impl DataCopy for BlockSet {
+        type Master = BlockSet;
+        type T = u64;
+
+        fn delete_range(&mut self, range: Range<usize>, output: &mut Vec<u64>) {
+            self.0.delete_range(range.start as u64..range.end as u64, UncheckedVecRefSink::new(output));
+        }
+
+        #[inline(never)]
+        fn refill(&mut self, master: &Self::Master) {
+            self.0.refill(&master.0)
+        }
+
+        fn size(&self) -> usize {
+            self.0.size()
+        }
+
+        fn clear(&mut self) {
+            self.0.clear();
+        }
+
+        fn as_vec(&self) -> Vec<Self::T> {
+            self.0.iter().cloned().collect()
+        }
+    }
+
+    impl Display for BlockSet {
+        fn fmt(&self, fmt: &mut Formatter) -> Result {
+            Debug::fmt(&self.0, fmt)
+        }
+    }
+
+
     //----- TeardownSet::delete() ------------------------------------------------------------------
     #[derive(Clone, Debug)]
     pub struct PlainSetSingle(TeardownSet<usize>);
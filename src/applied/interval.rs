@@ -3,6 +3,7 @@ use std::ops::{Deref, DerefMut, Range};
 use std::fmt;
 
 use base::{Node, Entry};
+use base::snapshot::{Pod, SnapshotNode, write_field};
 
 
 pub trait Interval: Sized+Ord+Clone {
@@ -31,6 +32,7 @@ impl Interval for usize {
 
 
 #[derive(Debug, Clone, Copy)]
+#[repr(C)]
 pub struct KeyInterval<K: Ord+Clone> {
     a: K,
     b: K
@@ -58,6 +60,9 @@ impl<K: Ord+Clone> Interval for KeyInterval<K> {
     }
 }
 
+// Two fields of the same `Pod` type in a `repr(C)` struct leave no room for padding.
+unsafe impl<K: Ord+Pod> Pod for KeyInterval<K> {}
+
 impl<K: Ord+Clone> From<Range<K>> for KeyInterval<K> {
     fn from(range: Range<K>) -> Self {
         Self::from_range(&range)
@@ -107,6 +112,14 @@ impl<Iv: Interval, V> Node for IvNode<Iv, V> {
     }
 }
 
+unsafe impl<Iv: Interval+Pod, V: Pod> SnapshotNode for IvNode<Iv, V> where Iv::K: Pod {
+    fn write_fields(&self, out: &mut [u8]) {
+        write_field(self, self.entry.key(), out);
+        write_field(self, self.entry.val(), out);
+        write_field(self, &self.maxb, out);
+    }
+}
+
 impl<K: Ord+Clone> PartialEq for KeyInterval<K> {
     fn eq(&self, other: &Self) -> bool {
         self.a() == other.a() && self.b() == other.b()
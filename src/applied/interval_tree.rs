@@ -1,6 +1,6 @@
 use applied::AppliedTree;
 use applied::interval::{Interval, IvNode};
-use base::{TreeRepr, Sink, NoopFilter, Node, Entry, BulkDeleteCommon, ItemVisitor, ItemFilter, lefti, righti, parenti};
+use base::{TreeRepr, Sink, NoopFilter, Node, Entry, BulkDeleteCommon, ItemVisitor, ItemFilter, lefti, righti, parenti, left_enclosing};
 
 use std::ops::{Deref, DerefMut};
 use std::fmt::{Debug, Display, Formatter};
@@ -12,6 +12,11 @@ pub struct IvTree<Iv: Interval, V> {
     pub repr: UnsafeCell<TreeRepr<IvNode<Iv, V>>>,
 }
 
+// The `UnsafeCell` only lets `work()` take a bitwise copy of `repr`; `repr` is never modified
+// through `&self` (queries do not go through `work()`), so sharing an `IvTree` is as safe as
+// sharing the `TreeRepr` itself.
+unsafe impl<Iv: Interval+Sync, V: Sync> Sync for IvTree<Iv, V> where Iv::K: Sync {}
+
 //---- constructors and helpers --------------------------------------------------------------------
 impl<Iv: Interval, V> IvTree<Iv, V> {
     fn update_parent_maxb(&mut self, child_idx: usize) {
@@ -32,6 +37,20 @@ impl<Iv: Interval, V> IvTree<Iv, V> {
         }
     }
 
+    /// Rebuilds the remaining items into a storage just large enough to hold them (see
+    /// `TreeRepr::compact()`) and recomputes `maxb`.
+    pub fn compact(&mut self) {
+        self.repr_mut().compact();
+        self.init_maxb();
+    }
+
+    #[inline]
+    fn compact_if_needed(&mut self) {
+        if self.repr().should_compact() {
+            self.compact();
+        }
+    }
+
     fn repr(&self) -> &TreeRepr<IvNode<Iv, V>> {
         // This is safe according to UnsafeCell::get(), because there are no mutable aliases to
         // self.repr possible at the time when &self is taken.
@@ -70,6 +89,47 @@ impl<Iv: Interval, V> IvTree<Iv, V> {
             };
     }
 
+    /// Recomputes `maxb` of every item in O(n) time. Used after the nodes have been loaded from an
+    /// untrusted source, which might carry wrong `maxb` values.
+    #[cfg(feature = "serde")]
+    pub fn update_all_maxb(&mut self) {
+        self.update_subtree_maxb(0);
+    }
+
+    // Recomputes maxb of every item in the subtree rooted at `root`, bottom-up.
+    fn update_subtree_maxb(&mut self, root: usize) {
+        let mut levels = Vec::new();
+        let (mut from, mut width) = (root, 1);
+        while from < self.capacity() {
+            levels.push((from, width));
+            from = lefti(from);
+            width <<= 1;
+        }
+
+        for &(from, width) in levels.iter().rev() {
+            let to = cmp::min(from + width, self.capacity());
+            for idx in from..to {
+                if !self.is_nil(idx) {
+                    self.update_maxb(idx);
+                }
+            }
+        }
+    }
+
+    #[inline]
+    fn update_ancestors_after_insert(&mut self, mut idx: usize) {
+        while idx != 0 {
+            let b = self.node(idx).maxb.clone();
+            idx = parenti(idx);
+            let parent = self.node_mut(idx);
+            if b > parent.maxb {
+                parent.maxb = b;
+            } else {
+                break;
+            }
+        }
+    }
+
     #[inline]
     fn update_ancestors_after_delete(&mut self, mut idx: usize, idx_to: usize, removed_b: &Iv::K) {
         while idx != idx_to {
@@ -83,8 +143,48 @@ impl<Iv: Interval, V> IvTree<Iv, V> {
     }
 }
 
+
+#[cfg(feature = "rayon")]
+impl<Iv: Interval+Send, V: Send> IvTree<Iv, V> where Iv::K: Send {
+    /// Same as `new()`, but the items are sorted and the tree is built in parallel.
+    pub fn par_new(items: Vec<(Iv, V)>) -> Self {
+        let mut tree = Self::with_repr(TreeRepr::par_new(items));
+        tree.par_init_maxb();
+        tree
+    }
+
+    /// Same as `with_sorted()`, but the tree is built in parallel.
+    /// Note: the argument must be sorted!
+    pub fn par_with_sorted(sorted: Vec<(Iv, V)>) -> Self {
+        let mut tree = Self::with_repr(TreeRepr::par_with_sorted(sorted));
+        tree.par_init_maxb();
+        tree
+    }
+
+    fn par_init_maxb(&mut self) {
+        self.repr_mut().par_fold_postorder(&|node: &mut IvNode<Iv, V>, left: Option<Iv::K>, right: Option<Iv::K>| {
+            let mut maxb = node.key().b().clone();
+            for b in left.into_iter().chain(right) {
+                if b > maxb {
+                    maxb = b;
+                }
+            }
+            node.maxb = maxb.clone();
+            maxb
+        });
+    }
+}
+
 //---- single-item queries -------------------------------------------------------------------------
 impl<Iv: Interval, V> IvTree<Iv, V> {
+    /// Inserts the item into the tree. Items with equal keys are kept in insertion order.
+    pub fn insert(&mut self, key: Iv, val: V) {
+        let root = self.repr_mut().insert(key, val);
+        // `insert()` has either placed a new leaf at `root`, or rebuilt the whole subtree
+        self.update_subtree_maxb(root);
+        self.update_ancestors_after_insert(root);
+    }
+
     /// Deletes the item with the given key from the tree and returns it (or None).
     #[inline]
     pub fn delete<Q>(&mut self, query: &Q) -> Option<V>
@@ -94,12 +194,33 @@ impl<Iv: Interval, V> IvTree<Iv, V> {
         if self.is_nil(idx) {
             None
         } else {
-            let entry = self.delete_idx(idx);
-            self.update_ancestors_after_delete(idx, 0, entry.key().b());
-            Some(entry.into_tuple().1)
+            Some(self.delete_at(idx).into_tuple().1)
         }
     }
 
+    /// Deletes the smallest item from the tree and returns it (or None if the tree is empty).
+    #[inline]
+    pub fn pop_first(&mut self) -> Option<(Iv, V)> {
+        self.first_idx().map(|idx| self.delete_at(idx).into_tuple())
+    }
+
+    /// Deletes the largest item from the tree and returns it (or None if the tree is empty).
+    #[inline]
+    pub fn pop_last(&mut self) -> Option<(Iv, V)> {
+        self.last_idx().map(|idx| self.delete_at(idx).into_tuple())
+    }
+
+    // Deletes the item at `idx` and updates `maxb` of its ancestors.
+    // The caller must make sure that `!is_nil(idx)`.
+    #[inline]
+    fn delete_at(&mut self, idx: usize) -> Entry<Iv, V> {
+        let entry = self.delete_idx(idx);
+        self.update_ancestors_after_delete(idx, 0, entry.key().b());
+        self.repr_mut().update_counts();
+        self.compact_if_needed();
+        entry
+    }
+
     // The caller must make sure that `!is_nil(idx)`.
     #[inline]
     fn delete_idx(&mut self, idx: usize) -> Entry<Iv, V> {
@@ -179,11 +300,74 @@ impl<Iv: Interval, V> IvTree<Iv, V> {
     }
 
 
-    pub fn query_overlap<'a, Q, S>(&'a self, idx: usize, query: &Q, sink: S)
+    pub fn query_overlap<'a, Q, S>(&'a self, idx: usize, query: &Q, mut sink: S)
         where Q: Interval<K=Iv::K>,
               S: Sink<&'a (Iv, V)>
     {
-        self.work(sink, NoopFilter, |worker: &mut IvWorker<Iv,V,S,_>| worker.query_overlap_rec(idx, query))
+        self.query_overlap_rec(idx, query, &mut sink)
+    }
+
+    // Only reads the tree, so concurrent queries through shared references are safe.
+    fn query_overlap_rec<'a, Q, S>(&'a self, idx: usize, query: &Q, sink: &mut S)
+        where Q: Interval<K=Iv::K>,
+              S: Sink<&'a (Iv, V)>
+    {
+        let repr = self.repr();
+        if repr.is_nil(idx) {
+            return;
+        }
+
+        let node = repr.node(idx);
+        let k: &Iv = node.entry.key();
+
+        if &node.maxb < query.a() {
+            // whole subtree outside the range
+        } else if query.b() <= k.a() && k.a() != query.a() {
+            // root and right are outside the range
+            self.query_overlap_rec(lefti(idx), query, sink);
+        } else {
+            self.query_overlap_rec(lefti(idx), query, sink);
+            if query.overlaps(k) { sink.consume(node.as_tuple()) }
+            self.query_overlap_rec(righti(idx), query, sink);
+        }
+    }
+
+    /// Returns a lazy in-order iterator over the items that overlap with `query`. Subtrees are
+    /// pruned in the same way as in `query_overlap`.
+    pub fn overlapping<'a, Q>(&'a self, query: &'a Q) -> OverlapIter<'a, Iv, V, Q>
+        where Q: Interval<K=Iv::K>
+    {
+        OverlapIter::new(self.repr(), query)
+    }
+
+    /// Calls `f` on every item that overlaps with `query` in order, giving it mutable access to
+    /// the value.
+    pub fn for_each_overlap_mut<Q, F>(&mut self, query: &Q, mut f: F)
+        where Q: Interval<K=Iv::K>, F: FnMut(&Iv, &mut V)
+    {
+        self.for_each_overlap_mut_rec(0, query, &mut f)
+    }
+
+    fn for_each_overlap_mut_rec<Q, F>(&mut self, idx: usize, query: &Q, f: &mut F)
+        where Q: Interval<K=Iv::K>, F: FnMut(&Iv, &mut V)
+    {
+        if self.is_nil(idx) || &self.node(idx).maxb < query.a() {
+            // whole subtree outside the range
+            return;
+        }
+
+        self.for_each_overlap_mut_rec(lefti(idx), query, f);
+
+        let k = self.key(idx);
+        if query.b() <= k.a() && k.a() != query.a() {
+            // root and right are outside the range
+            return;
+        }
+        if query.overlaps(k) {
+            let (key, val) = self.node_mut(idx).entry.key_val_mut();
+            f(key, val);
+        }
+        self.for_each_overlap_mut_rec(righti(idx), query, f);
     }
 
 //    /// returns index of the first item in the tree that may overlap `query`
@@ -208,7 +392,7 @@ impl<Iv: Interval, V> IvTree<Iv, V> {
 //    }
 
     #[inline]
-    fn work<S, Flt, F, R>(&self, sink: S, filter: Flt, mut f: F) -> R
+    fn work<S, Flt, F, R>(&mut self, sink: S, filter: Flt, mut f: F) -> R
         where Flt: ItemFilter<Iv>,
               F: FnMut(&mut IvWorker<Iv,V,S,Flt>) -> R
     {
@@ -219,21 +403,87 @@ impl<Iv: Interval, V> IvTree<Iv, V> {
         let mut worker = IvWorker::new(repr, sink, filter);
         let result = f(&mut worker);
 
-        // We do not reallocate the vecs inside repr, and the only thing that changes in its memory
-        // is the size of the tree. So we can get away with only updating the size as opposed to
-        // doing another expensive copy of the whole TreeRepr struct.
+        // We do not reallocate the vecs inside repr (except for the count log), and the only other
+        // thing that changes in its memory is the size of the tree. So we can get away with only
+        // updating these as opposed to doing another expensive copy of the whole TreeRepr struct.
         //
         // This optimization results in a measurable speed-up to tiny/small range queries.
         unsafe {
-            (*self.repr.get()).size = worker.repr.size;
-            mem::forget(worker.repr);
+            self.repr_mut().merge_copy(worker.repr);
         }
+        self.compact_if_needed();
 
         result
     }
 }
 
 
+/// A lazy in-order iterator over the items overlapping with a query interval. The traversal is
+/// stackless: it walks the implicit tree using index arithmetic.
+pub struct OverlapIter<'a, Iv: Interval+'a, V: 'a, Q: 'a> {
+    tree: &'a TreeRepr<IvNode<Iv, V>>,
+    query: &'a Q,
+    next_idx: Option<usize>,
+}
+
+impl<'a, Iv: Interval, V, Q: Interval<K=Iv::K>> OverlapIter<'a, Iv, V, Q> {
+    fn new(tree: &'a TreeRepr<IvNode<Iv, V>>, query: &'a Q) -> Self {
+        let mut iter = OverlapIter { tree: tree, query: query, next_idx: None };
+        iter.next_idx = iter.descend(0);
+        iter
+    }
+
+    #[inline]
+    fn is_pruned(&self, idx: usize) -> bool {
+        // whole subtree outside the range
+        self.tree.is_nil(idx) || &self.tree.node(idx).maxb < self.query.a()
+    }
+
+    /// Returns the first node to visit in the subtree rooted at `idx`.
+    fn descend(&self, mut idx: usize) -> Option<usize> {
+        if self.is_pruned(idx) {
+            return self.ascend(idx);
+        }
+        while !self.is_pruned(lefti(idx)) {
+            idx = lefti(idx);
+        }
+        Some(idx)
+    }
+
+    /// Returns the node to visit after the subtree rooted at `idx` is done with.
+    #[inline]
+    fn ascend(&self, idx: usize) -> Option<usize> {
+        let left = left_enclosing(idx + 1);
+        if left == 0 {
+            None
+        } else {
+            Some(parenti(left - 1))
+        }
+    }
+}
+
+impl<'a, Iv: Interval, V, Q: Interval<K=Iv::K>> Iterator for OverlapIter<'a, Iv, V, Q> {
+    type Item = &'a (Iv, V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some(idx) = self.next_idx {
+            let node = self.tree.node(idx);
+            let k = node.entry.key();
+            if self.query.b() <= k.a() && k.a() != self.query.a() {
+                // root and right are outside the range
+                self.next_idx = self.ascend(idx);
+            } else {
+                self.next_idx = self.descend(righti(idx));
+                if self.query.overlaps(k) {
+                    return Some(node.as_tuple());
+                }
+            }
+        }
+        None
+    }
+}
+
+
 impl<Iv: Interval, V> AppliedTree<IvNode<Iv, V>> for IvTree<Iv, V> {
     /// Constructs a new AppliedTree
     fn new(items: Vec<(Iv, V)>) -> Self {
@@ -320,40 +570,6 @@ pub struct IvWorker<Iv, V, S, Flt>
 }
 
 
-// query_overlap worker
-impl<'a, Iv: 'a, V: 'a, S, Flt> IvWorker<Iv, V, S, Flt>
-    where Iv: Interval, S: Sink<&'a (Iv, V)>, Flt: ItemFilter<Iv>
-{
-    fn query_overlap_rec<Q>(&mut self, idx: usize, query: &Q)
-        where Q: Interval<K=Iv::K>
-    {
-        if self.is_nil(idx) {
-            return;
-        }
-
-        // This is safe: it is guaranteed that the reference (`&node.entry.item`) does not outlive
-        // the storage (`self.data`), because the declaration:
-        //          IvTree::query_overlap::<'a, _, S: Sink<&'a _>>()
-        // ensures that the lifetime of references inside Sink is bounded from above by the lifetime
-        // of &self.
-        let node = self.node_unsafe(idx);
-        let k: &Iv = node.entry.key();
-
-        if &node.maxb < query.a() {
-            // whole subtree outside the range
-        } else if query.b() <= k.a() && k.a() != query.a() {
-            // root and right are outside the range
-            self.query_overlap_rec(lefti(idx), query);
-        } else {
-            self.query_overlap_rec(lefti(idx), query);
-            if query.overlaps(k) { self.sink.consume(node.as_tuple()) }
-            self.query_overlap_rec(righti(idx), query);
-        }
-    }
-
-}
-
-
 // filter_overlap worker
 impl<Iv, V, S, Flt> IvWorker<Iv, V, S, Flt>
     where Iv: Interval, S: Sink<(Iv, V)>, Flt: ItemFilter<Iv>
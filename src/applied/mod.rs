@@ -4,8 +4,9 @@ pub mod interval;
 
 
 use base::{Node, TreeDeref, TreeRepr};
+use base::layout::{Layout, Bfs};
 
-pub trait AppliedTree<N: Node>: TreeDeref<N> + Sized {
+pub trait AppliedTree<N: Node, L: Layout = Bfs>: TreeDeref<N, L> + Sized {
     /// Constructs a new AppliedTree
     fn new(items: Vec<(N::K, N::V)>) -> Self {
         Self::with_repr(TreeRepr::new(items))
@@ -22,7 +23,7 @@ pub trait AppliedTree<N: Node>: TreeDeref<N> + Sized {
     }
 
 
-    fn with_repr(repr: TreeRepr<N>) -> Self;
+    fn with_repr(repr: TreeRepr<N, L>) -> Self;
 
     unsafe fn with_shape(items: Vec<Option<(N::K, N::V)>>) -> Self;
 }
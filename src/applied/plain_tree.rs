@@ -1,26 +1,35 @@
 use applied::AppliedTree;
-use base::{Key, Node, TreeRepr, Traverse, Sink, BulkDeleteCommon, ItemVisitor, Entry, righti, lefti};
-use base::{ItemFilter, TraversalDriver, TraversalDecision, RangeRefDriver, RangeDriver, NoopFilter};
-
-use std::ops::Range;
+use base::{Key, Node, TreeRepr, Traverse, Sink, BulkDeleteCommon, ItemVisitor, Entry, righti, lefti, depth_of};
+#[cfg(feature = "rayon")] use base::ChunkedDriver;
+use base::{ItemFilter, TraversalDriver, TraversalDecision, RangeRefDriver, BoundsDriver, SlotRangeDriver, KeyBounds,
+           NoopFilter};
+use base::layout::{Layout, Bfs};
+use base::snapshot::{Pod, SnapshotNode, write_field};
+
+use std::ops::{Range, RangeBounds};
 use std::ops::{Deref, DerefMut};
 use std::fmt::{Debug, Display, Formatter};
 use std::marker::PhantomData;
 use std::cell::UnsafeCell;
-use std::{fmt, ptr, mem};
+use std::{fmt, ptr};
+#[cfg(feature = "rayon")] use std::mem;
 
-pub struct PlTree<K: Key, V> {
-    pub repr: UnsafeCell<TreeRepr<PlNode<K, V>>>,
+pub struct PlTree<K: Key, V, L: Layout = Bfs> {
+    pub repr: UnsafeCell<TreeRepr<PlNode<K, V>, L>>,
 }
 
+// The `UnsafeCell` only lets `work()` take a bitwise copy of `repr`; `repr` is never modified
+// through `&self`, so sharing a `PlTree` is as safe as sharing the `TreeRepr` itself.
+unsafe impl<K: Key+Sync, V: Sync, L: Layout> Sync for PlTree<K, V, L> {}
+
 #[derive(Clone)]
 pub struct PlNode<K: Key, V> {
     pub entry: Entry<K, V>,
 }
 
 
-impl<K: Key, V> AppliedTree<PlNode<K, V>> for PlTree<K, V> {
-    fn with_repr(repr: TreeRepr<PlNode<K, V>>) -> Self {
+impl<K: Key, V, L: Layout> AppliedTree<PlNode<K, V>, L> for PlTree<K, V, L> {
+    fn with_repr(repr: TreeRepr<PlNode<K, V>, L>) -> Self {
         PlTree { repr: UnsafeCell::new(repr) }
     }
 
@@ -66,6 +75,13 @@ impl<K: Key, V> Node for PlNode<K, V> {
     }
 }
 
+unsafe impl<K: Key+Pod, V: Pod> SnapshotNode for PlNode<K, V> {
+    fn write_fields(&self, out: &mut [u8]) {
+        write_field(self, self.entry.key(), out);
+        write_field(self, self.entry.val(), out);
+    }
+}
+
 impl<K: Key+fmt::Debug, V> fmt::Debug for PlNode<K, V> {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
         fmt::Debug::fmt(self.entry.key(), fmt)
@@ -74,40 +90,40 @@ impl<K: Key+fmt::Debug, V> fmt::Debug for PlNode<K, V> {
 
 
 //---- constructors and helpers --------------------------------------------------------------------
-impl<K: Key, V> PlTree<K, V> {
+impl<K: Key, V, L: Layout> PlTree<K, V, L> {
     /// Constructs a new PlTree
-    pub fn new(items: Vec<(K, V)>) -> PlTree<K, V> {
+    pub fn new(items: Vec<(K, V)>) -> PlTree<K, V, L> {
         PlTree::with_repr(TreeRepr::new(items))
     }
 
-    pub fn with_repr(repr: TreeRepr<PlNode<K, V>>) -> PlTree<K, V> {
+    pub fn with_repr(repr: TreeRepr<PlNode<K, V>, L>) -> PlTree<K, V, L> {
         PlTree { repr: UnsafeCell::new(repr) }
     }
 
     /// Constructs a new PlTree
     /// Note: the argument must be sorted!
-    pub fn with_sorted(sorted: Vec<(K, V)>) -> PlTree<K, V> {
+    pub fn with_sorted(sorted: Vec<(K, V)>) -> PlTree<K, V, L> {
         PlTree::with_repr(TreeRepr::with_sorted(sorted))
     }
 
-    pub fn with_nodes(nodes: Vec<Option<PlNode<K, V>>>) -> PlTree<K, V> {
+    pub fn with_nodes(nodes: Vec<Option<PlNode<K, V>>>) -> PlTree<K, V, L> {
         PlTree::with_repr(TreeRepr::with_nodes(nodes))
     }
 
 
-    fn repr(&self) -> &TreeRepr<PlNode<K, V>> {
+    fn repr(&self) -> &TreeRepr<PlNode<K, V>, L> {
         // This is safe according to UnsafeCell::get(), because there are no mutable aliases to
         // self.repr possible at the time when &self is taken.
         unsafe { &*self.repr.get() }
     }
 
-    fn repr_mut(&mut self) -> &mut TreeRepr<PlNode<K, V>> {
+    fn repr_mut(&mut self) -> &mut TreeRepr<PlNode<K, V>, L> {
         // This is safe according to UnsafeCell::get(), because the access to self.repr is unique at
         // the time when &mut self is taken.
         unsafe { &mut *self.repr.get() }
     }
 
-    pub fn into_repr(self) -> TreeRepr<PlNode<K, V>> {
+    pub fn into_repr(self) -> TreeRepr<PlNode<K, V>, L> {
         // This is safe according to UnsafeCell::into_inner(), because no thread can be inspecting
         // the inner value when self is passed by value.
         unsafe { self.repr.into_inner() }
@@ -117,7 +133,13 @@ impl<K: Key, V> PlTree<K, V> {
 
 
 //---- single-item queries -------------------------------------------------------------------------
-impl<K: Key, V> PlTree<K, V> {
+impl<K: Key, V, L: Layout> PlTree<K, V, L> {
+    /// Inserts the item into the tree. Items with equal keys are kept in insertion order.
+    #[inline]
+    pub fn insert(&mut self, key: K, val: V) {
+        self.repr_mut().insert(key, val);
+    }
+
     /// Deletes the item with the given key from the tree and returns it (or None).
     #[inline]
     pub fn delete<Q: PartialOrd<K>>(&mut self, query: &Q) -> Option<V> {
@@ -125,13 +147,53 @@ impl<K: Key, V> PlTree<K, V> {
         if self.is_nil(idx) {
             None
         } else {
-            Some(self.delete_idx(idx))
+            let (_, val) = self.delete_idx(idx);
+            self.repr_mut().update_counts();
+            self.compact_if_needed();
+            Some(val)
         }
     }
 
+    /// Deletes the smallest item from the tree and returns it (or None if the tree is empty).
+    #[inline]
+    pub fn pop_first(&mut self) -> Option<(K, V)> {
+        let item = self.first_idx().map(|idx| self.delete_idx(idx));
+        self.repr_mut().update_counts();
+        self.compact_if_needed();
+        item
+    }
+
+    /// Deletes the largest item from the tree and returns it (or None if the tree is empty).
+    #[inline]
+    pub fn pop_last(&mut self) -> Option<(K, V)> {
+        let item = self.last_idx().map(|idx| self.delete_idx(idx));
+        self.repr_mut().update_counts();
+        self.compact_if_needed();
+        item
+    }
+
+    /// Deletes the item at `idx` and returns it along with the index of the item that followed it
+    /// (or None if it was the last one). The caller must ensure that `!is_nil(idx)`.
+    pub fn delete_at(&mut self, idx: usize) -> ((K, V), Option<usize>) {
+        // `delete_idx()` fills the hole at `idx` with the predecessor or the successor of the item
+        // (in that order of preference), or leaves the other items in place if it is a leaf.
+        let (item, next) =
+            if self.has_left(idx) {
+                let item = self.delete_idx(idx);
+                (item, self.next_idx(idx))
+            } else if self.has_right(idx) {
+                (self.delete_idx(idx), Some(idx))
+            } else {
+                let next = self.next_idx(idx);
+                (self.delete_idx(idx), next)
+            };
+        self.repr_mut().update_counts();
+        (item, next)
+    }
+
     // The caller must ensure that `!is_nil(idx)`.
     #[inline]
-    fn delete_idx(&mut self, idx: usize) -> V {
+    fn delete_idx(&mut self, idx: usize) -> (K, V) {
         debug_assert!(!self.is_nil(idx));
 
         let node = self.take(idx);
@@ -141,7 +203,7 @@ impl<K: Key, V> PlTree<K, V> {
         } else if self.has_right(idx) {
             self.delete_min(idx, righti(idx));
         }
-        node.entry.into_tuple().1
+        node.entry.into_tuple()
     }
 
 
@@ -192,22 +254,89 @@ impl<K: Key, V> PlTree<K, V> {
 
 
 //---- range queries -------------------------------------------------------------------------------
-impl<K: Key, V> PlTree<K, V> {
+impl<K: Key, V, L: Layout> PlTree<K, V, L> {
     /// Deletes all items inside `range` from the tree and feeds them into `sink`.
     /// The items are returned in order.
     #[inline]
-    pub fn delete_range<Q, S>(&mut self, range: Range<Q>, sink: S)
-        where Q: PartialOrd<K>, S: Sink<(K, V)>
+    pub fn delete_range<Q, R, S>(&mut self, range: R, sink: S)
+        where Q: PartialOrd<K>+Clone, R: RangeBounds<Q>, S: Sink<(K, V)>
     {
-        self.filter_with_driver(RangeDriver::new(range, sink), NoopFilter)
+        self.filter_range(range, NoopFilter, sink)
     }
 
     /// Deletes all items inside `range` that match `filter` from the tree and feeds them into
     /// `sink`. The items are returned in order.
-    pub fn filter_range<Q: PartialOrd<K>, Flt, S>(&mut self, range: Range<Q>, filter: Flt, sink: S)
-        where Flt: ItemFilter<K>, S: Sink<(K, V)>
+    pub fn filter_range<Q, R, Flt, S>(&mut self, range: R, filter: Flt, sink: S)
+        where Q: PartialOrd<K>+Clone, R: RangeBounds<Q>, Flt: ItemFilter<K>, S: Sink<(K, V)>
+    {
+        self.filter_with_driver(BoundsDriver::new(KeyBounds::compat(range), sink), filter)
+    }
+
+    /// Deletes the first `count` items inside `bounds` (more if the last of them has duplicates)
+    /// and feeds them into `sink` in order. Returns false if there were no items inside `bounds`.
+    pub fn delete_range_front<Q, S>(&mut self, bounds: &KeyBounds<Q>, count: usize, sink: S) -> bool
+        where Q: PartialOrd<K>, S: Sink<(K, V)>
     {
-        self.filter_with_driver(RangeDriver::new(range, sink), filter)
+        debug_assert!(count > 0);
+        // If the range is empty, the first item above its start is already above its end.
+        let from = self.lower_bound(bounds);
+        if self.is_nil(from) || !bounds.below_end(self.key(from)) {
+            return false;
+        }
+
+        let mut last = (from, 0);
+        TreeRepr::traverse_inorder_from(self, from, 0, &mut last, |this, last, idx| {
+            if bounds.below_end(this.key(idx)) {
+                *last = (idx, last.1 + 1);
+                last.1 == count
+            } else {
+                true
+            }
+        });
+
+        // Every item between the first and the last one is inside `bounds`. The deleted items are
+        // gone from the tree, so the next call starts right after them.
+        let chunk = KeyBounds::new(self.key(from).clone() ..= self.key(last.0).clone());
+        self.filter_with_driver(BoundsDriver::new(chunk, sink), NoopFilter);
+        true
+    }
+
+    /// Deletes the `n` smallest items (or all items, if there are fewer) and feeds them into `sink`
+    /// in order.
+    pub fn delete_first<S>(&mut self, n: usize, sink: S)
+        where S: Sink<(K, V)>
+    {
+        if n >= self.size() {
+            return self.delete_range::<K, _, _>(.., sink);
+        } else if n == 0 {
+            return;
+        }
+
+        // The items are deleted up to the n-th one in-order, which is found by walking from the
+        // first one. Duplicates of its key that follow it stay in the tree.
+        let mut last = self.first_idx().unwrap();
+        for _ in 1..n {
+            last = self.next_idx(last).unwrap();
+        }
+        self.filter_with_driver(SlotRangeDriver::new(None, Some(last), sink), NoopFilter);
+    }
+
+    /// Deletes the `n` largest items (or all items, if there are fewer) and feeds them into `sink`
+    /// in order.
+    pub fn delete_last<S>(&mut self, n: usize, sink: S)
+        where S: Sink<(K, V)>
+    {
+        if n >= self.size() {
+            return self.delete_range::<K, _, _>(.., sink);
+        } else if n == 0 {
+            return;
+        }
+
+        let mut first = self.last_idx().unwrap();
+        for _ in 1..n {
+            first = self.prev_idx(first).unwrap();
+        }
+        self.filter_with_driver(SlotRangeDriver::new(Some(first), None, sink), NoopFilter);
     }
 
     /// Deletes all items inside `range` from the tree and feeds them into `sink`. The items are
@@ -232,73 +361,148 @@ impl<K: Key, V> PlTree<K, V> {
     pub fn filter_with_driver<D, Flt>(&mut self, driver: D, filter: Flt)
         where D: TraversalDriver<K, V>, Flt: ItemFilter<K>
     {
-        self.work(driver, filter, |worker: &mut PlWorker<K,V,D,Flt>| worker.filter())
+        self.work(driver, filter, |worker: &mut PlWorker<K,V,D,Flt,L>| worker.filter())
     }
 
-    pub fn query_range<'a, Q, S>(&'a self, query: Range<Q>, mut sink: S)
-        where Q: PartialOrd<K>, S: Sink<&'a (K, V)>
+    /// Feeds references to all items inside `range` into `sink`. The items are returned in order.
+    pub fn query_range<'a, Q, R, S>(&'a self, range: R, mut sink: S)
+        where Q: PartialOrd<K>+Clone, R: RangeBounds<Q>, S: Sink<&'a (K, V)>
     {
-        let mut from = self.index_of(&query.start);
+        // If the range is empty, the first item above its start is already above its end.
+        let bounds = KeyBounds::compat(range);
+        let from = self.lower_bound(&bounds);
         if self.is_nil(from) {
-            from = self.succ(from);
-            if self.is_nil(from) {
-                return;
-            }
+            return;
         }
 
         TreeRepr::traverse_inorder_from(self, from, 0, &mut sink, |this, sink, idx| {
             let node = this.node(idx);
-            if &query.end <= node.key() && &query.start != node.key() {
-                true
-            } else {
+            if bounds.below_end(node.key()) {
                 sink.consume(node.as_tuple());
                 false
+            } else {
+                true
             }
         })
     }
 
+    /// Calls `f` on every item inside `range` in order, giving it mutable access to the value.
+    pub fn for_each_in_range_mut<Q, R, F>(&mut self, range: R, f: F)
+        where Q: PartialOrd<K>+Clone, R: RangeBounds<Q>, F: FnMut(&K, &mut V)
+    {
+        self.repr_mut().for_each_in_range_mut(&KeyBounds::new(range), f)
+    }
+
 
     #[inline]
     fn work<D, Flt, F, R>(&mut self, driver: D, filter: Flt, mut f: F) -> R
         where D: TraversalDriver<K, V>,
               Flt: ItemFilter<K>,
-              F: FnMut(&mut PlWorker<K,V,D,Flt>) -> R
+              F: FnMut(&mut PlWorker<K,V,D,Flt,L>) -> R
     {
         // TODO: this can be sped up in several ways, e.g. having TreeRepr::filter of &Flt type, then we don't have to copy repr
-        let repr: TreeRepr<PlNode<K, V>> = unsafe {
+        let repr: TreeRepr<PlNode<K, V>, L> = unsafe {
             ptr::read(self.repr.get())
         };
 
         let mut worker = PlWorker::new(repr, driver, filter);
         let result = f(&mut worker);
 
-        // We do not reallocate the vecs inside repr, and the only thing that changes in its memory
-        // is the size of the tree. So we can get away with only updating the size as opposed to
-        // doing another expensive copy of the whole TreeRepr struct.
+        // We do not reallocate the vecs inside repr (except for the count log), and the only other
+        // thing that changes in its memory is the size of the tree. So we can get away with only
+        // updating these as opposed to doing another expensive copy of the whole TreeRepr struct.
         //
         // This optimization results in a measurable speed-up to tiny/small range queries.
-        self.repr_mut().size = worker.repr.size;
-        mem::forget(worker.repr);
+        unsafe {
+            self.repr_mut().merge_copy(worker.repr);
+        }
+        self.compact_if_needed();
 
         result
     }
+
+    // `delete_at()` does not call this: the cursors rely on the indices it returns.
+    #[inline]
+    fn compact_if_needed(&mut self) {
+        if self.repr().should_compact() {
+            self.repr_mut().compact();
+        }
+    }
 }
 
 
 
-impl<K: Key+Clone+Debug, V> Debug for PlTree<K, V> {
+/// The minimum number of slots in a subtree for the parallel operations to split it among threads.
+#[cfg(feature = "rayon")]
+pub const PAR_MIN_SUBTREE: usize = 1 << 14;
+
+#[cfg(feature = "rayon")]
+impl<K: Key+Send, V: Send> PlTree<K, V> {
+    /// Same as `delete_range()`, but large subtrees inside the range are consumed in parallel. The
+    /// items are fed into sinks created by `sink_factory`; the sinks are returned in order, so that
+    /// concatenating their contents yields the deleted items in order. The resulting tree is the
+    /// same as after `delete_range()`, except that the empty range `a..a` deletes nothing.
+    #[inline]
+    pub fn par_delete_range<R, S, F>(&mut self, range: R, sink_factory: F) -> Vec<S>
+        where R: RangeBounds<K>, S: Sink<(K, V)>+Send, F: Fn() -> S + Sync
+    {
+        self.par_delete_range_with(range, PAR_MIN_SUBTREE, sink_factory)
+    }
+
+    /// Same as `par_delete_range()`, with subtrees of at least `min_subtree` slots split among threads.
+    pub fn par_delete_range_with<R, S, F>(&mut self, range: R, min_subtree: usize, sink_factory: F) -> Vec<S>
+        where R: RangeBounds<K>, S: Sink<(K, V)>+Send, F: Fn() -> S + Sync
+    {
+        let bounds = KeyBounds::new(range);
+        if bounds.is_empty() {
+            return Vec::new();
+        }
+
+        // The traversal is sequential, but it leaves the large subtrees inside the range in place.
+        // Nothing is moved into or out of them, so they can be consumed afterwards in any order.
+        let driver = ChunkedDriver::new(bounds, &sink_factory, min_subtree);
+        self.work(driver, NoopFilter, |worker: &mut PlWorker<K, V, _, _>| {
+            worker.filter();
+            let chunks = mem::replace(&mut worker.drv.chunks, Vec::new());
+            worker.repr.par_consume_chunks(chunks, min_subtree, &sink_factory)
+        })
+    }
+}
+
+#[cfg(feature = "rayon")]
+impl<K: Key+Sync, V: Sync> PlTree<K, V> {
+    /// Same as `query_range()`, but large subtrees are traversed in parallel. The items are fed
+    /// into sinks created by `sink_factory`; the sinks are returned in order, so that concatenating
+    /// their contents yields the items in order.
+    #[inline]
+    pub fn par_query_range<'a, R, S, F>(&'a self, range: R, sink_factory: F) -> Vec<S>
+        where R: RangeBounds<K>, S: Sink<&'a (K, V)>+Send, F: Fn() -> S + Sync
+    {
+        self.par_query_range_with(range, PAR_MIN_SUBTREE, sink_factory)
+    }
+
+    /// Same as `par_query_range()`, with subtrees of at least `min_subtree` slots split among threads.
+    pub fn par_query_range_with<'a, R, S, F>(&'a self, range: R, min_subtree: usize, sink_factory: F) -> Vec<S>
+        where R: RangeBounds<K>, S: Sink<&'a (K, V)>+Send, F: Fn() -> S + Sync
+    {
+        let bounds = KeyBounds::compat(range);
+        self.repr().par_query_range(&bounds, min_subtree, &|node: &'a PlNode<K, V>| node.as_tuple(), &sink_factory)
+    }
+}
+
+impl<K: Key+Clone+Debug, V, L: Layout> Debug for PlTree<K, V, L> {
     fn fmt(&self, fmt: &mut Formatter) -> fmt::Result {
         Debug::fmt(self.repr(), fmt)
     }
 }
 
-impl<K: Key+Clone+Debug, V> Display for PlTree<K, V> {
+impl<K: Key+Clone+Debug, V, L: Layout> Display for PlTree<K, V, L> {
     fn fmt(&self, fmt: &mut Formatter) -> fmt::Result {
         Display::fmt(self.repr(), fmt)
     }
 }
 
-impl<K: Key, V: Clone> Clone for PlTree<K, V> {
+impl<K: Key, V: Clone, L: Layout> Clone for PlTree<K, V, L> {
     fn clone(&self) -> Self {
         PlTree { repr: UnsafeCell::new(self.repr().clone()) }
     }
@@ -310,10 +514,10 @@ pub struct NoUpdate<K, D, Flt> {
     _ph: PhantomData<(K, D, Flt)>
 }
 
-impl<K, V, D, Flt> ItemVisitor<PlNode<K, V>> for NoUpdate<K, D, Flt>
-    where K: Key, D: TraversalDriver<K, V>, Flt: ItemFilter<K>
+impl<K, V, D, Flt, L> ItemVisitor<PlNode<K, V>, L> for NoUpdate<K, D, Flt>
+    where K: Key, D: TraversalDriver<K, V>, Flt: ItemFilter<K>, L: Layout
 {
-    type Tree = PlWorker<K,V,D,Flt>;
+    type Tree = PlWorker<K,V,D,Flt,L>;
 
     #[inline(always)]
     fn visit<F>(tree: &mut Self::Tree, idx: usize, mut f: F)
@@ -324,15 +528,15 @@ impl<K, V, D, Flt> ItemVisitor<PlNode<K, V>> for NoUpdate<K, D, Flt>
 
 
 
-impl<K: Key, V> Deref for PlTree<K, V> {
-    type Target = TreeRepr<PlNode<K, V>>;
+impl<K: Key, V, L: Layout> Deref for PlTree<K, V, L> {
+    type Target = TreeRepr<PlNode<K, V>, L>;
 
     fn deref(&self) -> &Self::Target {
         self.repr()
     }
 }
 
-impl<K: Key, V> DerefMut for PlTree<K, V> {
+impl<K: Key, V, L: Layout> DerefMut for PlTree<K, V, L> {
     fn deref_mut(&mut self) -> &mut Self::Target {
         self.repr_mut()
     }
@@ -341,16 +545,16 @@ impl<K: Key, V> DerefMut for PlTree<K, V> {
 
 
 #[derive(new)]
-pub struct PlWorker<K, V, D, Flt>
-    where K: Key
+pub struct PlWorker<K, V, D, Flt, L = Bfs>
+    where K: Key, L: Layout
 {
-    repr: TreeRepr<PlNode<K, V>>,
+    repr: TreeRepr<PlNode<K, V>, L>,
     drv: D,
     filter: Flt
 }
 
-impl<K, V, D, Flt> PlWorker<K, V, D, Flt>
-    where K: Key, D: TraversalDriver<K, V>, Flt: ItemFilter<K>
+impl<K, V, D, Flt, L> PlWorker<K, V, D, Flt, L>
+    where K: Key, D: TraversalDriver<K, V>, Flt: ItemFilter<K>, L: Layout
 {
     #[inline]
     fn filter(&mut self) {
@@ -366,7 +570,7 @@ impl<K, V, D, Flt> PlWorker<K, V, D, Flt>
                 return;
             }
 
-            let decision = self.drv.decide(self.key(idx));
+            let decision = self.drv.decide_at(self.key(idx), idx);
 
             if decision.left() && decision.right() {
                 let item = self.filter_take(idx);
@@ -380,9 +584,11 @@ impl<K, V, D, Flt> PlWorker<K, V, D, Flt>
                 return;
             } else if decision.left() {
                 idx = lefti(idx);
-            } else {
-                debug_assert!(decision.right());
+            } else if decision.right() {
                 idx = righti(idx);
+            } else {
+                // the key lies above the end and below the start: the range is empty
+                return;
             }
         }
     }
@@ -390,7 +596,7 @@ impl<K, V, D, Flt> PlWorker<K, V, D, Flt>
     // The caller must make sure that `!is_nil(idx)`.
     #[inline(never)]
     fn delete_range_min(&mut self, idx: usize) {
-        let decision = self.drv.decide(self.key(idx));
+        let decision = self.drv.decide_at(self.key(idx), idx);
         debug_assert!(decision.left());
 
         if decision.right() {
@@ -429,7 +635,7 @@ impl<K, V, D, Flt> PlWorker<K, V, D, Flt>
     // The caller must make sure that `!is_nil(idx)`.
     #[inline(never)]
     fn delete_range_max(&mut self, idx: usize) {
-        let decision = self.drv.decide(self.key(idx));
+        let decision = self.drv.decide_at(self.key(idx), idx);
         debug_assert!(decision.right(), "idx={}", idx);
 
         if decision.left() {
@@ -503,26 +709,26 @@ impl<K, V, D, Flt> PlWorker<K, V, D, Flt>
 
 
 
-impl<K, V, D, Flt> Deref for PlWorker<K, V, D, Flt>
-    where K: Key, D: TraversalDriver<K, V>, Flt: ItemFilter<K>
+impl<K, V, D, Flt, L> Deref for PlWorker<K, V, D, Flt, L>
+    where K: Key, D: TraversalDriver<K, V>, Flt: ItemFilter<K>, L: Layout
 {
-    type Target = TreeRepr<PlNode<K, V>>;
+    type Target = TreeRepr<PlNode<K, V>, L>;
 
     fn deref(&self) -> &Self::Target {
         &self.repr
     }
 }
 
-impl<K, V, D, Flt> DerefMut for PlWorker<K, V, D, Flt>
-    where K: Key, D: TraversalDriver<K, V>, Flt: ItemFilter<K>
+impl<K, V, D, Flt, L> DerefMut for PlWorker<K, V, D, Flt, L>
+    where K: Key, D: TraversalDriver<K, V>, Flt: ItemFilter<K>, L: Layout
 {
     fn deref_mut(&mut self) -> &mut Self::Target {
         &mut self.repr
     }
 }
 
-impl<K, V, D, Flt> BulkDeleteCommon<PlNode<K, V>> for PlWorker<K, V, D, Flt>
-    where K: Key, D: TraversalDriver<K, V>, Flt: ItemFilter<K>
+impl<K, V, D, Flt, L> BulkDeleteCommon<PlNode<K, V>, L> for PlWorker<K, V, D, Flt, L>
+    where K: Key, D: TraversalDriver<K, V>, Flt: ItemFilter<K>, L: Layout
 {
     type Visitor = NoUpdate<K, D, Flt>;
     type Sink = D;
@@ -535,4 +741,13 @@ impl<K, V, D, Flt> BulkDeleteCommon<PlNode<K, V>> for PlWorker<K, V, D, Flt>
     fn sink_mut(&mut self) -> &mut Self::Sink {
         &mut self.drv
     }
+
+    #[inline(always)]
+    fn defer_subtree(&mut self, root: usize) -> bool {
+        if self.is_nil(root) {
+            return false;
+        }
+        let nslots = self.capacity() >> depth_of(root);
+        self.drv.defer_subtree(root, nslots)
+    }
 }
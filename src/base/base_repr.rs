@@ -1,10 +1,25 @@
-use base::{Node, Entry, Sink, lefti, righti, parenti, SlotStack, Refill};
+use base::{Node, Entry, Sink, KeyBounds, lefti, righti, parenti, SlotStack, Refill, RefillClone};
 use base::bulk_delete::DeleteRangeCache;
+use base::bitset::{BitSet, Ones};
+use base::layout::{Layout, Bfs};
+use base::snapshot::{SnapshotNode, SnapshotError, SnapshotHeader, SnapshotBytes, validate_mask, padding,
+                     SNAPSHOT_VERSION, HEADER_LEN, PAYLOAD_ALIGN};
 use std::fmt::{Debug, Formatter};
 use std::fmt;
+use std::io::{self, Read, Write};
 use std::mem;
+use std::slice;
 use std::ptr;
+use std::cmp;
 use std::cmp::{max};
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+#[cfg(feature = "rayon")] use rayon;
+#[cfg(feature = "rayon")] use rayon::slice::ParallelSliceMut;
+#[cfg(feature = "rayon")] use rayon::iter::{IntoParallelIterator, ParallelIterator};
+#[cfg(feature = "rayon")] use base::drivers::Chunk;
+#[cfg(feature = "rayon")] use base::bitset::SharedBits;
 use std::ops::{Deref, DerefMut};
 
 
@@ -13,25 +28,70 @@ pub trait Key: Ord+Clone {}
 impl<T: Ord+Clone> Key for T {}
 
 
-pub trait TreeDeref<N: Node>: Deref<Target=TreeRepr<N>> {}
-pub trait TreeDerefMut<N: Node>: TreeDeref<N> + DerefMut {}
+pub trait TreeDeref<N: Node, L: Layout = Bfs>: Deref<Target=TreeRepr<N, L>> {}
+pub trait TreeDerefMut<N: Node, L: Layout = Bfs>: TreeDeref<N, L> + DerefMut {}
 
-impl<N: Node, T> TreeDeref<N> for T where T: Deref<Target=TreeRepr<N>> {}
-impl<N: Node, T> TreeDerefMut<N> for T where T: Deref<Target=TreeRepr<N>> + DerefMut {}
+impl<N: Node, L: Layout, T> TreeDeref<N, L> for T where T: Deref<Target=TreeRepr<N, L>> {}
+impl<N: Node, L: Layout, T> TreeDerefMut<N, L> for T where T: Deref<Target=TreeRepr<N, L>> + DerefMut {}
 
-#[derive(Clone)]
-pub struct TreeRepr<N: Node> {
+/// The number of slots covered by one flag in `TreeRepr::dirty` (as a power of 2).
+const DIRTY_BLOCK_BITS: usize = 8;
+
+/// The source of `TreeRepr::id`.
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+fn next_id() -> usize {
+    NEXT_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+/// What the contents of a tree were last copied from. `refill_incremental()` only copies the
+/// dirty blocks if the master is the same and has not been modified since.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Origin {
+    None,
+    Tree { id: usize, generation: usize },
+    Snapshot { checksum: u64 },
+}
+
+/// An implicit binary search tree. The slots are addressed by their BFS index; `L` determines
+/// where each slot is kept in the storage (see `base::layout`). `data`, `mask` and `dirty` are
+/// indexed by storage position, `counts` by BFS index.
+pub struct TreeRepr<N: Node, L: Layout = Bfs> {
     data: Vec<N>,
-    mask: Vec<bool>,
+    // One bit per slot: set if the slot is occupied.
+    mask: BitSet,
     pub size: usize,
 
+    // One flag per block of 2^DIRTY_BLOCK_BITS slots; set when any slot in the block is modified.
+    // Used by `refill_incremental()`.
+    dirty: Vec<bool>,
+
+    // `id` is unique to the tree, and `generation` changes whenever its contents do. Together with
+    // `origin`, they tell `refill_incremental()` whether the dirty flags can be trusted.
+    id: usize,
+    generation: usize,
+    origin: Origin,
+
+    // Optional order statistics: `counts[i]` is the number of items in the subtree rooted at `i`.
+    // Maintained lazily: the slots whose mask has changed are logged in `count_log` and the counts
+    // are brought up to date by `update_counts()`.
+    counts: Option<Vec<usize>>,
+    count_log: Vec<usize>,
+    // Same as `dirty`, but for the counts, which are indexed by BFS index. Empty without counts.
+    counts_dirty: Vec<bool>,
+
     delete_range_cache: DeleteRangeCache,
+
+    // The fraction of the capacity below which the size must fall for `should_compact()` to hold.
+    auto_compact: Option<f64>,
+
+    layout: L,
 }
 
 
 //---- Entry points --------------------------------------------------------------------------------
-impl<N: Node> TreeRepr<N> {
-    pub fn new(mut items: Vec<(N::K, N::V)>) -> TreeRepr<N> {
+impl<N: Node, L: Layout> TreeRepr<N, L> {
+    pub fn new(mut items: Vec<(N::K, N::V)>) -> TreeRepr<N, L> {
         items.sort_by(|a, b| a.0.cmp(&b.0));
         Self::with_sorted(items)
     }
@@ -39,29 +99,35 @@ impl<N: Node> TreeRepr<N> {
 
     /// Constructs a new TeardownTree<T>
     /// Note: the argument must be sorted!
-    pub fn with_sorted(mut sorted: Vec<(N::K, N::V)>) -> TreeRepr<N> {
+    pub fn with_sorted(mut sorted: Vec<(N::K, N::V)>) -> TreeRepr<N, L> {
         let size = sorted.len();
+        let capacity = L::capacity_for(size);
+        let layout = L::with_capacity(capacity);
 
-        let mut data = Vec::with_capacity(size);
+        let mut data = Vec::with_capacity(capacity);
         // We use manual management of `data`'s memory. To ensure nothing bad is going on, we
         // analyze each access to `data`.
-        unsafe { data.set_len(size); }
+        unsafe { data.set_len(capacity); }
 
-        let mask: Vec<bool> = vec![true; size];
-        let height = Self::build(&mut sorted, 0, &mut data);
+        let mut mask = BitSet::new(capacity);
+        let height = Self::build(&mut sorted, 0, &mut data, &mut mask, &layout);
         // As per contract with `build()`, we safely dispose of the contents of `sorted` without dropping them.
         unsafe { sorted.set_len(0); }
         let cache = DeleteRangeCache::new(height);
-        TreeRepr { data: data, mask: mask, size: size, delete_range_cache: cache }
+        TreeRepr { data: data, mask: mask, size: size, dirty: vec![false; dirty_blocks(capacity)],
+                   id: next_id(), generation: 0, origin: Origin::None,
+                   counts: None, count_log: Vec::new(), counts_dirty: Vec::new(), delete_range_cache: cache,
+                   auto_compact: None, layout: layout }
     }
 
     /// Constructs a new TreeRepr<T> based on raw nodes vec.
-    pub fn with_nodes(mut nodes: Vec<Option<N>>) -> TreeRepr<N> {
+    pub fn with_nodes(mut nodes: Vec<Option<N>>) -> TreeRepr<N, L> {
         let size = nodes.iter().filter(|x| x.is_some()).count();
         let height = Self::calc_height(&nodes, 0);
-        let capacity = nodes.len();
+        let capacity = L::capacity_for(nodes.len());
+        let layout = L::with_capacity(capacity);
 
-        let mut mask = vec![false; capacity];
+        let mut mask = BitSet::new(capacity);
         let mut data = Vec::with_capacity(capacity);
         // We use manual management of the memory inside `data`. To ensure nothing bad is going on,
         // we analyze each access to `data`.
@@ -69,18 +135,66 @@ impl<N: Node> TreeRepr<N> {
             data.set_len(capacity);
         }
 
-        for i in 0..capacity {
+        for i in 0..nodes.len() {
             if let Some(node) = nodes[i].take() {
-                mask[i] = true;
-                // This is safe: data[i] contains garbage, therefore we must not drop its content.
+                let pos = layout.pos(i);
+                mask.set(pos, true);
+                // This is safe: data[pos] contains garbage, therefore we must not drop its content.
+                unsafe {
+                    ptr::write(&mut data[pos], node);
+                }
+            }
+        }
+
+        let cache = DeleteRangeCache::new(height);
+        TreeRepr { data: data, mask: mask, size: size, dirty: vec![false; dirty_blocks(capacity)],
+                   id: next_id(), generation: 0, origin: Origin::None,
+                   counts: None, count_log: Vec::new(), counts_dirty: Vec::new(), delete_range_cache: cache,
+                   auto_compact: None, layout: layout }
+    }
+
+    /// Constructs a new TreeRepr<T> from the slot mask and the nodes occupying the slots (listed in
+    /// slot order). Nothing is sorted or rebuilt: the nodes are placed into the slots as is, so the
+    /// caller is responsible for their ordering. Fails if `mask` does not describe a valid tree shape
+    /// or does not match the number of nodes.
+    pub fn with_raw_parts(mask: Vec<bool>, nodes: Vec<N>) -> Result<TreeRepr<N, L>, &'static str> {
+        let capacity = L::capacity_for(mask.len());
+        let layout = L::with_capacity(capacity);
+        let size = mask.iter().filter(|&&x| x).count();
+        if size != nodes.len() {
+            return Err("the number of nodes does not match the mask");
+        }
+        if (1..mask.len()).any(|i| mask[i] && !mask[parenti(i)]) {
+            return Err("the mask contains a node without a parent");
+        }
+        let height = mask.iter().rposition(|&x| x).map_or(0, |i| depth_of(i) + 1);
+
+        let mut data = Vec::with_capacity(capacity);
+        // We use manual management of the memory inside `data`. To ensure nothing bad is going on,
+        // we analyze each access to `data`.
+        unsafe {
+            data.set_len(capacity);
+        }
+
+        let mut nodes = nodes.into_iter();
+        let mut bits = BitSet::new(capacity);
+        for i in 0..mask.len() {
+            if mask[i] {
+                let pos = layout.pos(i);
+                bits.set(pos, true);
+                // This is safe: data[pos] contains garbage, therefore we must not drop its content.
                 unsafe {
-                    ptr::write(&mut data[i], node);
+                    ptr::write(&mut data[pos], nodes.next().unwrap());
                 }
             }
         }
 
         let cache = DeleteRangeCache::new(height);
-        TreeRepr { data: data, mask: mask, size: size, delete_range_cache: cache }
+        // The tree has not been copied from anything, so every block is dirty.
+        Ok(TreeRepr { data: data, mask: bits, size: size, dirty: vec![true; dirty_blocks(capacity)],
+                      id: next_id(), generation: 0, origin: Origin::None,
+                      counts: None, count_log: Vec::new(), counts_dirty: Vec::new(), delete_range_cache: cache,
+                      auto_compact: None, layout: layout })
     }
 
 //    fn into_node_vec(self) -> Vec<Option<Node<T>>> {
@@ -108,6 +222,18 @@ impl<N: Node> TreeRepr<N> {
         }
     }
 
+    /// Finds the item with the given key and returns a mutable reference to its value (or None).
+    pub fn find_mut<'a, Q>(&'a mut self, query: &Q) -> Option<&'a mut N::V>
+        where N: 'a, Q: PartialOrd<N::K>
+    {
+        let idx = self.index_of(query);
+        if self.is_nil(idx) {
+            None
+        } else {
+            Some(self.node_mut(idx).val_mut())
+        }
+    }
+
     pub fn contains<Q: PartialOrd<N::K>>(&self, query: &Q) -> bool {
         self.find(query).is_some()
     }
@@ -118,6 +244,7 @@ impl<N: Node> TreeRepr<N> {
 
     pub fn clear(&mut self) {
         self.drop_items();
+        self.update_counts();
     }
 
     pub fn capacity(&self) -> usize {
@@ -127,25 +254,49 @@ impl<N: Node> TreeRepr<N> {
 
 
 //---- Accessors -----------------------------------------------------------------------------------
-impl<N: Node> TreeRepr<N> {
+impl<N: Node, L: Layout> TreeRepr<N, L> {
+    /// Returns the position of the slot `idx` in the storage.
+    #[inline(always)]
+    fn pos(&self, idx: usize) -> usize {
+        self.layout.pos(idx)
+    }
+
     // The caller must make sure idx is inside bounds.
     #[inline(always)]
     pub fn mask(&self, idx: usize) -> bool {
         debug_assert!(idx < self.data.len());
         unsafe {
-            *self.mask.get_unchecked(idx)
+            self.mask.get_unchecked(self.pos(idx))
         }
     }
 
     // The caller must make sure idx is inside bounds.
     #[inline(always)]
-    pub fn mask_mut(&mut self, idx: usize) -> &mut bool {
+    pub fn set_mask(&mut self, idx: usize, value: bool) {
         debug_assert!(idx < self.data.len());
+        if self.counts.is_some() {
+            self.count_log.push(idx);
+        }
+        let pos = self.pos(idx);
         unsafe {
-            self.mask.get_unchecked_mut(idx)
+            self.mark_dirty(pos);
+            self.mask.set_unchecked(pos, value);
         }
     }
 
+    // The caller must make sure pos is inside bounds.
+    #[inline(always)]
+    unsafe fn mark_dirty(&mut self, pos: usize) {
+        *self.dirty.get_unchecked_mut(pos >> DIRTY_BLOCK_BITS) = true;
+        self.touch();
+    }
+
+    /// Records that the contents of the tree have changed (see `Origin`).
+    #[inline(always)]
+    fn touch(&mut self) {
+        self.generation = self.generation.wrapping_add(1);
+    }
+
 
     #[inline(always)]
     pub fn key<'a>(&'a self, idx: usize) -> &'a N::K where N: 'a {
@@ -182,7 +333,7 @@ impl<N: Node> TreeRepr<N> {
 
     #[inline(always)]
     pub fn node(&self, idx: usize) -> &N {
-        &self.data[idx]
+        &self.data[self.pos(idx)]
     }
 
     #[inline(always)]
@@ -192,7 +343,10 @@ impl<N: Node> TreeRepr<N> {
 
     #[inline(always)]
     pub fn node_mut(&mut self, idx: usize) -> &mut N {
-        &mut self.data[idx]
+        let pos = self.pos(idx);
+        self.dirty[pos >> DIRTY_BLOCK_BITS] = true;
+        self.touch();
+        &mut self.data[pos]
     }
 
     // Spoofs the lifetime of the reference to self.node(idx), which is required to work around the
@@ -202,7 +356,7 @@ impl<N: Node> TreeRepr<N> {
     pub fn node_unsafe<'a>(&self, idx: usize) -> &'a N where N: 'a {
         debug_assert!(idx < self.data.len());
         unsafe {
-            mem::transmute(self.data.get_unchecked(idx))
+            mem::transmute(self.data.get_unchecked(self.pos(idx)))
         }
     }
 
@@ -211,8 +365,11 @@ impl<N: Node> TreeRepr<N> {
     // the content and there is no race condition in access to the content.
     #[inline(always)]
     pub fn node_mut_unsafe<'a>(&mut self, idx: usize) -> &'a mut N where N: 'a {
+        debug_assert!(idx < self.data.len());
+        let pos = self.pos(idx);
         unsafe {
-            mem::transmute(self.data.get_unchecked_mut(idx))
+            self.mark_dirty(pos);
+            mem::transmute(self.data.get_unchecked_mut(pos))
         }
     }
 
@@ -289,7 +446,7 @@ impl<N: Node> TreeRepr<N> {
 
 
 //---- Helpers -------------------------------------------------------------------------------------
-impl<N: Node> TreeRepr<N> {
+impl<N: Node, L: Layout> TreeRepr<N, L> {
     fn calc_height(nodes: &Vec<Option<N>>, idx: usize) -> usize {
         if idx < nodes.len() && nodes[idx].is_some() {
             1 + max(Self::calc_height(nodes, lefti(idx)),
@@ -314,27 +471,38 @@ impl<N: Node> TreeRepr<N> {
         }
     }
 
-    /// Returns the height of the tree. This consumes the contents of `data`, so the caller must
+    /// Returns the height of the tree. This consumes the contents of `sorted`, so the caller must
     /// make sure the contents are never reused or dropped after this call returns.
-    fn build(sorted: &mut [(N::K, N::V)], idx: usize, data: &mut [N]) -> usize {
+    fn build(sorted: &mut [(N::K, N::V)], idx: usize, data: &mut [N], mask: &mut BitSet, layout: &L) -> usize {
+        debug_assert!(data.len() == mask.len());
+        let n = sorted.len();
+        // This is safe: `build_raw()` only writes to the slots of the subtree rooted at `idx`, all of
+        // which are inside `data`.
+        let height = unsafe { Self::build_raw(sorted, idx, data.as_mut_ptr(), layout) };
+        Self::mark_built(mask, idx, n, layout);
+        height
+    }
+
+    /// Same as `build()`, but writes through a raw pointer to the storage and leaves the mask to
+    /// the caller (see `mark_built()`). Subtrees rooted at different indices occupy disjoint sets of
+    /// slots, so they can be built concurrently.
+    unsafe fn build_raw(sorted: &mut [(N::K, N::V)], idx: usize, data: *mut N, layout: &L) -> usize {
         match sorted.len() {
             0 => 0,
             n => {
                 let mid = Self::build_select_root(n);
                 let (lefti, righti) = (lefti(idx), righti(idx));
-                let lh = Self::build(&mut sorted[..mid], lefti, data);
-                let rh = Self::build(&mut sorted[mid+1..], righti, data);
+                let lh = Self::build_raw(&mut sorted[..mid], lefti, data, layout);
+                let rh = Self::build_raw(&mut sorted[mid+1..], righti, data, layout);
 
                 // This is safe because:
                 //   a) we read each element in `sorted` exactly once
                 //   b) we write to each index in `data` exactly once
                 //   c) `data` is initially filled with garbage (therefore we must not drop its contents before overwriting)
                 //   d) the caller of `build` makes sure the contents are never reused or dropped after this call returns
-                unsafe {
-                    let p = sorted.get_unchecked(mid);
-                    let (k, v) = ptr::read(p);
-                    ptr::write(data.get_unchecked_mut(idx), N::new(k, v));
-                }
+                let p = sorted.get_unchecked(mid);
+                let (k, v) = ptr::read(p);
+                ptr::write(data.offset(layout.pos(idx) as isize), N::new(k, v));
 
                 debug_assert!(rh <= lh);
                 1 + lh
@@ -342,6 +510,27 @@ impl<N: Node> TreeRepr<N> {
         }
     }
 
+    /// Marks the slots occupied by the `n` items that `build()` has placed into the subtree rooted
+    /// at `root`. `build_select_root()` makes the subtree nearly complete: every level is full,
+    /// except for the last one, which is filled from the left. The slots of each level are
+    /// contiguous, so with the BFS layout they are marked a word at a time.
+    fn mark_built(mask: &mut BitSet, root: usize, mut n: usize, layout: &L) {
+        let (mut first, mut width) = (root, 1);
+        while n > 0 {
+            let k = cmp::min(width, n);
+            if L::is_identity() {
+                mask.set_range(first, first + k);
+            } else {
+                for idx in first..first+k {
+                    mask.set(layout.pos(idx), true);
+                }
+            }
+            n -= k;
+            first = lefti(first);
+            width <<= 1;
+        }
+    }
+
     pub fn succ(&self, idx: usize) -> usize {
         if self.has_right(idx) {
             righti(idx)
@@ -352,17 +541,48 @@ impl<N: Node> TreeRepr<N> {
         }
     }
 
+    /// Returns the index of the leftmost item inside the lower bound of `bounds`, or `capacity()`
+    /// if there is no such item.
+    pub fn lower_bound<Q: PartialOrd<N::K>>(&self, bounds: &KeyBounds<Q>) -> usize {
+        let mut found = self.data.len();
+        let mut idx = 0;
+        while !self.is_nil(idx) {
+            if bounds.above_start(self.key(idx)) {
+                found = idx;
+                idx = lefti(idx);
+            } else {
+                idx = righti(idx);
+            }
+        }
+        found
+    }
+
+    /// Returns the index of the rightmost item inside the upper bound of `bounds`, or `capacity()`
+    /// if there is no such item.
+    pub fn upper_bound<Q: PartialOrd<N::K>>(&self, bounds: &KeyBounds<Q>) -> usize {
+        let mut found = self.data.len();
+        let mut idx = 0;
+        while !self.is_nil(idx) {
+            if bounds.below_end(self.key(idx)) {
+                found = idx;
+                idx = righti(idx);
+            } else {
+                idx = lefti(idx);
+            }
+        }
+        found
+    }
+
     /// Returns either the index of the first element equal to `query` if it is contained in the tree;
     /// or the index where it can be inserted if it is not.
     pub fn index_of<Q>(&self, query: &Q) -> usize
         where Q: PartialOrd<N::K>
     {
-        if self.data.is_empty() {
+        if self.is_nil(0) {
             return 0;
         }
 
         let mut idx = 0;
-        debug_assert!(self.mask[idx]);
 
 
         loop {
@@ -402,16 +622,62 @@ impl<N: Node> TreeRepr<N> {
         idx
     }
 
+    /// Returns the index of the smallest item, or None if the tree is empty.
+    #[inline]
+    pub fn first_idx(&self) -> Option<usize> {
+        if self.is_nil(0) { None } else { Some(self.find_min(0)) }
+    }
+
+    /// Returns the index of the largest item, or None if the tree is empty.
+    #[inline]
+    pub fn last_idx(&self) -> Option<usize> {
+        if self.is_nil(0) { None } else { Some(self.find_max(0)) }
+    }
+
+    /// Returns the index of the in-order successor of the item at `idx`, or None if it is the
+    /// last item. The caller must make sure that `!is_nil(idx)`.
+    #[inline]
+    pub fn next_idx(&self, idx: usize) -> Option<usize> {
+        debug_assert!(!self.is_nil(idx));
+        iter_next_idx(idx, self)
+    }
+
+    /// Returns the index of the in-order predecessor of the item at `idx`, or None if it is the
+    /// first item. The caller must make sure that `!is_nil(idx)`.
+    #[inline]
+    pub fn prev_idx(&self, idx: usize) -> Option<usize> {
+        debug_assert!(!self.is_nil(idx));
+        iter_prev_idx(idx, self)
+    }
+
+    /// Returns the index of the leftmost item whose key is not less than `query`, or None if there
+    /// is no such item.
+    pub fn lower_bound_idx<Q>(&self, query: &Q) -> Option<usize>
+        where Q: PartialOrd<N::K>
+    {
+        let mut found = None;
+        let mut idx = 0;
+        while !self.is_nil(idx) {
+            if query <= self.key(idx) {
+                found = Some(idx);
+                idx = lefti(idx);
+            } else {
+                idx = righti(idx);
+            }
+        }
+        found
+    }
+
     // The caller must make sure that `!self.is_nil(idx)`
     #[inline(always)]
     pub fn take(&mut self, idx: usize) -> N {
-        debug_assert!(!self.is_nil(idx), "idx={}, mask[idx]={}", idx, self.mask[idx]);
+        debug_assert!(!self.is_nil(idx), "idx={}, mask[idx]={}", idx, self.mask(idx));
         let node = unsafe {
             let p: &N = self.node_unsafe(idx);
-            // We take care to set `mask[idx]` to `false`, so we must not drop the content of `p`.
+            // We take care to clear `mask[idx]`, so we must not drop the content of `p`.
             ptr::read(p)
         };
-        *self.mask_mut(idx) = false;
+        self.set_mask(idx, false);
         self.size -= 1;
         node
     }
@@ -433,53 +699,76 @@ impl<N: Node> TreeRepr<N> {
     pub unsafe fn move_from_to(&mut self, src: usize, dst: usize) {
         debug_assert!(!self.is_nil(src) && self.is_nil(dst), "is_nil(src)={}, is_nil(dst)={}", self.is_nil(src), self.is_nil(dst));
         let pdata = self.data.as_mut_ptr();
-        let psrc: *mut N = pdata.offset(src as isize);
-        let pdst: *mut N = pdata.offset(dst as isize);
+        let psrc: *mut N = pdata.offset(self.pos(src) as isize);
+        let pdst: *mut N = pdata.offset(self.pos(dst) as isize);
         let x = ptr::read(psrc);
-        *self.mask_mut(src) = false;
-        *self.mask_mut(dst) = true;
+        self.set_mask(src, false);
+        self.set_mask(dst, true);
         ptr::write(pdst, x);
     }
 
-//    // The caller must make sure that idx is inside bounds.
-//    #[inline(always)]
-//    pub fn place(&mut self, idx: usize, node: N) {
-//        // TODO: we could remove bounds checks in accesses to self.mask below
-//        if self.mask[idx] {
-//            // The old content of `data[idx]` is correctly dropped after being overwritten.
-//            self.data[idx] = node;
-//        } else {
-//            self.mask[idx] = true;
-//            self.size += 1;
-//            unsafe {
-//                let p = self.data.get_unchecked_mut(idx);
-//                // We must not drop the old content of `data[idx]`, as it was garbage.
-//                ptr::write(p, node);
-//            };
-//        }
-//    }
+    // The caller must make sure that `idx` is inside bounds and `is_nil(idx)`.
+    #[inline(always)]
+    pub fn place(&mut self, idx: usize, node: N) {
+        debug_assert!(idx < self.data.len() && self.is_nil(idx));
+        unsafe {
+            let p = self.data.get_unchecked_mut(self.layout.pos(idx));
+            // We must not drop the old content of `data[idx]`, as it is garbage.
+            ptr::write(p, node);
+        }
+        self.set_mask(idx, true);
+        self.size += 1;
+    }
+
+    /// Same as `place()`, but takes a position in the storage rather than an index, and does not
+    /// log the change for `update_counts()`. Used to copy the occupied slots of a tree with the
+    /// same capacity.
+    fn place_at_pos(&mut self, pos: usize, node: N) {
+        debug_assert!(pos < self.data.len() && !self.mask.get(pos));
+        unsafe {
+            // We must not drop the old content of `data[pos]`, as it is garbage.
+            ptr::write(self.data.get_unchecked_mut(pos), node);
+            self.mark_dirty(pos);
+            self.mask.set_unchecked(pos, true);
+        }
+        self.size += 1;
+    }
+
+    /// Writes back the state of `copy`, a bitwise copy of `self` that a bulk operation has been
+    /// performed on (see `PlTree::work()`), and forgets `copy` without dropping it. Apart from
+    /// the size and the generation, only the count log may have changed (and been reallocated) in
+    /// `copy`.
+    pub unsafe fn merge_copy(&mut self, mut copy: TreeRepr<N, L>) {
+        copy.update_counts();
+        self.size = copy.size;
+        self.generation = copy.generation;
+        // The old log has been moved into `copy` and possibly reallocated there.
+        ptr::write(&mut self.count_log, ptr::read(&copy.count_log));
+        mem::forget(copy);
+    }
 
     fn drop_items(&mut self) {
+        // The occupied slots are found a word of the mask at a time, so empty regions are skipped quickly.
         let p = self.data.as_mut_ptr();
-        if self.size*2 <= self.data.len() {
-            Self::traverse_preorder_mut(self, 0, &mut 0, |this, _, idx| {
-                unsafe {
-                    // We know that `!is_nil(idx)`, therefore we must drop `*data[idx]` before dropping `data`.
-                    *this.mask_mut(idx) = false;
-                    ptr::drop_in_place(p.offset(idx as isize));
-                }
-            })
-        } else {
-            for i in 0..self.size {
-                if self.mask(i) {
-                    unsafe {
-                        // We know that `!is_nil(i)`, therefore we must drop `*data[i]` before dropping `data`.
-                        *self.mask_mut(i) = false;
-                        ptr::drop_in_place(p.offset(i as isize));
-                    }
-                }
+        let dirty = &mut self.dirty;
+        self.mask.drain_ones(|idx| {
+            unsafe {
+                // We know that `!is_nil(idx)`, therefore we must drop `*data[idx]` before dropping `data`.
+                ptr::drop_in_place(p.offset(idx as isize));
+                *dirty.get_unchecked_mut(idx >> DIRTY_BLOCK_BITS) = true;
+            }
+        });
+        // Rather than logging every slot, we bring the counts up to date right away.
+        if let Some(ref mut counts) = self.counts {
+            for count in counts.iter_mut() {
+                *count = 0;
+            }
+            for flag in self.counts_dirty.iter_mut() {
+                *flag = true;
             }
         }
+        self.count_log.clear();
+        self.touch();
 
         self.size = 0;
     }
@@ -493,15 +782,495 @@ impl<N: Node> TreeRepr<N> {
         &mut self.delete_range_cache.slots_max
     }
 
-    pub fn iter<'a>(&'a self) -> Iter<'a, N> {
+    pub fn iter<'a>(&'a self) -> Iter<'a, N, L> {
         Iter::new(self)
     }
+
+    pub fn iter_mut<'a>(&'a mut self) -> IterMut<'a, N, L> {
+        IterMut::new(self)
+    }
+
+    /// Iterates over the items in storage order rather than in key order. This is cheaper than
+    /// `iter()`, as the occupied slots are found a word of the mask at a time.
+    pub fn iter_unordered<'a>(&'a self) -> UnorderedIter<'a, N, L> {
+        UnorderedIter { tree: self, slots: self.mask.ones(), remaining: self.size }
+    }
+
+    pub fn range<'a, Q: PartialOrd<N::K>>(&'a self, bounds: &KeyBounds<Q>) -> RangeIter<'a, N, L> {
+        RangeIter::new(self, bounds)
+    }
+
+    /// Calls `f` on every item whose key lies within `bounds` in order, giving it mutable access
+    /// to the value.
+    pub fn for_each_in_range_mut<Q, F>(&mut self, bounds: &KeyBounds<Q>, mut f: F)
+        where Q: PartialOrd<N::K>, F: FnMut(&N::K, &mut N::V)
+    {
+        // An empty range ends before its lower bound, so the traversal stops right away.
+        let from = self.lower_bound(bounds);
+        if self.is_nil(from) {
+            return;
+        }
+
+        TreeRepr::traverse_inorder_from_mut(self, from, 0, &mut f, |this, f, idx| {
+            let (key, val) = this.node_mut(idx).key_val_mut();
+            if bounds.below_end(key) {
+                f(key, val);
+                false
+            } else {
+                true
+            }
+        })
+    }
+}
+
+
+//---- insertion -----------------------------------------------------------------------------------
+impl<N: Node, L: Layout> TreeRepr<N, L> {
+    /// Inserts the item into the tree. Duplicate keys are supported (the new item is placed after
+    /// the existing equal ones).
+    ///
+    /// If the empty slot where the item belongs lies inside the array, it is simply put there.
+    /// Otherwise we look for the lowest ancestor of that slot whose subtree is sparse enough to
+    /// accommodate another item without exceeding the current height, and rebuild that subtree
+    /// into a nearly-complete shape. The density threshold decreases linearly from 1 at the
+    /// leaves to 1/2 at the root, as described in "Cache Oblivious Search Trees via Binary Trees
+    /// of Small Height" (Brodal, Fagerberg, Jacob). If even the root is too dense, the array is
+    /// reallocated with a larger height and the whole tree is rebuilt.
+    ///
+    /// Returns the root of the subtree that was modified: either the index of the new item, or the
+    /// root of the rebuilt subtree (0 when the whole tree was rebuilt).
+    pub fn insert(&mut self, key: N::K, val: N::V) -> usize {
+        let root = self.insert_item(key, val);
+        self.update_counts();
+        root
+    }
+
+    fn insert_item(&mut self, key: N::K, val: N::V) -> usize {
+        let idx = self.insertion_point(&key);
+        if idx < self.data.len() {
+            self.place(idx, N::new(key, val));
+            self.ensure_cache_height(depth_of(idx) + 1);
+            return idx;
+        }
+
+        let height = levels_of(self.data.len());
+        if idx < full_capacity(height) {
+            // the last level is only partially allocated
+            self.grow_storage(height);
+            self.place(idx, N::new(key, val));
+            return idx;
+        }
+
+        // `idx` lies one level below the lowest allowed level
+        debug_assert!(depth_of(idx) == height);
+        self.grow_storage(height);
+
+        let mut root = idx;
+        while root != 0 {
+            root = parenti(root);
+
+            let depth = depth_of(root);
+            let capacity = full_capacity(height - depth);
+            let count = self.count_subtree(root);
+            // (count+1)/capacity <= 1/2 + depth/(2*(height-1))
+            if count < capacity && (count + 1) * 2 * (height - 1) <= capacity * (height - 1 + depth) {
+                self.rebuild_subtree(root, count, key, val);
+                return root;
+            }
+        }
+
+        // the root is too dense as well
+        let mut new_height = height + 1;
+        while (self.size + 1) * 2 > full_capacity(new_height) {
+            new_height += 1;
+        }
+        self.rebuild_with_height(new_height, key, val);
+        0
+    }
+
+    /// Returns the index of the empty slot where an item with the given key belongs.
+    fn insertion_point(&self, key: &N::K) -> usize {
+        let mut idx = 0;
+        while !self.is_nil(idx) {
+            idx = if key < self.key(idx) { lefti(idx) } else { righti(idx) };
+        }
+        idx
+    }
+
+    fn count_subtree(&self, root: usize) -> usize {
+        let mut count = 0;
+        TreeRepr::traverse_preorder(self, root, &mut count, |_, count, _| *count += 1);
+        count
+    }
+
+    /// Removes all items in the subtree rooted at `root` and returns them in order, with the
+    /// item (`key`, `val`) merged in.
+    fn take_subtree_sorted(&mut self, root: usize, count: usize, key: N::K, val: N::V) -> Vec<(N::K, N::V)> {
+        let mut items = Vec::with_capacity(count + 1);
+        TreeRepr::traverse_inorder_mut(self, root, &mut items, |this, items, idx| {
+            items.push(this.take(idx).into_tuple());
+            false
+        });
+
+        let pos = items.iter()
+            .position(|item| key < item.0)
+            .unwrap_or(items.len());
+        items.insert(pos, (key, val));
+        items
+    }
+
+    fn rebuild_subtree(&mut self, root: usize, count: usize, key: N::K, val: N::V) {
+        let mut sorted = self.take_subtree_sorted(root, count, key, val);
+        let len = sorted.len();
+        self.mark_subtree_dirty(root);
+        Self::build(&mut sorted, root, &mut self.data, &mut self.mask, &self.layout);
+        // As per contract with `build()`, we safely dispose of the contents of `sorted` without dropping them.
+        unsafe { sorted.set_len(0); }
+        self.size += len;
+        self.recompute_subtree_counts(root);
+    }
+
+    fn rebuild_with_height(&mut self, height: usize, key: N::K, val: N::V) {
+        let count = self.size;
+        let mut sorted = self.take_subtree_sorted(0, count, key, val);
+        debug_assert!(self.size == 0);
+
+        let capacity = full_capacity(height);
+        let mut data = Vec::with_capacity(capacity);
+        // We use manual management of `data`'s memory, see `with_sorted()`.
+        unsafe { data.set_len(capacity); }
+        let mut old_data = mem::replace(&mut self.data, data);
+        // All items have been moved out of `old_data`, so only the storage must be dropped.
+        unsafe { old_data.set_len(0); }
+        self.mask = BitSet::new(capacity);
+        self.dirty = vec![true; dirty_blocks(capacity)];
+        self.touch();
+        self.layout = L::with_capacity(capacity);
+
+        Self::build(&mut sorted, 0, &mut self.data, &mut self.mask, &self.layout);
+        self.size = sorted.len();
+        // As per contract with `build()`, we safely dispose of the contents of `sorted` without dropping them.
+        unsafe { sorted.set_len(0); }
+        self.ensure_cache_height(height);
+        if self.counts.is_some() {
+            self.enable_counts();
+        }
+    }
+
+    /// Extends the storage to hold a complete tree of the given height.
+    fn grow_storage(&mut self, height: usize) {
+        let capacity = full_capacity(height);
+        let len = self.data.len();
+        if len < capacity {
+            if L::is_identity() {
+                self.data.reserve_exact(capacity - len);
+                // The new cells contain garbage and are marked as empty in `mask`.
+                unsafe { self.data.set_len(capacity); }
+                self.mask.resize(capacity);
+                self.dirty.resize(dirty_blocks(capacity), true);
+                self.touch();
+            } else {
+                self.relayout(capacity);
+            }
+            if let Some(ref mut counts) = self.counts {
+                counts.resize(capacity, 0);
+                self.counts_dirty.resize(dirty_blocks(capacity), true);
+            }
+        }
+        self.ensure_cache_height(height);
+    }
+
+    /// Moves the items into a new storage of the given capacity, where the layout places them at
+    /// different positions.
+    fn relayout(&mut self, capacity: usize) {
+        let layout = L::with_capacity(capacity);
+        let mut data = Vec::with_capacity(capacity);
+        // We use manual management of `data`'s memory, see `with_sorted()`.
+        unsafe { data.set_len(capacity); }
+        let mut mask = BitSet::new(capacity);
+        for idx in 0..self.data.len() {
+            if self.mask(idx) {
+                let pos = layout.pos(idx);
+                // This is safe: the node is moved exactly once, and `data[pos]` contains garbage.
+                unsafe { ptr::write(&mut data[pos], ptr::read(self.node(idx))); }
+                mask.set(pos, true);
+            }
+        }
+
+        let mut old_data = mem::replace(&mut self.data, data);
+        // All items have been moved out of `old_data`, so only the storage must be dropped.
+        unsafe { old_data.set_len(0); }
+        self.mask = mask;
+        self.layout = layout;
+        self.dirty = vec![true; dirty_blocks(capacity)];
+        self.touch();
+    }
+
+    /// Resets the dirty flags for a storage of the given capacity, reusing the allocation.
+    fn clear_dirty(&mut self, capacity: usize) {
+        let nblocks = dirty_blocks(capacity);
+        self.dirty.clear();
+        self.dirty.resize(nblocks, false);
+    }
+
+    /// Records that the contents of the tree have been replaced with those of `origin`.
+    fn set_origin(&mut self, origin: Origin) {
+        self.origin = origin;
+        self.touch();
+    }
+
+    fn mark_subtree_dirty(&mut self, root: usize) {
+        let (mut from, mut width) = (root, 1);
+        while from < self.data.len() {
+            let to = cmp::min(from + width, self.data.len());
+            if L::is_identity() {
+                for block in (from >> DIRTY_BLOCK_BITS) .. ((to - 1) >> DIRTY_BLOCK_BITS) + 1 {
+                    self.dirty[block] = true;
+                }
+            } else {
+                for idx in from..to {
+                    let pos = self.pos(idx);
+                    self.dirty[pos >> DIRTY_BLOCK_BITS] = true;
+                }
+            }
+            from = lefti(from);
+            width <<= 1;
+        }
+        self.touch();
+    }
+
+    fn ensure_cache_height(&mut self, height: usize) {
+        if self.delete_range_cache.slots_max.capacity < height {
+            self.delete_range_cache = DeleteRangeCache::new(height);
+        }
+    }
+}
+
+
+//---- compaction ----------------------------------------------------------------------------------
+impl<N: Node, L: Layout> TreeRepr<N, L> {
+    /// Rebuilds the remaining items into a new storage just large enough to hold them, as
+    /// `with_sorted()` would, and releases the old storage. Afterwards, the height of the tree (and
+    /// with it the cost of the logarithmic operations) only depends on the current size. The
+    /// counts stay enabled if they were.
+    pub fn compact(&mut self) {
+        let counts = self.counts.take();
+        self.count_log.clear();
+
+        let mut sorted = Vec::with_capacity(self.size);
+        if self.size > 0 {
+            TreeRepr::traverse_inorder_mut(self, 0, &mut sorted, |this, sorted, idx| {
+                sorted.push(this.take(idx).into_tuple());
+                false
+            });
+        }
+
+        let mut compacted = TreeRepr::with_sorted(sorted);
+        // the layout has nothing in common with the one `refill_incremental()` would expect
+        compacted.dirty = vec![true; dirty_blocks(compacted.data.len())];
+        compacted.auto_compact = self.auto_compact;
+        if counts.is_some() {
+            compacted.enable_counts();
+        }
+        // All items have been moved out of `self`, so only its storage is dropped.
+        *self = compacted;
+    }
+
+    /// Sets the policy for automatic compaction: once the size falls below `ratio * capacity()`,
+    /// `should_compact()` returns true (unless compacting would not shrink the storage, which can
+    /// happen with layouts that round the capacity up), and the trees call `compact()` after a
+    /// deletion. None
+    /// disables the policy (the default). A ratio of 1 or more would compact the tree after every
+    /// deletion, so it is rejected.
+    pub fn set_auto_compact(&mut self, ratio: Option<f64>) {
+        if let Some(ratio) = ratio {
+            assert!(ratio > 0.0 && ratio < 1.0, "the compaction ratio must be in (0, 1): {}", ratio);
+        }
+        self.auto_compact = ratio;
+    }
+
+    #[inline]
+    pub fn auto_compact(&self) -> Option<f64> {
+        self.auto_compact
+    }
+
+    #[inline]
+    pub fn should_compact(&self) -> bool {
+        match self.auto_compact {
+            Some(ratio) => (self.size as f64) < ratio * self.data.len() as f64
+                && L::capacity_for(self.size) < self.data.len(),
+            None => false
+        }
+    }
+}
+
+//---- order statistics ----------------------------------------------------------------------------
+impl<N: Node, L: Layout> TreeRepr<N, L> {
+    /// Starts maintaining the number of items in every subtree, so that `select()`,
+    /// `count_prefix()` and the queries built on them take O(log n) rather than O(n) time. The
+    /// counts are stored in an additional array of `capacity()` integers.
+    pub fn enable_counts(&mut self) {
+        let len = self.data.len();
+        let mut counts = vec![0; len];
+        for idx in (0..len).rev() {
+            counts[idx] = self.mask(idx) as usize + count_at(&counts, lefti(idx)) + count_at(&counts, righti(idx));
+        }
+        self.counts = Some(counts);
+        self.counts_dirty = vec![true; dirty_blocks(len)];
+        self.count_log.clear();
+    }
+
+    pub fn has_counts(&self) -> bool {
+        self.counts.is_some()
+    }
+
+    /// Brings the subtree counts up to date with the changes logged by `mask_mut()`. Only the
+    /// changed slots and their ancestors are visited.
+    pub fn update_counts(&mut self) {
+        if self.count_log.is_empty() {
+            return;
+        }
+
+        let mut counts = match self.counts.take() {
+            Some(counts) => counts,
+            None => {
+                self.count_log.clear();
+                return;
+            }
+        };
+
+        let mut pending = HashSet::with_capacity(self.count_log.len() * 2);
+        for &idx in self.count_log.iter() {
+            let mut idx = idx;
+            // stop as soon as we reach a node whose ancestors have already been added
+            while pending.insert(idx) && idx != 0 {
+                idx = parenti(idx);
+            }
+        }
+        self.count_log.clear();
+
+        self.update_counts_rec(&mut counts, &pending, 0);
+        self.counts = Some(counts);
+    }
+
+    fn update_counts_rec(&mut self, counts: &mut [usize], pending: &HashSet<usize>, idx: usize) -> usize {
+        if idx >= counts.len() {
+            return 0;
+        }
+        if !pending.contains(&idx) {
+            return counts[idx];
+        }
+
+        let count = self.mask(idx) as usize
+            + self.update_counts_rec(counts, pending, lefti(idx))
+            + self.update_counts_rec(counts, pending, righti(idx));
+        counts[idx] = count;
+        // This is safe: `idx < counts.len() == data.len()`.
+        let pos = self.pos(idx);
+        unsafe {
+            self.mark_dirty(pos);
+            *self.counts_dirty.get_unchecked_mut(idx >> DIRTY_BLOCK_BITS) = true;
+        }
+        count
+    }
+
+    /// Recomputes the counts of all nodes in the subtree rooted at `root`, bottom-up.
+    fn recompute_subtree_counts(&mut self, root: usize) {
+        if let Some(ref mut counts) = self.counts {
+            let len = counts.len();
+            let mut levels = vec![];
+            let (mut from, mut width) = (root, 1);
+            while from < len {
+                levels.push((from, cmp::min(from + width, len)));
+                from = lefti(from);
+                width <<= 1;
+            }
+
+            for &(from, to) in levels.iter().rev() {
+                for idx in from..to {
+                    counts[idx] = self.mask.get(self.layout.pos(idx)) as usize + count_at(counts, lefti(idx)) + count_at(counts, righti(idx));
+                }
+                for block in (from >> DIRTY_BLOCK_BITS) .. ((to - 1) >> DIRTY_BLOCK_BITS) + 1 {
+                    self.counts_dirty[block] = true;
+                }
+            }
+        }
+    }
+
+    /// Returns the number of items whose keys satisfy `pred`. The predicate must hold for a prefix
+    /// of the items in sorted order and fail for the rest.
+    pub fn count_prefix<F>(&self, pred: F) -> usize
+        where F: Fn(&N::K) -> bool
+    {
+        match self.counts {
+            Some(ref counts) => {
+                let mut idx = 0;
+                let mut count = 0;
+                while !self.is_nil(idx) {
+                    if pred(self.key(idx)) {
+                        count += 1 + count_at(counts, lefti(idx));
+                        idx = righti(idx);
+                    } else {
+                        idx = lefti(idx);
+                    }
+                }
+                count
+            },
+            None => self.iter().take_while(|entry| pred(entry.key())).count()
+        }
+    }
+
+    /// Returns the index of the `k`-th smallest item (counting from 0), or None if `k >= size()`.
+    pub fn select(&self, k: usize) -> Option<usize> {
+        if k >= self.size {
+            return None;
+        }
+
+        match self.counts {
+            Some(ref counts) => {
+                let (mut idx, mut k) = (0, k);
+                loop {
+                    let nleft = count_at(counts, lefti(idx));
+                    if k < nleft {
+                        idx = lefti(idx);
+                    } else if k == nleft {
+                        return Some(idx);
+                    } else {
+                        k -= nleft + 1;
+                        idx = righti(idx);
+                    }
+                }
+            },
+            None => {
+                let mut idx = self.find_min(0);
+                for _ in 0..k {
+                    idx = iter_next_idx(idx, self).unwrap();
+                }
+                Some(idx)
+            }
+        }
+    }
+
+    /// Returns the number of items whose keys are less than `query`.
+    pub fn rank<Q>(&self, query: &Q) -> usize
+        where Q: PartialOrd<N::K>
+    {
+        self.count_prefix(|key| query > key)
+    }
+
+    /// Returns the number of items whose keys lie within `bounds`.
+    pub fn count_range<Q: PartialOrd<N::K>>(&self, bounds: &KeyBounds<Q>) -> usize {
+        // If the range is empty, the items below its start include all those below its end.
+        let below_end = self.count_prefix(|key| bounds.below_end(key));
+        let below_start = self.count_prefix(|key| !bounds.above_start(key));
+        below_end.saturating_sub(below_start)
+    }
 }
 
 
-impl<N: Node> IntoIterator for TreeRepr<N> {
+impl<N: Node, L: Layout> IntoIterator for TreeRepr<N, L> {
     type Item = (N::K, N::V);
-    type IntoIter = IntoIter<N>;
+    type IntoIter = IntoIter<N, L>;
 
     fn into_iter(self) -> Self::IntoIter {
         IntoIter::new(self)
@@ -616,79 +1385,661 @@ macro_rules! traverse_inorder_rev_block {
     )
 }
 
-pub trait Traverse<N: Node> {
+pub trait Traverse<N: Node, L: Layout = Bfs> {
     #[inline(always)]
-    fn traverse_preorder<'a, A, F>(tree: &'a TreeRepr<N>, root: usize, a: &mut A, mut on_next: F)
-        where F: FnMut(&'a TreeRepr<N>, &mut A, usize)
+    fn traverse_preorder<'a, A, F>(tree: &'a TreeRepr<N, L>, root: usize, a: &mut A, mut on_next: F)
+        where F: FnMut(&'a TreeRepr<N, L>, &mut A, usize)
     {
         traverse_preorder_block!(tree, root, a, on_next);
     }
 
     #[inline(always)]
-    fn traverse_inorder<'a, A, F>(tree: &'a TreeRepr<N>, root: usize, a: &mut A, on_next: F)
-        where F: FnMut(&'a TreeRepr<N>, &mut A, usize) -> bool
+    fn traverse_inorder<'a, A, F>(tree: &'a TreeRepr<N, L>, root: usize, a: &mut A, on_next: F)
+        where F: FnMut(&'a TreeRepr<N, L>, &mut A, usize) -> bool
     {
         TreeRepr::traverse_inorder_from(tree, tree.find_min(root), root, a, on_next)
     }
 
-    fn traverse_inorder_from<'a, A, F>(tree: &'a TreeRepr<N>, from: usize, root: usize, a: &mut A, mut on_next: F)
-        where F: FnMut(&'a TreeRepr<N>, &mut A, usize) -> bool
+    fn traverse_inorder_from<'a, A, F>(tree: &'a TreeRepr<N, L>, from: usize, root: usize, a: &mut A, mut on_next: F)
+        where F: FnMut(&'a TreeRepr<N, L>, &mut A, usize) -> bool
     {
         traverse_inorder_block!(tree, from, root, a, on_next);
     }
 
-    fn traverse_inorder_rev<'a, A, F>(tree: &'a TreeRepr<N>, root: usize, a: &mut A, mut on_next: F)
-        where F: FnMut(&'a TreeRepr<N>, &mut A, usize)
+    fn traverse_inorder_rev<'a, A, F>(tree: &'a TreeRepr<N, L>, root: usize, a: &mut A, mut on_next: F)
+        where F: FnMut(&'a TreeRepr<N, L>, &mut A, usize)
     {
         traverse_inorder_rev_block!(tree, root, a, on_next);
     }
 }
 
-pub trait TraverseMut<N: Node>: Traverse<N> {
-    fn traverse_preorder_mut<'a, A, F>(tree: &'a mut TreeRepr<N>, root: usize, a: &mut A, mut on_next: F)
-        where for<'b> F: FnMut(&'b mut TreeRepr<N>, &mut A, usize)
+pub trait TraverseMut<N: Node, L: Layout = Bfs>: Traverse<N, L> {
+    fn traverse_preorder_mut<'a, A, F>(tree: &'a mut TreeRepr<N, L>, root: usize, a: &mut A, mut on_next: F)
+        where for<'b> F: FnMut(&'b mut TreeRepr<N, L>, &mut A, usize)
     {
         traverse_preorder_block!(tree, root, a, on_next);
     }
 
     #[inline(always)]
-    fn traverse_inorder_mut<'a, A, F>(tree: &'a mut TreeRepr<N>, root: usize, a: &mut A, on_next: F)
-        where for<'b> F: FnMut(&'b mut TreeRepr<N>, &mut A, usize) -> bool
+    fn traverse_inorder_mut<'a, A, F>(tree: &'a mut TreeRepr<N, L>, root: usize, a: &mut A, on_next: F)
+        where for<'b> F: FnMut(&'b mut TreeRepr<N, L>, &mut A, usize) -> bool
     {
         let from = tree.find_min(root);
         Self::traverse_inorder_from_mut(tree, from, root, a, on_next)
     }
 
-    fn traverse_inorder_from_mut<'a, A, F>(tree: &'a mut TreeRepr<N>, from: usize, root: usize, a: &mut A, mut on_next: F)
-        where for<'b> F: FnMut(&'b mut TreeRepr<N>, &mut A, usize) -> bool
+    fn traverse_inorder_from_mut<'a, A, F>(tree: &'a mut TreeRepr<N, L>, from: usize, root: usize, a: &mut A, mut on_next: F)
+        where for<'b> F: FnMut(&'b mut TreeRepr<N, L>, &mut A, usize) -> bool
     {
         traverse_inorder_block!(tree, from, root, a, on_next);
     }
 
 
-    fn traverse_inorder_rev_mut<'a, A, F>(tree: &'a mut TreeRepr<N>, root: usize, a: &mut A, mut on_next: F)
-        where for<'b> F: FnMut(&'b mut TreeRepr<N>, &mut A, usize)
+    fn traverse_inorder_rev_mut<'a, A, F>(tree: &'a mut TreeRepr<N, L>, root: usize, a: &mut A, mut on_next: F)
+        where for<'b> F: FnMut(&'b mut TreeRepr<N, L>, &mut A, usize)
     {
         traverse_inorder_rev_block!(tree, root, a, on_next);
     }
 }
 
-impl<N: Node> Traverse<N> for TreeRepr<N> {}
-impl<N: Node> TraverseMut<N> for TreeRepr<N> {}
+impl<N: Node, L: Layout> Traverse<N, L> for TreeRepr<N, L> {}
+impl<N: Node, L: Layout> TraverseMut<N, L> for TreeRepr<N, L> {}
 
 
 
-impl<N: Node> Refill for TreeRepr<N> where N::K: Copy, N::V: Copy {
-    fn refill(&mut self, master: &TreeRepr<N>) {
-        let len = self.data.len();
-        debug_assert!(len == master.data.len());
+impl<N: Node, L: Layout> Refill for TreeRepr<N, L> where N::K: Copy, N::V: Copy {
+    fn refill(&mut self, master: &TreeRepr<N, L>) {
+        let len = master.data.len();
+        if self.data.len() != len {
+            // The storage of `self` has been resized by `insert()`, so we restore the master's
+            // layout. The items are `Copy`, therefore nothing has to be dropped.
+            let mut data = Vec::with_capacity(len);
+            unsafe { data.set_len(len); }
+            self.data = data;
+            self.mask = BitSet::new(len);
+            self.layout = master.layout.clone();
+            self.delete_range_cache = master.delete_range_cache.clone();
+        }
         unsafe {
             ptr::copy_nonoverlapping(master.data.as_ptr(), self.data.as_mut_ptr(), len);
-            ptr::copy_nonoverlapping(master.mask.as_ptr(), self.mask.as_mut_ptr(), len);
         }
+        self.mask.copy_from(&master.mask);
         self.size = master.size;
+        self.clear_dirty(len);
+        self.refill_counts(master);
+        self.set_origin(master.as_origin());
     }
-}
+
+    /// Falls back to `refill()` unless `self` was cloned or last refilled from `master`, and
+    /// `master` has not been modified since.
+    fn refill_incremental(&mut self, master: &TreeRepr<N, L>) {
+        let len = master.data.len();
+        if !self.is_copy_of(master) || self.data.len() != len || (self.counts.is_some() && master.counts.is_none()) {
+            return self.refill(master);
+        }
+
+        for block in 0..self.dirty.len() {
+            if self.dirty[block] {
+                let from = block << DIRTY_BLOCK_BITS;
+                let n = cmp::min(len - from, 1 << DIRTY_BLOCK_BITS);
+                unsafe {
+                    ptr::copy_nonoverlapping(master.data.as_ptr().offset(from as isize), self.data.as_mut_ptr().offset(from as isize), n);
+                }
+                self.mask.copy_range_from(&master.mask, from, n);
+                self.dirty[block] = false;
+            }
+        }
+        if let (Some(counts), Some(master_counts)) = (self.counts.as_mut(), master.counts.as_ref()) {
+            for block in 0..self.counts_dirty.len() {
+                if self.counts_dirty[block] {
+                    let from = block << DIRTY_BLOCK_BITS;
+                    let n = cmp::min(len - from, 1 << DIRTY_BLOCK_BITS);
+                    counts[from..from+n].copy_from_slice(&master_counts[from..from+n]);
+                    self.counts_dirty[block] = false;
+                }
+            }
+        }
+        self.size = master.size;
+        self.count_log.clear();
+        self.set_origin(master.as_origin());
+    }
+}
+
+impl<N: Node+Clone, L: Layout> RefillClone for TreeRepr<N, L> {
+    fn refill_clone(&mut self, master: &TreeRepr<N, L>) {
+        self.drop_items();
+
+        let len = master.data.len();
+        if self.data.len() != len {
+            // The storage of `self` has been resized by `insert()`, so we restore the master's
+            // layout. All items have been dropped above, so only the storage must be dropped.
+            let mut data = Vec::with_capacity(len);
+            unsafe {
+                data.set_len(len);
+                self.data.set_len(0);
+            }
+            self.data = data;
+            self.mask = BitSet::new(len);
+            self.layout = master.layout.clone();
+            self.delete_range_cache = master.delete_range_cache.clone();
+        }
+
+        // If `clone()` panics, the tree remains valid and contains the items placed so far.
+        for pos in master.mask.ones() {
+            self.place_at_pos(pos, master.data[pos].clone());
+        }
+        self.clear_dirty(len);
+        self.refill_counts(master);
+        self.set_origin(master.as_origin());
+    }
+}
+
+//---- parallel construction -----------------------------------------------------------------------
+#[cfg(feature = "rayon")]
+const PAR_BUILD_MIN: usize = 1 << 14;
+
+/// A pointer into the storage that can be shared between the threads building disjoint subtrees.
+#[cfg(feature = "rayon")]
+struct SharedPtr<T>(*mut T);
+
+#[cfg(feature = "rayon")]
+impl<T> Clone for SharedPtr<T> {
+    fn clone(&self) -> Self { *self }
+}
+#[cfg(feature = "rayon")]
+impl<T> Copy for SharedPtr<T> {}
+
+#[cfg(feature = "rayon")]
+unsafe impl<T: Send> Send for SharedPtr<T> {}
+#[cfg(feature = "rayon")]
+unsafe impl<T: Send> Sync for SharedPtr<T> {}
+
+#[cfg(feature = "rayon")]
+impl<N: Node+Send> TreeRepr<N> where N::K: Send, N::V: Send {
+    /// Same as `new()`, but the items are sorted and the tree is built in parallel.
+    pub fn par_new(mut items: Vec<(N::K, N::V)>) -> TreeRepr<N> {
+        items.par_sort_by(|a, b| a.0.cmp(&b.0));
+        Self::par_with_sorted(items)
+    }
+
+    /// Same as `with_sorted()`, but the tree is built in parallel.
+    /// Note: the argument must be sorted!
+    pub fn par_with_sorted(mut sorted: Vec<(N::K, N::V)>) -> TreeRepr<N> {
+        let size = sorted.len();
+
+        let mut data = Vec::with_capacity(size);
+        // We use manual management of `data`'s memory. To ensure nothing bad is going on, we
+        // analyze each access to `data`.
+        unsafe { data.set_len(size); }
+
+        // All `size` slots are occupied (see `mark_built()`).
+        let mask = BitSet::filled(size);
+        let height = Self::par_build(&mut sorted, 0, SharedPtr(data.as_mut_ptr()));
+        // As per contract with `build()`, we safely dispose of the contents of `sorted` without dropping them.
+        unsafe { sorted.set_len(0); }
+        let cache = DeleteRangeCache::new(height);
+        TreeRepr { data: data, mask: mask, size: size, dirty: vec![false; dirty_blocks(size)],
+                   id: next_id(), generation: 0, origin: Origin::None,
+                   counts: None, count_log: Vec::new(), counts_dirty: Vec::new(), delete_range_cache: cache,
+                   auto_compact: None, layout: Bfs }
+    }
+
+    /// Same as `build()`, but the two subtrees of each large enough subtree are built in parallel.
+    fn par_build(sorted: &mut [(N::K, N::V)], idx: usize, data: SharedPtr<N>) -> usize {
+        let n = sorted.len();
+        if n < PAR_BUILD_MIN {
+            // This is safe: see `build_raw()`.
+            return unsafe { Self::build_raw(sorted, idx, data.0, &Bfs) };
+        }
+
+        let mid = Self::build_select_root(n);
+        let (left, rest) = sorted.split_at_mut(mid);
+        let (root, right) = rest.split_first_mut().unwrap();
+        let (lh, rh) = rayon::join(|| Self::par_build(left, lefti(idx), data),
+                                   || Self::par_build(right, righti(idx), data));
+
+        // This is safe for the same reasons as in `build_raw()`; the subtrees built concurrently
+        // above write to disjoint sets of slots, none of which is `idx`.
+        unsafe {
+            let (k, v) = ptr::read(root);
+            ptr::write(data.0.offset(idx as isize), N::new(k, v));
+        }
+
+        debug_assert!(rh <= lh);
+        1 + lh
+    }
+
+    /// Computes a value for every node bottom-up: `f` receives the node along with the values
+    /// computed for its children (None for an empty child). Large subtrees are processed in
+    /// parallel. Returns the value of the root, or None if the tree is empty.
+    pub fn par_fold_postorder<T, F>(&mut self, f: &F) -> Option<T>
+        where T: Send, F: Fn(&mut N, Option<T>, Option<T>) -> T + Sync
+    {
+        let len = self.data.len();
+        let (data, mask) = (SharedPtr(self.data.as_mut_ptr()), self.mask.as_shared());
+        for block in self.dirty.iter_mut() {
+            *block = true;
+        }
+        self.touch();
+        Self::par_fold_rec(data, mask, len, 0, f)
+    }
+
+    fn par_fold_rec<T, F>(data: SharedPtr<N>, mask: SharedBits, len: usize, idx: usize, f: &F) -> Option<T>
+        where T: Send, F: Fn(&mut N, Option<T>, Option<T>) -> T + Sync
+    {
+        // This is safe: `idx < len` is checked before any access, and each invocation only accesses
+        // the slots of its own subtree, so concurrent invocations never touch the same node.
+        unsafe {
+            if idx >= len || !mask.get(idx) {
+                return None;
+            }
+
+            // the number of slots in the subtree halves with each level
+            let (l, r) = if len >> depth_of(idx) >= PAR_BUILD_MIN {
+                rayon::join(|| Self::par_fold_rec(data, mask, len, lefti(idx), f),
+                            || Self::par_fold_rec(data, mask, len, righti(idx), f))
+            } else {
+                (Self::par_fold_rec(data, mask, len, lefti(idx), f),
+                 Self::par_fold_rec(data, mask, len, righti(idx), f))
+            };
+            Some(f(&mut *data.0.offset(idx as isize), l, r))
+        }
+    }
+}
+
+
+//---- parallel bulk operations --------------------------------------------------------------------
+#[cfg(feature = "rayon")]
+impl<N: Node+Send> TreeRepr<N> where N::K: Send, N::V: Send {
+    /// Consumes the subtrees recorded in `chunks` by a `ChunkedDriver`, in parallel, and returns
+    /// the sinks holding the output in order. Subtrees spanning at least `min_subtree` slots are
+    /// split further, with each part consumed into its own sink created by `sink_factory`.
+    pub fn par_consume_chunks<S, F>(&mut self, chunks: Vec<Chunk<S>>, min_subtree: usize, sink_factory: &F) -> Vec<S>
+        where S: Sink<(N::K, N::V)>+Send, F: Fn() -> S + Sync
+    {
+        let roots: Vec<usize> = chunks.iter()
+            .filter_map(|chunk| match *chunk { Chunk::Subtree(root) => Some(root), Chunk::Items(_) => None })
+            .collect();
+
+        let len = self.data.len();
+        let (data, mask) = (SharedPtr(self.data.as_mut_ptr()), self.mask.as_shared());
+        let parts: Vec<(Vec<S>, usize)> = chunks.into_par_iter()
+            .map(|chunk| match chunk {
+                Chunk::Items(sink) => (vec![sink], 0),
+                Chunk::Subtree(root) => Self::par_consume_rec(data, mask, len, root, min_subtree, sink_factory),
+            })
+            .collect();
+
+        // The bookkeeping that `take()` does for each item is done per subtree instead.
+        for &root in roots.iter() {
+            self.mark_subtree_dirty(root);
+            if self.counts.is_some() {
+                self.recompute_subtree_counts(root);
+                self.count_log.push(root);
+            }
+        }
+
+        let mut sinks = Vec::with_capacity(parts.len());
+        for (part, nconsumed) in parts {
+            self.size -= nconsumed;
+            sinks.extend(part);
+        }
+        sinks
+    }
+
+    /// Consumes the subtree rooted at `idx` in order. Returns the sinks holding its items and the
+    /// number of items.
+    fn par_consume_rec<S, F>(data: SharedPtr<N>, mask: SharedBits, len: usize, idx: usize,
+                             min_subtree: usize, sink_factory: &F) -> (Vec<S>, usize)
+        where S: Sink<(N::K, N::V)>+Send, F: Fn() -> S + Sync
+    {
+        // This is safe: `idx < len` is checked before any access, and each invocation only accesses
+        // the slots of its own subtree, so concurrent invocations never touch the same node. Each
+        // node is read exactly once, and its slot is marked as empty, so it is never dropped.
+        unsafe {
+            if idx >= len || !mask.get(idx) {
+                return (Vec::new(), 0);
+            }
+
+            if len >> depth_of(idx) < min_subtree {
+                let mut sink = sink_factory();
+                let n = Self::consume_raw(data, mask, len, idx, &mut sink);
+                return (vec![sink], n);
+            }
+
+            let ((mut sinks, nleft), (right, nright)) =
+                rayon::join(|| Self::par_consume_rec(data, mask, len, lefti(idx), min_subtree, sink_factory),
+                            || Self::par_consume_rec(data, mask, len, righti(idx), min_subtree, sink_factory));
+            if sinks.is_empty() {
+                sinks.push(sink_factory());
+            }
+            mask.clear(idx);
+            let node = ptr::read(data.0.offset(idx as isize));
+            sinks.last_mut().unwrap().consume(node.into_tuple());
+            sinks.extend(right);
+            (sinks, nleft + nright + 1)
+        }
+    }
+
+    /// The sequential part of `par_consume_rec()`. The caller must make sure that `idx < len`.
+    unsafe fn consume_raw<S>(data: SharedPtr<N>, mask: SharedBits, len: usize, idx: usize, sink: &mut S) -> usize
+        where S: Sink<(N::K, N::V)>
+    {
+        if !mask.get(idx) {
+            return 0;
+        }
+        let mut n = 1;
+        if lefti(idx) < len {
+            n += Self::consume_raw(data, mask, len, lefti(idx), sink);
+        }
+        mask.clear(idx);
+        sink.consume(ptr::read(data.0.offset(idx as isize)).into_tuple());
+        if righti(idx) < len {
+            n += Self::consume_raw(data, mask, len, righti(idx), sink);
+        }
+        n
+    }
+}
+
+#[cfg(feature = "rayon")]
+impl<N: Node+Sync> TreeRepr<N> where N::K: Sync {
+    /// Feeds the nodes inside `bounds`, mapped by `f`, into sinks created by `sink_factory`, and
+    /// returns the sinks in order. Subtrees spanning at least `min_subtree` slots are traversed in
+    /// parallel, each half into its own sinks.
+    pub fn par_query_range<'a, T, G, S, F>(&'a self, bounds: &KeyBounds<N::K>, min_subtree: usize, f: &G,
+                                           sink_factory: &F) -> Vec<S>
+        where G: Fn(&'a N) -> T + Sync, S: Sink<T>+Send, F: Fn() -> S + Sync
+    {
+        if bounds.is_empty() {
+            return Vec::new();
+        }
+        Self::par_query_rec(&self.data, &self.mask, 0, bounds, min_subtree, f, sink_factory)
+    }
+
+    fn par_query_rec<'a, T, G, S, F>(data: &'a [N], mask: &'a BitSet, idx: usize, bounds: &KeyBounds<N::K>,
+                                     min_subtree: usize, f: &G, sink_factory: &F) -> Vec<S>
+        where G: Fn(&'a N) -> T + Sync, S: Sink<T>+Send, F: Fn() -> S + Sync
+    {
+        if idx >= data.len() || !mask.get(idx) {
+            return Vec::new();
+        }
+
+        if data.len() >> depth_of(idx) < min_subtree {
+            let mut sink = sink_factory();
+            Self::query_rec(data, mask, idx, bounds, f, &mut sink);
+            return vec![sink];
+        }
+
+        let key = data[idx].key();
+        let (go_left, go_right) = (bounds.above_start(key), bounds.below_end(key));
+        let (mut sinks, right) = rayon::join(
+            || if go_left { Self::par_query_rec(data, mask, lefti(idx), bounds, min_subtree, f, sink_factory) }
+               else { Vec::new() },
+            || if go_right { Self::par_query_rec(data, mask, righti(idx), bounds, min_subtree, f, sink_factory) }
+               else { Vec::new() });
+        if go_left && go_right {
+            if sinks.is_empty() {
+                sinks.push(sink_factory());
+            }
+            sinks.last_mut().unwrap().consume(f(&data[idx]));
+        }
+        sinks.extend(right);
+        sinks
+    }
+
+    /// The sequential part of `par_query_rec()`.
+    fn query_rec<'a, T, G, S>(data: &'a [N], mask: &'a BitSet, idx: usize, bounds: &KeyBounds<N::K>, f: &G,
+                              sink: &mut S)
+        where G: Fn(&'a N) -> T, S: Sink<T>
+    {
+        if idx >= data.len() || !mask.get(idx) {
+            return;
+        }
+        let key = data[idx].key();
+        let (go_left, go_right) = (bounds.above_start(key), bounds.below_end(key));
+        if go_left {
+            Self::query_rec(data, mask, lefti(idx), bounds, f, sink);
+        }
+        if go_left && go_right {
+            sink.consume(f(&data[idx]));
+        }
+        if go_right {
+            Self::query_rec(data, mask, righti(idx), bounds, f, sink);
+        }
+    }
+}
+
+
+//---- snapshots -----------------------------------------------------------------------------------
+impl<N: SnapshotNode> TreeRepr<N> {
+    /// Constructs a tree from a snapshot held in memory. See `refill_from_snapshot()`.
+    pub fn from_snapshot(bytes: &[u8]) -> Result<TreeRepr<N>, SnapshotError> {
+        let mut repr = TreeRepr::with_sorted(Vec::new());
+        repr.refill_from_snapshot(bytes)?;
+        Ok(repr)
+    }
+
+    /// Writes a binary snapshot of the tree to `w`. See `base::snapshot` for the format.
+    pub fn write_snapshot<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        let mut header = SnapshotHeader {
+            version: SNAPSHOT_VERSION,
+            node_size: mem::size_of::<N>() as u32,
+            capacity: self.data.len() as u64,
+            size: self.size as u64,
+            height: levels_of(self.data.len()) as u64,
+            checksum: 0,
+        };
+        let mut checksum = header.checksum();
+        self.for_each_snapshot_chunk(|chunk| {
+            checksum.update(chunk);
+            Ok(())
+        })?;
+        header.checksum = checksum.value();
+
+        w.write_all(&header.to_bytes())?;
+        self.for_each_snapshot_chunk(|chunk| w.write_all(chunk))
+    }
+
+    /// Refills the tree from a snapshot held in memory (e.g. the contents of a file or a
+    /// memory-mapped buffer). The nodes are copied from `bytes` straight into the storage. The
+    /// snapshot is validated before anything is copied, so on error the tree is left unchanged.
+    pub fn refill_from_snapshot(&mut self, bytes: &[u8]) -> Result<(), SnapshotError> {
+        let snapshot = SnapshotBytes::parse(bytes, mem::size_of::<N>())?;
+        self.refill_from_bytes(&snapshot);
+        Ok(())
+    }
+
+    /// Refills the tree from a snapshot that has already been validated by `SnapshotBytes::parse()`.
+    pub fn refill_from_bytes(&mut self, snapshot: &SnapshotBytes) {
+        let header = snapshot.header;
+        debug_assert!(header.node_size as usize == mem::size_of::<N>());
+
+        self.reset_storage(header.capacity(), header.height as usize);
+        self.mask.copy_from_bytes(0, snapshot.mask);
+        // This is safe: the items are Pod, so the old contents need not be dropped and any bytes
+        // make valid nodes, and the snapshot has been checked to contain exactly `capacity` nodes
+        // of the right size.
+        unsafe {
+            ptr::copy_nonoverlapping(snapshot.data.as_ptr(), self.data.as_mut_ptr() as *mut u8, snapshot.data.len());
+        }
+        self.clear_dirty(header.capacity());
+        self.finish_snapshot_refill(header.size as usize);
+        self.set_origin(Origin::Snapshot { checksum: header.checksum });
+    }
+
+    /// Same as `refill_from_bytes()`, but restores only the parts of the storage that were modified
+    /// since `self` was last refilled from the same snapshot (see `Refill::refill_incremental()`).
+    /// Falls back to `refill_from_bytes()` if `self` was last refilled from something else.
+    pub fn refill_incremental_from_bytes(&mut self, snapshot: &SnapshotBytes) {
+        let header = snapshot.header;
+        let len = header.capacity();
+        if self.origin != (Origin::Snapshot { checksum: header.checksum }) || self.data.len() != len
+            || self.counts.is_some() {
+            // the snapshot carries no counts, so they would have to be recomputed anyway
+            return self.refill_from_bytes(snapshot);
+        }
+
+        let node_size = mem::size_of::<N>();
+        for block in 0..self.dirty.len() {
+            if self.dirty[block] {
+                let from = block << DIRTY_BLOCK_BITS;
+                let n = cmp::min(len - from, 1 << DIRTY_BLOCK_BITS);
+                self.mask.copy_from_bytes(from, &snapshot.mask[from..from+n]);
+                // This is safe: see refill_from_bytes().
+                unsafe {
+                    ptr::copy_nonoverlapping(snapshot.data.as_ptr().offset((from * node_size) as isize),
+                                             self.data.as_mut_ptr().offset(from as isize) as *mut u8,
+                                             n * node_size);
+                }
+                self.dirty[block] = false;
+            }
+        }
+        self.size = header.size as usize;
+        self.count_log.clear();
+        self.touch();
+    }
+
+    /// Refills the tree from a snapshot read from `r`. The nodes are read straight into the
+    /// storage, so no intermediate buffer is needed. If the snapshot turns out to be invalid after
+    /// the reading of the nodes has begun, the tree is left empty.
+    pub fn refill_from_reader<R: Read>(&mut self, r: &mut R) -> Result<(), SnapshotError> {
+        let mut header = [0; HEADER_LEN];
+        r.read_exact(&mut header)?;
+        let header = SnapshotHeader::parse(&header, mem::size_of::<N>())?;
+
+        let mut checksum = header.checksum();
+        // Nothing in the header has been verified yet, so the mask is not allocated up front but
+        // grows as it is read. Once it has been read, the storage allocated for the nodes is
+        // proportional to the size of the input.
+        let mut mask = Vec::new();
+        r.by_ref().take(header.mask_len() as u64).read_to_end(&mut mask)?;
+        if mask.len() != header.mask_len() {
+            return Err(SnapshotError::Truncated);
+        }
+        checksum.update(&mask);
+        mask.truncate(header.capacity());
+        validate_mask(&mask, header.size as usize)?;
+
+        self.reset_storage(header.capacity(), header.height as usize);
+        self.mask.clear();
+        self.size = 0;
+
+        let result = {
+            // This is safe: the items are Pod, so the old contents need not be dropped and any bytes
+            // make valid nodes. The storage is zeroed first, so `r` never sees uninitialized memory.
+            let data = unsafe {
+                let ptr = self.data.as_mut_ptr() as *mut u8;
+                ptr::write_bytes(ptr, 0, header.data_len());
+                slice::from_raw_parts_mut(ptr, header.data_len())
+            };
+            r.read_exact(data).map_err(SnapshotError::from).and_then(|_| {
+                checksum.update(data);
+                if checksum.value() == header.checksum { Ok(()) } else { Err(SnapshotError::ChecksumMismatch) }
+            })
+        };
+        if result.is_ok() {
+            self.mask.copy_from_bytes(0, &mask);
+            self.clear_dirty(header.capacity());
+            self.finish_snapshot_refill(header.size as usize);
+            self.set_origin(Origin::Snapshot { checksum: header.checksum });
+        } else {
+            self.finish_snapshot_refill(0);
+            self.set_origin(Origin::None);
+        }
+        result
+    }
+
+    /// Passes the payload of a snapshot to `f` in chunks.
+    fn for_each_snapshot_chunk<F>(&self, mut f: F) -> io::Result<()>
+        where F: FnMut(&[u8]) -> io::Result<()>
+    {
+        let capacity = self.data.len();
+        let node_size = mem::size_of::<N>();
+
+        // The snapshot stores one byte per slot, so the mask is expanded in chunks.
+        let max_run = 1 << DIRTY_BLOCK_BITS;
+        let mut bytes = Vec::with_capacity(max_run);
+        for from in (0..capacity).step_by(max_run) {
+            bytes.clear();
+            self.mask.extend_bytes(from, cmp::min(max_run, capacity - from), &mut bytes);
+            f(&bytes)?;
+        }
+        f(&[0; PAYLOAD_ALIGN][..padding(capacity)])?;
+
+        // The nodes are written field by field, so that neither the padding bytes of the nodes nor
+        // the garbage in the empty slots is read; those bytes are left zeroed.
+        let mut buf = vec![0; node_size * max_run];
+        for from in (0..capacity).step_by(max_run) {
+            let n = cmp::min(max_run, capacity - from);
+            for b in &mut buf[..n * node_size] {
+                *b = 0;
+            }
+            for i in from..from+n {
+                if self.mask(i) {
+                    let offset = (i - from) * node_size;
+                    self.data[i].write_fields(&mut buf[offset..offset + node_size]);
+                }
+            }
+            f(&buf[..n * node_size])?;
+        }
+        Ok(())
+    }
+
+    /// Makes sure the storage has the given capacity. The items are Copy, so the old contents are
+    /// simply discarded. All blocks are marked dirty until the caller has filled them.
+    fn reset_storage(&mut self, capacity: usize, height: usize) {
+        if self.data.len() != capacity {
+            let mut data = Vec::with_capacity(capacity);
+            unsafe { data.set_len(capacity); }
+            self.data = data;
+            self.mask = BitSet::new(capacity);
+        }
+        if self.delete_range_cache.slots_max.capacity != height {
+            self.delete_range_cache = DeleteRangeCache::new(height);
+        }
+        self.dirty.clear();
+        self.dirty.resize(dirty_blocks(capacity), true);
+        self.touch();
+    }
+
+    fn finish_snapshot_refill(&mut self, size: usize) {
+        self.size = size;
+        if self.counts.is_some() {
+            self.enable_counts();
+        } else {
+            self.count_log.clear();
+        }
+    }
+}
+
+
+impl<N: Node, L: Layout> TreeRepr<N, L> {
+    /// True if `self` was cloned or last refilled from `master`, and `master` has not been modified
+    /// since. Only then do the dirty flags of `self` tell where it differs from `master`.
+    pub fn is_copy_of(&self, master: &TreeRepr<N, L>) -> bool {
+        self.origin == master.as_origin()
+    }
+
+    fn as_origin(&self) -> Origin {
+        Origin::Tree { id: self.id, generation: self.generation }
+    }
+
+    /// Restores the subtree counts after `self` has been refilled from `master`.
+    fn refill_counts(&mut self, master: &TreeRepr<N, L>) {
+        self.count_log.clear();
+        match (self.counts.is_some(), master.counts.as_ref()) {
+            (true, Some(master_counts)) => {
+                let counts = self.counts.as_mut().unwrap();
+                counts.clear();
+                counts.extend_from_slice(master_counts);
+                self.counts_dirty.clear();
+                self.counts_dirty.resize(dirty_blocks(master_counts.len()), false);
+            },
+            (true, None) => self.enable_counts(),
+            (false, _) => {}
+        }
+    }
+}
 
 
 /// Returns the closest subtree A enclosing `idx`, such that A is the left child (or 0 if no such
@@ -696,7 +2047,7 @@ impl<N: Node> Refill for TreeRepr<N> where N::K: Copy, N::V: Copy {
 /// child.
 /// **Attention!** For efficiency reasons, idx and return value are both **1-based**.
 #[inline(always)]
-fn left_enclosing(idx: usize) -> usize {
+pub fn left_enclosing(idx: usize) -> usize {
     if idx & 1 == 0 {
         idx
     } else if idx & 2 == 0 {
@@ -728,25 +2079,207 @@ fn right_enclosing(idx: usize) -> usize {
 }
 
 
+/// Returns the depth of the node at `idx` (the root is at depth 0).
+#[inline(always)]
+pub fn depth_of(idx: usize) -> usize {
+    let bits = mem::size_of::<usize>() * 8;
+    bits - 1 - (idx + 1).leading_zeros() as usize
+}
+
+/// Returns true if the node at `a` comes before the node at `b` in-order, or is the same node.
+#[inline(always)]
+pub fn inorder_le(a: usize, b: usize) -> bool {
+    let (depth_a, depth_b) = (depth_of(a), depth_of(b));
+    let depth = cmp::max(depth_a, depth_b);
+    // The in-order position of a node among the nodes of a complete tree of the given depth.
+    let pos = |idx: usize, d: usize| (2*(idx + 1 - (1 << d)) + 1) << (depth - d);
+    pos(a, depth_a) <= pos(b, depth_b)
+}
+
+/// Returns the number of levels needed to store a tree in an array of the given capacity.
+#[inline]
+pub fn levels_of(capacity: usize) -> usize {
+    if capacity == 0 { 0 } else { depth_of(capacity - 1) + 1 }
+}
+
+/// Returns the number of flags in `TreeRepr::dirty` for the given capacity.
+#[inline]
+fn dirty_blocks(capacity: usize) -> usize {
+    (capacity + (1 << DIRTY_BLOCK_BITS) - 1) >> DIRTY_BLOCK_BITS
+}
+
+/// Returns the subtree count at `idx`, or 0 if `idx` lies outside the array.
+#[inline(always)]
+fn count_at(counts: &[usize], idx: usize) -> usize {
+    if idx < counts.len() { counts[idx] } else { 0 }
+}
+
+/// Returns the capacity of a complete tree of the given height.
+#[inline]
+pub fn full_capacity(height: usize) -> usize {
+    (1 << height) - 1
+}
+
 
 
 
-pub struct Iter<'a, N: Node> where N: 'a, N::K: 'a, N::V: 'a {
-    tree: &'a TreeRepr<N>,
+pub struct Iter<'a, N: Node, L: Layout = Bfs> where N: 'a, N::K: 'a, N::V: 'a {
+    tree: &'a TreeRepr<N, L>,
     next_idx: usize,
+    next_back_idx: usize,
     remaining: usize
 }
 
-impl <'a, N: Node> Iter<'a, N> where N::K: 'a, N::V: 'a {
-    fn new(tree: &'a TreeRepr<N>) -> Iter<'a, N> {
+impl <'a, N: Node, L: Layout> Iter<'a, N, L> where N::K: 'a, N::V: 'a {
+    fn new(tree: &'a TreeRepr<N, L>) -> Iter<'a, N, L> {
         let next_idx = tree.find_min(0);
-        Iter { tree:tree, next_idx:next_idx, remaining:tree.size }
+        let next_back_idx = tree.find_max(0);
+        Iter { tree:tree, next_idx:next_idx, next_back_idx:next_back_idx, remaining:tree.size }
     }
 }
 
-impl<'a, N: Node> Iterator for Iter<'a, N> where N: 'a, N::K: 'a, N::V: 'a {
+impl<'a, N: Node, L: Layout> Iterator for Iter<'a, N, L> where N: 'a, N::K: 'a, N::V: 'a {
     type Item = &'a Entry<N::K, N::V>;
 
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            None
+        } else {
+            let curr = self.next_idx;
+            self.next_idx =
+                iter_next_idx(curr, self.tree)
+                    .map_or_else(|| self.tree.data.capacity(), |x| x);
+            self.remaining -= 1;
+            Some(self.tree.node(curr).deref())
+        }
+    }
+
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl<'a, N: Node, L: Layout> DoubleEndedIterator for Iter<'a, N, L> where N: 'a, N::K: 'a, N::V: 'a {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            None
+        } else {
+            let curr = self.next_back_idx;
+            self.next_back_idx =
+                iter_prev_idx(curr, self.tree)
+                    .map_or_else(|| self.tree.data.capacity(), |x| x);
+            self.remaining -= 1;
+            Some(self.tree.node(curr).deref())
+        }
+    }
+}
+
+impl<'a, N: Node, L: Layout> ExactSizeIterator for Iter<'a, N, L> {}
+
+
+
+/// Iterates over the items in storage order; see `TreeRepr::iter_unordered()`.
+pub struct UnorderedIter<'a, N: Node, L: Layout = Bfs> where N: 'a, N::K: 'a, N::V: 'a {
+    tree: &'a TreeRepr<N, L>,
+    slots: Ones<'a>,
+    remaining: usize
+}
+
+impl<'a, N: Node, L: Layout> Iterator for UnorderedIter<'a, N, L> where N: 'a, N::K: 'a, N::V: 'a {
+    type Item = &'a Entry<N::K, N::V>;
+
+    #[inline]
+    fn next(&mut self) -> Option<Self::Item> {
+        self.slots.next().map(|idx| {
+            self.remaining -= 1;
+            self.tree.data[idx].deref()
+        })
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl<'a, N: Node, L: Layout> ExactSizeIterator for UnorderedIter<'a, N, L> {}
+
+
+
+/// Iterates over the items inside a range in order.
+pub struct RangeIter<'a, N: Node, L: Layout = Bfs> where N: 'a, N::K: 'a, N::V: 'a {
+    tree: &'a TreeRepr<N, L>,
+    // The first and the last item that have not been yielded yet (None when the range is exhausted).
+    next_idx: Option<usize>,
+    next_back_idx: Option<usize>
+}
+
+impl <'a, N: Node, L: Layout> RangeIter<'a, N, L> where N::K: 'a, N::V: 'a {
+    pub fn new<Q: PartialOrd<N::K>>(tree: &'a TreeRepr<N, L>, bounds: &KeyBounds<Q>) -> RangeIter<'a, N, L> {
+        let first = tree.lower_bound(bounds);
+        let last = tree.upper_bound(bounds);
+        let empty = tree.is_nil(first) || !bounds.below_end(tree.key(first))
+            || tree.is_nil(last) || !bounds.above_start(tree.key(last));
+
+        if empty {
+            RangeIter { tree:tree, next_idx:None, next_back_idx:None }
+        } else {
+            RangeIter { tree:tree, next_idx:Some(first), next_back_idx:Some(last) }
+        }
+    }
+}
+
+impl<'a, N: Node, L: Layout> Iterator for RangeIter<'a, N, L> where N: 'a, N::K: 'a, N::V: 'a {
+    type Item = &'a Entry<N::K, N::V>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_idx.map(|curr| {
+            if self.next_back_idx == Some(curr) {
+                self.next_idx = None;
+                self.next_back_idx = None;
+            } else {
+                self.next_idx = iter_next_idx(curr, self.tree);
+            }
+            self.tree.node(curr).deref()
+        })
+    }
+}
+
+impl<'a, N: Node, L: Layout> DoubleEndedIterator for RangeIter<'a, N, L> where N: 'a, N::K: 'a, N::V: 'a {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.next_back_idx.map(|curr| {
+            if self.next_idx == Some(curr) {
+                self.next_idx = None;
+                self.next_back_idx = None;
+            } else {
+                self.next_back_idx = iter_prev_idx(curr, self.tree);
+            }
+            self.tree.node(curr).deref()
+        })
+    }
+}
+
+
+
+/// Iterates over the items in order, giving mutable access to the values (but not the keys, which
+/// would break the order of the tree).
+pub struct IterMut<'a, N: Node, L: Layout = Bfs> where N: 'a, N::K: 'a, N::V: 'a {
+    tree: &'a mut TreeRepr<N, L>,
+    next_idx: usize,
+    remaining: usize
+}
+
+impl <'a, N: Node, L: Layout> IterMut<'a, N, L> where N::K: 'a, N::V: 'a {
+    fn new(tree: &'a mut TreeRepr<N, L>) -> IterMut<'a, N, L> {
+        let next_idx = tree.find_min(0);
+        let remaining = tree.size;
+        IterMut { tree:tree, next_idx:next_idx, remaining:remaining }
+    }
+}
+
+impl<'a, N: Node, L: Layout> Iterator for IterMut<'a, N, L> where N: 'a, N::K: 'a, N::V: 'a {
+    type Item = (&'a N::K, &'a mut N::V);
+
     fn next(&mut self) -> Option<Self::Item> {
         let curr = self.next_idx;
         if self.tree.is_nil(curr) {
@@ -756,7 +2289,10 @@ impl<'a, N: Node> Iterator for Iter<'a, N> where N: 'a, N::K: 'a, N::V: 'a {
                 iter_next_idx(curr, self.tree)
                     .map_or_else(|| self.tree.data.capacity(), |x| x);
             self.remaining -= 1;
-            Some(self.tree.node(curr).deref())
+            // This is safe: every node is visited once, so the references we hand out never alias,
+            // and they cannot outlive the mutable borrow of the tree.
+            let node: &'a mut N = self.tree.node_mut_unsafe(curr);
+            Some(node.deref_mut().key_val_mut())
         }
     }
 
@@ -766,50 +2302,103 @@ impl<'a, N: Node> Iterator for Iter<'a, N> where N: 'a, N::K: 'a, N::V: 'a {
     }
 }
 
-impl<'a, N: Node> ExactSizeIterator for Iter<'a, N> {}
+impl<'a, N: Node, L: Layout> ExactSizeIterator for IterMut<'a, N, L> {}
 
 
-pub struct IntoIter<N: Node> {
-    tree: TreeRepr<N>,
-    next_idx: usize
+pub struct IntoIter<N: Node, L: Layout = Bfs> {
+    tree: TreeRepr<N, L>,
+    next_idx: usize,
+    next_back_idx: usize,
+    remaining: usize
 }
 
-impl <N: Node> IntoIter<N> {
-    pub fn new(tree: TreeRepr<N>) -> Self {
+impl <N: Node, L: Layout> IntoIter<N, L> {
+    pub fn new(mut tree: TreeRepr<N, L>) -> Self {
         let next_idx = tree.find_min(0);
-        IntoIter { tree:tree, next_idx:next_idx }
+        let next_back_idx = tree.find_max(0);
+        let remaining = tree.size;
+        // the counts are of no use while the tree is being consumed
+        tree.counts = None;
+        tree.count_log = Vec::new();
+        IntoIter { tree:tree, next_idx:next_idx, next_back_idx:next_back_idx, remaining:remaining }
+    }
+
+    /// Moves the item at `idx` out of the tree. The item stays marked as present in `mask`, so
+    /// that we can keep navigating the tree from both ends; `drop()` takes care of the rest.
+    #[inline]
+    fn read(&mut self, idx: usize) -> (N::K, N::V) {
+        self.remaining -= 1;
+        // This is safe: every item is read at most once, as `remaining` prevents the two ends
+        // from passing each other.
+        unsafe {
+            ptr::read(self.tree.node(idx)).into_tuple()
+        }
     }
 }
 
-impl<N: Node> Iterator for IntoIter<N> {
+impl<N: Node, L: Layout> Iterator for IntoIter<N, L> {
     type Item = (N::K, N::V);
 
     fn next(&mut self) -> Option<Self::Item> {
-        let curr = self.next_idx;
-        let done = self.tree.data.capacity();
-        if curr == done {
+        if self.remaining == 0 {
             None
         } else {
-            debug_assert!(!self.tree.is_nil(curr));
+            let curr = self.next_idx;
+            let done = self.tree.data.capacity();
             self.next_idx =
-                iter_next_idx(self.next_idx, &self.tree)
+                iter_next_idx(curr, &self.tree)
                     .map_or_else(|| done, |x| x);
 
-            Some(self.tree.take(curr).into_tuple())
+            Some(self.read(curr))
         }
     }
 
     fn size_hint(&self) -> (usize, Option<usize>) {
-        (self.tree.size, Some(self.tree.size))
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl<N: Node, L: Layout> DoubleEndedIterator for IntoIter<N, L> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            None
+        } else {
+            let curr = self.next_back_idx;
+            let done = self.tree.data.capacity();
+            self.next_back_idx =
+                iter_prev_idx(curr, &self.tree)
+                    .map_or_else(|| done, |x| x);
+
+            Some(self.read(curr))
+        }
     }
 }
 
-impl<N: Node> ExactSizeIterator for IntoIter<N> {}
+impl<N: Node, L: Layout> ExactSizeIterator for IntoIter<N, L> {}
+
+impl<N: Node, L: Layout> Drop for IntoIter<N, L> {
+    fn drop(&mut self) {
+        // Drop the items that have not been yielded: they lie between `next_idx` and `next_back_idx`.
+        let mut idx = self.next_idx;
+        for i in 0..self.remaining {
+            unsafe {
+                ptr::drop_in_place(self.tree.node_mut_unsafe(idx));
+            }
+            if i + 1 < self.remaining {
+                idx = iter_next_idx(idx, &self.tree).unwrap();
+            }
+        }
+
+        // All items have been either moved out or dropped, so `tree` must not drop any of them.
+        self.tree.mask.clear();
+        self.tree.size = 0;
+    }
+}
 
 
 
 #[inline]
-fn iter_next_idx<N: Node>(curr: usize, tree: &TreeRepr<N>) -> Option<usize> {
+fn iter_next_idx<N: Node, L: Layout>(curr: usize, tree: &TreeRepr<N, L>) -> Option<usize> {
     let next = if tree.has_right(curr) {
         tree.find_min(righti(curr))
     } else {
@@ -826,12 +2415,60 @@ fn iter_next_idx<N: Node>(curr: usize, tree: &TreeRepr<N>) -> Option<usize> {
     Some(next)
 }
 
+#[inline]
+fn iter_prev_idx<N: Node, L: Layout>(curr: usize, tree: &TreeRepr<N, L>) -> Option<usize> {
+    let prev = if tree.has_left(curr) {
+        tree.find_max(lefti(curr))
+    } else {
+        let r_enclosing = right_enclosing(curr+1);
+
+        if r_enclosing <= 1 {
+            // done
+            return None
+        }
+
+        parenti(r_enclosing-1)
+    };
+
+    Some(prev)
+}
 
 
 
 
-impl<N: Node> Drop for TreeRepr<N> {
+
+impl<N: Node+Clone, L: Layout> Clone for TreeRepr<N, L> {
+    fn clone(&self) -> Self {
+        let capacity = self.data.len();
+        let mut data = Vec::with_capacity(capacity);
+        // We use manual management of `data`'s memory, see `with_sorted()`.
+        unsafe { data.set_len(capacity); }
+
+        let mut tree = TreeRepr { data: data, mask: BitSet::new(capacity), size: 0,
+                                  dirty: vec![false; dirty_blocks(capacity)],
+                                  id: next_id(), generation: 0, origin: self.as_origin(),
+                                  counts: None, count_log: Vec::new(),
+                                  counts_dirty: vec![false; self.counts_dirty.len()],
+                                  delete_range_cache: self.delete_range_cache.clone(),
+                                  auto_compact: self.auto_compact, layout: self.layout.clone() };
+        // Only the occupied cells are cloned: the rest contain garbage. If `clone()` panics, `tree`
+        // is dropped along with the items placed so far.
+        for pos in self.mask.ones() {
+            tree.place_at_pos(pos, self.data[pos].clone());
+        }
+        // The clone is identical to `self`, which is what `refill_incremental()` expects.
+        for flag in tree.dirty.iter_mut() {
+            *flag = false;
+        }
+        tree.counts = self.counts.clone();
+
+        tree
+    }
+}
+
+impl<N: Node, L: Layout> Drop for TreeRepr<N, L> {
     fn drop(&mut self) {
+        self.counts = None;
         self.drop_items();
         unsafe {
             // the above call drops all contents of data, what remains is to drop the storage
@@ -840,17 +2477,16 @@ impl<N: Node> Drop for TreeRepr<N> {
     }
 }
 
-impl<N: Node> Debug for TreeRepr<N> where N: Debug {
+impl<N: Node, L: Layout> Debug for TreeRepr<N, L> where N: Debug {
     fn fmt(&self, fmt: &mut Formatter) -> fmt::Result {
-        let mut nz: Vec<_> = self.mask.iter().enumerate()
-            .rev()
-            .skip_while(|&(_, flag)| !flag)
-            .map(|(i, &flag)| match (self.node(i), flag) {
-                (_, false) => String::from("X"),
-                (ref node, true) => format!("{:?}", node)
-            })
+        let end = if L::is_identity() {
+            self.mask.last_one().map_or(0, |i| i + 1)
+        } else {
+            (0..self.data.len()).rposition(|i| self.mask(i)).map_or(0, |i| i + 1)
+        };
+        let nz: Vec<_> = (0..end)
+            .map(|i| if self.mask(i) { format!("{:?}", self.node(i)) } else { String::from("X") })
             .collect();
-        nz.reverse();
 
         let _ = write!(fmt, "[size={}: ", self.size);
         let mut sep = "";
@@ -864,7 +2500,7 @@ impl<N: Node> Debug for TreeRepr<N> where N: Debug {
     }
 }
 
-impl<N: Node> fmt::Display for TreeRepr<N> where N: fmt::Debug {
+impl<N: Node, L: Layout> fmt::Display for TreeRepr<N, L> where N: fmt::Debug {
     fn fmt(&self, fmt: &mut Formatter) -> fmt::Result {
         writeln!(fmt, "")?;
         let mut ancestors = vec![];
@@ -873,7 +2509,7 @@ impl<N: Node> fmt::Display for TreeRepr<N> where N: fmt::Debug {
 }
 
 
-impl<N: Node> TreeRepr<N> where N: fmt::Debug {
+impl<N: Node, L: Layout> TreeRepr<N, L> where N: fmt::Debug {
     fn fmt_branch(&self, fmt: &mut Formatter, ancestors: &Vec<bool>) -> fmt::Result {
         for (i, c) in ancestors.iter().enumerate() {
             if i == ancestors.len() - 1 {
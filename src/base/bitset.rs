@@ -0,0 +1,265 @@
+//! A fixed-length set of bits, used as the occupancy mask of `TreeRepr`.
+//!
+//! The bits are packed into 64-bit words, so that the mask takes one bit per slot and operations
+//! on many slots at once (copying, clearing, finding the occupied slots) work a word at a time.
+//!
+//! The words are stored as `AtomicU64`, which may be more strictly aligned than `u64` (e.g. on
+//! 32-bit x86), so that `SharedBits` can access them atomically. Everything else goes through a
+//! plain `u64` view of the words: `SharedBits` is only created from `&mut BitSet`, so the atomic
+//! and the plain accesses never overlap.
+
+use std::{cmp, mem, slice};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+
+const WORD_BITS: usize = 64;
+
+#[derive(Default)]
+pub struct BitSet {
+    words: Vec<AtomicU64>,
+    len: usize,
+}
+
+impl Clone for BitSet {
+    fn clone(&self) -> BitSet {
+        BitSet { words: self.words().iter().map(|&w| AtomicU64::new(w)).collect(), len: self.len }
+    }
+}
+
+impl PartialEq for BitSet {
+    fn eq(&self, other: &BitSet) -> bool {
+        self.len == other.len && self.words() == other.words()
+    }
+}
+
+impl Eq for BitSet {}
+
+impl BitSet {
+    /// Creates a set of `len` bits, all of them clear.
+    pub fn new(len: usize) -> BitSet {
+        BitSet { words: (0..nwords(len)).map(|_| AtomicU64::new(0)).collect(), len: len }
+    }
+
+    #[inline(always)]
+    fn words(&self) -> &[u64] {
+        // This is safe: `AtomicU64` has the same size and bit validity as `u64` and at least its
+        // alignment, and there are no concurrent atomic accesses (see the module docs).
+        unsafe { slice::from_raw_parts(self.words.as_ptr() as *const u64, self.words.len()) }
+    }
+
+    #[inline(always)]
+    fn words_mut(&mut self) -> &mut [u64] {
+        // This is safe: see words().
+        unsafe { slice::from_raw_parts_mut(self.words.as_mut_ptr() as *mut u64, self.words.len()) }
+    }
+
+    /// Creates a set of `len` bits, all of them set.
+    pub fn filled(len: usize) -> BitSet {
+        let mut bits = BitSet::new(len);
+        bits.set_range(0, len);
+        bits
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    #[inline]
+    pub fn get(&self, idx: usize) -> bool {
+        assert!(idx < self.len, "idx={}, len={}", idx, self.len);
+        unsafe { self.get_unchecked(idx) }
+    }
+
+    /// The caller must make sure that `idx < len()`.
+    #[inline(always)]
+    pub unsafe fn get_unchecked(&self, idx: usize) -> bool {
+        debug_assert!(idx < self.len);
+        *self.words().get_unchecked(idx / WORD_BITS) & bit(idx) != 0
+    }
+
+    #[inline]
+    pub fn set(&mut self, idx: usize, value: bool) {
+        assert!(idx < self.len, "idx={}, len={}", idx, self.len);
+        unsafe { self.set_unchecked(idx, value) }
+    }
+
+    /// The caller must make sure that `idx < len()`.
+    #[inline(always)]
+    pub unsafe fn set_unchecked(&mut self, idx: usize, value: bool) {
+        debug_assert!(idx < self.len);
+        let word = self.words_mut().get_unchecked_mut(idx / WORD_BITS);
+        if value {
+            *word |= bit(idx);
+        } else {
+            *word &= !bit(idx);
+        }
+    }
+
+    /// Sets the bits in `[from, to)`.
+    pub fn set_range(&mut self, from: usize, to: usize) {
+        debug_assert!(from <= to && to <= self.len);
+        let mut i = from;
+        while i < to {
+            let (w, offset) = (i / WORD_BITS, i % WORD_BITS);
+            let n = cmp::min(WORD_BITS - offset, to - i);
+            self.words_mut()[w] |= low_bits(n) << offset;
+            i += n;
+        }
+    }
+
+    /// Clears all bits, keeping the allocation.
+    pub fn clear(&mut self) {
+        for word in self.words_mut() {
+            *word = 0;
+        }
+    }
+
+    /// Changes the length to `len`. New bits are clear.
+    pub fn resize(&mut self, len: usize) {
+        if len < self.len {
+            // the bits past the end must be clear
+            let (w, offset) = (len / WORD_BITS, len % WORD_BITS);
+            if offset != 0 {
+                self.words_mut()[w] &= low_bits(offset);
+            }
+        }
+        self.words.resize_with(nwords(len), || AtomicU64::new(0));
+        self.len = len;
+    }
+
+    /// Copies the bits of `other`, which must have the same length.
+    #[inline]
+    pub fn copy_from(&mut self, other: &BitSet) {
+        debug_assert!(self.len == other.len);
+        self.words_mut().copy_from_slice(other.words());
+    }
+
+    /// Copies the bits in `[from, from + n)` from `other`, which must have the same length. `from`
+    /// must be a multiple of 64.
+    #[inline]
+    pub fn copy_range_from(&mut self, other: &BitSet, from: usize, n: usize) {
+        debug_assert!(self.len == other.len && from % WORD_BITS == 0 && from + n <= self.len);
+        let (w_from, w_to) = (from / WORD_BITS, nwords(from + n));
+        self.words_mut()[w_from..w_to].copy_from_slice(&other.words()[w_from..w_to]);
+    }
+
+    /// Returns the index of the last set bit, or None.
+    pub fn last_one(&self) -> Option<usize> {
+        let words = self.words();
+        words.iter().rposition(|&w| w != 0)
+            .map(|w| w * WORD_BITS + WORD_BITS - 1 - words[w].leading_zeros() as usize)
+    }
+
+    /// Iterates over the indices of the set bits in increasing order, skipping over clear words.
+    #[inline]
+    pub fn ones<'a>(&'a self) -> Ones<'a> {
+        let words = self.words();
+        Ones { words: words, word_idx: 0, word: words.first().cloned().unwrap_or(0) }
+    }
+
+    /// Clears all bits, calling `f` with the index of each bit that was set, in increasing order.
+    /// Each word is cleared before `f` is called for its bits, so if `f` panics, the bits it has not
+    /// been called for may be cleared as well.
+    pub fn drain_ones<F: FnMut(usize)>(&mut self, mut f: F) {
+        for (w, word) in self.words_mut().iter_mut().enumerate() {
+            let mut bits = mem::replace(word, 0);
+            while bits != 0 {
+                f(w * WORD_BITS + bits.trailing_zeros() as usize);
+                bits &= bits - 1;
+            }
+        }
+    }
+
+    /// Returns the bits of `[from, from + n)` as bytes, one per bit (0 or 1), appending them to `out`.
+    pub fn extend_bytes(&self, from: usize, n: usize, out: &mut Vec<u8>) {
+        debug_assert!(from + n <= self.len);
+        out.extend((from..from+n).map(|i| unsafe { self.get_unchecked(i) } as u8));
+    }
+
+    /// Sets the bits of `[from, from + bytes.len())` from bytes, one per bit (nonzero meaning set).
+    pub fn copy_from_bytes(&mut self, from: usize, bytes: &[u8]) {
+        debug_assert!(from + bytes.len() <= self.len);
+        for (i, &b) in bytes.iter().enumerate() {
+            unsafe { self.set_unchecked(from + i, b != 0); }
+        }
+    }
+
+    /// A raw pointer to the words, for sharing the set between threads that access disjoint bits.
+    #[inline]
+    pub fn as_shared(&mut self) -> SharedBits {
+        SharedBits(self.words.as_ptr())
+    }
+}
+
+
+/// Iterates over the indices of the set bits of a `BitSet`.
+pub struct Ones<'a> {
+    words: &'a [u64],
+    word_idx: usize,
+    // the bits of `words[word_idx]` that have not been yielded yet
+    word: u64,
+}
+
+impl<'a> Iterator for Ones<'a> {
+    type Item = usize;
+
+    #[inline]
+    fn next(&mut self) -> Option<usize> {
+        while self.word == 0 {
+            self.word_idx += 1;
+            if self.word_idx >= self.words.len() {
+                return None;
+            }
+            self.word = self.words[self.word_idx];
+        }
+        let offset = self.word.trailing_zeros() as usize;
+        // clear the lowest set bit
+        self.word &= self.word - 1;
+        Some(self.word_idx * WORD_BITS + offset)
+    }
+}
+
+
+/// A pointer to the words of a `BitSet` that can be shared between threads. Several bits share a
+/// word, so the accesses are atomic: threads working on disjoint sets of bits do not interfere.
+#[derive(Clone, Copy)]
+pub struct SharedBits(*const AtomicU64);
+
+unsafe impl Send for SharedBits {}
+unsafe impl Sync for SharedBits {}
+
+impl SharedBits {
+    /// The caller must make sure that `idx` is inside the set and that the set outlives `self`.
+    #[inline]
+    pub unsafe fn get(self, idx: usize) -> bool {
+        self.word(idx).load(Ordering::Relaxed) & bit(idx) != 0
+    }
+
+    /// The caller must make sure that `idx` is inside the set and that the set outlives `self`.
+    #[inline]
+    pub unsafe fn clear(self, idx: usize) {
+        self.word(idx).fetch_and(!bit(idx), Ordering::Relaxed);
+    }
+
+    #[inline]
+    unsafe fn word<'a>(self, idx: usize) -> &'a AtomicU64 {
+        &*self.0.offset((idx / WORD_BITS) as isize)
+    }
+}
+
+
+#[inline(always)]
+fn bit(idx: usize) -> u64 {
+    1 << (idx % WORD_BITS)
+}
+
+#[inline(always)]
+fn low_bits(n: usize) -> u64 {
+    if n >= WORD_BITS { !0 } else { (1 << n) - 1 }
+}
+
+#[inline]
+fn nwords(len: usize) -> usize {
+    (len + WORD_BITS - 1) / WORD_BITS
+}
@@ -1,5 +1,6 @@
 use base::{Node, TreeRepr, TreeDerefMut, TraverseMut, Sink, lefti, righti};
 use base::{SlotStack, ItemFilter};
+use base::layout::{Layout, Bfs};
 
 use std::mem;
 
@@ -25,8 +26,8 @@ impl DeleteRangeCache {
 }
 
 
-pub trait ItemVisitor<N: Node>: Sized {
-    type Tree: BulkDeleteCommon<N, Visitor=Self>;
+pub trait ItemVisitor<N: Node, L: Layout = Bfs>: Sized {
+    type Tree: BulkDeleteCommon<N, L, Visitor=Self>;
 
     #[inline(always)]
     fn visit<F>(arg: &mut Self::Tree, idx: usize, f: F)
@@ -36,14 +37,18 @@ pub trait ItemVisitor<N: Node>: Sized {
 
 
 //==== methdos common to bulk-delete operations ====================================================
-pub trait BulkDeleteCommon<N: Node>: TreeDerefMut<N>+Sized  {
-    type Visitor: ItemVisitor<N, Tree=Self>;
+pub trait BulkDeleteCommon<N: Node, L: Layout = Bfs>: TreeDerefMut<N, L>+Sized  {
+    type Visitor: ItemVisitor<N, L, Tree=Self>;
     type Sink: Sink<(N::K, N::V)>;
     type Filter: ItemFilter<N::K>;
 
     #[inline(always)] fn filter_mut(&mut self) -> &mut Self::Filter;
     #[inline(always)] fn sink_mut(&mut self) -> &mut Self::Sink;
 
+    /// Called before a subtree that lies entirely inside the range is consumed without filtering.
+    /// Returns true if the subtree is to be left in place and consumed later.
+    #[inline(always)] fn defer_subtree(&mut self, _root: usize) -> bool { false }
+
     //---- consume_subtree_* ---------------------------------------------------------------
     #[inline(always)]
     fn consume_subtree<S>(&mut self, idx: usize) {
@@ -56,6 +61,10 @@ pub trait BulkDeleteCommon<N: Node>: TreeDerefMut<N>+Sized  {
 
     #[inline]
     fn consume_subtree_unfiltered(&mut self, root: usize) {
+        if self.defer_subtree(root) {
+            return;
+        }
+
         // work around the borrow checker (this is completely safe)
         let sink: &mut Self::Sink = unsafe { mem::transmute(self.sink_mut()) };
 
@@ -1,13 +1,30 @@
-use std::ops::Range;
+use std::ops::{Range, RangeBounds, Bound};
+use std::ops::Bound::{Included, Excluded, Unbounded};
 use std::marker::PhantomData;
 
-use base::{Key, Sink};
+use base::{Key, Sink, inorder_le};
 
 pub trait TraversalDriver<K: Key, V>: Sink<(K, V)> {
     type Decision: TraversalDecision;
 
     #[inline(always)]
     fn decide(&self, key: &K) -> Self::Decision;
+
+    /// Same as `decide()`, given the index of the item as well. The traversal decides on an item
+    /// before it moves any item into or out of its subtree, so `idx` is where the item was stored
+    /// when the traversal started.
+    #[inline(always)]
+    fn decide_at(&self, key: &K, _idx: usize) -> Self::Decision {
+        self.decide(key)
+    }
+
+    /// Called before a subtree that lies entirely inside the range is consumed. `nslots` is the
+    /// number of slots the subtree spans. Returns true if the subtree is to be left in place and
+    /// consumed later (see `ChunkedDriver`).
+    #[inline(always)]
+    fn defer_subtree(&mut self, _root: usize, _nslots: usize) -> bool {
+        false
+    }
 }
 
 
@@ -85,49 +102,274 @@ impl<'a, K, Q, V, S> Sink<(K, V)> for RangeRefDriver<'a, K, Q, V, S>
 }
 
 
-pub struct RangeDriver<K, Q, V, S>
+/// A range of keys with arbitrary bounds, taken from any `RangeBounds<Q>`. The bounds can be of any
+/// type `Q: PartialOrd<K>` that the keys can be compared against.
+#[derive(Clone, Debug)]
+pub struct KeyBounds<Q> {
+    start: Bound<Q>,
+    end: Bound<Q>,
+    // If set, the empty range `a..a` (i.e. included start and excluded end, both equal to `a`) is
+    // treated as the point `a..=a`. See `compat()`.
+    empty_as_point: bool,
+}
+
+impl<Q: Clone> KeyBounds<Q> {
+    pub fn new<R: RangeBounds<Q>>(range: R) -> KeyBounds<Q> {
+        KeyBounds { start: clone_bound(range.start_bound()), end: clone_bound(range.end_bound()), empty_as_point: false }
+    }
+
+    /// Same as `new()`, but the empty range `a..a` is treated as the point `a..=a`. This is what
+    /// `delete_range()`, `filter_range()` and `query_range()` did when they only took a `Range`,
+    /// and they keep doing it for compatibility.
+    pub fn compat<R: RangeBounds<Q>>(range: R) -> KeyBounds<Q> {
+        KeyBounds { empty_as_point: true, ..KeyBounds::new(range) }
+    }
+}
+
+impl<Q> KeyBounds<Q> {
+    /// Returns true if no key can be inside the range.
+    pub fn is_empty(&self) -> bool
+        where Q: PartialOrd
+    {
+        match (&self.start, &self.end) {
+            (&Included(ref s), &Excluded(ref e)) if self.empty_as_point => s > e,
+            (&Included(ref s), &Included(ref e)) => s > e,
+            (&Included(ref s), &Excluded(ref e)) |
+            (&Excluded(ref s), &Included(ref e)) |
+            (&Excluded(ref s), &Excluded(ref e)) => s >= e,
+            _ => false
+        }
+    }
+
+    /// Returns true if `key` is not below the start of the range.
+    #[inline(always)]
+    pub fn above_start<K>(&self, key: &K) -> bool
+        where Q: PartialOrd<K>
+    {
+        match self.start {
+            Included(ref s) => *s <= *key,
+            Excluded(ref s) => *s < *key,
+            Unbounded => true
+        }
+    }
+
+    /// Returns true if `key` is not above the end of the range.
+    #[inline(always)]
+    pub fn below_end<K>(&self, key: &K) -> bool
+        where Q: PartialOrd<K>
+    {
+        match (&self.start, &self.end) {
+            (&Included(ref s), &Excluded(ref e)) if self.empty_as_point => *e > *key || (*s == *key && *e == *key),
+            (_, &Included(ref e)) => *e >= *key,
+            (_, &Excluded(ref e)) => *e > *key,
+            (_, &Unbounded) => true
+        }
+    }
+
+    #[inline(always)]
+    pub fn contains<K>(&self, key: &K) -> bool
+        where Q: PartialOrd<K>
+    {
+        self.above_start(key) && self.below_end(key)
+    }
+
+    #[inline(always)]
+    pub fn start_bound(&self) -> Bound<&Q> {
+        match self.start {
+            Included(ref s) => Included(s),
+            Excluded(ref s) => Excluded(s),
+            Unbounded => Unbounded
+        }
+    }
+
+    /// Returns the end of the range as `below_end()` applies it, i.e. `Included(a)` for `a..a`
+    /// if the range was created by `compat()`.
+    #[inline(always)]
+    pub fn end_bound(&self) -> Bound<&Q>
+        where Q: PartialEq
+    {
+        match (&self.start, &self.end) {
+            (&Included(ref s), &Excluded(ref e)) if self.empty_as_point && *s == *e => Included(e),
+            (_, &Included(ref e)) => Included(e),
+            (_, &Excluded(ref e)) => Excluded(e),
+            (_, &Unbounded) => Unbounded
+        }
+    }
+}
+
+#[inline]
+fn clone_bound<K: Clone>(bound: Bound<&K>) -> Bound<K> {
+    match bound {
+        Included(x) => Included(x.clone()),
+        Excluded(x) => Excluded(x.clone()),
+        Unbounded => Unbounded
+    }
+}
+
+
+pub struct BoundsDriver<K, Q, V, S>
     where K: Key, Q: PartialOrd<K>, S: Sink<(K, V)>
 {
-    range: Range<Q>,
+    bounds: KeyBounds<Q>,
     sink: S,
     _ph: PhantomData<(K, V)>
 }
 
-impl<K, Q, V, S> RangeDriver<K, Q, V, S>
+impl<K, Q, V, S> BoundsDriver<K, Q, V, S>
     where K: Key, Q: PartialOrd<K>, S: Sink<(K, V)>
 {
-    pub fn new(range: Range<Q>, sink: S) -> RangeDriver<K, Q, V, S> {
-        RangeDriver { range:range, sink: sink, _ph: PhantomData }
+    /// The bounds can be empty (e.g. `b..a` with `a < b`), in which case no key gets both a left and
+    /// a right decision.
+    pub fn new(bounds: KeyBounds<Q>, sink: S) -> BoundsDriver<K, Q, V, S> {
+        BoundsDriver { bounds:bounds, sink: sink, _ph: PhantomData }
     }
+}
 
-    pub fn from(&self) -> &Q {
-        &self.range.start
-    }
+impl<K, Q, V, S> TraversalDriver<K, V> for BoundsDriver<K, Q, V, S>
+    where K: Key, Q: PartialOrd<K>, S: Sink<(K, V)>
+{
+    type Decision = RangeDecision;
+
+    #[inline(always)]
+    fn decide(&self, key: &K) -> Self::Decision {
+        let left = self.bounds.above_start(key);
+        let right = self.bounds.below_end(key);
 
-    pub fn to(&self) -> &Q {
-        &self.range.end
+        RangeDecision { left: left, right: right }
     }
 }
 
-impl<K, Q, V, S> TraversalDriver<K, V> for RangeDriver<K, Q, V, S>
+impl<K, Q, V, S> Sink<(K, V)> for BoundsDriver<K, Q, V, S>
     where K: Key, Q: PartialOrd<K>, S: Sink<(K, V)>
+{
+    #[inline(always)]
+    fn consume(&mut self, item: (K, V)) {
+        self.sink.consume(item)
+    }
+}
+
+
+/// A driver that selects the items by position rather than by key: the items that lie in-order
+/// between the slots `from` and `to` (both inclusive, `None` meaning unbounded). Unlike a range of
+/// keys, it can split a run of duplicate keys at any item.
+pub struct SlotRangeDriver<K, V, S>
+    where K: Key, S: Sink<(K, V)>
+{
+    from: Option<usize>,
+    to: Option<usize>,
+    sink: S,
+    _ph: PhantomData<(K, V)>
+}
+
+impl<K, V, S> SlotRangeDriver<K, V, S>
+    where K: Key, S: Sink<(K, V)>
+{
+    pub fn new(from: Option<usize>, to: Option<usize>, sink: S) -> SlotRangeDriver<K, V, S> {
+        SlotRangeDriver { from: from, to: to, sink: sink, _ph: PhantomData }
+    }
+}
+
+impl<K, V, S> TraversalDriver<K, V> for SlotRangeDriver<K, V, S>
+    where K: Key, S: Sink<(K, V)>
 {
     type Decision = RangeDecision;
 
     #[inline(always)]
-    fn decide(&self, key: &K) -> Self::Decision {
-        let left = self.from() <= key;
-        let right = self.to() > key || self.from() == key;
+    fn decide(&self, _: &K) -> Self::Decision {
+        unreachable!("the slots are only known to decide_at()")
+    }
+
+    #[inline(always)]
+    fn decide_at(&self, _: &K, idx: usize) -> Self::Decision {
+        let left = self.from.map_or(true, |from| inorder_le(from, idx));
+        let right = self.to.map_or(true, |to| inorder_le(idx, to));
 
         RangeDecision { left: left, right: right }
     }
 }
 
-impl<K, Q, V, S> Sink<(K, V)> for RangeDriver<K, Q, V, S>
-    where K: Key, Q: PartialOrd<K>, S: Sink<(K, V)>
+impl<K, V, S> Sink<(K, V)> for SlotRangeDriver<K, V, S>
+    where K: Key, S: Sink<(K, V)>
 {
     #[inline(always)]
     fn consume(&mut self, item: (K, V)) {
         self.sink.consume(item)
     }
 }
+
+
+/// A part of the output of `ChunkedDriver`.
+#[cfg(feature = "rayon")]
+pub enum Chunk<S> {
+    /// Items consumed during the traversal.
+    Items(S),
+    /// The root of a subtree that has been left in place.
+    Subtree(usize),
+}
+
+/// A driver for parallel deletes. It works like `BoundsDriver`, except that subtrees spanning at
+/// least `min_subtree` slots are left in place, so that they can be consumed in parallel after
+/// the traversal. The output is recorded as a sequence of chunks in key order; the items are fed
+/// into sinks created by `sink_factory`, one per chunk.
+#[cfg(feature = "rayon")]
+pub struct ChunkedDriver<'a, K, V, S, F>
+    where K: Key, S: Sink<(K, V)>, F: Fn() -> S + 'a
+{
+    bounds: KeyBounds<K>,
+    sink_factory: &'a F,
+    min_subtree: usize,
+    pub chunks: Vec<Chunk<S>>,
+    _ph: PhantomData<V>
+}
+
+#[cfg(feature = "rayon")]
+impl<'a, K, V, S, F> ChunkedDriver<'a, K, V, S, F>
+    where K: Key, S: Sink<(K, V)>, F: Fn() -> S + 'a
+{
+    // The caller must make sure that `!bounds.is_empty()`.
+    pub fn new(bounds: KeyBounds<K>, sink_factory: &'a F, min_subtree: usize) -> Self {
+        debug_assert!(!bounds.is_empty());
+        ChunkedDriver { bounds: bounds, sink_factory: sink_factory, min_subtree: min_subtree,
+                        chunks: Vec::new(), _ph: PhantomData }
+    }
+}
+
+#[cfg(feature = "rayon")]
+impl<'a, K, V, S, F> TraversalDriver<K, V> for ChunkedDriver<'a, K, V, S, F>
+    where K: Key, S: Sink<(K, V)>, F: Fn() -> S + 'a
+{
+    type Decision = RangeDecision;
+
+    #[inline(always)]
+    fn decide(&self, key: &K) -> Self::Decision {
+        let left = self.bounds.above_start(key);
+        let right = self.bounds.below_end(key);
+
+        RangeDecision { left: left, right: right }
+    }
+
+    #[inline(always)]
+    fn defer_subtree(&mut self, root: usize, nslots: usize) -> bool {
+        if nslots >= self.min_subtree {
+            self.chunks.push(Chunk::Subtree(root));
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(feature = "rayon")]
+impl<'a, K, V, S, F> Sink<(K, V)> for ChunkedDriver<'a, K, V, S, F>
+    where K: Key, S: Sink<(K, V)>, F: Fn() -> S + 'a
+{
+    #[inline(always)]
+    fn consume(&mut self, item: (K, V)) {
+        if let Some(&mut Chunk::Items(ref mut sink)) = self.chunks.last_mut() {
+            return sink.consume(item);
+        }
+        let mut sink = (self.sink_factory)();
+        sink.consume(item);
+        self.chunks.push(Chunk::Items(sink));
+    }
+}
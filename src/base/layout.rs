@@ -0,0 +1,146 @@
+//! Memory layouts of the implicit tree.
+//!
+//! The algorithms address the slots of a tree by their BFS index: the root is 0, and the children of
+//! `i` are `2*i+1` and `2*i+2` (see `lefti()`, `righti()`, `parenti()`). A `Layout` maps the BFS index
+//! of a slot to its position in the storage. `Bfs` stores the slots in BFS order. `Veb` stores them
+//! in van Emde Boas order, which keeps every subtree of height `2^k` in a contiguous block of
+//! memory, so that a root-to-leaf path touches `O(log_B n)` cache lines (B being the number of slots
+//! per cache line) rather than `O(log n)` for the deep levels. See "Cache Oblivious Search Trees via
+//! Binary Trees of Small Height" by Brodal, Fagerberg and Jacob.
+
+use base::{depth_of, levels_of, full_capacity};
+
+use std::fmt;
+
+
+/// A mapping of BFS indices to storage positions.
+///
+/// # Safety
+///
+/// The trees access the storage at the positions returned by `pos()` without bounds checks, so an
+/// implementation must uphold the following for every `n`, with `capacity = capacity_for(n)`:
+///
+/// * `capacity >= n`, and `capacity_for()` returns the same value when called again;
+/// * for the layout returned by `with_capacity(capacity)`, `pos()` is a bijection from
+///   `0..capacity` onto `0..capacity`;
+/// * `is_identity()` returns true only if `pos(idx) == idx` for every `idx`.
+pub unsafe trait Layout: Clone+Send+Sync {
+    /// Creates the layout for a storage of `capacity` slots, as returned by `capacity_for()`.
+    fn with_capacity(capacity: usize) -> Self;
+
+    /// Returns the storage capacity needed to hold a tree occupying the BFS indices `[0, n)`.
+    fn capacity_for(n: usize) -> usize;
+
+    /// Returns the position of the slot with the given BFS index in the storage.
+    fn pos(&self, idx: usize) -> usize;
+
+    /// True if `pos()` is the identity, which allows operating on whole ranges of slots at once.
+    #[inline(always)]
+    fn is_identity() -> bool {
+        false
+    }
+}
+
+
+/// The slots are stored in BFS order. This is the default layout.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Bfs;
+
+unsafe impl Layout for Bfs {
+    #[inline(always)]
+    fn with_capacity(_: usize) -> Self {
+        Bfs
+    }
+
+    #[inline(always)]
+    fn capacity_for(n: usize) -> usize {
+        n
+    }
+
+    #[inline(always)]
+    fn pos(&self, idx: usize) -> usize {
+        idx
+    }
+
+    #[inline(always)]
+    fn is_identity() -> bool {
+        true
+    }
+}
+
+
+/// The slots are stored in van Emde Boas order: a tree of height `h` is split into the top tree
+/// of height `h/2` and the bottom trees hanging off its leaves; the top tree is stored first,
+/// followed by the bottom trees from left to right, each of them laid out recursively.
+///
+/// The storage always holds a complete tree, so the capacity is `2^h - 1` for some `h`. Finding
+/// the position of a slot takes `O(log h)` steps, using the tables described in section 4 of the
+/// paper: every slot below the root is the root of a bottom tree at exactly one level of the
+/// recursion, and its position follows from the position of the root of the corresponding top
+/// tree. This pays off once the tree is much larger than the cache.
+#[derive(Clone, Copy)]
+pub struct Veb {
+    height: usize,
+    // For every depth `d > 0`: the depth of the root of the top tree above the bottom trees rooted
+    // at depth `d`, and the height of those bottom trees.
+    top_root: [u8; MAX_HEIGHT],
+    bottom_height: [u8; MAX_HEIGHT],
+}
+
+const MAX_HEIGHT: usize = 64;
+
+impl Veb {
+    fn split(&mut self, root: usize, height: usize) {
+        if height > 1 {
+            let top = height >> 1;
+            let bottom = height - top;
+            self.top_root[root + top] = root as u8;
+            self.bottom_height[root + top] = bottom as u8;
+            self.split(root, top);
+            self.split(root + top, bottom);
+        }
+    }
+}
+
+unsafe impl Layout for Veb {
+    fn with_capacity(capacity: usize) -> Self {
+        let height = levels_of(capacity);
+        debug_assert!(capacity == full_capacity(height), "capacity={}", capacity);
+        let mut veb = Veb { height: height, top_root: [0; MAX_HEIGHT], bottom_height: [0; MAX_HEIGHT] };
+        veb.split(0, height);
+        veb
+    }
+
+    #[inline]
+    fn capacity_for(n: usize) -> usize {
+        full_capacity(levels_of(n))
+    }
+
+    #[inline]
+    fn pos(&self, idx: usize) -> usize {
+        // `j` is the 1-based BFS index of the current slot and `depth` its depth.
+        let mut j = idx + 1;
+        let mut depth = depth_of(idx);
+        debug_assert!(depth < self.height, "idx={}, height={}", idx, self.height);
+
+        let mut pos = 0;
+        while depth > 0 {
+            let top_root = self.top_root[depth] as usize;
+            let top_height = depth - top_root;
+            // The top tree takes `2^top_height - 1` slots, followed by the bottom trees. The low
+            // bits of `j` tell which of them the slot is the root of.
+            let top_size = (1 << top_height) - 1;
+            let bottom_size = (1 << self.bottom_height[depth]) - 1;
+            pos += top_size + (j & top_size) * bottom_size;
+            j >>= top_height;
+            depth = top_root;
+        }
+        pos
+    }
+}
+
+impl fmt::Debug for Veb {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "Veb {{ height: {} }}", self.height)
+    }
+}
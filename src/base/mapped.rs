@@ -0,0 +1,178 @@
+//! Read-only trees that live in a memory-mapped snapshot file (enabled by the `mmap` feature).
+//!
+//! The snapshot payload has the same layout as the storage of `TreeRepr` (see `base::snapshot`), so
+//! the queries run directly on the mapped pages. Several processes mapping the same file share a
+//! single physical copy of the tree.
+
+use memmap2::Mmap;
+
+use base::{Node, KeyBounds, lefti, righti, parenti, left_enclosing};
+use base::snapshot::{SnapshotNode, SnapshotBytes, SnapshotHeader, SnapshotError};
+
+use std::fs::File;
+use std::marker::PhantomData;
+use std::path::Path;
+use std::mem;
+
+
+pub struct MappedRepr<N: Node> {
+    mmap: Mmap,
+    header: SnapshotHeader,
+    mask: *const bool,
+    data: *const N,
+    _ph: PhantomData<N>
+}
+
+// The mapped storage is never modified, so sharing it is as safe as sharing `&[N]`.
+unsafe impl<N: Node+Sync> Sync for MappedRepr<N> {}
+unsafe impl<N: Node+Sync> Send for MappedRepr<N> {}
+
+impl<N: SnapshotNode> MappedRepr<N> {
+    /// Maps the snapshot file at `path` and validates it.
+    ///
+    /// # Safety
+    ///
+    /// The file must not be modified or truncated (by this or any other process) while it is
+    /// mapped: the tree reads it directly, and the snapshot is only validated once.
+    pub unsafe fn open<P: AsRef<Path>>(path: P) -> Result<MappedRepr<N>, SnapshotError> {
+        let file = File::open(path)?;
+        let mmap = Mmap::map(&file)?;
+        Self::with_mmap(mmap)
+    }
+
+    /// Validates the snapshot contained in `mmap` (see `SnapshotBytes::parse()`) and wraps it. The
+    /// caller of `Mmap::map()` has already taken on the obligation not to modify the file.
+    pub fn with_mmap(mmap: Mmap) -> Result<MappedRepr<N>, SnapshotError> {
+        let (header, mask, data) = {
+            let snapshot = SnapshotBytes::parse(&mmap, mem::size_of::<N>())?;
+            (snapshot.header, snapshot.mask.as_ptr() as *const bool, snapshot.data.as_ptr() as *const N)
+        };
+        if data as usize % mem::align_of::<N>() != 0 {
+            return Err(SnapshotError::Corrupt("the node array is misaligned"));
+        }
+
+        // The pointers stay valid when `mmap` is moved: they point into the mapped pages, not into
+        // the `Mmap` struct. The mask has been checked to contain only 0/1 bytes, which are valid bools,
+        // and the nodes are made of Pod fields, so any bytes are valid nodes.
+        Ok(MappedRepr { mmap: mmap, header: header, mask: mask, data: data, _ph: PhantomData })
+    }
+
+    /// The snapshot sections, e.g. for refilling a `TreeRepr`.
+    #[inline]
+    pub fn bytes<'a>(&'a self) -> SnapshotBytes<'a> {
+        SnapshotBytes::split(&self.mmap[..], self.header)
+    }
+}
+
+impl<N: Node> MappedRepr<N> {
+    #[inline]
+    pub fn size(&self) -> usize {
+        self.header.size as usize
+    }
+
+    #[inline]
+    pub fn capacity(&self) -> usize {
+        self.header.capacity()
+    }
+
+    #[inline]
+    pub fn is_nil(&self, idx: usize) -> bool {
+        // This is safe: the mask has `capacity()` entries.
+        idx >= self.capacity() || unsafe { !*self.mask.offset(idx as isize) }
+    }
+
+    /// The caller must make sure that `!is_nil(idx)`.
+    #[inline]
+    pub fn node(&self, idx: usize) -> &N {
+        debug_assert!(!self.is_nil(idx));
+        // This is safe: occupied slots contain valid nodes, as validated by `with_mmap()`.
+        unsafe { &*self.data.offset(idx as isize) }
+    }
+
+    #[inline]
+    pub fn key(&self, idx: usize) -> &N::K {
+        self.node(idx).key()
+    }
+
+    /// Finds the item with the given key and returns it (or None).
+    pub fn find<'a, Q>(&'a self, query: &Q) -> Option<&'a N::V>
+        where Q: PartialOrd<N::K>
+    {
+        let mut idx = 0;
+        while !self.is_nil(idx) {
+            let k = self.key(idx);
+            idx =
+                if query == k { return Some(self.node(idx).val()); }
+                else if query < k { lefti(idx) }
+                else { righti(idx) };
+        }
+        None
+    }
+
+    /// Returns the index of the leftmost item inside the lower bound of `bounds`, or None.
+    pub fn lower_bound(&self, bounds: &KeyBounds<N::K>) -> Option<usize> {
+        let mut found = None;
+        let mut idx = 0;
+        while !self.is_nil(idx) {
+            if bounds.above_start(self.key(idx)) {
+                found = Some(idx);
+                idx = lefti(idx);
+            } else {
+                idx = righti(idx);
+            }
+        }
+        found
+    }
+
+    pub fn first_idx(&self) -> Option<usize> {
+        if self.is_nil(0) { None } else { Some(self.find_min(0)) }
+    }
+
+    /// Returns the index of the in-order successor of the item at `idx`, or None.
+    pub fn next_idx(&self, idx: usize) -> Option<usize> {
+        if !self.is_nil(righti(idx)) {
+            Some(self.find_min(righti(idx)))
+        } else {
+            let l_enclosing = left_enclosing(idx + 1);
+            if l_enclosing <= 1 { None } else { Some(parenti(l_enclosing - 1)) }
+        }
+    }
+
+    #[inline]
+    fn find_min(&self, mut idx: usize) -> usize {
+        while !self.is_nil(lefti(idx)) {
+            idx = lefti(idx);
+        }
+        idx
+    }
+
+    pub fn iter<'a>(&'a self) -> MappedIter<'a, N> {
+        MappedIter { tree: self, next_idx: self.first_idx(), remaining: self.size() }
+    }
+}
+
+
+/// An in-order iterator over a `MappedRepr`.
+pub struct MappedIter<'a, N: Node+'a> {
+    tree: &'a MappedRepr<N>,
+    next_idx: Option<usize>,
+    remaining: usize,
+}
+
+impl<'a, N: Node> Iterator for MappedIter<'a, N> {
+    type Item = &'a N;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_idx.map(|idx| {
+            self.next_idx = self.tree.next_idx(idx);
+            self.remaining -= 1;
+            self.tree.node(idx)
+        })
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl<'a, N: Node> ExactSizeIterator for MappedIter<'a, N> {}
@@ -7,6 +7,10 @@ mod node;
 pub mod util;
 pub mod drivers;
 pub mod sink;
+pub mod snapshot;
+pub mod bitset;
+pub mod layout;
+#[cfg(feature = "mmap")] pub mod mapped;
 
 pub use self::slot_stack::*;
 pub use self::bulk_delete::*;
@@ -21,24 +25,26 @@ pub trait Sink<T> {
 
 
 /// A fast way to refill the tree from a master copy; adds the requirement for T to implement Copy.
-pub trait Refill {
-    fn refill(&mut self, master: &Self);
+/// The master is usually a tree of the same type, but can also be a read-only tree with the same
+/// layout (e.g. a memory-mapped one).
+pub trait Refill<Master: ?Sized = Self> {
+    fn refill(&mut self, master: &Master);
+
+    /// Refills the tree from a master copy, restoring only the parts of the storage that were
+    /// modified since `self` was cloned or refilled from `master`. If `self` comes from elsewhere, or
+    /// `master` has been modified since, a full refill is performed. The default implementation
+    /// always performs a full refill.
+    fn refill_incremental(&mut self, master: &Master) {
+        self.refill(master)
+    }
 }
 
 
-//impl<T: Clone+Item> Refill<T> for TeardownTree<T> {
-//    fn refill(&mut self, master: &TeardownTree<T>) {
-//            let len = self.data().len();
-//            debug_assert!(len == master.data.len());
-//            self.drop_items();
-//
-//            for i in 0..master.size() {
-//                if master.mask[i] {
-//                    self.place(i, master.data[i].item.clone());
-//                }
-//            }
-//    }
-//}
+/// Refills the tree from a master copy by cloning the items. Unlike `Refill`, only requires the
+/// items to implement Clone. The internal storage is reused.
+pub trait RefillClone {
+    fn refill_clone(&mut self, master: &Self);
+}
 
 
 
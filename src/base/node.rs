@@ -27,6 +27,9 @@ impl<K, V> Entry<K, V> {
 
     #[inline(always)] pub fn val(&self) -> &V { &self.item.1 }
     #[inline(always)] pub fn val_mut(&mut self) -> &mut V { &mut self.item.1 }
+
+    /// Returns the key along with a mutable reference to the value.
+    #[inline(always)] pub fn key_val_mut(&mut self) -> (&K, &mut V) { (&self.item.0, &mut self.item.1) }
 }
 
 
@@ -10,6 +10,12 @@ impl<T> Sink<T> for Vec<T> {
     }
 }
 
+impl<'a, T, S: Sink<T>> Sink<T> for &'a mut S {
+    #[inline(always)] fn consume(&mut self, x: T) {
+        (**self).consume(x);
+    }
+}
+
 
 
 #[derive(new)]
@@ -94,6 +100,11 @@ impl<T, S: Sink<T>> SinkAdapter<T, S> {
     pub fn new(sink: S) -> Self {
         SinkAdapter { sink: sink, _ph: PhantomData }
     }
+
+    #[inline]
+    pub fn into_inner(self) -> S {
+        self.sink
+    }
 }
 
 impl<T, S: Sink<T>> Sink<(T, ())> for SinkAdapter<T, S> {
@@ -115,6 +126,11 @@ impl<'a, T: 'a, S: Sink<&'a T>> RefSinkAdapter<'a, T, S> {
     pub fn new(sink: S) -> Self {
         RefSinkAdapter { sink: sink, _ph: PhantomData }
     }
+
+    #[inline]
+    pub fn into_inner(self) -> S {
+        self.sink
+    }
 }
 
 impl<'a, T: 'a, S: Sink<&'a T>> Sink<&'a (T, ())> for RefSinkAdapter<'a, T, S> {
@@ -1,5 +1,3 @@
-//use std::ptr::Unique;
-use std::mem;
 use std::fmt::{Debug, Formatter};
 
 
@@ -18,26 +16,20 @@ impl Debug for Slot {
 pub struct SlotStack {
     pub nslots: usize,
     pub nfilled: usize,
-//    pub slots: Unique<T>, // uncomment when Unique is stabilized
-    pub slots: *mut Slot,
+    pub slots: Vec<Slot>,
     pub capacity: usize
 }
 
 impl SlotStack {
     pub fn new(capacity: usize) -> SlotStack {
-        unsafe {
-            // we manage the allocated memory manually
-            let mut slots = vec![mem::uninitialized(); capacity];
-            let ptr: *mut Slot = slots.as_mut_ptr();
-            mem::forget(slots);
-            SlotStack { nslots: 0, nfilled: 0, slots: ptr, capacity: capacity }
-        }
+        SlotStack { nslots: 0, nfilled: 0, slots: vec![Slot { idx: 0 }; capacity], capacity: capacity }
     }
 
     #[inline(always)]
     pub fn push(&mut self, idx: usize) {
         debug_assert!(self.nslots < self.capacity);
-        self.slot_at(self.nslots).idx = idx;
+        let nslots = self.nslots;
+        self.slot_at_mut(nslots).idx = idx;
         self.nslots += 1;
     }
 
@@ -69,10 +61,21 @@ impl SlotStack {
     }
 
 
+    // The caller must make sure that `idx < self.capacity`.
+    #[inline(always)]
+    pub fn slot_at(&self, idx: usize) -> &Slot {
+        debug_assert!(idx < self.capacity);
+        unsafe {
+            self.slots.get_unchecked(idx)
+        }
+    }
+
+    // The caller must make sure that `idx < self.capacity`.
     #[inline(always)]
-    pub fn slot_at(&self, idx: usize) -> &mut Slot {
+    pub fn slot_at_mut(&mut self, idx: usize) -> &mut Slot {
+        debug_assert!(idx < self.capacity);
         unsafe {
-            mem::transmute(self.slots.offset(idx as isize))
+            self.slots.get_unchecked_mut(idx)
         }
     }
 
@@ -99,31 +102,6 @@ impl SlotStack {
 
 impl Debug for SlotStack {
     fn fmt(&self, fmt: &mut Formatter) -> ::std::fmt::Result {
-        let slots = unsafe { Vec::from_raw_parts(self.slots, self.nslots, self.capacity) };
-        let result = write!(fmt, "SlotStack: {{nslots={}, nfilled={}, slots={:?}}}", self.nslots, self.nfilled, &slots);
-        mem::forget(slots);
-        result
-    }
-}
-
-// Uncomment when Specialization is stabilized.
-//impl <T: Item+Debug> Debug for SlotStack<T> {
-//    fn fmt(&self, fmt: &mut Formatter) -> ::std::fmt::Result {
-//        unsafe {
-//            let ptr: *mut Slot<T> = mem::transmute(self.slots.get());
-//            let slots_vec = Vec::from_raw_parts(ptr, self.nfilled, self.capacity);
-//            let result = write!(fmt, "SlotStack: nslots={}, nfilled={}, slots={:?}", self.nslots, self.nfilled, &slots_vec);
-//            mem::forget(slots_vec);
-//            result
-//        }
-//    }
-//}
-
-impl Drop for SlotStack {
-    fn drop(&mut self) {
-        unsafe {
-            Vec::from_raw_parts(self.slots, self.nfilled, self.capacity);
-            // let it drop
-        }
+        write!(fmt, "SlotStack: {{nslots={}, nfilled={}, slots={:?}}}", self.nslots, self.nfilled, &self.slots[..self.nslots])
     }
 }
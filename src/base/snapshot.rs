@@ -0,0 +1,329 @@
+//! Binary snapshot format for trees whose items are `Pod`.
+//!
+//! A snapshot consists of a fixed-size header followed by the payload:
+//!
+//! | offset | size | field                                             |
+//! |--------|------|---------------------------------------------------|
+//! | 0      | 8    | magic (`SNAPSHOT_MAGIC`)                          |
+//! | 8      | 4    | format version (`SNAPSHOT_VERSION`)               |
+//! | 12     | 4    | node size in bytes                                |
+//! | 16     | 8    | capacity (number of slots)                        |
+//! | 24     | 8    | size (number of items)                            |
+//! | 32     | 8    | height (the number of levels for the capacity)    |
+//! | 40     | 8    | checksum of the above and the payload (FNV-1a)    |
+//!
+//! The header fields are little-endian. The payload is the slot mask (one byte per slot), padded
+//! with zeros to a multiple of `PAYLOAD_ALIGN` bytes, followed by the raw node array (empty slots
+//! are zeroed). The nodes are stored in their in-memory representation, so a snapshot can only be
+//! loaded by a build with the same node layout and byte order; the node size is checked as a
+//! safeguard. The padding bytes of the nodes, if any, are zeroed.
+//!
+//! Loading a snapshot copies arbitrary bytes into the nodes, so the keys and values must be `Pod`
+//! types, for which every bit pattern is a valid value.
+
+use base::{Node, levels_of};
+
+use std::{error, fmt, io, mem, ptr};
+
+
+pub const SNAPSHOT_MAGIC: [u8; 8] = *b"TDTREE\0\0";
+pub const SNAPSHOT_VERSION: u32 = 2;
+pub const HEADER_LEN: usize = 48;
+/// The offset of the checksum in the header. The header bytes before it are checksummed.
+pub const CHECKSUM_OFFSET: usize = 40;
+pub const PAYLOAD_ALIGN: usize = 16;
+
+
+/// "Plain old data": a type that has no padding bytes and for which every bit pattern is a valid
+/// value, so that it can be copied to and from the raw bytes of a snapshot. Implemented for the
+/// primitive integer and floating-point types, `()` and `KeyInterval<K: Pod>`.
+///
+/// Implementing it for a type that does not meet these requirements results in undefined behavior
+/// when a snapshot is written or loaded.
+pub unsafe trait Pod: Copy+'static {}
+
+macro_rules! pod {
+    ($($t:ty),*) => { $(unsafe impl Pod for $t {})* }
+}
+
+pod!(u8, u16, u32, u64, u128, usize, i8, i16, i32, i64, i128, isize, f32, f64, ());
+
+
+/// A node that can be stored in a snapshot: its fields are `Pod`, so a node made of arbitrary
+/// bytes is valid. The node itself may have padding between the fields, so it is written to a
+/// snapshot field by field.
+pub unsafe trait SnapshotNode: Node {
+    /// Copies the bytes of every field of `self` to the same offset in `out`, which has the size of
+    /// `Self`. The padding bytes of `out` are left untouched.
+    fn write_fields(&self, out: &mut [u8]);
+}
+
+/// Copies the bytes of `field`, which must be a field of `node`, to the same offset in `out`.
+#[inline(always)]
+pub fn write_field<N: SnapshotNode, T: Pod>(node: &N, field: &T, out: &mut [u8]) {
+    let offset = field as *const T as usize - node as *const N as usize;
+    assert!(out.len() == mem::size_of::<N>() && offset + mem::size_of::<T>() <= out.len());
+    // This is safe: a `Pod` has no padding, so all of its bytes are initialized.
+    unsafe {
+        ptr::copy_nonoverlapping(field as *const T as *const u8, out.as_mut_ptr().offset(offset as isize),
+                                 mem::size_of::<T>());
+    }
+}
+
+
+#[derive(Debug)]
+pub enum SnapshotError {
+    Io(io::Error),
+    BadMagic,
+    UnsupportedVersion(u32),
+    NodeSizeMismatch { expected: usize, found: usize },
+    Truncated,
+    ChecksumMismatch,
+    Corrupt(&'static str),
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SnapshotError::Io(ref e) => write!(f, "I/O error: {}", e),
+            SnapshotError::BadMagic => write!(f, "not a teardown tree snapshot"),
+            SnapshotError::UnsupportedVersion(v) => write!(f, "unsupported snapshot version {}", v),
+            SnapshotError::NodeSizeMismatch { expected, found } =>
+                write!(f, "node size mismatch: expected {}, found {}", expected, found),
+            SnapshotError::Truncated => write!(f, "the snapshot is truncated"),
+            SnapshotError::ChecksumMismatch => write!(f, "checksum mismatch"),
+            SnapshotError::Corrupt(msg) => write!(f, "corrupt snapshot: {}", msg),
+        }
+    }
+}
+
+impl error::Error for SnapshotError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            SnapshotError::Io(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for SnapshotError {
+    fn from(e: io::Error) -> Self {
+        if e.kind() == io::ErrorKind::UnexpectedEof {
+            SnapshotError::Truncated
+        } else {
+            SnapshotError::Io(e)
+        }
+    }
+}
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SnapshotHeader {
+    pub version: u32,
+    pub node_size: u32,
+    pub capacity: u64,
+    pub size: u64,
+    pub height: u64,
+    pub checksum: u64,
+}
+
+impl SnapshotHeader {
+    pub fn to_bytes(&self) -> [u8; HEADER_LEN] {
+        let mut bytes = [0; HEADER_LEN];
+        bytes[0..8].copy_from_slice(&SNAPSHOT_MAGIC);
+        bytes[8..12].copy_from_slice(&self.version.to_le_bytes());
+        bytes[12..16].copy_from_slice(&self.node_size.to_le_bytes());
+        bytes[16..24].copy_from_slice(&self.capacity.to_le_bytes());
+        bytes[24..32].copy_from_slice(&self.size.to_le_bytes());
+        bytes[32..40].copy_from_slice(&self.height.to_le_bytes());
+        bytes[40..48].copy_from_slice(&self.checksum.to_le_bytes());
+        bytes
+    }
+
+    /// Parses the header and checks that it is compatible with nodes of size `node_size`.
+    pub fn parse(bytes: &[u8], node_size: usize) -> Result<SnapshotHeader, SnapshotError> {
+        if bytes.len() < HEADER_LEN {
+            return Err(SnapshotError::Truncated);
+        }
+        if bytes[0..8] != SNAPSHOT_MAGIC {
+            return Err(SnapshotError::BadMagic);
+        }
+
+        let header = SnapshotHeader {
+            version: read_u32(&bytes[8..12]),
+            node_size: read_u32(&bytes[12..16]),
+            capacity: read_u64(&bytes[16..24]),
+            size: read_u64(&bytes[24..32]),
+            height: read_u64(&bytes[32..40]),
+            checksum: read_u64(&bytes[40..48]),
+        };
+        if header.version != SNAPSHOT_VERSION {
+            return Err(SnapshotError::UnsupportedVersion(header.version));
+        }
+        if header.node_size as usize != node_size {
+            return Err(SnapshotError::NodeSizeMismatch { expected: node_size, found: header.node_size as usize });
+        }
+        if header.size > header.capacity || header.checked_lens().is_none() {
+            return Err(SnapshotError::Corrupt("bad capacity"));
+        }
+        // The height sizes the stacks of the delete_range algorithm, so it must cover every slot.
+        if header.height != levels_of(header.capacity()) as u64 {
+            return Err(SnapshotError::Corrupt("the height does not match the capacity"));
+        }
+        Ok(header)
+    }
+
+    /// Returns a checksum that covers the header fields, to be updated with the payload.
+    pub fn checksum(&self) -> Checksum {
+        let mut checksum = Checksum::new();
+        checksum.update(&self.to_bytes()[..CHECKSUM_OFFSET]);
+        checksum
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity as usize
+    }
+
+    /// The length of the mask section, including the padding.
+    pub fn mask_len(&self) -> usize {
+        self.lens().0
+    }
+
+    /// The length of the node array section.
+    pub fn data_len(&self) -> usize {
+        self.lens().1
+    }
+
+    pub fn payload_len(&self) -> usize {
+        self.mask_len() + self.data_len()
+    }
+
+    /// Returns the lengths of the mask and the node array sections, which `parse()` has checked.
+    fn lens(&self) -> (usize, usize) {
+        self.checked_lens().expect("the snapshot header has not been validated")
+    }
+
+    /// Returns the lengths of the mask and the node array sections, or None if the whole snapshot
+    /// would be longer than `usize::max_value()` bytes.
+    fn checked_lens(&self) -> Option<(usize, usize)> {
+        if self.capacity > usize::max_value() as u64 {
+            return None;
+        }
+        let mask_len = padded(self.capacity())?;
+        let data_len = self.capacity().checked_mul(self.node_size as usize)?;
+        mask_len.checked_add(data_len)?.checked_add(HEADER_LEN)?;
+        Some((mask_len, data_len))
+    }
+}
+
+
+/// A snapshot held in memory (e.g. read from a file or memory-mapped), split into sections.
+pub struct SnapshotBytes<'a> {
+    pub header: SnapshotHeader,
+    pub mask: &'a [u8],
+    pub data: &'a [u8],
+}
+
+impl<'a> SnapshotBytes<'a> {
+    /// Parses and validates the snapshot: checks the header, the checksum and the shape of the tree
+    /// described by the mask. The node contents are not inspected.
+    pub fn parse(bytes: &'a [u8], node_size: usize) -> Result<SnapshotBytes<'a>, SnapshotError> {
+        let header = SnapshotHeader::parse(bytes, node_size)?;
+        if bytes.len() - HEADER_LEN < header.payload_len() {
+            return Err(SnapshotError::Truncated);
+        }
+
+        let mut checksum = header.checksum();
+        checksum.update(&bytes[HEADER_LEN..HEADER_LEN + header.payload_len()]);
+        if checksum.value() != header.checksum {
+            return Err(SnapshotError::ChecksumMismatch);
+        }
+
+        let snapshot = SnapshotBytes::split(bytes, header);
+        validate_mask(snapshot.mask, header.size as usize)?;
+        Ok(snapshot)
+    }
+
+    /// Splits a snapshot into sections according to `header`. Nothing is validated: `bytes` must
+    /// have been accepted by `parse()` before.
+    pub fn split(bytes: &'a [u8], header: SnapshotHeader) -> SnapshotBytes<'a> {
+        let payload = &bytes[HEADER_LEN..HEADER_LEN + header.payload_len()];
+        let (mask, data) = payload.split_at(header.mask_len());
+        SnapshotBytes { header: header, mask: &mask[..header.capacity()], data: data }
+    }
+}
+
+
+/// Checks that `mask` contains only 0/1 bytes, describes a valid tree shape and has `size` items.
+pub fn validate_mask(mask: &[u8], size: usize) -> Result<(), SnapshotError> {
+    let mut count = 0;
+    for i in 0..mask.len() {
+        match mask[i] {
+            0 => {},
+            1 => {
+                if i > 0 && mask[(i - 1) >> 1] == 0 {
+                    return Err(SnapshotError::Corrupt("the mask contains a node without a parent"));
+                }
+                count += 1;
+            },
+            _ => return Err(SnapshotError::Corrupt("invalid mask byte")),
+        }
+    }
+    if count != size {
+        return Err(SnapshotError::Corrupt("the number of nodes does not match the mask"));
+    }
+    Ok(())
+}
+
+/// Returns the number of zero bytes that pad `len` bytes to a multiple of `PAYLOAD_ALIGN`.
+#[inline]
+pub fn padding(len: usize) -> usize {
+    (PAYLOAD_ALIGN - len % PAYLOAD_ALIGN) % PAYLOAD_ALIGN
+}
+
+/// Rounds `len` up to a multiple of `PAYLOAD_ALIGN`, or returns None on overflow.
+#[inline]
+pub fn padded(len: usize) -> Option<usize> {
+    len.checked_add(padding(len))
+}
+
+
+/// 64-bit FNV-1a.
+pub struct Checksum {
+    hash: u64
+}
+
+impl Checksum {
+    pub fn new() -> Self {
+        Checksum { hash: 0xcbf29ce484222325 }
+    }
+
+    #[inline]
+    pub fn update(&mut self, bytes: &[u8]) {
+        let mut hash = self.hash;
+        for &b in bytes {
+            hash ^= b as u64;
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+        self.hash = hash;
+    }
+
+    pub fn value(&self) -> u64 {
+        self.hash
+    }
+}
+
+
+#[inline]
+fn read_u32(bytes: &[u8]) -> u32 {
+    let mut buf = [0; 4];
+    buf.copy_from_slice(bytes);
+    u32::from_le_bytes(buf)
+}
+
+#[inline]
+fn read_u64(bytes: &[u8]) -> u64 {
+    let mut buf = [0; 8];
+    buf.copy_from_slice(bytes);
+    u64::from_le_bytes(buf)
+}
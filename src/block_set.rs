@@ -0,0 +1,559 @@
+//! A set of integer keys stored as a B-ary implicit search tree.
+//!
+//! `BlockTeardownSet` packs the keys into blocks of one cache line (16 `u32`s or 8 `u64`s/`i64`s).
+//! The blocks are laid out like the nodes of `TreeRepr`, except that block `k` has `B+1` children:
+//! the `i`-th of them is `k*(B+1) + i + 1`. The keys are filled in in-order, the last blocks padded
+//! with the largest key. A lookup reads one block per level and finds the child to descend into by
+//! comparing the query against the whole block at once (see `BlockKey::rank()`), so it touches
+//! `log_{B+1} n` cache lines instead of `log_2 n`.
+//!
+//! The keys never move. A deleted key stays in its slot as a separator, and only its bit in the
+//! occupancy mask is cleared. Every block keeps the number of live keys in its subtree, so that the
+//! traversals skip the subtrees that have been torn down. As the keys are never modified, a master
+//! shares them with its copies: `clone()` and `refill()` only copy the mask and the counts.
+
+use base::{Refill, Sink, KeyBounds};
+use base::bitset::BitSet;
+
+use std::{cmp, fmt};
+use std::fmt::Debug;
+use std::ops::RangeBounds;
+use std::ops::Bound::{Included, Excluded, Unbounded};
+use std::sync::Arc;
+
+
+/// A key type that can be stored in a `BlockTeardownSet`. Implemented for `u32`, `u64` and `i64`.
+pub trait BlockKey: Copy+Ord+Debug+Send+Sync+'static {
+    /// A block of `LEN` keys, aligned to a cache line.
+    type Block: Copy+Send+Sync;
+
+    /// The number of keys in a block.
+    const LEN: usize;
+
+    /// The largest key, which pads the unused slots of the last blocks.
+    const MAX: Self;
+
+    fn filled(key: Self) -> Self::Block;
+
+    fn keys(block: &Self::Block) -> &[Self];
+
+    fn keys_mut(block: &mut Self::Block) -> &mut [Self];
+
+    /// Returns the number of keys in `block` that are less than `query`.
+    fn rank(block: &Self::Block, query: Self) -> usize;
+}
+
+macro_rules! block_key {
+    ($key:ty, $block:ident, $len:expr, $max:expr, $rank:path) => {
+        #[repr(align(64))]
+        #[derive(Clone, Copy)]
+        pub struct $block([$key; $len]);
+
+        impl BlockKey for $key {
+            type Block = $block;
+            const LEN: usize = $len;
+            const MAX: $key = $max;
+
+            #[inline]
+            fn filled(key: $key) -> $block {
+                $block([key; $len])
+            }
+
+            #[inline(always)]
+            fn keys(block: &$block) -> &[$key] {
+                &block.0
+            }
+
+            #[inline(always)]
+            fn keys_mut(block: &mut $block) -> &mut [$key] {
+                &mut block.0
+            }
+
+            #[inline(always)]
+            fn rank(block: &$block, query: $key) -> usize {
+                $rank(&block.0, query)
+            }
+        }
+    }
+}
+
+block_key!(u32, U32Block, 16, ::std::u32::MAX, simd::rank_u32);
+block_key!(u64, U64Block, 8, ::std::u64::MAX, simd::rank_u64);
+block_key!(i64, I64Block, 8, ::std::i64::MAX, simd::rank_i64);
+
+
+/// Counting the keys of a block that are less than the query. On x86_64, the `u32` blocks are
+/// compared with SSE2, which every x86_64 CPU supports. Comparing 64-bit integers needs AVX2, so the
+/// `u64`/`i64` blocks only use it when the crate is compiled with AVX2 enabled (e.g. with
+/// `-C target-cpu=native`). Otherwise, they are searched with a branchless binary search.
+mod simd {
+    /// A branchless binary search over the sorted `keys`, whose length must be a power of two.
+    #[inline(always)]
+    pub fn rank_scalar<K: Ord+Copy>(keys: &[K], query: K) -> usize {
+        debug_assert!(keys.len().is_power_of_two());
+        let mut base = 0;
+        let mut len = keys.len();
+        while len > 1 {
+            let half = len / 2;
+            if keys[base + half - 1] < query {
+                base += half;
+            }
+            len -= half;
+        }
+        base + (keys[base] < query) as usize
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[inline(always)]
+    pub fn rank_u32(keys: &[u32; 16], query: u32) -> usize {
+        use std::arch::x86_64::*;
+
+        // This is safe: SSE2 is available on every x86_64 CPU, and the loads read the 16 keys.
+        unsafe {
+            // SSE2 only compares signed integers. Flipping the sign bits maps the unsigned order
+            // onto the signed one.
+            let flip = _mm_set1_epi32(i32::min_value());
+            let query = _mm_xor_si128(_mm_set1_epi32(query as i32), flip);
+            let ptr = keys.as_ptr() as *const __m128i;
+            let less = |i| _mm_cmpgt_epi32(query, _mm_xor_si128(_mm_loadu_si128(ptr.offset(i)), flip));
+
+            // Narrow the 32-bit results (0 or -1) down to one byte per key.
+            let lo = _mm_packs_epi32(less(0), less(1));
+            let hi = _mm_packs_epi32(less(2), less(3));
+            _mm_movemask_epi8(_mm_packs_epi16(lo, hi)).count_ones() as usize
+        }
+    }
+
+    #[cfg(not(target_arch = "x86_64"))]
+    #[inline(always)]
+    pub fn rank_u32(keys: &[u32; 16], query: u32) -> usize {
+        rank_scalar(keys, query)
+    }
+
+    #[cfg(all(target_arch = "x86_64", target_feature = "avx2"))]
+    #[inline(always)]
+    pub fn rank_i64(keys: &[i64; 8], query: i64) -> usize {
+        use std::arch::x86_64::*;
+        // This is safe: the loads read the 8 keys, and the crate is compiled with AVX2 enabled.
+        unsafe { rank_avx2(keys.as_ptr() as *const __m256i, query, _mm256_setzero_si256()) }
+    }
+
+    #[cfg(all(target_arch = "x86_64", target_feature = "avx2"))]
+    #[inline(always)]
+    pub fn rank_u64(keys: &[u64; 8], query: u64) -> usize {
+        use std::arch::x86_64::*;
+        // This is safe: see `rank_i64()`. Flipping the sign bits maps the unsigned order onto the
+        // signed one.
+        unsafe {
+            let flip = _mm256_set1_epi64x(i64::min_value());
+            rank_avx2(keys.as_ptr() as *const __m256i, (query as i64) ^ i64::min_value(), flip)
+        }
+    }
+
+    /// Counts the keys less than `query` among the 8 keys at `ptr`, each XORed with `flip`.
+    #[cfg(all(target_arch = "x86_64", target_feature = "avx2"))]
+    #[inline(always)]
+    unsafe fn rank_avx2(ptr: *const ::std::arch::x86_64::__m256i, query: i64,
+                        flip: ::std::arch::x86_64::__m256i) -> usize {
+        use std::arch::x86_64::*;
+
+        let query = _mm256_set1_epi64x(query);
+        let less = |i| _mm256_castsi256_pd(
+            _mm256_cmpgt_epi64(query, _mm256_xor_si256(_mm256_loadu_si256(ptr.offset(i)), flip)));
+        let mask = _mm256_movemask_pd(less(0)) | (_mm256_movemask_pd(less(1)) << 4);
+        mask.count_ones() as usize
+    }
+
+    #[cfg(not(all(target_arch = "x86_64", target_feature = "avx2")))]
+    #[inline(always)]
+    pub fn rank_i64(keys: &[i64; 8], query: i64) -> usize {
+        rank_scalar(keys, query)
+    }
+
+    #[cfg(not(all(target_arch = "x86_64", target_feature = "avx2")))]
+    #[inline(always)]
+    pub fn rank_u64(keys: &[u64; 8], query: u64) -> usize {
+        rank_scalar(keys, query)
+    }
+}
+
+
+/// A set of `u32`, `u64` or `i64` keys that supports the same teardown pattern as `TeardownSet`
+/// (clone or refill from a master, then tear down with `delete_range`), with faster lookups on
+/// large sets. Unlike `TeardownSet`, it does not support `insert`: the keys are fixed when the set
+/// is built. See the module documentation for the details.
+#[derive(Clone)]
+pub struct BlockTeardownSet<K: BlockKey> {
+    /// The keys, `K::LEN` per block. They are never modified, so the copies share them.
+    blocks: Arc<Vec<K::Block>>,
+    /// The live slots; slot `i` of block `k` has the index `k*K::LEN + i`.
+    mask: BitSet,
+    /// The number of live keys in the subtree of every block.
+    counts: Vec<usize>,
+}
+
+impl<K: BlockKey> BlockTeardownSet<K> {
+    /// Creates a new `BlockTeardownSet` with the given set of items. The items can be given in any
+    /// order. Duplicates are supported.
+    pub fn new(mut items: Vec<K>) -> BlockTeardownSet<K> {
+        items.sort();
+        Self::with_sorted(items)
+    }
+
+    /// Creates a new `BlockTeardownSet` with the given set of items. Duplicates are supported.
+    /// **Note**: the items are assumed to be sorted!
+    pub fn with_sorted(sorted: Vec<K>) -> BlockTeardownSet<K> {
+        let nblocks = (sorted.len() + K::LEN - 1) / K::LEN;
+        let mut blocks = vec![K::filled(K::MAX); nblocks];
+        let mut mask = BitSet::new(nblocks * K::LEN);
+        let mut counts = vec![0; nblocks];
+        build(0, &sorted, &mut 0, &mut blocks, &mut mask, &mut counts);
+
+        BlockTeardownSet { blocks: Arc::new(blocks), mask: mask, counts: counts }
+    }
+
+    /// Returns true if the set contains the given item.
+    #[inline]
+    pub fn contains(&self, query: &K) -> bool {
+        match self.lower_bound(*query) {
+            Some(slot) if self.key(slot) == *query =>
+                // a deleted duplicate may come before a live one
+                self.mask.get(slot) || self.find_live(0, &KeyBounds::new(*query..=*query)).is_some(),
+            _ => false
+        }
+    }
+
+    /// Deletes one item equal to `query` from the set. Returns true if there was one.
+    pub fn delete(&mut self, query: &K) -> bool {
+        let slot = match self.lower_bound(*query) {
+            Some(slot) if self.key(slot) == *query => slot,
+            _ => return false
+        };
+
+        if self.mask.get(slot) {
+            self.remove(slot);
+            true
+        } else if let Some(slot) = self.find_live(0, &KeyBounds::new(*query..=*query)) {
+            self.remove(slot);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Deletes all items inside `range` from the set and feeds them into `sink`.
+    /// The items are returned in order. Like `TeardownSet::delete_range()`, the empty range
+    /// `a..a` matches the item equal to `a`.
+    pub fn delete_range<R, S>(&mut self, range: R, mut sink: S)
+        where R: RangeBounds<K>, S: Sink<K>
+    {
+        let bounds = KeyBounds::compat(range);
+        if !bounds.is_empty() {
+            self.delete_range_rec(0, &bounds, &mut sink);
+        }
+    }
+
+    /// Executes a range query and feeds references to the matching items into `sink`.
+    /// Like `TeardownSet::query_range()`, the empty range `a..a` matches the item equal to `a`.
+    pub fn query_range<'a, R, S>(&'a self, range: R, mut sink: S)
+        where R: RangeBounds<K>, S: Sink<&'a K>
+    {
+        let bounds = KeyBounds::compat(range);
+        if !bounds.is_empty() {
+            self.query_range_rec(0, &bounds, &mut sink);
+        }
+    }
+
+    /// Returns the number of items in this set.
+    #[inline]
+    pub fn size(&self) -> usize {
+        self.counts.first().cloned().unwrap_or(0)
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.size() == 0
+    }
+
+    /// Returns the number of slots in the internal storage.
+    #[inline]
+    pub fn capacity(&self) -> usize {
+        self.mask.len()
+    }
+
+    /// Removes all items from the set (the internal storage is kept).
+    pub fn clear(&mut self) {
+        self.mask.clear();
+        for count in self.counts.iter_mut() {
+            *count = 0;
+        }
+    }
+
+    /// Creates an iterator into the set.
+    pub fn iter<'a>(&'a self) -> BlockSetIter<'a, K> {
+        BlockSetIter::new(self)
+    }
+
+
+    #[inline(always)]
+    fn key(&self, slot: usize) -> K {
+        K::keys(&self.blocks[slot / K::LEN])[slot % K::LEN]
+    }
+
+    /// Returns the slot of the first key (in order) that is not less than `query`, dead or alive.
+    #[inline]
+    fn lower_bound(&self, query: K) -> Option<usize> {
+        let blocks = &self.blocks[..];
+        let mut found = None;
+        let mut k = 0;
+        while k < blocks.len() {
+            let i = K::rank(&blocks[k], query);
+            if i < K::LEN {
+                found = Some(k * K::LEN + i);
+            }
+            k = childi::<K>(k, i);
+        }
+        found
+    }
+
+    /// Returns the range `[from, to)` of the slots of block `k` whose keys are inside `bounds`. The
+    /// keys in the subtrees of the children `from..to+1` may be inside `bounds`, the rest are not.
+    #[inline]
+    fn block_range(&self, k: usize, bounds: &KeyBounds<K>) -> (usize, usize) {
+        let block = &self.blocks[k];
+        let from = match bounds.start_bound() {
+            Included(&start) => K::rank(block, start),
+            Excluded(&start) => rank_le::<K>(block, start),
+            Unbounded => 0
+        };
+        let to = match bounds.end_bound() {
+            Included(&end) => rank_le::<K>(block, end),
+            Excluded(&end) => K::rank(block, end),
+            Unbounded => K::LEN
+        };
+        // the range may be empty, e.g. `b..a` with `a < b`
+        (from, cmp::max(from, to))
+    }
+
+    #[inline]
+    fn is_empty_subtree(&self, k: usize) -> bool {
+        k >= self.counts.len() || self.counts[k] == 0
+    }
+
+    /// Returns the first live slot (in order) inside `bounds` in the subtree of block `k`, or None.
+    fn find_live(&self, k: usize, bounds: &KeyBounds<K>) -> Option<usize> {
+        if self.is_empty_subtree(k) {
+            return None;
+        }
+
+        let (from, to) = self.block_range(k, bounds);
+        for i in from..to+1 {
+            let found = self.find_live(childi::<K>(k, i), bounds);
+            if found.is_some() {
+                return found;
+            }
+            if i < to && self.mask.get(k * K::LEN + i) {
+                return Some(k * K::LEN + i);
+            }
+        }
+        None
+    }
+
+    /// Marks the live `slot` as deleted, updating the counts of its block and of the ancestors.
+    fn remove(&mut self, slot: usize) {
+        self.mask.set(slot, false);
+        let mut k = slot / K::LEN;
+        loop {
+            self.counts[k] -= 1;
+            if k == 0 {
+                break;
+            }
+            k = parenti::<K>(k);
+        }
+    }
+
+    /// Deletes the key in `slot` if it is live, feeding it into `sink`. Returns the number of deleted
+    /// keys (0 or 1).
+    #[inline]
+    fn take<S: Sink<K>>(&mut self, slot: usize, sink: &mut S) -> usize {
+        if self.mask.get(slot) {
+            self.mask.set(slot, false);
+            sink.consume(self.key(slot));
+            1
+        } else {
+            0
+        }
+    }
+
+    fn delete_range_rec<S: Sink<K>>(&mut self, k: usize, bounds: &KeyBounds<K>, sink: &mut S) -> usize {
+        if self.is_empty_subtree(k) {
+            return 0;
+        }
+
+        let (from, to) = self.block_range(k, bounds);
+        let mut deleted = 0;
+        for i in from..to+1 {
+            // the subtrees between two keys inside the range are entirely inside it
+            let child = childi::<K>(k, i);
+            deleted += if from < i && i < to { self.drain_subtree(child, sink) }
+                       else { self.delete_range_rec(child, bounds, sink) };
+            if i < to {
+                deleted += self.take(k * K::LEN + i, sink);
+            }
+        }
+        self.counts[k] -= deleted;
+        deleted
+    }
+
+    /// Deletes all keys in the subtree of block `k`, feeding them into `sink`.
+    fn drain_subtree<S: Sink<K>>(&mut self, k: usize, sink: &mut S) -> usize {
+        if self.is_empty_subtree(k) {
+            return 0;
+        }
+
+        for i in 0..K::LEN {
+            self.drain_subtree(childi::<K>(k, i), sink);
+            self.take(k * K::LEN + i, sink);
+        }
+        self.drain_subtree(childi::<K>(k, K::LEN), sink);
+
+        let deleted = self.counts[k];
+        self.counts[k] = 0;
+        deleted
+    }
+
+    fn query_range_rec<'a, S: Sink<&'a K>>(&'a self, k: usize, bounds: &KeyBounds<K>, sink: &mut S) {
+        if self.is_empty_subtree(k) {
+            return;
+        }
+
+        let (from, to) = self.block_range(k, bounds);
+        let keys = K::keys(&self.blocks[k]);
+        for i in from..to+1 {
+            self.query_range_rec(childi::<K>(k, i), bounds, sink);
+            if i < to && self.mask.get(k * K::LEN + i) {
+                sink.consume(&keys[i]);
+            }
+        }
+    }
+}
+
+/// Fills the subtree of block `k` with the items of `sorted` in-order, starting from `sorted[*next]`.
+/// Returns the number of items placed in the subtree.
+fn build<K: BlockKey>(k: usize, sorted: &[K], next: &mut usize, blocks: &mut [K::Block], mask: &mut BitSet,
+                      counts: &mut [usize]) -> usize {
+    if k >= blocks.len() {
+        return 0;
+    }
+
+    let mut count = 0;
+    for i in 0..K::LEN {
+        count += build(childi::<K>(k, i), sorted, next, blocks, mask, counts);
+        if *next < sorted.len() {
+            K::keys_mut(&mut blocks[k])[i] = sorted[*next];
+            mask.set(k * K::LEN + i, true);
+            *next += 1;
+            count += 1;
+        }
+    }
+    count += build(childi::<K>(k, K::LEN), sorted, next, blocks, mask, counts);
+
+    counts[k] = count;
+    count
+}
+
+#[inline(always)]
+fn childi<K: BlockKey>(k: usize, i: usize) -> usize {
+    k * (K::LEN + 1) + i + 1
+}
+
+#[inline(always)]
+fn parenti<K: BlockKey>(k: usize) -> usize {
+    (k - 1) / (K::LEN + 1)
+}
+
+/// Returns the number of keys in `block` that are less than or equal to `query`.
+#[inline(always)]
+fn rank_le<K: BlockKey>(block: &K::Block, query: K) -> usize {
+    let rank = K::rank(block, query);
+    rank + K::keys(block)[rank..].iter().take_while(|&&key| key == query).count()
+}
+
+
+impl<K: BlockKey> Refill for BlockTeardownSet<K> {
+    /// The keys are shared with the master, so only the mask and the counts are copied (unless
+    /// `self` was built from a different master).
+    fn refill(&mut self, master: &BlockTeardownSet<K>) {
+        if !Arc::ptr_eq(&self.blocks, &master.blocks) {
+            self.blocks = master.blocks.clone();
+        }
+
+        if self.mask.len() == master.mask.len() {
+            self.mask.copy_from(&master.mask);
+        } else {
+            self.mask = master.mask.clone();
+        }
+        self.counts.clone_from(&master.counts);
+    }
+}
+
+impl<K: BlockKey> Debug for BlockTeardownSet<K> {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_set().entries(self.iter()).finish()
+    }
+}
+
+
+/// An in-order iterator over a `BlockTeardownSet`.
+pub struct BlockSetIter<'a, K: BlockKey+'a> {
+    set: &'a BlockTeardownSet<K>,
+    /// The blocks on the path to the next key, each with the index of its next slot.
+    stack: Vec<(usize, usize)>,
+    remaining: usize,
+}
+
+impl<'a, K: BlockKey> BlockSetIter<'a, K> {
+    fn new(set: &'a BlockTeardownSet<K>) -> BlockSetIter<'a, K> {
+        let mut iter = BlockSetIter { set: set, stack: Vec::new(), remaining: set.size() };
+        iter.descend(0);
+        iter
+    }
+
+    /// Pushes the path to the leftmost key of the subtree of block `k`.
+    #[inline]
+    fn descend(&mut self, mut k: usize) {
+        while !self.set.is_empty_subtree(k) {
+            self.stack.push((k, 0));
+            k = childi::<K>(k, 0);
+        }
+    }
+}
+
+impl<'a, K: BlockKey> Iterator for BlockSetIter<'a, K> {
+    type Item = &'a K;
+
+    fn next(&mut self) -> Option<&'a K> {
+        let set = self.set;
+        while let Some(&(k, i)) = self.stack.last() {
+            if i == K::LEN {
+                self.stack.pop();
+                continue;
+            }
+
+            // the subtree after slot `i` comes next
+            self.stack.last_mut().unwrap().1 += 1;
+            self.descend(childi::<K>(k, i + 1));
+            if set.mask.get(k * K::LEN + i) {
+                self.remaining -= 1;
+                return Some(&K::keys(&set.blocks[k])[i]);
+            }
+        }
+        None
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl<'a, K: BlockKey> ExactSizeIterator for BlockSetIter<'a, K> {}
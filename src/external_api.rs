@@ -4,32 +4,43 @@ pub use applied::interval::{Interval, KeyInterval};
 
 pub use self::plain::{TeardownMap, TeardownSet};
 pub use self::interval::{IntervalTeardownMap, IntervalTeardownSet};
-pub use base::{Refill, Sink};
+#[cfg(feature = "mmap")] pub use self::plain::mapped::{MappedTeardownMap, MappedTeardownSet};
+pub use base::{Refill, RefillClone, Sink};
 pub use base::sink;
 
 
 pub mod iter {
-    pub use super::plain::{SetIter, MapIter, SetIntoIter, MapIntoIter};
-    pub use super::interval::{IntervalSetIter, IntervalMapIter, IntervalSetIntoIter, IntervalMapIntoIter};
+    pub use super::plain::{SetIter, MapIter, MapIterMut, SetIntoIter, MapIntoIter, SetRange, MapRange,
+                           SetDrainRange, MapDrainRange, MapCursor, MapCursorMut, MapUnorderedIter, SetUnorderedIter};
+    pub use super::interval::{IntervalSetIter, IntervalMapIter, IntervalMapIterMut, IntervalSetIntoIter, IntervalMapIntoIter,
+                              IntervalMapOverlapIter, IntervalSetOverlapIter, IntervalMapUnorderedIter,
+                              IntervalSetUnorderedIter};
+    #[cfg(feature = "mmap")] pub use super::plain::mapped::{MappedMapIter, MappedSetIter};
+    pub use block_set::BlockSetIter;
+    pub use split_map::SplitMapIter;
 }
 
 
 
 mod plain {
-    use base::{Refill, Sink, ItemFilter};
+    use base::{Refill, RefillClone, Sink, ItemFilter, KeyBounds};
+    use base::snapshot::{Pod, SnapshotError};
     use applied::plain_tree::{PlTree, PlNode};
+    use base::layout::{Layout, Bfs};
     use super::sink::{SinkAdapter, RefSinkAdapter};
 
-    use std::fmt;
+    use std::{cmp, fmt};
     use std::fmt::{Debug, Display, Formatter};
-    use std::ops::Range;
+    use std::ops::{Range, RangeBounds};
+    use std::io::{self, Read, Write};
 
     #[cfg(test)] use base::{TreeRepr, Key};
+    #[cfg(feature = "serde")] use serde::{Serialize, Serializer, Deserialize, Deserializer};
 
 
     #[derive(Clone)]
-    pub struct TeardownMap<K: Ord+Clone, V> {
-        internal: PlTree<K,V>
+    pub struct TeardownMap<K: Ord+Clone, V, L: Layout = Bfs> {
+        internal: PlTree<K,V,L>
     }
 
     impl<K: Ord+Clone, V> TeardownMap<K, V> {
@@ -46,6 +57,22 @@ mod plain {
         pub fn with_sorted(sorted: Vec<(K, V)>) -> TeardownMap<K, V> {
             TeardownMap { internal: PlTree::with_sorted(sorted) }
         }
+    }
+
+    impl<K: Ord+Clone, V, L: Layout> TeardownMap<K, V, L> {
+        /// Same as `new()`, but the tree is stored in the layout `L` (see `Veb`), e.g.
+        /// `TeardownMap::<K, V, Veb>::with_layout(items)`.
+        #[inline]
+        pub fn with_layout(items: Vec<(K, V)>) -> TeardownMap<K, V, L> {
+            TeardownMap { internal: PlTree::new(items) }
+        }
+
+        /// Same as `with_sorted()`, but the tree is stored in the layout `L`.
+        /// **Note**: the items are assumed to be sorted!
+        #[inline]
+        pub fn with_sorted_layout(sorted: Vec<(K, V)>) -> TeardownMap<K, V, L> {
+            TeardownMap { internal: PlTree::with_sorted(sorted) }
+        }
 
         /// Finds the item with the given key and returns it (or None).
         #[inline]
@@ -55,6 +82,14 @@ mod plain {
             self.internal.find(query)
         }
 
+        /// Finds the item with the given key and returns a mutable reference to its value (or None).
+        #[inline]
+        pub fn get_mut<'a, Q>(&'a mut self, query: &Q) -> Option<&'a mut V>
+            where Q: PartialOrd<K>
+        {
+            self.internal.find_mut(query)
+        }
+
         /// Returns true if the map contains the given key.
         #[inline]
         pub fn contains_key<Q>(&self, query: &Q) -> bool
@@ -63,15 +98,38 @@ mod plain {
             self.internal.contains(query)
         }
 
-        /// Executes a range query.
+        /// Executes a range query. `range` can be any kind of range over the keys or over a type
+        /// that they can be compared against, e.g. `a..b`, `a..=b`, `..b`, `a..` or `..` (as with
+        /// `BTreeMap::range()`, the latter needs the type of the bounds spelled out:
+        /// `query_range::<K, _, _>(.., sink)`). For compatibility, the empty range `a..a` matches the
+        /// items equal to `a` (the newer range operations, such as `range()`, treat it as empty).
         #[inline]
-        pub fn query_range<'a, Q, S>(&'a self, range: Range<Q>, sink: S)
-            where Q: PartialOrd<K>,
+        pub fn query_range<'a, Q, R, S>(&'a self, range: R, sink: S)
+            where Q: PartialOrd<K>+Clone,
+                  R: RangeBounds<Q>,
                   S: Sink<&'a (K, V)>
         {
             self.internal.query_range(range, sink)
         }
 
+        /// Calls `f` on every item inside `range` in order, giving it mutable access to the value.
+        #[inline]
+        pub fn for_each_in_range_mut<Q, R, F>(&mut self, range: R, f: F)
+            where Q: PartialOrd<K>+Clone,
+                  R: RangeBounds<Q>,
+                  F: FnMut(&K, &mut V)
+        {
+            self.internal.for_each_in_range_mut(range, f)
+        }
+
+        /// Inserts the item into the tree. Duplicate keys are supported. The new item is placed into
+        /// a free slot if one is available on its path; otherwise the affected subtree is rebuilt
+        /// (or, if the tree is too dense, the storage is reallocated with a larger height).
+        #[inline]
+        pub fn insert(&mut self, key: K, val: V) {
+            self.internal.insert(key, val)
+        }
+
         /// Deletes the item with the given key from the tree and returns it (or None).
         #[inline]
         pub fn delete<Q>(&mut self, query: &Q) -> Option<V>
@@ -80,24 +138,77 @@ mod plain {
             self.internal.delete(query)
         }
 
+        /// Returns the item with the smallest key (or None if the map is empty).
+        #[inline]
+        pub fn first(&self) -> Option<&(K, V)> {
+            self.internal.first_idx().map(|idx| self.internal.node(idx).as_tuple())
+        }
+
+        /// Returns the item with the largest key (or None if the map is empty).
+        #[inline]
+        pub fn last(&self) -> Option<&(K, V)> {
+            self.internal.last_idx().map(|idx| self.internal.node(idx).as_tuple())
+        }
+
+        /// Deletes the item with the smallest key from the tree and returns it (or None if the map
+        /// is empty).
+        #[inline]
+        pub fn pop_first(&mut self) -> Option<(K, V)> {
+            self.internal.pop_first()
+        }
+
+        /// Deletes the item with the largest key from the tree and returns it (or None if the map
+        /// is empty).
+        #[inline]
+        pub fn pop_last(&mut self) -> Option<(K, V)> {
+            self.internal.pop_last()
+        }
+
         /// Deletes all items inside `range` from the tree and feeds them into `sink`.
-        /// The items are returned in order.
+        /// The items are returned in order. As with `query_range()`, the empty range `a..a`
+        /// matches the items equal to `a`.
         #[inline]
-        pub fn delete_range<Q, S>(&mut self, range: Range<Q>, sink: S)
-            where Q: PartialOrd<K>, S: Sink<(K, V)>
+        pub fn delete_range<Q, R, S>(&mut self, range: R, sink: S)
+            where Q: PartialOrd<K>+Clone, R: RangeBounds<Q>, S: Sink<(K, V)>
         {
             self.internal.delete_range(range, sink)
         }
 
+        /// Returns an iterator that lazily deletes the items inside `range` from the tree and yields
+        /// them in order. The items are removed from the tree in small batches as the iterator
+        /// advances. If the iterator is dropped before it is exhausted, the items that have not
+        /// been yielded remain in the tree.
+        #[inline]
+        pub fn drain_range<'a, Q, R>(&'a mut self, range: R) -> MapDrainRange<'a, K, V, L, Q>
+            where Q: PartialOrd<K>+Clone, R: RangeBounds<Q>
+        {
+            MapDrainRange::new(self, KeyBounds::new(range))
+        }
+
         /// Deletes all items inside `range` that match `filter` from the tree and feeds them into
-        /// `sink`. The items are returned in order.
+        /// `sink`. The items are returned in order. As with `query_range()`, the empty range `a..a`
+        /// matches the items equal to `a`.
         #[inline]
-        pub fn filter_range<Q, Flt, S>(&mut self, range: Range<Q>, filter: Flt, sink: S)
-            where Q: PartialOrd<K>, Flt: ItemFilter<K>, S: Sink<(K, V)>
+        pub fn filter_range<Q, R, Flt, S>(&mut self, range: R, filter: Flt, sink: S)
+            where Q: PartialOrd<K>+Clone, R: RangeBounds<Q>, Flt: ItemFilter<K>, S: Sink<(K, V)>
         {
             self.internal.filter_range(range, filter, sink)
         }
 
+        /// Deletes the `n` smallest items (or all items, if there are fewer) from the tree and feeds
+        /// them into `sink`. The items are returned in order.
+        #[inline]
+        pub fn delete_first<S: Sink<(K, V)>>(&mut self, n: usize, sink: S) {
+            self.internal.delete_first(n, sink)
+        }
+
+        /// Deletes the `n` largest items (or all items, if there are fewer) from the tree and feeds
+        /// them into `sink`. The items are returned in order.
+        #[inline]
+        pub fn delete_last<S: Sink<(K, V)>>(&mut self, n: usize, sink: S) {
+            self.internal.delete_last(n, sink)
+        }
+
         /// Deletes all items inside `range` from the tree and feeds them into `sink`.
         #[inline]
         pub fn delete_range_ref<Q, S>(&mut self, range: Range<&Q>, sink: S)
@@ -122,33 +233,231 @@ mod plain {
 
         #[inline] pub fn is_empty(&self) -> bool { self.size() == 0 }
 
+        /// Starts maintaining the number of items in every subtree, so that `nth()`, `rank()` and
+        /// `count_range()` run in `O(log n)` rather than `O(n)` time. The counts are kept up to
+        /// date by all modifying operations and are preserved by `clone()` and refills. They take
+        /// an additional `usize` per slot of the internal storage.
+        #[inline] pub fn enable_counts(&mut self) { self.internal.enable_counts() }
+
+        /// Returns the number of slots in the internal storage. Deletions do not shrink it; see
+        /// `compact()`.
+        #[inline] pub fn capacity(&self) -> usize { self.internal.capacity() }
+
+        /// Rebuilds the remaining items into an internal storage just large enough to hold them and
+        /// releases the old one. This reclaims the memory left behind by heavy deletion and
+        /// restores the logarithmic height of the tree. Takes `O(n)` time.
+        #[inline] pub fn compact(&mut self) { self.internal.compact() }
+
+        /// Sets the policy for automatic compaction: once a deletion leaves fewer than
+        /// `ratio * capacity()` items, the tree is compacted (see `compact()`). None (the default)
+        /// disables it. Panics unless `0 < ratio < 1`.
+        #[inline] pub fn set_auto_compact(&mut self, ratio: Option<f64>) { self.internal.set_auto_compact(ratio) }
+
+        /// Returns the ratio set with `set_auto_compact()`.
+        #[inline] pub fn auto_compact(&self) -> Option<f64> { self.internal.auto_compact() }
+
+        /// Returns the `k`-th smallest item (counting from 0), or None if `k >= size()`.
+        #[inline]
+        pub fn nth(&self, k: usize) -> Option<&(K, V)> {
+            self.internal.select(k).map(|idx| self.internal.node(idx).as_tuple())
+        }
+
+        /// Returns the number of items whose keys are less than `query`.
+        #[inline]
+        pub fn rank<Q>(&self, query: &Q) -> usize
+            where Q: PartialOrd<K>
+        {
+            self.internal.rank(query)
+        }
+
+        /// Returns the number of items inside `range`.
+        #[inline]
+        pub fn count_range<Q, R>(&self, range: R) -> usize
+            where Q: PartialOrd<K>+Clone, R: RangeBounds<Q>
+        {
+            self.internal.count_range(&KeyBounds::new(range))
+        }
+
         /// Removes all items from the tree (the items are dropped, but the internal storage is not).
         #[inline] pub fn clear(&mut self) { self.internal.clear(); }
 
         /// Creates an iterator into the map.
         #[inline]
-        pub fn iter<'a>(&'a self) -> MapIter<'a, K, V> {
+        pub fn iter<'a>(&'a self) -> MapIter<'a, K, V, L> {
             MapIter::new(self.internal.iter())
         }
+
+        /// Creates an iterator into the map that yields the items in no particular order. This is
+        /// cheaper than `iter()`.
+        #[inline]
+        pub fn iter_unordered<'a>(&'a self) -> MapUnorderedIter<'a, K, V, L> {
+            MapUnorderedIter::new(self.internal.iter_unordered())
+        }
+
+        /// Creates an iterator over the items inside `range`, e.g. `a..b`, `a..=b`, `..b`, `a..` or
+        /// `..`. The iterator can be traversed from both ends. As with `query_range()`, the bounds
+        /// may be of any type the keys can be compared against, so `..` needs the type spelled out:
+        /// `range::<K, _>(..)`. Unlike `query_range()`, the empty range `a..a` yields nothing.
+        #[inline]
+        pub fn range<'a, Q, R>(&'a self, range: R) -> MapRange<'a, K, V, L>
+            where Q: PartialOrd<K>+Clone, R: RangeBounds<Q>
+        {
+            MapRange::new(self.internal.range(&KeyBounds::new(range)))
+        }
+
+        /// Creates an iterator into the map that gives mutable access to the values.
+        #[inline]
+        pub fn iter_mut<'a>(&'a mut self) -> MapIterMut<'a, K, V, L> {
+            MapIterMut::new(self.internal.iter_mut())
+        }
+
+        /// Returns a cursor pointing at the first item whose key is not less than `query`, or at the
+        /// ghost position if there is no such item.
+        #[inline]
+        pub fn cursor_at<'a, Q>(&'a self, query: &Q) -> MapCursor<'a, K, V, L>
+            where Q: PartialOrd<K>
+        {
+            MapCursor::new(&self.internal, self.internal.lower_bound_idx(query))
+        }
+
+        /// Returns a cursor pointing at the first item whose key is not less than `query`, or at the
+        /// ghost position if there is no such item. The cursor can modify the values and remove
+        /// items.
+        #[inline]
+        pub fn cursor_mut_at<'a, Q>(&'a mut self, query: &Q) -> MapCursorMut<'a, K, V, L>
+            where Q: PartialOrd<K>
+        {
+            let idx = self.internal.lower_bound_idx(query);
+            MapCursorMut::new(&mut self.internal, idx)
+        }
     }
 
-    impl<K: Ord+Clone+Debug, V> Debug for TeardownMap<K, V> {
+    impl<K: Ord+Clone+Debug, V, L: Layout> Debug for TeardownMap<K, V, L> {
         fn fmt(&self, fmt: &mut Formatter) -> fmt::Result {
             Debug::fmt(&self.internal, fmt)
         }
     }
 
-    impl<K: Ord+Clone+Debug, V> Display for TeardownMap<K, V> {
+    impl<K: Ord+Clone+Debug, V, L: Layout> Display for TeardownMap<K, V, L> {
         fn fmt(&self, fmt: &mut Formatter) -> fmt::Result {
             Display::fmt(&self.internal, fmt)
         }
     }
 
-    impl<K: Ord+Clone+Copy, V: Copy> Refill for TeardownMap<K, V> {
+    #[cfg(feature = "rayon")]
+    impl<K: Ord+Clone+Send, V: Send> TeardownMap<K, V> {
+        /// Same as `new()`, but the items are sorted and the tree is built in parallel.
+        ///
+        /// This is a separate constructor rather than what `new()` does when the `rayon` feature is
+        /// enabled: the parallel build needs `K: Send` and `V: Send`, and requiring them from `new()`
+        /// would break callers with non-`Send` items (e.g. `Rc`) as soon as any crate in the
+        /// dependency graph turns the feature on.
+        #[inline]
+        pub fn par_new(items: Vec<(K, V)>) -> TeardownMap<K, V> {
+            TeardownMap { internal: PlTree::with_repr(::base::TreeRepr::par_new(items)) }
+        }
+
+        /// Same as `with_sorted()`, but the tree is built in parallel.
+        /// **Note**: the items are assumed to be sorted!
+        #[inline]
+        pub fn par_with_sorted(sorted: Vec<(K, V)>) -> TeardownMap<K, V> {
+            TeardownMap { internal: PlTree::with_repr(::base::TreeRepr::par_with_sorted(sorted)) }
+        }
+
+        /// Same as `delete_range()`, but large subtrees inside the range are consumed in parallel.
+        /// The items are fed into sinks created by `sink_factory`, which are returned in order:
+        /// concatenating their contents yields the deleted items in order. The resulting tree is
+        /// the same as after `delete_range()`, except that the empty range `a..a` deletes nothing.
+        #[inline]
+        pub fn par_delete_range<R, S, F>(&mut self, range: R, sink_factory: F) -> Vec<S>
+            where R: RangeBounds<K>, S: Sink<(K, V)>+Send, F: Fn() -> S + Sync
+        {
+            self.internal.par_delete_range(range, sink_factory)
+        }
+    }
+
+    #[cfg(feature = "rayon")]
+    impl<K: Ord+Clone+Sync, V: Sync> TeardownMap<K, V> {
+        /// Same as `query_range()`, but large subtrees are traversed in parallel. The items are fed
+        /// into sinks created by `sink_factory`, which are returned in order: concatenating their
+        /// contents yields the items in order.
+        #[inline]
+        pub fn par_query_range<'a, R, S, F>(&'a self, range: R, sink_factory: F) -> Vec<S>
+            where R: RangeBounds<K>, S: Sink<&'a (K, V)>+Send, F: Fn() -> S + Sync
+        {
+            self.internal.par_query_range(range, sink_factory)
+        }
+    }
+
+    impl<K: Ord+Clone+Copy, V: Copy, L: Layout> Refill for TeardownMap<K, V, L> {
         #[inline]
         fn refill(&mut self, master: &Self) {
             self.internal.refill(&master.internal)
         }
+
+        #[inline]
+        fn refill_incremental(&mut self, master: &Self) {
+            self.internal.refill_incremental(&master.internal)
+        }
+    }
+
+    impl<K: Ord+Clone, V, L: Layout> TeardownMap<K, V, L> {
+        /// True if `refill_incremental(master)` would only restore the modified parts of `self`.
+        #[inline]
+        pub(crate) fn is_copy_of(&self, master: &Self) -> bool {
+            self.internal.is_copy_of(&master.internal)
+        }
+    }
+
+    impl<K: Ord+Pod, V: Pod> TeardownMap<K, V> {
+        /// Constructs a map from a binary snapshot held in memory (e.g. the contents of a file or a
+        /// memory-mapped buffer). See the `snapshot` module for the format.
+        pub fn from_snapshot(bytes: &[u8]) -> Result<Self, SnapshotError> {
+            ::base::TreeRepr::from_snapshot(bytes).map(|repr| TeardownMap { internal: PlTree::with_repr(repr) })
+        }
+
+        /// Writes a binary snapshot of the map to `w`.
+        #[inline]
+        pub fn write_snapshot<W: Write>(&self, w: &mut W) -> io::Result<()> {
+            self.internal.write_snapshot(w)
+        }
+
+        /// Refills the map from a snapshot held in memory, copying the items straight into the
+        /// storage without building a master copy. On error, the map is left unchanged.
+        #[inline]
+        pub fn refill_from_snapshot(&mut self, bytes: &[u8]) -> Result<(), SnapshotError> {
+            self.internal.refill_from_snapshot(bytes)
+        }
+
+        /// Refills the map from a snapshot read from `r`. If the snapshot turns out to be invalid
+        /// after the items have started loading, the map is left empty.
+        #[inline]
+        pub fn refill_from_reader<R: Read>(&mut self, r: &mut R) -> Result<(), SnapshotError> {
+            self.internal.refill_from_reader(r)
+        }
+    }
+
+    impl<K: Ord+Clone, V: Clone, L: Layout> RefillClone for TeardownMap<K, V, L> {
+        #[inline]
+        fn refill_clone(&mut self, master: &Self) {
+            self.internal.refill_clone(&master.internal)
+        }
+    }
+
+
+    #[cfg(feature = "serde")]
+    impl<K: Ord+Clone+Serialize, V: Serialize> Serialize for TeardownMap<K, V> {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            (*self.internal).serialize(serializer)
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    impl<'de, K: Ord+Clone+Deserialize<'de>, V: Deserialize<'de>> Deserialize<'de> for TeardownMap<K, V> {
+        fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            let repr = ::base::TreeRepr::deserialize(deserializer)?;
+            Ok(TeardownMap { internal: PlTree::with_repr(repr) })
+        }
     }
 
 
@@ -180,8 +489,8 @@ mod plain {
 
 
     #[derive(Clone, Debug)]
-    pub struct TeardownSet<T: Ord+Clone> {
-        map: TeardownMap<T, ()>
+    pub struct TeardownSet<T: Ord+Clone, L: Layout = Bfs> {
+        map: TeardownMap<T, (), L>
     }
 
     impl<T: Ord+Clone> TeardownSet<T> {
@@ -200,6 +509,23 @@ mod plain {
             let map_items = super::conv_to_tuple_vec(sorted);
             TeardownSet { map: TeardownMap::with_sorted(map_items) }
         }
+    }
+
+    impl<T: Ord+Clone, L: Layout> TeardownSet<T, L> {
+        /// Same as `new()`, but the tree is stored in the layout `L` (see `Veb`).
+        #[inline]
+        pub fn with_layout(items: Vec<T>) -> TeardownSet<T, L> {
+            let map_items = super::conv_to_tuple_vec(items);
+            TeardownSet { map: TeardownMap::with_layout(map_items) }
+        }
+
+        /// Same as `with_sorted()`, but the tree is stored in the layout `L`.
+        /// **Note**: the items are assumed to be sorted!
+        #[inline]
+        pub fn with_sorted_layout(sorted: Vec<T>) -> TeardownSet<T, L> {
+            let map_items = super::conv_to_tuple_vec(sorted);
+            TeardownSet { map: TeardownMap::with_sorted_layout(map_items) }
+        }
 
         /// Returns true if the set contains the given item.
         #[inline]
@@ -207,36 +533,82 @@ mod plain {
             self.map.contains_key(query)
         }
 
-        /// Executes a range query and feeds references to the matching items into `sink`.
+        /// Executes a range query and feeds references to the matching items into `sink`. For
+        /// compatibility, the empty range `a..a` matches the items equal to `a` (the newer range
+        /// operations, such as `range()`, treat it as empty).
         #[inline]
-        pub fn query_range<'a, Q, S>(&'a self, query: Range<Q>, sink: S)
-            where Q: PartialOrd<T>,
+        pub fn query_range<'a, Q, R, S>(&'a self, query: R, sink: S)
+            where Q: PartialOrd<T>+Clone,
+                  R: RangeBounds<Q>,
                   S: Sink<&'a T>
         {
             self.map.query_range(query, RefSinkAdapter::new(sink))
         }
 
+        /// Inserts the item into the tree. Duplicates are supported.
+        #[inline]
+        pub fn insert(&mut self, item: T) {
+            self.map.insert(item, ())
+        }
+
         /// Deletes the item with the given key from the tree and returns it (or None).
         #[inline]
         pub fn delete<Q: PartialOrd<T>>(&mut self, query: &Q) -> bool {
             self.map.delete(query).is_some()
         }
 
+        /// Returns the smallest item (or None if the set is empty).
+        #[inline]
+        pub fn first(&self) -> Option<&T> {
+            self.map.first().map(|item| &item.0)
+        }
+
+        /// Returns the largest item (or None if the set is empty).
+        #[inline]
+        pub fn last(&self) -> Option<&T> {
+            self.map.last().map(|item| &item.0)
+        }
+
+        /// Deletes the smallest item from the tree and returns it (or None if the set is empty).
+        #[inline]
+        pub fn pop_first(&mut self) -> Option<T> {
+            self.map.pop_first().map(|item| item.0)
+        }
+
+        /// Deletes the largest item from the tree and returns it (or None if the set is empty).
+        #[inline]
+        pub fn pop_last(&mut self) -> Option<T> {
+            self.map.pop_last().map(|item| item.0)
+        }
+
         /// Deletes all items inside `range` from the tree and feeds them into `sink`.
-        /// The items are returned in order.
+        /// The items are returned in order. As with `query_range()`, the empty range `a..a`
+        /// matches the items equal to `a`.
         #[inline]
-        pub fn delete_range<Q, S>(&mut self, query: Range<Q>, sink: S)
-            where Q: PartialOrd<T>, S: Sink<T>
+        pub fn delete_range<Q, R, S>(&mut self, query: R, sink: S)
+            where Q: PartialOrd<T>+Clone, R: RangeBounds<Q>, S: Sink<T>
         {
             let map_sink = SinkAdapter::new(sink);
             self.map.delete_range(query, map_sink)
         }
 
+        /// Returns an iterator that lazily deletes the items inside `range` from the tree and yields
+        /// them in order. If the iterator is dropped before it is exhausted, the items that have
+        /// not been yielded remain in the tree.
+        #[inline]
+        pub fn drain_range<'a, Q, R>(&'a mut self, range: R) -> SetDrainRange<'a, T, L, Q>
+            where Q: PartialOrd<T>+Clone, R: RangeBounds<Q>
+        {
+            SetDrainRange::new(self.map.drain_range(range))
+        }
+
         /// Deletes all items inside `range` that match `filter` from the tree and feeds them into
-        /// `sink`. The items are returned in order.
+        /// `sink`. The items are returned in order. As with `query_range()`, the empty range `a..a`
+        /// matches the items equal to `a`.
         #[inline]
-        pub fn filter_range<Q, Flt, S>(&mut self, range: Range<Q>, filter: Flt, sink: S)
-            where Q: PartialOrd<T>,
+        pub fn filter_range<Q, R, Flt, S>(&mut self, range: R, filter: Flt, sink: S)
+            where Q: PartialOrd<T>+Clone,
+                  R: RangeBounds<Q>,
                   Flt: ItemFilter<T>,
                   S: Sink<T>
         {
@@ -244,6 +616,20 @@ mod plain {
             self.map.filter_range(range, filter, map_sink)
         }
 
+        /// Deletes the `n` smallest items (or all items, if there are fewer) from the tree and feeds
+        /// them into `sink`. The items are returned in order.
+        #[inline]
+        pub fn delete_first<S: Sink<T>>(&mut self, n: usize, sink: S) {
+            self.map.delete_first(n, SinkAdapter::new(sink))
+        }
+
+        /// Deletes the `n` largest items (or all items, if there are fewer) from the tree and feeds
+        /// them into `sink`. The items are returned in order.
+        #[inline]
+        pub fn delete_last<S: Sink<T>>(&mut self, n: usize, sink: S) {
+            self.map.delete_last(n, SinkAdapter::new(sink))
+        }
+
         /// Deletes all items inside `range` from the tree and feeds them into `sink`.
         #[inline]
         pub fn delete_range_ref<Q, S>(&mut self, range: Range<&Q>, sink: S)
@@ -270,22 +656,173 @@ mod plain {
 
         #[inline] pub fn is_empty(&self) -> bool { self.map.is_empty() }
 
+        /// Starts maintaining the number of items in every subtree, so that `nth()`, `rank()` and
+        /// `count_range()` run in `O(log n)` rather than `O(n)` time.
+        #[inline] pub fn enable_counts(&mut self) { self.map.enable_counts() }
+
+        /// Returns the number of slots in the internal storage. See `compact()`.
+        #[inline] pub fn capacity(&self) -> usize { self.map.capacity() }
+
+        /// Rebuilds the remaining items into an internal storage just large enough to hold them.
+        /// See `TeardownMap::compact()`.
+        #[inline] pub fn compact(&mut self) { self.map.compact() }
+
+        /// Sets the policy for automatic compaction. See `TeardownMap::set_auto_compact()`.
+        #[inline] pub fn set_auto_compact(&mut self, ratio: Option<f64>) { self.map.set_auto_compact(ratio) }
+
+        #[inline] pub fn auto_compact(&self) -> Option<f64> { self.map.auto_compact() }
+
+        /// Returns the `k`-th smallest item (counting from 0), or None if `k >= size()`.
+        #[inline]
+        pub fn nth(&self, k: usize) -> Option<&T> {
+            self.map.nth(k).map(|item| &item.0)
+        }
+
+        /// Returns the number of items less than `query`.
+        #[inline]
+        pub fn rank<Q: PartialOrd<T>>(&self, query: &Q) -> usize {
+            self.map.rank(query)
+        }
+
+        /// Returns the number of items inside `range`.
+        #[inline]
+        pub fn count_range<Q, R>(&self, range: R) -> usize
+            where Q: PartialOrd<T>+Clone, R: RangeBounds<Q>
+        {
+            self.map.count_range(range)
+        }
+
         /// Removes all items from the tree (the items are dropped, but the internal storage is not).
         #[inline] pub fn clear(&mut self) { self.map.clear(); }
 
         /// Creates an iterator into the set.
-        #[inline] pub fn iter<'a>(&'a self) -> SetIter<'a, T> {
+        #[inline] pub fn iter<'a>(&'a self) -> SetIter<'a, T, L> {
             SetIter::new(self.map.internal.iter())
         }
+
+        /// Creates an iterator into the set that yields the items in no particular order. This is
+        /// cheaper than `iter()`.
+        #[inline] pub fn iter_unordered<'a>(&'a self) -> SetUnorderedIter<'a, T, L> {
+            SetUnorderedIter::new(self.map.internal.iter_unordered())
+        }
+
+        /// Creates an iterator over the items inside `range`. The iterator can be traversed from
+        /// both ends.
+        #[inline]
+        pub fn range<'a, Q, R>(&'a self, range: R) -> SetRange<'a, T, L>
+            where Q: PartialOrd<T>+Clone, R: RangeBounds<Q>
+        {
+            SetRange::new(self.map.internal.range(&KeyBounds::new(range)))
+        }
     }
 
-    impl<K: Ord+Clone+Copy> Refill for TeardownSet<K> {
+    #[cfg(feature = "rayon")]
+    impl<T: Ord+Clone+Send> TeardownSet<T> {
+        /// Same as `new()`, but the items are sorted and the tree is built in parallel. It is kept
+        /// apart from `new()` because of the `Send` bound, see `TeardownMap::par_new()`.
+        #[inline]
+        pub fn par_new(items: Vec<T>) -> TeardownSet<T> {
+            let map_items = super::conv_to_tuple_vec(items);
+            TeardownSet { map: TeardownMap::par_new(map_items) }
+        }
+
+        /// Same as `with_sorted()`, but the tree is built in parallel.
+        /// **Note**: the items are assumed to be sorted!
+        #[inline]
+        pub fn par_with_sorted(sorted: Vec<T>) -> TeardownSet<T> {
+            let map_items = super::conv_to_tuple_vec(sorted);
+            TeardownSet { map: TeardownMap::par_with_sorted(map_items) }
+        }
+
+        /// Same as `delete_range()`, but large subtrees inside the range are consumed in parallel.
+        /// The items are fed into sinks created by `sink_factory`, which are returned in order:
+        /// concatenating their contents yields the deleted items in order. The resulting tree is
+        /// the same as after `delete_range()`, except that the empty range `a..a` deletes nothing.
+        #[inline]
+        pub fn par_delete_range<R, S, F>(&mut self, range: R, sink_factory: F) -> Vec<S>
+            where R: RangeBounds<T>, S: Sink<T>+Send, F: Fn() -> S + Sync
+        {
+            self.map.par_delete_range(range, || SinkAdapter::new(sink_factory()))
+                .into_iter().map(SinkAdapter::into_inner).collect()
+        }
+    }
+
+    #[cfg(feature = "rayon")]
+    impl<T: Ord+Clone+Sync> TeardownSet<T> {
+        /// Same as `query_range()`, but large subtrees are traversed in parallel. The items are fed
+        /// into sinks created by `sink_factory`, which are returned in order: concatenating their
+        /// contents yields the items in order.
+        #[inline]
+        pub fn par_query_range<'a, R, S, F>(&'a self, range: R, sink_factory: F) -> Vec<S>
+            where R: RangeBounds<T>, S: Sink<&'a T>+Send, F: Fn() -> S + Sync
+        {
+            self.map.par_query_range(range, || RefSinkAdapter::new(sink_factory()))
+                .into_iter().map(RefSinkAdapter::into_inner).collect()
+        }
+    }
+
+    impl<K: Ord+Clone+Copy, L: Layout> Refill for TeardownSet<K, L> {
         #[inline]
         fn refill(&mut self, master: &Self) {
             self.map.refill(&master.map)
         }
+
+        #[inline]
+        fn refill_incremental(&mut self, master: &Self) {
+            self.map.refill_incremental(&master.map)
+        }
     }
 
+    impl<K: Ord+Pod> TeardownSet<K> {
+        /// Constructs a set from a binary snapshot held in memory (e.g. the contents of a file or a
+        /// memory-mapped buffer). See the `snapshot` module for the format.
+        pub fn from_snapshot(bytes: &[u8]) -> Result<Self, SnapshotError> {
+            TeardownMap::from_snapshot(bytes).map(|map| TeardownSet { map: map })
+        }
+
+        /// Writes a binary snapshot of the set to `w`.
+        #[inline]
+        pub fn write_snapshot<W: Write>(&self, w: &mut W) -> io::Result<()> {
+            self.map.write_snapshot(w)
+        }
+
+        /// Refills the set from a snapshot held in memory, copying the items straight into the
+        /// storage without building a master copy. On error, the set is left unchanged.
+        #[inline]
+        pub fn refill_from_snapshot(&mut self, bytes: &[u8]) -> Result<(), SnapshotError> {
+            self.map.refill_from_snapshot(bytes)
+        }
+
+        /// Refills the set from a snapshot read from `r`. If the snapshot turns out to be invalid
+        /// after the items have started loading, the set is left empty.
+        #[inline]
+        pub fn refill_from_reader<R: Read>(&mut self, r: &mut R) -> Result<(), SnapshotError> {
+            self.map.refill_from_reader(r)
+        }
+    }
+
+    impl<K: Ord+Clone, L: Layout> RefillClone for TeardownSet<K, L> {
+        #[inline]
+        fn refill_clone(&mut self, master: &Self) {
+            self.map.refill_clone(&master.map)
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    impl<T: Ord+Clone+Serialize> Serialize for TeardownSet<T> {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            self.map.serialize(serializer)
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    impl<'de, T: Ord+Clone+Deserialize<'de>> Deserialize<'de> for TeardownSet<T> {
+        fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            TeardownMap::deserialize(deserializer).map(|map| TeardownSet { map: map })
+        }
+    }
+
+
     #[cfg(test)]
     impl<K: Key> super::TreeWrapperAccess for TeardownSet<K> {
         type Repr = TreeRepr<PlNode<K, ()>>;
@@ -312,7 +849,7 @@ mod plain {
         }
     }
 
-    impl<T: Ord+Clone+Debug> Display for TeardownSet<T> {
+    impl<T: Ord+Clone+Debug, L: Layout> Display for TeardownSet<T, L> {
         fn fmt(&self, fmt: &mut Formatter) -> fmt::Result {
             Display::fmt(&self.map, fmt)
         }
@@ -320,11 +857,11 @@ mod plain {
 
 
     #[derive(new)]
-    pub struct MapIter<'a, K: Ord+Clone+'a, V: 'a> {
-        inner: ::base::Iter<'a, PlNode<K, V>>
+    pub struct MapIter<'a, K: Ord+Clone+'a, V: 'a, L: Layout+'a = Bfs> {
+        inner: ::base::Iter<'a, PlNode<K, V>, L>
     }
 
-    impl<'a, K: Ord+Clone+'a, V: 'a> Iterator for MapIter<'a, K, V> {
+    impl<'a, K: Ord+Clone+'a, V: 'a, L: Layout+'a> Iterator for MapIter<'a, K, V, L> {
         type Item = &'a (K, V);
 
         fn next(&mut self) -> Option<Self::Item> {
@@ -336,15 +873,246 @@ mod plain {
         }
     }
 
-    impl<'a, K: Ord+Clone+'a, V: 'a> ExactSizeIterator for MapIter<'a, K, V> {}
+    impl<'a, K: Ord+Clone+'a, V: 'a, L: Layout+'a> DoubleEndedIterator for MapIter<'a, K, V, L> {
+        fn next_back(&mut self) -> Option<Self::Item> {
+            self.inner.next_back().map(|entry| entry.as_tuple())
+        }
+    }
+
+    impl<'a, K: Ord+Clone+'a, V: 'a, L: Layout+'a> ExactSizeIterator for MapIter<'a, K, V, L> {}
+
+
+    #[derive(new)]
+    pub struct MapRange<'a, K: Ord+Clone+'a, V: 'a, L: Layout+'a = Bfs> {
+        inner: ::base::RangeIter<'a, PlNode<K, V>, L>
+    }
+
+    impl<'a, K: Ord+Clone+'a, V: 'a, L: Layout+'a> Iterator for MapRange<'a, K, V, L> {
+        type Item = &'a (K, V);
+
+        fn next(&mut self) -> Option<Self::Item> {
+            self.inner.next().map(|entry| entry.as_tuple())
+        }
+    }
+
+    impl<'a, K: Ord+Clone+'a, V: 'a, L: Layout+'a> DoubleEndedIterator for MapRange<'a, K, V, L> {
+        fn next_back(&mut self) -> Option<Self::Item> {
+            self.inner.next_back().map(|entry| entry.as_tuple())
+        }
+    }
+
+
+    #[derive(new)]
+    pub struct MapIterMut<'a, K: Ord+Clone+'a, V: 'a, L: Layout+'a = Bfs> {
+        inner: ::base::IterMut<'a, PlNode<K, V>, L>
+    }
+
+    impl<'a, K: Ord+Clone+'a, V: 'a, L: Layout+'a> Iterator for MapIterMut<'a, K, V, L> {
+        type Item = (&'a K, &'a mut V);
+
+        fn next(&mut self) -> Option<Self::Item> {
+            self.inner.next()
+        }
+
+        fn size_hint(&self) -> (usize, Option<usize>) {
+            self.inner.size_hint()
+        }
+    }
+
+    impl<'a, K: Ord+Clone+'a, V: 'a, L: Layout+'a> ExactSizeIterator for MapIterMut<'a, K, V, L> {}
+
+
+    #[derive(new)]
+    pub struct MapUnorderedIter<'a, K: Ord+Clone+'a, V: 'a, L: Layout+'a = Bfs> {
+        inner: ::base::UnorderedIter<'a, PlNode<K, V>, L>
+    }
+
+    impl<'a, K: Ord+Clone+'a, V: 'a, L: Layout+'a> Iterator for MapUnorderedIter<'a, K, V, L> {
+        type Item = &'a (K, V);
+
+        fn next(&mut self) -> Option<Self::Item> {
+            self.inner.next().map(|entry| entry.as_tuple())
+        }
+
+        fn size_hint(&self) -> (usize, Option<usize>) {
+            self.inner.size_hint()
+        }
+    }
+
+    impl<'a, K: Ord+Clone+'a, V: 'a, L: Layout+'a> ExactSizeIterator for MapUnorderedIter<'a, K, V, L> {}
+
+
+    /// A cursor over a `TeardownMap`. It points either at an item, or at the "ghost" position that
+    /// lies past the last item and before the first one.
+    #[derive(new)]
+    pub struct MapCursor<'a, K: Ord+Clone+'a, V: 'a, L: Layout+'a = Bfs> {
+        tree: &'a PlTree<K, V, L>,
+        idx: Option<usize>
+    }
+
+    impl<'a, K: Ord+Clone+'a, V: 'a, L: Layout+'a> MapCursor<'a, K, V, L> {
+        /// Returns the current item, or None at the ghost position.
+        #[inline]
+        pub fn current(&self) -> Option<&'a (K, V)> {
+            let tree = self.tree;
+            self.idx.map(|idx| tree.node(idx).as_tuple())
+        }
+
+        #[inline] pub fn key(&self) -> Option<&'a K> { self.current().map(|item| &item.0) }
+
+        #[inline] pub fn value(&self) -> Option<&'a V> { self.current().map(|item| &item.1) }
+
+        /// Moves to the next item (from the ghost position, to the first one) and returns it.
+        #[inline]
+        pub fn next(&mut self) -> Option<&'a (K, V)> {
+            self.idx = self.peek_next_idx();
+            self.current()
+        }
+
+        /// Moves to the previous item (from the ghost position, to the last one) and returns it.
+        #[inline]
+        pub fn prev(&mut self) -> Option<&'a (K, V)> {
+            self.idx = self.peek_prev_idx();
+            self.current()
+        }
+
+        /// Returns the item that `next()` would move to without moving the cursor.
+        #[inline]
+        pub fn peek_next(&self) -> Option<&'a (K, V)> {
+            let tree = self.tree;
+            self.peek_next_idx().map(|idx| tree.node(idx).as_tuple())
+        }
+
+        /// Returns the item that `prev()` would move to without moving the cursor.
+        #[inline]
+        pub fn peek_prev(&self) -> Option<&'a (K, V)> {
+            let tree = self.tree;
+            self.peek_prev_idx().map(|idx| tree.node(idx).as_tuple())
+        }
+
+        fn peek_next_idx(&self) -> Option<usize> {
+            match self.idx {
+                Some(idx) => self.tree.next_idx(idx),
+                None => self.tree.first_idx()
+            }
+        }
+
+        fn peek_prev_idx(&self) -> Option<usize> {
+            match self.idx {
+                Some(idx) => self.tree.prev_idx(idx),
+                None => self.tree.last_idx()
+            }
+        }
+    }
+
+
+    /// A cursor over a `TeardownMap` that can modify the values and remove items. Like `MapCursor`,
+    /// it points either at an item or at the ghost position.
+    #[derive(new)]
+    pub struct MapCursorMut<'a, K: Ord+Clone+'a, V: 'a, L: Layout+'a = Bfs> {
+        tree: &'a mut PlTree<K, V, L>,
+        idx: Option<usize>
+    }
+
+    impl<'a, K: Ord+Clone+'a, V: 'a, L: Layout+'a> MapCursorMut<'a, K, V, L> {
+        /// Returns the current item, or None at the ghost position.
+        #[inline]
+        pub fn current(&self) -> Option<&(K, V)> {
+            self.idx.map(|idx| self.tree.node(idx).as_tuple())
+        }
+
+        #[inline] pub fn key(&self) -> Option<&K> { self.current().map(|item| &item.0) }
+
+        #[inline] pub fn value(&self) -> Option<&V> { self.current().map(|item| &item.1) }
+
+        /// Returns a mutable reference to the value of the current item, or None at the ghost
+        /// position.
+        #[inline]
+        pub fn value_mut(&mut self) -> Option<&mut V> {
+            match self.idx {
+                Some(idx) => Some(self.tree.node_mut(idx).val_mut()),
+                None => None
+            }
+        }
+
+        /// Moves to the next item (from the ghost position, to the first one) and returns it.
+        #[inline]
+        pub fn next(&mut self) -> Option<&(K, V)> {
+            self.idx = self.as_cursor().peek_next_idx();
+            self.current()
+        }
+
+        /// Moves to the previous item (from the ghost position, to the last one) and returns it.
+        #[inline]
+        pub fn prev(&mut self) -> Option<&(K, V)> {
+            self.idx = self.as_cursor().peek_prev_idx();
+            self.current()
+        }
+
+        /// Returns the item that `next()` would move to without moving the cursor.
+        #[inline]
+        pub fn peek_next(&self) -> Option<&(K, V)> {
+            self.as_cursor().peek_next()
+        }
+
+        /// Returns the item that `prev()` would move to without moving the cursor.
+        #[inline]
+        pub fn peek_prev(&self) -> Option<&(K, V)> {
+            self.as_cursor().peek_prev()
+        }
+
+        /// Removes the current item from the tree and returns it. The cursor moves to the next item
+        /// (or to the ghost position if the removed item was the last one). Returns None at the
+        /// ghost position.
+        #[inline]
+        pub fn remove_current(&mut self) -> Option<(K, V)> {
+            self.idx.map(|idx| {
+                let (item, next) = self.tree.delete_at(idx);
+                self.idx = next;
+                item
+            })
+        }
+
+        /// Returns a read-only cursor at the same position.
+        #[inline]
+        pub fn as_cursor(&self) -> MapCursor<K, V, L> {
+            MapCursor::new(self.tree, self.idx)
+        }
+    }
+
+
+    #[derive(new)]
+    pub struct SetIter<'a, T: Ord+Clone+'a, L: Layout+'a = Bfs> {
+        inner: ::base::Iter<'a, PlNode<T, ()>, L>
+    }
+
+    impl<'a, T: Ord+Clone+'a, L: Layout+'a> Iterator for SetIter<'a, T, L> {
+        type Item = &'a T;
+
+        fn next(&mut self) -> Option<Self::Item> {
+            self.inner.next().map(|entry| entry.key())
+        }
+
+        fn size_hint(&self) -> (usize, Option<usize>) {
+            self.inner.size_hint()
+        }
+    }
+
+    impl<'a, T: Ord+Clone+'a, L: Layout+'a> DoubleEndedIterator for SetIter<'a, T, L> {
+        fn next_back(&mut self) -> Option<Self::Item> {
+            self.inner.next_back().map(|entry| entry.key())
+        }
+    }
+
+    impl<'a, T: Ord+Clone+'a, L: Layout+'a> ExactSizeIterator for SetIter<'a, T, L> {}
 
 
     #[derive(new)]
-    pub struct SetIter<'a, T: Ord+Clone+'a> {
-        inner: ::base::Iter<'a, PlNode<T, ()>>
+    pub struct SetUnorderedIter<'a, T: Ord+Clone+'a, L: Layout+'a = Bfs> {
+        inner: ::base::UnorderedIter<'a, PlNode<T, ()>, L>
     }
 
-    impl<'a, T: Ord+Clone+'a> Iterator for SetIter<'a, T> {
+    impl<'a, T: Ord+Clone+'a, L: Layout+'a> Iterator for SetUnorderedIter<'a, T, L> {
         type Item = &'a T;
 
         fn next(&mut self) -> Option<Self::Item> {
@@ -356,12 +1124,32 @@ mod plain {
         }
     }
 
-    impl<'a, T: Ord+Clone+'a> ExactSizeIterator for SetIter<'a, T> {}
+    impl<'a, T: Ord+Clone+'a, L: Layout+'a> ExactSizeIterator for SetUnorderedIter<'a, T, L> {}
 
 
-    impl<K: Ord+Clone, V> IntoIterator for TeardownMap<K, V> {
+    #[derive(new)]
+    pub struct SetRange<'a, T: Ord+Clone+'a, L: Layout+'a = Bfs> {
+        inner: ::base::RangeIter<'a, PlNode<T, ()>, L>
+    }
+
+    impl<'a, T: Ord+Clone+'a, L: Layout+'a> Iterator for SetRange<'a, T, L> {
+        type Item = &'a T;
+
+        fn next(&mut self) -> Option<Self::Item> {
+            self.inner.next().map(|entry| entry.key())
+        }
+    }
+
+    impl<'a, T: Ord+Clone+'a, L: Layout+'a> DoubleEndedIterator for SetRange<'a, T, L> {
+        fn next_back(&mut self) -> Option<Self::Item> {
+            self.inner.next_back().map(|entry| entry.key())
+        }
+    }
+
+
+    impl<K: Ord+Clone, V, L: Layout> IntoIterator for TeardownMap<K, V, L> {
         type Item = (K, V);
-        type IntoIter = MapIntoIter<K, V>;
+        type IntoIter = MapIntoIter<K, V, L>;
 
         fn into_iter(self) -> Self::IntoIter {
             MapIntoIter::new(::base::IntoIter::new(self.internal.into_repr()))
@@ -370,11 +1158,11 @@ mod plain {
 
     // this is just a wrapper for ::base::IntoIter<Node> to avoid leaking the Node type
     #[derive(new)]
-    pub struct MapIntoIter<K: Ord+Clone, V> {
-        inner: ::base::IntoIter<PlNode<K, V>>
+    pub struct MapIntoIter<K: Ord+Clone, V, L: Layout = Bfs> {
+        inner: ::base::IntoIter<PlNode<K, V>, L>
     }
 
-    impl<K: Ord+Clone, V> Iterator for MapIntoIter<K, V> {
+    impl<K: Ord+Clone, V, L: Layout> Iterator for MapIntoIter<K, V, L> {
         type Item = (K, V);
         fn next(&mut self) -> Option<Self::Item> {
             self.inner.next()
@@ -385,37 +1173,318 @@ mod plain {
         }
     }
 
-    impl<K: Ord+Clone, V> ExactSizeIterator for MapIntoIter<K, V> {}
+    impl<K: Ord+Clone, V, L: Layout> DoubleEndedIterator for MapIntoIter<K, V, L> {
+        fn next_back(&mut self) -> Option<Self::Item> {
+            self.inner.next_back()
+        }
+    }
+
+    impl<K: Ord+Clone, V, L: Layout> ExactSizeIterator for MapIntoIter<K, V, L> {}
 
 
 
-    impl<T: Ord+Clone> IntoIterator for TeardownSet<T> {
+    impl<T: Ord+Clone, L: Layout> IntoIterator for TeardownSet<T, L> {
         type Item = T;
-        type IntoIter = SetIntoIter<T>;
+        type IntoIter = SetIntoIter<T, L>;
 
         fn into_iter(self) -> Self::IntoIter {
             SetIntoIter::new(::base::IntoIter::new(self.map.internal.into_repr()))
         }
     }
 
-    // this is just a wrapper for ::base::IntoIter<Node> to avoid leaking the Node type
-    #[derive(new)]
-    pub struct SetIntoIter<T: Ord+Clone> {
-        inner: ::base::IntoIter<PlNode<T, ()>>
-    }
+    // this is just a wrapper for ::base::IntoIter<Node> to avoid leaking the Node type
+    #[derive(new)]
+    pub struct SetIntoIter<T: Ord+Clone, L: Layout = Bfs> {
+        inner: ::base::IntoIter<PlNode<T, ()>, L>
+    }
+
+    impl<T: Ord+Clone, L: Layout> Iterator for SetIntoIter<T, L> {
+        type Item = T;
+        fn next(&mut self) -> Option<Self::Item> {
+            self.inner.next().map(|(item, _)| item)
+        }
+
+        fn size_hint(&self) -> (usize, Option<usize>) {
+            self.inner.size_hint()
+        }
+    }
+
+    impl<T: Ord+Clone, L: Layout> DoubleEndedIterator for SetIntoIter<T, L> {
+        fn next_back(&mut self) -> Option<Self::Item> {
+            self.inner.next_back().map(|(item, _)| item)
+        }
+    }
+
+    impl<T: Ord+Clone, L: Layout> ExactSizeIterator for SetIntoIter<T, L> {}
+
+
+
+    /// The minimal number of items deleted from the tree in one batch by `MapDrainRange`.
+    const DRAIN_BATCH_MIN: usize = 16;
+
+    pub struct MapDrainRange<'a, K: Ord+Clone+'a, V: 'a, L: Layout+'a = Bfs, Q: PartialOrd<K> = K> {
+        map: &'a mut TeardownMap<K, V, L>,
+        bounds: KeyBounds<Q>,
+        batch_size: usize,
+        // the items deleted from the tree, but not yet yielded (in reverse order)
+        buf: Vec<(K, V)>,
+    }
+
+    impl<'a, K: Ord+Clone+'a, V: 'a, L: Layout+'a, Q: PartialOrd<K>> MapDrainRange<'a, K, V, L, Q> {
+        fn new(map: &'a mut TeardownMap<K, V, L>, bounds: KeyBounds<Q>) -> Self {
+            // Each batch costs O(log n) on top of the deleted items, so batches of at least
+            // `height` items preserve the O(k + log n) bound of `delete_range`.
+            let height = map.internal.capacity().next_power_of_two().trailing_zeros() as usize + 1;
+            let batch_size = cmp::max(DRAIN_BATCH_MIN, height);
+            MapDrainRange { map: map, bounds: bounds, batch_size: batch_size, buf: Vec::new() }
+        }
+    }
+
+    impl<'a, K: Ord+Clone+'a, V: 'a, L: Layout+'a, Q: PartialOrd<K>> Iterator for MapDrainRange<'a, K, V, L, Q> {
+        type Item = (K, V);
+
+        fn next(&mut self) -> Option<Self::Item> {
+            if self.buf.is_empty() {
+                let found = self.map.internal.delete_range_front(&self.bounds, self.batch_size, &mut self.buf);
+                if !found {
+                    return None;
+                }
+                self.buf.reverse();
+            }
+
+            self.buf.pop()
+        }
+    }
+
+    impl<'a, K: Ord+Clone+'a, V: 'a, L: Layout+'a, Q: PartialOrd<K>> Drop for MapDrainRange<'a, K, V, L, Q> {
+        fn drop(&mut self) {
+            // Return the items that were not yielded. All the remaining items inside the range are
+            // greater than these, so the order of duplicates is preserved.
+            while let Some((k, v)) = self.buf.pop() {
+                self.map.insert(k, v);
+            }
+        }
+    }
+
+
+    #[derive(new)]
+    pub struct SetDrainRange<'a, T: Ord+Clone+'a, L: Layout+'a = Bfs, Q: PartialOrd<T> = T> {
+        inner: MapDrainRange<'a, T, (), L, Q>
+    }
+
+    impl<'a, T: Ord+Clone+'a, L: Layout+'a, Q: PartialOrd<T>> Iterator for SetDrainRange<'a, T, L, Q> {
+        type Item = T;
+
+        fn next(&mut self) -> Option<Self::Item> {
+            self.inner.next().map(|(item, _)| item)
+        }
+    }
+
+
+    /// Read-only collections backed by memory-mapped snapshot files.
+    #[cfg(feature = "mmap")]
+    pub mod mapped {
+        use base::{Refill, Sink, KeyBounds};
+        use base::mapped::{MappedRepr, MappedIter};
+        use base::snapshot::{Pod, SnapshotError};
+        use applied::plain_tree::PlNode;
+        use super::{TeardownMap, TeardownSet};
+        use super::super::sink::RefSinkAdapter;
+
+        use memmap2::Mmap;
+
+        use std::ops::RangeBounds;
+        use std::path::Path;
+
+
+        /// A read-only map whose storage lives in a memory-mapped snapshot file (see
+        /// `TeardownMap::write_snapshot()`). It can serve as the master copy for `Refill`, which lets
+        /// several processes share one physical master.
+        ///
+        /// **Attention!** The file must not be modified while it is mapped (see `open()`).
+        pub struct MappedTeardownMap<K: Ord+Pod, V: Pod> {
+            repr: MappedRepr<PlNode<K, V>>
+        }
+
+        impl<K: Ord+Pod, V: Pod> MappedTeardownMap<K, V> {
+            /// Maps the snapshot file at `path`. The snapshot is validated, which reads it in full.
+            ///
+            /// # Safety
+            ///
+            /// The file must not be modified or truncated (by this or any other process) while it
+            /// is mapped: the map reads it directly, and the snapshot is only validated once.
+            pub unsafe fn open<P: AsRef<Path>>(path: P) -> Result<Self, SnapshotError> {
+                MappedRepr::open(path).map(|repr| MappedTeardownMap { repr: repr })
+            }
+
+            /// Wraps a memory-mapped snapshot. The snapshot is validated, which reads it in full.
+            pub fn with_mmap(mmap: Mmap) -> Result<Self, SnapshotError> {
+                MappedRepr::with_mmap(mmap).map(|repr| MappedTeardownMap { repr: repr })
+            }
+
+            /// Finds the item with the given key and returns it (or None).
+            #[inline]
+            pub fn find<'a, Q>(&'a self, query: &Q) -> Option<&'a V>
+                where Q: PartialOrd<K>
+            {
+                self.repr.find(query)
+            }
+
+            /// Returns true if the map contains the given key.
+            #[inline]
+            pub fn contains_key<Q>(&self, query: &Q) -> bool
+                where Q: PartialOrd<K>
+            {
+                self.repr.find(query).is_some()
+            }
+
+            /// Executes a range query. `range` can be any kind of range over the keys, e.g. `a..b`,
+            /// `a..=b`, `..b`, `a..` or `..`.
+            pub fn query_range<'a, R, S>(&'a self, range: R, mut sink: S)
+                where R: RangeBounds<K>,
+                      S: Sink<&'a (K, V)>
+            {
+                let bounds = KeyBounds::compat(range);
+                let mut next = self.repr.lower_bound(&bounds);
+                while let Some(idx) = next {
+                    let node = self.repr.node(idx);
+                    if !bounds.below_end(node.key()) {
+                        break;
+                    }
+                    sink.consume(node.as_tuple());
+                    next = self.repr.next_idx(idx);
+                }
+            }
+
+            /// Returns the number of items in the map.
+            #[inline]
+            pub fn size(&self) -> usize {
+                self.repr.size()
+            }
+
+            /// Creates an iterator over the items in the map, sorted by key.
+            #[inline]
+            pub fn iter<'a>(&'a self) -> MappedMapIter<'a, K, V> {
+                MappedMapIter { inner: self.repr.iter() }
+            }
+        }
+
+        impl<K: Ord+Pod, V: Pod> Refill<MappedTeardownMap<K, V>> for TeardownMap<K, V> {
+            #[inline]
+            fn refill(&mut self, master: &MappedTeardownMap<K, V>) {
+                self.internal.refill_from_bytes(&master.repr.bytes())
+            }
+
+            #[inline]
+            fn refill_incremental(&mut self, master: &MappedTeardownMap<K, V>) {
+                self.internal.refill_incremental_from_bytes(&master.repr.bytes())
+            }
+        }
+
+
+        /// A read-only set whose storage lives in a memory-mapped snapshot file (see
+        /// `TeardownSet::write_snapshot()`). It can serve as the master copy for `Refill`.
+        ///
+        /// **Attention!** The file must not be modified while it is mapped (see `open()`).
+        pub struct MappedTeardownSet<T: Ord+Pod> {
+            map: MappedTeardownMap<T, ()>
+        }
+
+        impl<T: Ord+Pod> MappedTeardownSet<T> {
+            /// Maps the snapshot file at `path`. The snapshot is validated, which reads it in full.
+            ///
+            /// # Safety
+            ///
+            /// The same as for `MappedTeardownMap::open()`.
+            pub unsafe fn open<P: AsRef<Path>>(path: P) -> Result<Self, SnapshotError> {
+                MappedTeardownMap::open(path).map(|map| MappedTeardownSet { map: map })
+            }
+
+            /// Wraps a memory-mapped snapshot. The snapshot is validated, which reads it in full.
+            pub fn with_mmap(mmap: Mmap) -> Result<Self, SnapshotError> {
+                MappedTeardownMap::with_mmap(mmap).map(|map| MappedTeardownSet { map: map })
+            }
+
+            /// Returns true if the set contains the given item.
+            #[inline]
+            pub fn contains<Q>(&self, query: &Q) -> bool
+                where Q: PartialOrd<T>
+            {
+                self.map.contains_key(query)
+            }
+
+            /// Executes a range query and feeds references to the matching items into `sink`.
+            #[inline]
+            pub fn query_range<'a, R, S>(&'a self, range: R, sink: S)
+                where R: RangeBounds<T>,
+                      S: Sink<&'a T>
+            {
+                self.map.query_range(range, RefSinkAdapter::new(sink))
+            }
+
+            /// Returns the number of items in the set.
+            #[inline]
+            pub fn size(&self) -> usize {
+                self.map.size()
+            }
+
+            /// Creates an iterator over the items in the set, in sorted order.
+            #[inline]
+            pub fn iter<'a>(&'a self) -> MappedSetIter<'a, T> {
+                MappedSetIter { inner: self.map.repr.iter() }
+            }
+        }
+
+        impl<T: Ord+Pod> Refill<MappedTeardownSet<T>> for TeardownSet<T> {
+            #[inline]
+            fn refill(&mut self, master: &MappedTeardownSet<T>) {
+                self.map.refill(&master.map)
+            }
+
+            #[inline]
+            fn refill_incremental(&mut self, master: &MappedTeardownSet<T>) {
+                self.map.refill_incremental(&master.map)
+            }
+        }
+
+
+        pub struct MappedMapIter<'a, K: Ord+Pod+'a, V: Pod+'a> {
+            inner: MappedIter<'a, PlNode<K, V>>
+        }
+
+        impl<'a, K: Ord+Pod+'a, V: Pod+'a> Iterator for MappedMapIter<'a, K, V> {
+            type Item = &'a (K, V);
+
+            fn next(&mut self) -> Option<Self::Item> {
+                self.inner.next().map(|node| node.as_tuple())
+            }
+
+            fn size_hint(&self) -> (usize, Option<usize>) {
+                self.inner.size_hint()
+            }
+        }
+
+        impl<'a, K: Ord+Pod+'a, V: Pod+'a> ExactSizeIterator for MappedMapIter<'a, K, V> {}
 
-    impl<T: Ord+Clone> Iterator for SetIntoIter<T> {
-        type Item = T;
-        fn next(&mut self) -> Option<Self::Item> {
-            self.inner.next().map(|(item, _)| item)
+
+        pub struct MappedSetIter<'a, T: Ord+Pod+'a> {
+            inner: MappedIter<'a, PlNode<T, ()>>
         }
 
-        fn size_hint(&self) -> (usize, Option<usize>) {
-            self.inner.size_hint()
+        impl<'a, T: Ord+Pod+'a> Iterator for MappedSetIter<'a, T> {
+            type Item = &'a T;
+
+            fn next(&mut self) -> Option<Self::Item> {
+                self.inner.next().map(|node| node.key())
+            }
+
+            fn size_hint(&self) -> (usize, Option<usize>) {
+                self.inner.size_hint()
+            }
         }
-    }
 
-    impl<T: Ord+Clone> ExactSizeIterator for SetIntoIter<T> {}
+        impl<'a, T: Ord+Pod+'a> ExactSizeIterator for MappedSetIter<'a, T> {}
+    }
 }
 
 
@@ -423,15 +1492,19 @@ mod plain {
 mod interval {
     use std::fmt;
     use std::fmt::{Debug, Display, Formatter};
+    use std::ops::RangeBounds;
+    use std::io::{self, Read, Write};
 
-    use base::{Refill, ItemFilter, Sink};
+    use base::{Refill, RefillClone, ItemFilter, Sink, KeyBounds};
+    use base::snapshot::{Pod, SnapshotError};
     use super::sink::{SinkAdapter, RefSinkAdapter};
 
     use applied::AppliedTree;
     use applied::interval::{Interval, IvNode};
-    use applied::interval_tree::{IvTree};
+    use applied::interval_tree::{IvTree, OverlapIter};
 
     #[cfg(test)] use base::TreeRepr;
+    #[cfg(feature = "serde")] use serde::{Serialize, Serializer, Deserialize, Deserializer};
 
 
     #[derive(Clone)]
@@ -464,6 +1537,14 @@ mod interval {
             self.internal.find(query)
         }
 
+        /// Finds the item with the given key and returns a mutable reference to its value (or None).
+        #[inline]
+        pub fn get_mut<'a, Q>(&'a mut self, query: &Q) -> Option<&'a mut V>
+            where Q: Interval<K=Iv::K> + PartialOrd<Iv>
+        {
+            self.internal.find_mut(query)
+        }
+
         /// Returns true if the map contains the given key.
         #[inline]
         pub fn contains_key<Q>(&self, query: &Q) -> bool
@@ -481,6 +1562,60 @@ mod interval {
             self.internal.query_overlap(0, query, sink)
         }
 
+        /// Returns a lazy in-order iterator over the items that overlap with `query`.
+        #[inline]
+        pub fn overlapping<'a, Q>(&'a self, query: &'a Q) -> IntervalMapOverlapIter<'a, Iv, V, Q>
+            where Q: Interval<K=Iv::K>
+        {
+            IntervalMapOverlapIter::new(self.internal.overlapping(query))
+        }
+
+        /// Returns true if any item overlaps with `query`. Stops at the first hit.
+        #[inline]
+        pub fn any_overlap<Q>(&self, query: &Q) -> bool
+            where Q: Interval<K=Iv::K>
+        {
+            self.internal.overlapping(query).next().is_some()
+        }
+
+        /// Returns the first (smallest) item that overlaps with `query`, or None.
+        #[inline]
+        pub fn first_overlap<'a, Q>(&'a self, query: &'a Q) -> Option<&'a (Iv, V)>
+            where Q: Interval<K=Iv::K>
+        {
+            self.internal.overlapping(query).next()
+        }
+
+        /// Calls `f` on every interval that overlaps with `query` in order, giving it mutable
+        /// access to the value.
+        #[inline]
+        pub fn for_each_overlap_mut<Q, F>(&mut self, query: &Q, f: F)
+            where Q: Interval<K=Iv::K>,
+                  F: FnMut(&Iv, &mut V)
+        {
+            self.internal.for_each_overlap_mut(query, f)
+        }
+
+        /// Calls `f` on every item whose key lies inside `range` in order, giving it mutable access
+        /// to the value. Unlike `for_each_overlap_mut()`, `range` is a range over the intervals
+        /// themselves in the order of `Interval::cmp()` (as in `count_range()`), not an overlap query.
+        #[inline]
+        pub fn for_each_in_range_mut<Q, R, F>(&mut self, range: R, f: F)
+            where Q: PartialOrd<Iv>+Clone,
+                  R: RangeBounds<Q>,
+                  F: FnMut(&Iv, &mut V)
+        {
+            self.internal.for_each_in_range_mut(&KeyBounds::new(range), f)
+        }
+
+        /// Inserts the interval into the tree. Duplicate keys are supported. The new item is placed
+        /// into a free slot if one is available on its path; otherwise the affected subtree is
+        /// rebuilt (or, if the tree is too dense, the storage is reallocated with a larger height).
+        #[inline]
+        pub fn insert(&mut self, key: Iv, val: V) {
+            self.internal.insert(key, val)
+        }
+
         /// Deletes the item with the given key from the tree and returns it (or None).
         #[inline]
         pub fn delete<Q>(&mut self, query: &Q) -> Option<V>
@@ -489,6 +1624,32 @@ mod interval {
             self.internal.delete(query)
         }
 
+        /// Returns the smallest item with respect to `Interval::cmp()` (or None if the map is empty).
+        #[inline]
+        pub fn first(&self) -> Option<&(Iv, V)> {
+            self.internal.first_idx().map(|idx| self.internal.node(idx).as_tuple())
+        }
+
+        /// Returns the largest item with respect to `Interval::cmp()` (or None if the map is empty).
+        #[inline]
+        pub fn last(&self) -> Option<&(Iv, V)> {
+            self.internal.last_idx().map(|idx| self.internal.node(idx).as_tuple())
+        }
+
+        /// Deletes the smallest item with respect to `Interval::cmp()` from the tree and returns it
+        /// (or None if the map is empty).
+        #[inline]
+        pub fn pop_first(&mut self) -> Option<(Iv, V)> {
+            self.internal.pop_first()
+        }
+
+        /// Deletes the largest item with respect to `Interval::cmp()` from the tree and returns it
+        /// (or None if the map is empty).
+        #[inline]
+        pub fn pop_last(&mut self) -> Option<(Iv, V)> {
+            self.internal.pop_last()
+        }
+
         /// Deletes all intervals that overlap with `query` from the tree and feeds them into `sink`.
         /// The items are returned in order.
         #[inline]
@@ -517,6 +1678,53 @@ mod interval {
 
         #[inline] pub fn is_empty(&self) -> bool { self.size() == 0 }
 
+        /// Starts maintaining the number of items in every subtree, so that `nth()`, `rank()` and
+        /// `count_range()` run in `O(log n)` rather than `O(n)` time. The counts are kept up to
+        /// date by all modifying operations and are preserved by `clone()` and refills. They take
+        /// an additional `usize` per slot of the internal storage.
+        #[inline] pub fn enable_counts(&mut self) { self.internal.enable_counts() }
+
+        /// Returns the number of slots in the internal storage. Deletions do not shrink it; see
+        /// `compact()`.
+        #[inline] pub fn capacity(&self) -> usize { self.internal.capacity() }
+
+        /// Rebuilds the remaining items into an internal storage just large enough to hold them and
+        /// releases the old one. This reclaims the memory left behind by heavy deletion and
+        /// restores the logarithmic height of the tree. Takes `O(n)` time.
+        #[inline] pub fn compact(&mut self) { self.internal.compact() }
+
+        /// Sets the policy for automatic compaction: once a deletion leaves fewer than
+        /// `ratio * capacity()` items, the tree is compacted (see `compact()`). None (the default)
+        /// disables it. Panics unless `0 < ratio < 1`.
+        #[inline] pub fn set_auto_compact(&mut self, ratio: Option<f64>) { self.internal.set_auto_compact(ratio) }
+
+        /// Returns the ratio set with `set_auto_compact()`.
+        #[inline] pub fn auto_compact(&self) -> Option<f64> { self.internal.auto_compact() }
+
+        /// Returns the `k`-th smallest interval (counting from 0) with respect to
+        /// `Interval::cmp()`, or None if `k >= size()`.
+        #[inline]
+        pub fn nth(&self, k: usize) -> Option<&(Iv, V)> {
+            self.internal.select(k).map(|idx| self.internal.node(idx).as_tuple())
+        }
+
+        /// Returns the number of intervals less than `query` with respect to `Interval::cmp()`.
+        #[inline]
+        pub fn rank<Q>(&self, query: &Q) -> usize
+            where Q: PartialOrd<Iv>
+        {
+            self.internal.rank(query)
+        }
+
+        /// Returns the number of intervals that lie inside `range` with respect to
+        /// `Interval::cmp()` (note that this is not the same as overlapping with it).
+        #[inline]
+        pub fn count_range<Q, R>(&self, range: R) -> usize
+            where Q: PartialOrd<Iv>+Clone, R: RangeBounds<Q>
+        {
+            self.internal.count_range(&KeyBounds::new(range))
+        }
+
         /// Removes all items from the tree (the items are dropped, but the internal storage is not).
         #[inline] pub fn clear(&mut self) { self.internal.clear(); }
 
@@ -525,6 +1733,43 @@ mod interval {
         pub fn iter<'a>(&'a self) -> IntervalMapIter<'a, Iv, V> {
             IntervalMapIter::new(self.internal.iter())
         }
+
+        /// Creates an iterator into the map that yields the items in no particular order. This is
+        /// cheaper than `iter()`.
+        #[inline]
+        pub fn iter_unordered<'a>(&'a self) -> IntervalMapUnorderedIter<'a, Iv, V> {
+            IntervalMapUnorderedIter::new(self.internal.iter_unordered())
+        }
+
+        /// Creates an iterator into the map that gives mutable access to the values.
+        #[inline]
+        pub fn iter_mut<'a>(&'a mut self) -> IntervalMapIterMut<'a, Iv, V> {
+            IntervalMapIterMut::new(self.internal.iter_mut())
+        }
+    }
+
+
+    #[cfg(feature = "serde")]
+    impl<Iv, V> Serialize for IntervalTeardownMap<Iv, V>
+        where Iv: Interval+Serialize, Iv::K: Serialize, V: Serialize
+    {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            (*self.internal).serialize(serializer)
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    impl<'de, Iv, V> Deserialize<'de> for IntervalTeardownMap<Iv, V>
+        where Iv: Interval+Deserialize<'de>, Iv::K: Deserialize<'de>, V: Deserialize<'de>
+    {
+        fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            // maxb is part of the serialized nodes, but a wrong value would silently break the
+            // queries, so it is recomputed rather than trusted
+            let repr = ::base::TreeRepr::deserialize(deserializer)?;
+            let mut internal = IvTree::with_repr(repr);
+            internal.update_all_maxb();
+            Ok(IntervalTeardownMap { internal: internal })
+        }
     }
 
 
@@ -554,11 +1799,68 @@ mod interval {
         }
     }
 
+    #[cfg(feature = "rayon")]
+    impl<Iv: Interval+Send, V: Send> IntervalTeardownMap<Iv, V> where Iv::K: Send {
+        /// Same as `new()`, but the items are sorted and the tree is built in parallel. It is kept
+        /// apart from `new()` because of the `Send` bounds, see `TeardownMap::par_new()`.
+        #[inline]
+        pub fn par_new(items: Vec<(Iv, V)>) -> IntervalTeardownMap<Iv, V> {
+            IntervalTeardownMap { internal: IvTree::par_new(items) }
+        }
+
+        /// Same as `with_sorted()`, but the tree is built in parallel.
+        /// **Note**: the items are assumed to be sorted with respect to `Interval::cmp()`!
+        #[inline]
+        pub fn par_with_sorted(sorted: Vec<(Iv, V)>) -> IntervalTeardownMap<Iv, V> {
+            IntervalTeardownMap { internal: IvTree::par_with_sorted(sorted) }
+        }
+    }
+
     impl<Iv: Interval+Copy, V: Copy> Refill for IntervalTeardownMap<Iv, V> {
         #[inline]
         fn refill(&mut self, master: &Self) {
             self.internal.refill(&master.internal)
         }
+
+        #[inline]
+        fn refill_incremental(&mut self, master: &Self) {
+            self.internal.refill_incremental(&master.internal)
+        }
+    }
+
+    impl<Iv: Interval+Pod, V: Pod> IntervalTeardownMap<Iv, V> where Iv::K: Pod {
+        /// Constructs a map from a binary snapshot held in memory (e.g. the contents of a file or a
+        /// memory-mapped buffer). See the `snapshot` module for the format.
+        pub fn from_snapshot(bytes: &[u8]) -> Result<Self, SnapshotError> {
+            ::base::TreeRepr::from_snapshot(bytes).map(|repr| IntervalTeardownMap { internal: IvTree::with_repr(repr) })
+        }
+
+        /// Writes a binary snapshot of the map to `w`.
+        #[inline]
+        pub fn write_snapshot<W: Write>(&self, w: &mut W) -> io::Result<()> {
+            self.internal.write_snapshot(w)
+        }
+
+        /// Refills the map from a snapshot held in memory, copying the items straight into the
+        /// storage without building a master copy. On error, the map is left unchanged.
+        #[inline]
+        pub fn refill_from_snapshot(&mut self, bytes: &[u8]) -> Result<(), SnapshotError> {
+            self.internal.refill_from_snapshot(bytes)
+        }
+
+        /// Refills the map from a snapshot read from `r`. If the snapshot turns out to be invalid
+        /// after the items have started loading, the map is left empty.
+        #[inline]
+        pub fn refill_from_reader<R: Read>(&mut self, r: &mut R) -> Result<(), SnapshotError> {
+            self.internal.refill_from_reader(r)
+        }
+    }
+
+    impl<Iv: Interval, V: Clone> RefillClone for IntervalTeardownMap<Iv, V> {
+        #[inline]
+        fn refill_clone(&mut self, master: &Self) {
+            self.internal.refill_clone(&master.internal)
+        }
     }
 
 
@@ -603,6 +1905,36 @@ mod interval {
             self.map.query_overlap(query, RefSinkAdapter::new(sink))
         }
 
+        /// Returns a lazy in-order iterator over the intervals that overlap with `query`.
+        #[inline]
+        pub fn overlapping<'a, Q>(&'a self, query: &'a Q) -> IntervalSetOverlapIter<'a, Iv, Q>
+            where Q: Interval<K=Iv::K>
+        {
+            IntervalSetOverlapIter::new(self.map.internal.overlapping(query))
+        }
+
+        /// Returns true if any interval overlaps with `query`. Stops at the first hit.
+        #[inline]
+        pub fn any_overlap<Q>(&self, query: &Q) -> bool
+            where Q: Interval<K=Iv::K>
+        {
+            self.map.any_overlap(query)
+        }
+
+        /// Returns the first (smallest) interval that overlaps with `query`, or None.
+        #[inline]
+        pub fn first_overlap<'a, Q>(&'a self, query: &'a Q) -> Option<&'a Iv>
+            where Q: Interval<K=Iv::K>
+        {
+            self.map.first_overlap(query).map(|entry| &entry.0)
+        }
+
+        /// Inserts the interval into the tree. Duplicates are supported.
+        #[inline]
+        pub fn insert(&mut self, item: Iv) {
+            self.map.insert(item, ())
+        }
+
         /// Deletes the given interval from the tree and returns true (or false if it was not found).
         #[inline]
         pub fn delete<Q>(&mut self, query: &Q) -> bool
@@ -611,6 +1943,34 @@ mod interval {
             self.map.delete(query).is_some()
         }
 
+        /// Returns the smallest interval with respect to `Interval::cmp()` (or None if the set is
+        /// empty).
+        #[inline]
+        pub fn first(&self) -> Option<&Iv> {
+            self.map.first().map(|item| &item.0)
+        }
+
+        /// Returns the largest interval with respect to `Interval::cmp()` (or None if the set is
+        /// empty).
+        #[inline]
+        pub fn last(&self) -> Option<&Iv> {
+            self.map.last().map(|item| &item.0)
+        }
+
+        /// Deletes the smallest interval with respect to `Interval::cmp()` from the tree and
+        /// returns it (or None if the set is empty).
+        #[inline]
+        pub fn pop_first(&mut self) -> Option<Iv> {
+            self.map.pop_first().map(|item| item.0)
+        }
+
+        /// Deletes the largest interval with respect to `Interval::cmp()` from the tree and returns
+        /// it (or None if the set is empty).
+        #[inline]
+        pub fn pop_last(&mut self) -> Option<Iv> {
+            self.map.pop_last().map(|item| item.0)
+        }
+
         /// Deletes all intervals that overlap with `query` from the tree and and feeds them into
         /// `sink`. The items are returned in order.
         #[inline]
@@ -639,6 +1999,44 @@ mod interval {
 
         #[inline] pub fn is_empty(&self) -> bool { self.map.is_empty() }
 
+        /// Starts maintaining the number of items in every subtree, so that `nth()`, `rank()` and
+        /// `count_range()` run in `O(log n)` rather than `O(n)` time.
+        #[inline] pub fn enable_counts(&mut self) { self.map.enable_counts() }
+
+        /// Returns the number of slots in the internal storage. See `compact()`.
+        #[inline] pub fn capacity(&self) -> usize { self.map.capacity() }
+
+        /// Rebuilds the remaining items into an internal storage just large enough to hold them.
+        /// See `TeardownMap::compact()`.
+        #[inline] pub fn compact(&mut self) { self.map.compact() }
+
+        /// Sets the policy for automatic compaction. See `TeardownMap::set_auto_compact()`.
+        #[inline] pub fn set_auto_compact(&mut self, ratio: Option<f64>) { self.map.set_auto_compact(ratio) }
+
+        #[inline] pub fn auto_compact(&self) -> Option<f64> { self.map.auto_compact() }
+
+        /// Returns the `k`-th smallest interval (counting from 0) with respect to
+        /// `Interval::cmp()`, or None if `k >= size()`.
+        #[inline]
+        pub fn nth(&self, k: usize) -> Option<&Iv> {
+            self.map.nth(k).map(|item| &item.0)
+        }
+
+        /// Returns the number of intervals less than `query` with respect to `Interval::cmp()`.
+        #[inline]
+        pub fn rank<Q: PartialOrd<Iv>>(&self, query: &Q) -> usize {
+            self.map.rank(query)
+        }
+
+        /// Returns the number of intervals that lie inside `range` with respect to
+        /// `Interval::cmp()`.
+        #[inline]
+        pub fn count_range<Q, R>(&self, range: R) -> usize
+            where Q: PartialOrd<Iv>+Clone, R: RangeBounds<Q>
+        {
+            self.map.count_range(range)
+        }
+
         /// Removes all items from the tree (the items are dropped, but the internal storage is not).
         #[inline] pub fn clear(&mut self) { self.map.clear(); }
 
@@ -647,8 +2045,34 @@ mod interval {
         pub fn iter<'a>(&'a self) -> IntervalSetIter<'a, Iv> {
             IntervalSetIter::new(self.map.internal.iter())
         }
+
+        /// Creates an iterator into the set that yields the items in no particular order. This is
+        /// cheaper than `iter()`.
+        #[inline]
+        pub fn iter_unordered<'a>(&'a self) -> IntervalSetUnorderedIter<'a, Iv> {
+            IntervalSetUnorderedIter::new(self.map.internal.iter_unordered())
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    impl<Iv> Serialize for IntervalTeardownSet<Iv>
+        where Iv: Interval+Serialize, Iv::K: Serialize
+    {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            self.map.serialize(serializer)
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    impl<'de, Iv> Deserialize<'de> for IntervalTeardownSet<Iv>
+        where Iv: Interval+Deserialize<'de>, Iv::K: Deserialize<'de>
+    {
+        fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            IntervalTeardownMap::deserialize(deserializer).map(|map| IntervalTeardownSet { map: map })
+        }
     }
 
+
     #[cfg(test)]
     impl<Iv: Interval> super::TreeWrapperAccess for IntervalTeardownSet<Iv> {
         type Repr = TreeRepr<IvNode<Iv, ()>>;
@@ -675,10 +2099,67 @@ mod interval {
         }
     }
 
+    #[cfg(feature = "rayon")]
+    impl<Iv: Interval+Send> IntervalTeardownSet<Iv> where Iv::K: Send {
+        /// Same as `new()`, but the items are sorted and the tree is built in parallel. It is kept
+        /// apart from `new()` because of the `Send` bound, see `TeardownMap::par_new()`.
+        #[inline]
+        pub fn par_new(items: Vec<Iv>) -> IntervalTeardownSet<Iv> {
+            let map_items = super::conv_to_tuple_vec(items);
+            IntervalTeardownSet { map: IntervalTeardownMap::par_new(map_items) }
+        }
+
+        /// Same as `with_sorted()`, but the tree is built in parallel.
+        /// **Note**: the items are assumed to be sorted!
+        #[inline]
+        pub fn par_with_sorted(sorted: Vec<Iv>) -> IntervalTeardownSet<Iv> {
+            let map_items = super::conv_to_tuple_vec(sorted);
+            IntervalTeardownSet { map: IntervalTeardownMap::par_with_sorted(map_items) }
+        }
+    }
+
     impl<Iv: Interval+Copy> Refill for IntervalTeardownSet<Iv> {
         #[inline] fn refill(&mut self, master: &Self) {
             self.map.refill(&master.map)
         }
+
+        #[inline] fn refill_incremental(&mut self, master: &Self) {
+            self.map.refill_incremental(&master.map)
+        }
+    }
+
+    impl<Iv: Interval+Pod> IntervalTeardownSet<Iv> where Iv::K: Pod {
+        /// Constructs a set from a binary snapshot held in memory (e.g. the contents of a file or a
+        /// memory-mapped buffer). See the `snapshot` module for the format.
+        pub fn from_snapshot(bytes: &[u8]) -> Result<Self, SnapshotError> {
+            IntervalTeardownMap::from_snapshot(bytes).map(|map| IntervalTeardownSet { map: map })
+        }
+
+        /// Writes a binary snapshot of the set to `w`.
+        #[inline]
+        pub fn write_snapshot<W: Write>(&self, w: &mut W) -> io::Result<()> {
+            self.map.write_snapshot(w)
+        }
+
+        /// Refills the set from a snapshot held in memory, copying the items straight into the
+        /// storage without building a master copy. On error, the set is left unchanged.
+        #[inline]
+        pub fn refill_from_snapshot(&mut self, bytes: &[u8]) -> Result<(), SnapshotError> {
+            self.map.refill_from_snapshot(bytes)
+        }
+
+        /// Refills the set from a snapshot read from `r`. If the snapshot turns out to be invalid
+        /// after the items have started loading, the set is left empty.
+        #[inline]
+        pub fn refill_from_reader<R: Read>(&mut self, r: &mut R) -> Result<(), SnapshotError> {
+            self.map.refill_from_reader(r)
+        }
+    }
+
+    impl<Iv: Interval> RefillClone for IntervalTeardownSet<Iv> {
+        #[inline] fn refill_clone(&mut self, master: &Self) {
+            self.map.refill_clone(&master.map)
+        }
     }
 
 
@@ -727,6 +2208,46 @@ mod interval {
     impl<'a, Iv: Interval+'a, V: 'a> ExactSizeIterator for IntervalMapIter<'a, Iv, V> {}
 
 
+    #[derive(new)]
+    pub struct IntervalMapUnorderedIter<'a, Iv: Interval+'a, V: 'a> {
+        inner: ::base::UnorderedIter<'a, IvNode<Iv, V>>
+    }
+
+    impl<'a, Iv: Interval+'a, V: 'a> Iterator for IntervalMapUnorderedIter<'a, Iv, V> {
+        type Item = &'a (Iv, V);
+
+        fn next(&mut self) -> Option<Self::Item> {
+            self.inner.next().map(|entry| entry.as_tuple())
+        }
+
+        fn size_hint(&self) -> (usize, Option<usize>) {
+            self.inner.size_hint()
+        }
+    }
+
+    impl<'a, Iv: Interval+'a, V: 'a> ExactSizeIterator for IntervalMapUnorderedIter<'a, Iv, V> {}
+
+
+    #[derive(new)]
+    pub struct IntervalMapIterMut<'a, Iv: Interval+'a, V: 'a> {
+        inner: ::base::IterMut<'a, IvNode<Iv, V>>
+    }
+
+    impl<'a, Iv: Interval+'a, V: 'a> Iterator for IntervalMapIterMut<'a, Iv, V> {
+        type Item = (&'a Iv, &'a mut V);
+
+        fn next(&mut self) -> Option<Self::Item> {
+            self.inner.next()
+        }
+
+        fn size_hint(&self) -> (usize, Option<usize>) {
+            self.inner.size_hint()
+        }
+    }
+
+    impl<'a, Iv: Interval+'a, V: 'a> ExactSizeIterator for IntervalMapIterMut<'a, Iv, V> {}
+
+
     #[derive(new)]
     pub struct IntervalSetIter<'a, Iv: Interval+'a> {
         inner: ::base::Iter<'a, IvNode<Iv, ()>>
@@ -747,6 +2268,54 @@ mod interval {
     impl<'a, Iv: Interval+'a> ExactSizeIterator for IntervalSetIter<'a, Iv> {}
 
 
+    #[derive(new)]
+    pub struct IntervalSetUnorderedIter<'a, Iv: Interval+'a> {
+        inner: ::base::UnorderedIter<'a, IvNode<Iv, ()>>
+    }
+
+    impl<'a, Iv: Interval+'a> Iterator for IntervalSetUnorderedIter<'a, Iv> {
+        type Item = &'a Iv;
+
+        fn next(&mut self) -> Option<Self::Item> {
+            self.inner.next().map(|entry| entry.key())
+        }
+
+        fn size_hint(&self) -> (usize, Option<usize>) {
+            self.inner.size_hint()
+        }
+    }
+
+    impl<'a, Iv: Interval+'a> ExactSizeIterator for IntervalSetUnorderedIter<'a, Iv> {}
+
+
+    #[derive(new)]
+    pub struct IntervalMapOverlapIter<'a, Iv: Interval+'a, V: 'a, Q: 'a> {
+        inner: OverlapIter<'a, Iv, V, Q>
+    }
+
+    impl<'a, Iv: Interval+'a, V: 'a, Q: Interval<K=Iv::K>+'a> Iterator for IntervalMapOverlapIter<'a, Iv, V, Q> {
+        type Item = &'a (Iv, V);
+
+        fn next(&mut self) -> Option<Self::Item> {
+            self.inner.next()
+        }
+    }
+
+
+    #[derive(new)]
+    pub struct IntervalSetOverlapIter<'a, Iv: Interval+'a, Q: 'a> {
+        inner: OverlapIter<'a, Iv, (), Q>
+    }
+
+    impl<'a, Iv: Interval+'a, Q: Interval<K=Iv::K>+'a> Iterator for IntervalSetOverlapIter<'a, Iv, Q> {
+        type Item = &'a Iv;
+
+        fn next(&mut self) -> Option<Self::Item> {
+            self.inner.next().map(|entry| &entry.0)
+        }
+    }
+
+
     impl<Iv: Interval, V> IntoIterator for IntervalTeardownMap<Iv, V> {
         type Item = (Iv, V);
         type IntoIter = IntervalMapIntoIter<Iv, V>;
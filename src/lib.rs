@@ -9,19 +9,33 @@
 
 extern crate rand;
 #[macro_use] extern crate derive_new;
+#[cfg(feature = "serde")] extern crate serde;
+#[cfg(feature = "mmap")] extern crate memmap2;
+#[cfg(feature = "rayon")] extern crate rayon;
+#[cfg(all(test, feature = "serde"))] extern crate serde_json;
 
 mod base;
 mod applied;
 mod external_api;
+mod pool;
+mod block_set;
+mod split_map;
+#[cfg(feature = "serde")] mod serde_impl;
 
 mod rust_bench;
 
 pub use self::external_api::{IntervalTeardownMap, IntervalTeardownSet, Interval, KeyInterval,
-                             TeardownMap, TeardownSet, Refill,
+                             TeardownMap, TeardownSet, Refill, RefillClone,
                              iter};
+#[cfg(feature = "mmap")] pub use self::external_api::{MappedTeardownMap, MappedTeardownSet};
+pub use self::pool::{TeardownPool, PoolGuard};
+pub use self::block_set::{BlockTeardownSet, BlockKey};
+pub use self::split_map::SplitTeardownMap;
 pub use self::base::{ItemFilter, NoopFilter, Sink};
 pub use self::base::sink;
+pub use self::base::snapshot::{self, SnapshotError, Pod};
 pub use self::base::util;
+pub use self::base::layout::{Layout, Bfs, Veb};
 
 
 
@@ -30,14 +44,17 @@ mod test_delete_plain {
     use base::sink::UncheckedVecRefSink;
     use base::{ItemFilter, NoopFilter};
     use base::util::make_teardown_seq;
-    use base::validation::{check_bst_del_range, check_integrity_del_range};
+    use base::validation::{check_bst, check_integrity, check_bst_del_range, check_integrity_del_range};
     use applied::plain_tree::{PlTree, PlNode};
-    use external_api::{TeardownSet, TreeWrapperAccess};
-    use super::common::{conv_from_tuple_vec, check_tree, test_exhaustive_items, exhaustive_range_check, mk_prebuilt};
+    use external_api::{TeardownSet, TeardownMap, TreeWrapperAccess, Refill, RefillClone};
+    use super::common::{conv_from_tuple_vec, check_tree, test_exhaustive_items, exhaustive_range_check, mk_prebuilt,
+                        mk_bounds, bounds_contain};
 
     use rand::{Rng, XorShiftRng, SeedableRng};
+    use std::cmp;
     use std::fmt::Debug;
-    use std::ops::Range;
+    use std::ops::{Range, Bound};
+    use std::ops::Bound::{Included, Excluded};
 
     type Nd = PlNode<usize, ()>;
     type Tree = PlTree<usize, ()>;
@@ -199,6 +216,328 @@ mod test_delete_plain {
         }
     }
 
+    #[test]
+    fn delete_range_bounds_exhaustive() {
+        for n in 1..6 {
+            test_exhaustive_n(n, &|tree| delete_range_bounds_exhaustive_with_tree(tree));
+        }
+    }
+
+    fn delete_range_bounds_exhaustive_with_tree(tree: Tree) {
+        let set = TeardownSet::from_internal(tree);
+        let n = set.size();
+        let mut output = Vec::with_capacity(n);
+        for bounds in mk_bounds(n+2) {
+            let mut set_mod = set.clone();
+            output.truncate(0);
+            set_mod.delete_range(bounds, UncheckedVecRefSink::new(&mut output));
+
+            let (expected, rest): (Vec<_>, Vec<_>) = (1..n+1).partition(|x| bounds_contain(&bounds, x));
+            assert_eq!(output, expected, "bounds={:?}, tree={:?}", bounds, set);
+            assert_eq!(set_mod.iter().cloned().collect::<Vec<_>>(), rest, "bounds={:?}, tree={:?}", bounds, set);
+            assert!(check_bst(set_mod.internal(), 0).is_ok() && check_integrity(set_mod.internal()).is_ok());
+        }
+    }
+
+    #[test]
+    fn drain_range_exhaustive() {
+        for n in 1..6 {
+            test_exhaustive_n(n, &|tree| drain_range_exhaustive_with_tree(tree));
+        }
+    }
+
+    fn drain_range_exhaustive_with_tree(tree: Tree) {
+        let set = TeardownSet::from_internal(tree);
+        let n = set.size();
+        for bounds in mk_bounds(n+2) {
+            let expected: Vec<_> = (1..n+1).filter(|x| bounds_contain(&bounds, x)).collect();
+            for m in 0..expected.len()+1 {
+                check_drain(&set, bounds, m, &expected);
+            }
+        }
+    }
+
+    #[test]
+    fn drain_range_large() {
+        let set = TeardownSet::new((0..2000).collect());
+        let expected: Vec<_> = (300..1700).collect();
+        for &m in [0, 1, 15, 16, 17, 100, 1000, 1399, 1400].iter() {
+            check_drain(&set, (Included(300), Excluded(1700)), m, &expected);
+        }
+    }
+
+    #[test]
+    fn drain_range_duplicates() {
+        let mut items = vec![];
+        for i in 0..100 {
+            items.push((i % 10, i));
+        }
+        let mut map = TeardownMap::new(items);
+        let drained: Vec<_> = map.drain_range(3..5).take(15).collect();
+        let expected: Vec<_> = (0..10).map(|i| (3, 3 + i*10)).chain((0..5).map(|i| (4, 4 + i*10))).collect();
+        assert_eq!(drained, expected);
+
+        let fours: Vec<_> = map.iter().filter(|&&(k, _)| k == 4).map(|&(_, v)| v).collect();
+        assert_eq!(fours, (5..10).map(|i| 4 + i*10).collect::<Vec<_>>());
+        assert_eq!(map.size(), 85);
+    }
+
+    #[test]
+    fn empty_half_open_range() {
+        let mut set = TeardownSet::new((0..10).collect());
+        set.enable_counts();
+
+        assert_eq!(set.count_range(5..5), 0);
+        assert_eq!(set.range(5..5).count(), 0);
+        assert_eq!(set.drain_range(5..5).count(), 0);
+        assert_eq!(set.size(), 10);
+        assert_eq!(set.count_range(5..6), 1);
+
+        // the legacy entry points treat `a..a` as the point `a`
+        let mut refs = vec![];
+        set.query_range(5..5, &mut refs);
+        assert_eq!(refs, vec![&5]);
+
+        let mut output = vec![];
+        set.delete_range(5..5, &mut output);
+        assert_eq!(output, vec![5]);
+        assert_eq!(set.size(), 9);
+    }
+
+    #[test]
+    fn delete_first_last_exhaustive() {
+        for n in 1..7 {
+            test_exhaustive_n(n, &|tree| delete_first_last_exhaustive_with_tree(tree));
+        }
+    }
+
+    fn delete_first_last_exhaustive_with_tree(tree: Tree) {
+        let plain = TeardownSet::from_internal(tree);
+        let mut counted = plain.clone();
+        counted.enable_counts();
+        let n = plain.size();
+
+        for set in [plain, counted].iter() {
+            for m in 0..n+2 {
+                let k = cmp::min(m, n);
+
+                let mut set_mod = set.clone();
+                let mut output = vec![];
+                set_mod.delete_first(m, &mut output);
+                assert_eq!(output, (1..k+1).collect::<Vec<_>>(), "m={}, tree={:?}", m, set);
+                assert_eq!(set_mod.iter().cloned().collect::<Vec<_>>(), (k+1..n+1).collect::<Vec<_>>());
+                assert_eq!(set_mod.nth(0).cloned(), if k < n { Some(k+1) } else { None });
+                assert!(check_bst(set_mod.internal(), 0).is_ok() && check_integrity(set_mod.internal()).is_ok());
+
+                let mut set_mod = set.clone();
+                let mut output = vec![];
+                set_mod.delete_last(m, &mut output);
+                assert_eq!(output, (n-k+1..n+1).collect::<Vec<_>>(), "m={}, tree={:?}", m, set);
+                assert_eq!(set_mod.iter().cloned().collect::<Vec<_>>(), (1..n-k+1).collect::<Vec<_>>());
+                assert_eq!(set_mod.count_range::<usize, _>(..), n-k);
+                assert!(check_bst(set_mod.internal(), 0).is_ok() && check_integrity(set_mod.internal()).is_ok());
+            }
+        }
+    }
+
+    #[test]
+    fn delete_first_last_duplicates() {
+        let mut items = vec![];
+        for i in 0..100 {
+            items.push((i % 10, i));
+        }
+        let sorted = {
+            let map = TeardownMap::new(items);
+            map.iter().cloned().collect::<Vec<_>>()
+        };
+
+        for m in 0..101 {
+            let mut map = TeardownMap::new(sorted.clone());
+            let mut output = vec![];
+            map.delete_first(m, &mut output);
+            assert_eq!(&output[..], &sorted[..m]);
+            assert_eq!(map.iter().cloned().collect::<Vec<_>>(), &sorted[m..]);
+
+            let mut map = TeardownMap::new(sorted.clone());
+            let mut output = vec![];
+            map.delete_last(m, &mut output);
+            assert_eq!(&output[..], &sorted[100-m..]);
+            assert_eq!(map.iter().cloned().collect::<Vec<_>>(), &sorted[..100-m]);
+        }
+    }
+
+    #[test]
+    fn cursor_remove_exhaustive() {
+        for n in 1..7 {
+            test_exhaustive_n(n, &|tree| cursor_remove_exhaustive_with_tree(tree));
+        }
+    }
+
+    fn cursor_remove_exhaustive_with_tree(tree: Tree) {
+        let n = tree.size();
+        let map = TeardownMap::from_internal(tree);
+        // every subset of the items is removed while walking the tree with a cursor
+        for subset in 0..1usize << n {
+            let mut map_mod = map.clone();
+            let mut kept = vec![];
+            {
+                let mut cursor = map_mod.cursor_mut_at(&0);
+                for i in 1..n+1 {
+                    assert_eq!(cursor.key(), Some(&i), "subset={}, tree={:?}", subset, map);
+                    if subset & (1 << (i-1)) != 0 {
+                        assert_eq!(cursor.remove_current(), Some((i, ())));
+                    } else {
+                        kept.push(i);
+                        cursor.next();
+                    }
+                    assert_eq!(cursor.peek_prev().map(|item| item.0), kept.last().cloned());
+                }
+                assert_eq!(cursor.remove_current(), None);
+            }
+
+            assert_eq!(map_mod.iter().map(|item| item.0).collect::<Vec<_>>(), kept);
+            assert!(check_bst(map_mod.internal(), 0).is_ok() && check_integrity(map_mod.internal()).is_ok());
+        }
+    }
+
+    #[test]
+    fn pop_first_last_exhaustive() {
+        for n in 1..7 {
+            test_exhaustive_n(n, &|tree| pop_first_last_exhaustive_with_tree(tree));
+        }
+    }
+
+    fn pop_first_last_exhaustive_with_tree(tree: Tree) {
+        let set = TeardownSet::from_internal(tree);
+        let n = set.size();
+        for nfirst in 0..n+1 {
+            let mut set_mod = set.clone();
+            let (mut lo, mut hi) = (1, n);
+            for i in 0..n {
+                assert_eq!((set_mod.first().cloned(), set_mod.last().cloned()), (Some(lo), Some(hi)));
+                if i < nfirst {
+                    assert_eq!(set_mod.pop_first(), Some(lo));
+                    lo += 1;
+                } else {
+                    assert_eq!(set_mod.pop_last(), Some(hi));
+                    hi -= 1;
+                }
+                assert!(check_bst(set_mod.internal(), 0).is_ok() && check_integrity(set_mod.internal()).is_ok());
+                assert_eq!(set_mod.iter().cloned().collect::<Vec<_>>(), (lo..hi+1).collect::<Vec<_>>());
+            }
+
+            assert_eq!((set_mod.first(), set_mod.last()), (None, None));
+            assert_eq!((set_mod.pop_first(), set_mod.pop_last()), (None, None));
+        }
+    }
+
+    #[test]
+    fn refill_clone_strings() {
+        let master = TeardownMap::new((0..100).map(|i| (format!("{:03}", i), i.to_string())).collect());
+        let mut copy = master.clone();
+        for _ in 0..3 {
+            copy.delete_range("010".to_string().."090".to_string(), Vec::new());
+            copy.insert("zzz".to_string(), "late".to_string());
+            assert_eq!(copy.size(), 21);
+
+            copy.refill_clone(&master);
+            assert_eq!(copy.iter().collect::<Vec<_>>(), master.iter().collect::<Vec<_>>());
+        }
+
+        for i in 0..1000 {
+            copy.insert(format!("x{}", i), String::new());
+        }
+        copy.refill_clone(&master);
+        assert_eq!(copy.iter().collect::<Vec<_>>(), master.iter().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn refill_clone_drops_items() {
+        use std::rc::Rc;
+
+        let counter = Rc::new(());
+        let master = TeardownMap::new((0..50).map(|i| (i, counter.clone())).collect());
+        let mut copy = master.clone();
+        assert_eq!(Rc::strong_count(&counter), 101);
+
+        copy.delete_range(10..20, Vec::new());
+        copy.refill_clone(&master);
+        assert_eq!(Rc::strong_count(&counter), 101);
+
+        drop(copy);
+        drop(master);
+        assert_eq!(Rc::strong_count(&counter), 1);
+    }
+
+    #[test]
+    fn refill_incremental() {
+        let n = 5000;
+        let master = TeardownSet::new((0..n).collect());
+        let mut copy = master.clone();
+        let mut rng = XorShiftRng::from_seed([7, 3, 5, 11]);
+        for i in 0..50 {
+            for _ in 0..i % 5 + 1 {
+                let from = rng.gen_range(0, n);
+                match rng.gen_range(0, 3) {
+                    0 => { copy.delete_range(from..from + rng.gen_range(0, 100), Vec::new()); },
+                    1 => { copy.delete(&from); },
+                    _ => { copy.insert(from); },
+                }
+            }
+
+            copy.refill_incremental(&master);
+            assert!(check_integrity(copy.internal()).is_ok());
+            assert!(copy.iter().eq(master.iter()), "i={}", i);
+        }
+
+        // after growing the storage, a full refill is performed
+        for i in 0..2*n {
+            copy.insert(i);
+        }
+        copy.refill_incremental(&master);
+        assert!(copy.iter().eq(master.iter()));
+    }
+
+    #[test]
+    fn refill_incremental_from_other_master() {
+        let n = 1000;
+        let mut master = TeardownSet::new((0..n).collect());
+        let other = TeardownSet::new((n..2*n).collect());
+
+        // the copy does not come from `other`, so its dirty flags say nothing about it
+        let mut copy = master.clone();
+        copy.delete_range(0..10, Vec::new());
+        copy.refill_incremental(&other);
+        assert!(copy.iter().eq(other.iter()));
+        assert_eq!(copy.size(), other.size());
+
+        // nor do they after the master has been modified
+        let mut copy = master.clone();
+        master.delete_range(500..600, Vec::new());
+        copy.delete(&0);
+        copy.refill_incremental(&master);
+        assert!(copy.iter().eq(master.iter()));
+
+        // refilling from the same master again is incremental, and still correct
+        copy.delete_range(0..n, Vec::new());
+        copy.refill_incremental(&master);
+        assert!(copy.iter().eq(master.iter()));
+    }
+
+    fn check_drain(set: &TeardownSet<usize>, bounds: (Bound<usize>, Bound<usize>), m: usize, expected: &[usize]) {
+        let mut set_mod = set.clone();
+        let drained: Vec<_> = set_mod.drain_range(bounds).take(m).collect();
+        assert_eq!(&drained[..], &expected[..m], "bounds={:?}, m={}, tree={:?}", bounds, m, set);
+
+        let mut rest: Vec<_> = set.iter().cloned().collect();
+        for x in drained.iter() {
+            let pos = rest.iter().position(|y| y == x).unwrap();
+            rest.remove(pos);
+        }
+        assert_eq!(set_mod.iter().cloned().collect::<Vec<_>>(), rest, "bounds={:?}, m={}, tree={:?}", bounds, m, set);
+        assert!(check_bst(set_mod.internal(), 0).is_ok() && check_integrity(set_mod.internal()).is_ok());
+    }
+
     fn delete_range_exhaustive_with_tree(tree: Tree) {
         let n = tree.size();
         let mut output = Vec::with_capacity(n);
@@ -335,15 +674,19 @@ mod test_delete_plain {
 
 #[cfg(test)]
 mod test_query_plain {
+    use std::cmp;
+    use std::rc::Rc;
     use std::ops::Range;
+    use std::ops::Bound::{Included, Excluded, Unbounded};
 
     use applied::interval::{KeyInterval, Interval};
     use applied::plain_tree::{PlTree, PlNode};
-    use external_api::{TeardownSet, TeardownMap, TreeWrapperAccess};
+    use external_api::{TeardownSet, TeardownMap, TreeWrapperAccess, Refill};
     use base::{TreeRepr, Traverse};
     use base::sink::{RefCopyingSink, UncheckedVecRefSink};
     use super::test_delete_plain::test_exhaustive_n;
-    use super::common::{exhaustive_range_check, mk_prebuilt, check_output_sorted, test_exhaustive_items};
+    use super::common::{exhaustive_range_check, mk_prebuilt, check_output_sorted, test_exhaustive_items,
+                        mk_bounds, bounds_contain};
 
     type Nd = PlNode<usize, ()>;
     type Tree = PlTree<usize, ()>;
@@ -361,6 +704,188 @@ mod test_query_plain {
         test_exhaustive_n(n, &|tree| query_range_exhaustive_with_tree(tree));
     }
 
+    #[test]
+    fn query_range_bounds_exhaustive() {
+        for n in 1..6 {
+            test_exhaustive_n(n, &|tree| query_range_bounds_exhaustive_with_tree(tree));
+        }
+    }
+
+    fn query_range_bounds_exhaustive_with_tree(tree: Tree) {
+        let tree = TeardownSet::from_internal(tree);
+        let n = tree.size();
+        let mut output = Vec::with_capacity(n);
+        for bounds in mk_bounds(n+2) {
+            output.truncate(0);
+            {
+                let sink = RefCopyingSink::new(UncheckedVecRefSink::new(&mut output));
+                tree.query_range(bounds, sink);
+            }
+            let expected: Vec<_> = (1..n+1).filter(|x| bounds_contain(&bounds, x)).collect();
+            assert_eq!(output, expected, "bounds={:?}, tree={:?}", bounds, tree);
+        }
+    }
+
+    #[test]
+    fn query_range_duplicates() {
+        let tree = TeardownSet::new(vec![1, 2, 2, 2, 2, 2, 2, 3, 3, 4]);
+        let mut output = Vec::with_capacity(tree.size());
+        tree.query_range(2..=3, RefCopyingSink::new(UncheckedVecRefSink::new(&mut output)));
+        assert_eq!(output, vec![2, 2, 2, 2, 2, 2, 3, 3]);
+    }
+
+    #[test]
+    fn range_strings() {
+        let words = vec!["apple", "banana", "cherry", "date", "elderberry", "fig"];
+        let mut map = TeardownMap::new(words.iter().map(|w| (w.to_string(), w.len())).collect());
+
+        let mut output = Vec::with_capacity(map.size());
+        map.query_range("banana".to_string()..="date".to_string(), UncheckedVecRefSink::new(&mut output));
+        let keys: Vec<_> = output.iter().map(|&&(ref k, _)| k.as_str()).collect();
+        assert_eq!(keys, vec!["banana", "cherry", "date"]);
+
+        let mut deleted = Vec::with_capacity(map.size());
+        map.delete_range(.."cherry".to_string(), UncheckedVecRefSink::new(&mut deleted));
+        assert_eq!(deleted, vec![("apple".to_string(), 5), ("banana".to_string(), 6)]);
+
+        deleted.truncate(0);
+        map.delete_range((Excluded("date".to_string()), Unbounded), UncheckedVecRefSink::new(&mut deleted));
+        assert_eq!(deleted, vec![("elderberry".to_string(), 10), ("fig".to_string(), 3)]);
+
+        deleted.truncate(0);
+        map.delete_range::<String, _, _>(.., UncheckedVecRefSink::new(&mut deleted));
+        assert_eq!(deleted, vec![("cherry".to_string(), 6), ("date".to_string(), 4)]);
+        assert!(map.is_empty());
+    }
+
+    /// Compares against the first component of a pair only.
+    #[derive(Clone, Debug)]
+    struct Major(usize);
+
+    impl PartialEq<(usize, usize)> for Major {
+        fn eq(&self, other: &(usize, usize)) -> bool {
+            self.0 == other.0
+        }
+    }
+
+    impl PartialOrd<(usize, usize)> for Major {
+        fn partial_cmp(&self, other: &(usize, usize)) -> Option<cmp::Ordering> {
+            self.0.partial_cmp(&other.0)
+        }
+    }
+
+    #[test]
+    fn range_query_type() {
+        let items: Vec<_> = (0..5).flat_map(|a| (0..3).map(move |b| (a, b))).collect();
+        let mut set = TeardownSet::new(items);
+
+        let mut output = Vec::with_capacity(set.size());
+        set.query_range(Major(1)..Major(3), RefCopyingSink::new(UncheckedVecRefSink::new(&mut output)));
+        assert_eq!(output, vec![(1, 0), (1, 1), (1, 2), (2, 0), (2, 1), (2, 2)]);
+
+        output.truncate(0);
+        set.delete_range((Excluded(Major(2)), Included(Major(3))), &mut output);
+        assert_eq!(output, vec![(3, 0), (3, 1), (3, 2)]);
+
+        output.truncate(0);
+        set.delete_range(Major(4).., &mut output);
+        assert_eq!(output, vec![(4, 0), (4, 1), (4, 2)]);
+
+        output.truncate(0);
+        set.delete_range(Major(0)..Major(0), &mut output);
+        assert_eq!(output, vec![(0, 0), (0, 1), (0, 2)]);
+
+        let mut map = TeardownMap::new((0..3).flat_map(|a| (0..3).map(move |b| ((a, b), 0))).collect());
+        map.for_each_in_range_mut(Major(1)..=Major(1), |&(a, _), v| *v = a + 10);
+        map.for_each_in_range_mut(Major(2)..Major(2), |_, v| *v = 99);
+        assert!(map.iter().map(|&(_, v)| v).eq(vec![0, 0, 0, 11, 11, 11, 0, 0, 0]));
+
+        assert_eq!(set.count_range(Major(1)..Major(3)), 6);
+        assert_eq!(set.count_range(Major(1)..Major(1)), 0);
+        assert!(set.range(Major(1)..=Major(1)).cloned().eq(vec![(1, 0), (1, 1), (1, 2)]));
+        assert!(set.drain_range(Major(2)..).eq(vec![(2, 0), (2, 1), (2, 2)]));
+        assert_eq!(set.size(), 3);
+    }
+
+    #[test]
+    fn for_each_in_range_mut_exhaustive() {
+        for n in 1..7 {
+            test_exhaustive_n(n, &|tree| for_each_in_range_mut_exhaustive_with_tree(tree));
+        }
+    }
+
+    fn for_each_in_range_mut_exhaustive_with_tree(tree: Tree) {
+        let n = tree.size();
+        let master = TeardownMap::new(tree.iter().map(|entry| (*entry.key(), 0)).collect());
+        for bounds in mk_bounds(n+2) {
+            let mut map = master.clone();
+            let mut visited = vec![];
+            map.for_each_in_range_mut(bounds, |&k, v| {
+                visited.push(k);
+                *v += k;
+            });
+
+            let expected: Vec<_> = (1..n+1).filter(|x| bounds_contain(&bounds, x)).collect();
+            assert_eq!(visited, expected, "bounds={:?}", bounds);
+            for &(k, v) in map.iter() {
+                assert_eq!(v, if bounds_contain(&bounds, &k) { k } else { 0 });
+            }
+        }
+    }
+
+    #[test]
+    fn cursor_exhaustive() {
+        for n in 1..7 {
+            test_exhaustive_n(n, &|tree| cursor_exhaustive_with_tree(tree));
+        }
+    }
+
+    fn cursor_exhaustive_with_tree(tree: Tree) {
+        let n = tree.size();
+        let map = TeardownMap::from_internal(tree);
+        for q in 0..n+2 {
+            let mut cursor = map.cursor_at(&q);
+            let start = cmp::max(q, 1);
+            assert_eq!(cursor.key(), if start <= n { Some(&start) } else { None }, "q={}, tree={:?}", q, map);
+
+            // walk forward through the ghost position and around
+            for i in start..n+1 {
+                assert_eq!(cursor.key(), Some(&i));
+                assert_eq!(cursor.peek_next().map(|item| item.0), if i < n { Some(i+1) } else { None });
+                cursor.next();
+            }
+            assert_eq!(cursor.current(), None);
+            assert_eq!(cursor.peek_prev().map(|item| item.0), Some(n));
+            assert_eq!(cursor.next().map(|item| item.0), Some(1));
+
+            // and back
+            assert_eq!(cursor.prev(), None);
+            for i in (1..n+1).rev() {
+                assert_eq!(cursor.prev().map(|item| item.0), Some(i));
+                assert_eq!(cursor.peek_prev().map(|item| item.0), if i > 1 { Some(i-1) } else { None });
+            }
+        }
+    }
+
+    #[test]
+    fn values_mut() {
+        let master = TeardownMap::new((0..1000).map(|i| (i, i)).collect());
+        let mut map = master.clone();
+
+        *map.get_mut(&500).unwrap() = 0;
+        assert_eq!(map.find(&500), Some(&0));
+        assert_eq!(map.get_mut(&1000), None);
+
+        for (k, v) in map.iter_mut() {
+            *v += k;
+        }
+        assert!(map.iter().all(|&(k, v)| v == if k == 500 { 500 } else { 2*k }));
+
+        // the modified values are restored from the master
+        map.refill_incremental(&master);
+        assert!(map.iter().eq(master.iter()));
+    }
+
     fn query_range_exhaustive_with_tree(tree: Tree) {
         let tree = TeardownSet::from_internal(tree);
         let n = tree.size();
@@ -423,6 +948,77 @@ mod test_query_plain {
         assert!(n == 0);
     }
 
+    #[test]
+    fn double_ended_exhaustive() {
+        for n in 1..7 {
+            test_exhaustive_n(n, &|tree| double_ended_exhaustive_with_tree(tree));
+        }
+    }
+
+    fn double_ended_exhaustive_with_tree(tree: Tree) {
+        let set = TeardownSet::from_internal(tree);
+        let n = set.size();
+        let expected: Vec<_> = (1..n+1).collect();
+        assert_eq!(set.iter().rev().cloned().collect::<Vec<_>>(), expected.iter().rev().cloned().collect::<Vec<_>>());
+        assert_eq!(set.clone().into_iter().rev().collect::<Vec<_>>(), expected.iter().rev().cloned().collect::<Vec<_>>());
+
+        // take `nfront` items from the front, then the rest from the back
+        for nfront in 0..n+1 {
+            let mut iter = set.iter();
+            let front: Vec<_> = iter.by_ref().take(nfront).cloned().collect();
+            let back: Vec<_> = iter.rev().cloned().collect();
+            assert_eq!(front, &expected[..nfront]);
+            assert_eq!(back, expected[nfront..].iter().rev().cloned().collect::<Vec<_>>());
+
+            let mut into_iter = set.clone().into_iter();
+            let front: Vec<_> = into_iter.by_ref().take(nfront).collect();
+            assert_eq!(into_iter.len(), n - nfront);
+            assert_eq!(into_iter.next_back(), if nfront < n { Some(n) } else { None });
+            assert_eq!(front, &expected[..nfront]);
+        }
+
+        for bounds in mk_bounds(n+2) {
+            let in_range: Vec<_> = expected.iter().cloned().filter(|x| bounds_contain(&bounds, x)).collect();
+            assert_eq!(set.range(bounds).cloned().collect::<Vec<_>>(), in_range, "bounds={:?}", bounds);
+            assert_eq!(set.range(bounds).rev().cloned().collect::<Vec<_>>(),
+                       in_range.iter().rev().cloned().collect::<Vec<_>>(), "bounds={:?}", bounds);
+
+            // alternate between the two ends
+            let mut range = set.range(bounds);
+            let mut alternating = vec![];
+            let (mut lo, mut hi) = (0, in_range.len());
+            for i in 0..in_range.len() {
+                if i % 2 == 0 {
+                    alternating.push(*range.next().unwrap());
+                    lo += 1;
+                } else {
+                    alternating.push(*range.next_back().unwrap());
+                    hi -= 1;
+                }
+            }
+            assert_eq!(lo, hi);
+            assert_eq!((range.next(), range.next_back()), (None, None));
+            let mut sorted = alternating.clone();
+            sorted.sort();
+            assert_eq!(sorted, in_range);
+        }
+    }
+
+    #[test]
+    fn into_iter_drops_rest() {
+        let rc = Rc::new(());
+        let map = TeardownMap::new((0..100).map(|i| (i, rc.clone())).collect());
+        {
+            let mut iter = map.into_iter();
+            let (k, _) = iter.next().unwrap();
+            assert_eq!(k, 0);
+            let (k, _) = iter.next_back().unwrap();
+            assert_eq!(k, 99);
+            assert_eq!(Rc::strong_count(&rc), 99);
+        }
+        assert_eq!(Rc::strong_count(&rc), 1);
+    }
+
 
     //---- exhaustive find -------------------------------------------------------------------------
     #[test]
@@ -504,7 +1100,7 @@ mod test_delete_interval {
     use applied::AppliedTree;
     use applied::interval::{Interval, IvNode, KeyInterval};
     use applied::interval_tree::{IvTree};
-    use external_api::{IntervalTeardownSet, TreeWrapperAccess};
+    use external_api::{IntervalTeardownSet, TreeWrapperAccess, Refill};
     use super::common::{check_tree};
 
     type Iv = KeyInterval<usize>;
@@ -650,7 +1246,7 @@ mod test_delete_interval {
 
     //---- quickcheck helpers ----------------------------------------------------------------------
     use std::borrow::Borrow;
-    fn normalize_range<R: Borrow<Range<usize>>>(r: R) -> Range<usize> {
+    pub fn normalize_range<R: Borrow<Range<usize>>>(r: R) -> Range<usize> {
         let r: &Range<usize> = r.borrow();
         cmp::min(r.start, r.end) .. cmp::max(r.start, r.end)
     }
@@ -716,7 +1312,7 @@ mod test_delete_interval {
     }
 
 
-    fn check_maxb(orig: &Tree, tree: &Tree, idx: usize) -> usize {
+    pub fn check_maxb(orig: &Tree, tree: &Tree, idx: usize) -> usize {
         assert!(!tree.is_nil(idx));
 
         let mut expected_maxb = *tree.node(idx).key().b();
@@ -763,6 +1359,54 @@ mod test_delete_interval {
     }
 
 
+    #[test]
+    fn refill_incremental() {
+        let n = 5000;
+        let items: Vec<_> = (0..n).map(|x| Iv::new(x, x + x*7 % 50)).collect();
+        let master = IntervalTeardownSet::new(items);
+        let mut copy = master.clone();
+        let mut rng = XorShiftRng::from_seed([7, 3, 5, 11]);
+        for i in 0..50 {
+            for _ in 0..i % 5 + 1 {
+                let a = rng.gen_range(0, n);
+                match rng.gen_range(0, 3) {
+                    0 => { copy.delete_overlap(&Iv::new(a, a + rng.gen_range(0, 20)), Vec::new()); },
+                    1 => { copy.delete(&Iv::new(a, a + a*7 % 50)); },
+                    _ => { copy.insert(Iv::new(a, a + rng.gen_range(0, 500))); },
+                }
+            }
+
+            copy.refill_incremental(&master);
+            assert!(copy.iter().eq(master.iter()), "i={}", i);
+            check_maxb(master.internal(), copy.internal(), 0);
+        }
+    }
+
+    #[test]
+    fn pop_first_last() {
+        let mut items: Vec<_> = (0..200).map(|x| Iv::new(x % 37, x % 37 + x*7 % 50)).collect();
+        let mut set = IntervalTeardownSet::new(items.clone());
+        items.sort();
+
+        let mut rng = XorShiftRng::from_seed([2, 7, 1, 8]);
+        while !items.is_empty() {
+            if rng.gen() {
+                assert_eq!(set.first(), items.first());
+                assert_eq!(set.pop_first(), Some(items.remove(0)));
+            } else {
+                assert_eq!(set.last(), items.last());
+                assert_eq!(set.pop_last(), items.pop());
+            }
+
+            if set.size() > 0 {
+                check_maxb(set.internal(), set.internal(), 0);
+            }
+        }
+        assert_eq!(set.first(), None);
+        assert_eq!(set.pop_last(), None);
+    }
+
+
     #[derive(Clone, Debug)]
     struct SetRefFilter<'a> {
         set: &'a IntervalTeardownSet<KeyInterval<usize>>
@@ -872,12 +1516,13 @@ mod test_delete_interval {
 
 #[cfg(test)]
 mod test_query_interval {
-    use applied::interval::{KeyInterval};
+    use applied::interval::{Interval, KeyInterval};
     use applied::interval_tree::{IvTree};
-    use external_api::{IntervalTeardownSet, TreeWrapperAccess};
+    use external_api::{IntervalTeardownSet, IntervalTeardownMap, TreeWrapperAccess};
     use base::sink::{RefCopyingSink, UncheckedVecRefSink};
     use super::common::{exhaustive_range_check, test_exhaustive_items};
 
+    type Iv = KeyInterval<usize>;
     type Tree = IvTree<usize, ()>;
 
 
@@ -906,8 +1551,80 @@ mod test_query_interval {
                     tree_mod.query_overlap(&KeyInterval::new(i, j), sink);
                 }
                 exhaustive_range_check(n, &(i..j), &mut output, tree.internal());
+
+                let query = KeyInterval::new(i, j);
+                let lazy: Vec<_> = tree.overlapping(&query).cloned().collect();
+                assert_eq!(lazy, output);
+                assert_eq!(tree.any_overlap(&query), !output.is_empty());
+                assert_eq!(tree.first_overlap(&query), output.first());
+            }
+        }
+    }
+
+    #[test]
+    fn overlapping_with_holes() {
+        let items: Vec<_> = (0..300).map(|x| (Iv::new(x % 53, x % 53 + x*7 % 20), x)).collect();
+        let mut map = IntervalTeardownMap::new(items);
+        for x in 0..40 {
+            map.delete(&Iv::new(x*3 % 53, x*3 % 53 + x*21 % 20));
+        }
+
+        for a in 0..80 {
+            for &len in [0, 1, 5, 30].iter() {
+                let query = Iv::new(a, a + len);
+                let mut expected = vec![];
+                map.query_overlap(&query, &mut expected);
+
+                let lazy: Vec<_> = map.overlapping(&query).collect();
+                assert_eq!(lazy, expected, "query={:?}", query);
+                assert_eq!(map.any_overlap(&query), !expected.is_empty());
+                assert_eq!(map.first_overlap(&query), expected.first().cloned());
+            }
+        }
+    }
+
+    #[test]
+    fn for_each_overlap_mut() {
+        let items: Vec<_> = (0..300).map(|x| (Iv::new(x % 53, x % 53 + x*7 % 20), 0)).collect();
+        let master = IntervalTeardownMap::new(items);
+
+        for a in 0..80 {
+            for &len in [0, 1, 5, 30].iter() {
+                let query = Iv::new(a, a + len);
+                let mut map = master.clone();
+                let mut visited = vec![];
+                map.for_each_overlap_mut(&query, |iv, v| {
+                    visited.push(*iv);
+                    *v += 1;
+                });
+
+                let expected: Vec<_> = master.iter().map(|&(iv, _)| iv).filter(|iv| iv.overlaps(&query)).collect();
+                assert_eq!(visited, expected, "query={:?}", query);
+                assert!(map.iter().all(|&(iv, v)| v == iv.overlaps(&query) as usize));
             }
         }
+
+        for &(a, b) in [(0, 10), (5, 5), (20, 60), (50, 0)].iter() {
+            let (start, end) = (Iv::new(a, a), Iv::new(b, b));
+            let mut map = master.clone();
+            let mut visited = vec![];
+            map.for_each_in_range_mut(start..end, |iv, v| {
+                visited.push(*iv);
+                *v += 1;
+            });
+
+            let expected: Vec<_> = master.iter().map(|&(iv, _)| iv).filter(|iv| start <= *iv && *iv < end).collect();
+            assert_eq!(visited, expected, "range={:?}", start..end);
+            assert_eq!(map.iter().filter(|&&(_, v)| v == 1).count(), expected.len());
+        }
+
+        let mut map = master.clone();
+        for (_, v) in map.iter_mut() {
+            *v = 7;
+        }
+        *map.get_mut(&Iv::new(3, 4)).unwrap() = 1;
+        assert_eq!(map.find(&Iv::new(3, 4)), Some(&1));
+        assert_eq!(map.iter().filter(|&&(_, v)| v == 7).count(), 299);
     }
 
     pub fn test_exhaustive_n<F>(n: usize, check: &F)
@@ -924,39 +1641,1740 @@ mod test_query_interval {
 
 
 #[cfg(test)]
-mod common {
-    use base::validation::{check_bst_del_range, check_integrity_del_range};
-    use base::{Node, TreeRepr, TreeDeref, Traverse, ItemFilter, lefti, righti};
-    use applied::AppliedTree;
-    use applied::interval::{Interval, KeyInterval};
-    use applied::plain_tree::{PlNode};
+mod test_insert_plain {
+    use base::validation::{check_bst, check_integrity};
+    use base::sink::{RefCopyingSink, UncheckedVecRefSink};
+    use applied::plain_tree::PlTree;
+    use external_api::{TeardownMap, TeardownSet, TreeWrapperAccess, Refill};
+    use super::test_delete_plain::test_exhaustive_n;
 
-    use std::fmt::{Debug, Display};
-    use std::ops::{Range};
-    use std::cmp;
+    use std::ops::Range;
 
-    //---- exhaustive testing ----------------------------------------------------------------------
-    #[derive(Debug)]
-    struct TreeRangeInfo {
-        range: Range<usize>,
-        root_idx: usize
+    type Tree = PlTree<usize, ()>;
+
+
+    #[test]
+    fn insert_into_empty() {
+        let mut set = TeardownSet::new(vec![]);
+        for i in (0..100).rev() {
+            set.insert(i);
+            check_set(&set, &(i..100).collect::<Vec<_>>());
+        }
     }
 
-    pub fn test_exhaustive_items<N: Node, Tree: AppliedTree<N>, F>(items: &mut Vec<Option<(N::K, N::V)>>, check: &F)
-        where N::K: Clone, N::V: Clone,
-              F: Fn(Tree) -> ()
-    {
-        let n = items.len();
-        let mut stack = vec![TreeRangeInfo { range: (0..n), root_idx: 0 }];
-        let mut shape = vec![None; 1 << n];
-        test_exhaustive_rec::<_, Tree, _>(items, &mut shape, &mut stack, check)
+    #[test]
+    fn insert_after_clear() {
+        let mut set = TeardownSet::new((0..20).collect());
+        set.clear();
+        set.insert(5);
+        set.insert(3);
+        check_set(&set, &[3, 5]);
     }
 
-    fn test_exhaustive_rec<N: Node, Tree: AppliedTree<N>, F>(items: &mut Vec<Option<(N::K, N::V)>>, shape: &mut Vec<Option<(N::K, N::V)>>,
-                                                             stack: &mut Vec<TreeRangeInfo>, check: &F)
-        where N::K: Clone, N::V: Clone,
-              F: Fn(Tree) -> ()
-    {
+    #[test]
+    fn insert_duplicates_in_order() {
+        let mut map = TeardownMap::new(vec![(1, 0), (2, 0), (2, 1), (3, 0)]);
+        for i in 2..50 {
+            map.insert(2, i);
+        }
+
+        let vals: Vec<_> = map.iter()
+            .filter(|&&(k, _)| k == 2)
+            .map(|&(_, v)| v)
+            .collect();
+        assert_eq!(vals, (0..50).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn refill_after_growth() {
+        let master = TeardownSet::new(vec![1, 2, 3]);
+        let mut copy = master.clone();
+        for i in 10..100 {
+            copy.insert(i);
+        }
+        copy.refill(&master);
+        check_set(&copy, &[1, 2, 3]);
+
+        copy.insert(0);
+        check_set(&copy, &[0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn insert_exhaustive() {
+        for n in 1..7 {
+            test_exhaustive_n(n, &|tree| insert_exhaustive_with_tree(tree));
+        }
+    }
+
+    fn insert_exhaustive_with_tree(tree: Tree) {
+        let set = TeardownSet::from_internal(tree);
+        let n = set.size();
+        for i in 0..n+2 {
+            let mut set_mod = set.clone();
+            set_mod.insert(i);
+
+            let mut expected: Vec<_> = (1..n+1).collect();
+            expected.push(i);
+            expected.sort();
+            check_set(&set_mod, &expected);
+        }
+    }
+
+    quickcheck! {
+        fn quickcheck_insert(xs: Vec<usize>, rm: Range<usize>, ins: Vec<usize>) -> bool {
+            let rm = if rm.start <= rm.end { rm } else {rm.end .. rm.start};
+            let mut set = TeardownSet::new(xs.clone());
+            let mut expected = xs;
+            set.delete_range(rm.clone(), Vec::new());
+            expected.retain(|x| !(rm.start <= *x && *x < rm.end || rm.start == *x));
+
+            for &x in ins.iter() {
+                set.insert(x);
+                expected.push(x);
+            }
+            expected.sort();
+
+            check_set(&set, &expected);
+            true
+        }
+    }
+
+
+    fn check_set(set: &TeardownSet<usize>, expected: &[usize]) {
+        let repr = set.internal();
+        assert!(check_bst(repr, 0).is_ok(), "tree={:?}", repr);
+        assert!(check_integrity(repr).is_ok(), "tree={:?}", repr);
+        assert_eq!(set.size(), expected.len());
+
+        let items: Vec<_> = set.iter().cloned().collect();
+        assert_eq!(&items[..], expected);
+
+        for x in expected.iter() {
+            assert!(set.contains(x), "x={}, tree={:?}", x, repr);
+        }
+
+        let mut queried = Vec::with_capacity(expected.len());
+        set.query_range::<usize, _, _>(.., RefCopyingSink::new(UncheckedVecRefSink::new(&mut queried)));
+        assert_eq!(&queried[..], expected);
+    }
+}
+
+
+
+#[cfg(test)]
+mod test_insert_interval {
+    use std::ops::Range;
+
+    use applied::interval::{Interval, KeyInterval};
+    use base::validation::{check_bst, check_integrity};
+    use base::sink::{RefCopyingSink, UncheckedVecRefSink};
+    use external_api::{IntervalTeardownSet, TreeWrapperAccess};
+    use super::test_delete_interval::{normalize_range, check_maxb};
+
+    type Iv = KeyInterval<usize>;
+
+
+    #[test]
+    fn insert_into_empty() {
+        let mut set = IntervalTeardownSet::new(vec![]);
+        let mut expected = vec![];
+        for i in (0..50).rev() {
+            let iv = Iv::new(i, i + (i*7) % 13);
+            set.insert(iv);
+            expected.push(iv);
+            check_set(&set, &expected);
+        }
+    }
+
+    quickcheck! {
+        fn quickcheck_interval_insert(xs: Vec<Range<usize>>, rm: Range<usize>, ins: Vec<Range<usize>>) -> bool {
+            let mut expected: Vec<Iv> = xs.into_iter()
+                .map(|r| normalize_range(r).into())
+                .collect();
+            let mut set = IntervalTeardownSet::new(expected.clone());
+
+            let rm: Iv = normalize_range(rm).into();
+            set.delete_overlap(&rm, Vec::new());
+            expected.retain(|iv| !iv.overlaps(&rm));
+
+            for r in ins.into_iter() {
+                let iv = normalize_range(r).into();
+                set.insert(iv);
+                expected.push(iv);
+            }
+
+            check_set(&set, &expected);
+            true
+        }
+    }
+
+
+    fn check_set(set: &IntervalTeardownSet<Iv>, expected: &[Iv]) {
+        let tree = set.internal();
+        assert!(check_bst(tree, 0).is_ok(), "tree={:?}", tree);
+        assert!(check_integrity(tree).is_ok(), "tree={:?}", tree);
+        if tree.size() > 0 {
+            check_maxb(tree, tree, 0);
+        }
+
+        let mut sorted = expected.to_vec();
+        sorted.sort();
+        let items: Vec<_> = set.iter().cloned().collect();
+        assert_eq!(items, sorted);
+
+        // every query must find exactly the overlapping intervals
+        let max = sorted.iter().map(|iv| *iv.b()).max().unwrap_or(0);
+        for a in 0..max+2 {
+            let query = Iv::new(a, a+3);
+            let mut output = Vec::with_capacity(sorted.len());
+            set.query_overlap(&query, RefCopyingSink::new(UncheckedVecRefSink::new(&mut output)));
+
+            let overlapping: Vec<_> = sorted.iter().cloned().filter(|iv| iv.overlaps(&query)).collect();
+            assert_eq!(output, overlapping, "query={:?}, tree={:?}", query, tree);
+        }
+    }
+}
+
+
+
+#[cfg(test)]
+mod test_order_stats {
+    use std::ops::Range;
+
+    use applied::plain_tree::PlTree;
+    use applied::interval::{Interval, KeyInterval};
+    use external_api::{TeardownSet, IntervalTeardownSet, TreeWrapperAccess, Refill};
+    use super::test_delete_plain::test_exhaustive_n;
+    use super::test_delete_interval::normalize_range;
+    use super::common::{mk_bounds, bounds_contain};
+
+    type Tree = PlTree<usize, ()>;
+    type Iv = KeyInterval<usize>;
+
+
+    #[test]
+    fn order_queries_exhaustive() {
+        for n in 1..6 {
+            test_exhaustive_n(n, &|tree| order_queries_exhaustive_with_tree(tree));
+        }
+    }
+
+    fn order_queries_exhaustive_with_tree(tree: Tree) {
+        let plain = TeardownSet::from_internal(tree);
+        let mut counted = plain.clone();
+        counted.enable_counts();
+        let n = plain.size();
+
+        for set in [plain, counted].iter() {
+            check_order(set, &(1..n+1).collect::<Vec<_>>());
+
+            for bounds in mk_bounds(n+2) {
+                let mut set_mod = set.clone();
+                set_mod.delete_range(bounds, Vec::new());
+                let mut expected: Vec<_> = (1..n+1).filter(|x| !bounds_contain(&bounds, x)).collect();
+                check_order(&set_mod, &expected);
+
+                if let Some(x) = expected.pop() {
+                    set_mod.delete(&x);
+                    check_order(&set_mod, &expected);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn counts_survive_refill() {
+        let mut master = TeardownSet::new((0..1000).collect());
+        master.enable_counts();
+        let mut copy = master.clone();
+        let expected: Vec<_> = (0..1000).collect();
+
+        for i in 0..10 {
+            copy.delete_range(i*100 .. i*100 + 50, Vec::new());
+            copy.insert(i*100 + 25);
+            copy.refill_incremental(&master);
+            check_order(&copy, &expected);
+        }
+
+        for i in 1000..3000 {
+            copy.insert(i);
+        }
+        check_order(&copy, &(0..3000).collect::<Vec<_>>());
+        copy.refill(&master);
+        check_order(&copy, &expected);
+
+        // counts that were not enabled in the master are recomputed
+        let mut plain = TeardownSet::new((0..1000).collect());
+        plain.enable_counts();
+        let uncounted = TeardownSet::new((0..1000).collect());
+        plain.delete_range(100..200, Vec::new());
+        plain.refill(&uncounted);
+        check_order(&plain, &expected);
+    }
+
+    quickcheck! {
+        fn quickcheck_order(xs: Vec<usize>, rm: Range<usize>, ins: Vec<usize>, del: Vec<usize>) -> bool {
+            let rm = normalize_range(rm);
+            let mut set = TeardownSet::new(xs.clone());
+            set.enable_counts();
+            let mut expected = xs;
+
+            set.delete_range(rm.clone(), Vec::new());
+            expected.retain(|x| !(rm.start <= *x && *x < rm.end || rm.start == *x));
+            for &x in ins.iter() {
+                set.insert(x);
+                expected.push(x);
+            }
+            expected.sort();
+            for x in del.iter() {
+                if set.delete(x) {
+                    let pos = expected.iter().position(|y| y == x).unwrap();
+                    expected.remove(pos);
+                }
+            }
+
+            check_order(&set, &expected);
+            true
+        }
+
+        fn quickcheck_interval_order(xs: Vec<Range<usize>>, rm: Range<usize>, ins: Vec<Range<usize>>) -> bool {
+            let mut expected: Vec<Iv> = xs.into_iter()
+                .map(|r| normalize_range(r).into())
+                .collect();
+            let mut set = IntervalTeardownSet::new(expected.clone());
+            set.enable_counts();
+
+            let rm: Iv = normalize_range(rm).into();
+            set.delete_overlap(&rm, Vec::new());
+            expected.retain(|iv| !iv.overlaps(&rm));
+            for r in ins.into_iter() {
+                let iv = normalize_range(r).into();
+                set.insert(iv);
+                expected.push(iv);
+            }
+            expected.sort();
+
+            for k in 0..expected.len()+1 {
+                assert_eq!(set.nth(k), expected.get(k));
+            }
+            for iv in expected.iter() {
+                assert_eq!(set.rank(iv), expected.iter().filter(|x| *x < iv).count());
+                assert_eq!(set.count_range(*iv ..= *iv), expected.iter().filter(|x| *x == iv).count());
+            }
+            true
+        }
+    }
+
+
+    /// Checks `nth()`, `rank()` and `count_range()` against the sorted contents of the set.
+    fn check_order(set: &TeardownSet<usize>, expected: &[usize]) {
+        let max = expected.last().cloned().unwrap_or(0);
+
+        for k in 0..expected.len()+1 {
+            assert_eq!(set.nth(k), expected.get(k), "k={}, tree={:?}", k, set);
+        }
+
+        for x in 0..max+2 {
+            assert_eq!(set.rank(&x), expected.iter().filter(|&&y| y < x).count(), "x={}, tree={:?}", x, set);
+        }
+
+        // the number of bounds grows quadratically, so we only check all of them for small sets
+        if max < 16 {
+            for bounds in mk_bounds(max+2) {
+                let count = expected.iter().filter(|x| bounds_contain(&bounds, x)).count();
+                assert_eq!(set.count_range(bounds), count, "bounds={:?}, tree={:?}", bounds, set);
+            }
+        } else {
+            for a in (0..max+2).step_by(7) {
+                let count = expected.iter().filter(|&&x| a <= x && x < a+10).count();
+                assert_eq!(set.count_range(a..a+10), count);
+            }
+        }
+    }
+}
+
+
+
+#[cfg(test)]
+mod test_snapshot {
+    use applied::plain_tree::PlTree;
+    use applied::interval::{Interval, KeyInterval};
+    use base::snapshot::{Pod, Checksum, SnapshotError, SnapshotHeader, HEADER_LEN, CHECKSUM_OFFSET};
+    use external_api::{TeardownMap, TeardownSet, IntervalTeardownMap, TreeWrapperAccess, Refill};
+    use super::test_delete_plain::test_exhaustive_n;
+
+    type Tree = PlTree<usize, ()>;
+    type Iv = KeyInterval<usize>;
+
+
+    fn snapshot_of<T: Ord+Pod>(set: &TeardownSet<T>) -> Vec<u8> {
+        let mut bytes = vec![];
+        set.write_snapshot(&mut bytes).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn roundtrip_exhaustive() {
+        for n in 1..7 {
+            test_exhaustive_n(n, &|tree: Tree| {
+                let set = TeardownSet::from_internal(tree);
+                let bytes = snapshot_of(&set);
+
+                // the layout is preserved, not just the items
+                let restored = TeardownSet::<usize>::from_snapshot(&bytes).unwrap();
+                assert_eq!(format!("{:?}", restored), format!("{:?}", set));
+                assert_eq!(snapshot_of(&restored), bytes);
+
+                let mut other = TeardownSet::new(vec![1usize, 2, 3]);
+                other.refill_from_reader(&mut &bytes[..]).unwrap();
+                assert_eq!(format!("{:?}", other), format!("{:?}", set));
+            });
+        }
+    }
+
+    #[test]
+    fn refill_modified_copy() {
+        let mut master = TeardownMap::new((0..1000).map(|x| (x, x * 2)).collect());
+        for x in (0..1000).filter(|x| x % 7 == 0) {
+            master.delete(&x);
+        }
+        let mut bytes = vec![];
+        master.write_snapshot(&mut bytes).unwrap();
+
+        let mut copy = master.clone();
+        copy.enable_counts();
+        for x in 1000..3000 {
+            copy.insert(x, 0);
+        }
+        let mut output = vec![];
+        copy.delete_range(100..500, &mut output);
+
+        copy.refill_from_snapshot(&bytes).unwrap();
+        assert!(copy.iter().eq(master.iter()));
+        assert_eq!(copy.nth(100), master.iter().nth(100));
+        assert_eq!(copy.rank(&500), master.iter().take_while(|&&(k, _)| k < 500).count());
+
+        output.clear();
+        copy.delete_range(100..500, &mut output);
+        assert_eq!(output, (100..500).filter(|x| x % 7 != 0).map(|x| (x, x * 2)).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn interval_roundtrip() {
+        let items: Vec<_> = (0..300).map(|x| (Iv::new(x % 53, x % 53 + x*7 % 20), x)).collect();
+        let mut map = IntervalTeardownMap::new(items);
+        for x in 0..40 {
+            map.delete(&Iv::new(x*3 % 53, x*3 % 53 + x*21 % 20));
+        }
+        let mut bytes = vec![];
+        map.write_snapshot(&mut bytes).unwrap();
+
+        let restored = IntervalTeardownMap::<Iv, usize>::from_snapshot(&bytes).unwrap();
+        for a in 0..80 {
+            let query = Iv::new(a, a + 5);
+            let expected: Vec<_> = map.iter().filter(|&&(iv, _)| iv.overlaps(&query)).collect();
+            assert_eq!(restored.overlapping(&query).collect::<Vec<_>>(), expected);
+        }
+    }
+
+    #[test]
+    fn invalid_snapshots() {
+        let set = TeardownSet::new((0..100u64).collect());
+        let bytes = snapshot_of(&set);
+        let load = |bytes: &[u8]| TeardownSet::<u64>::from_snapshot(bytes).err().unwrap();
+
+        let mut bad = bytes.clone();
+        bad[0] = b'X';
+        match load(&bad) { SnapshotError::BadMagic => {}, e => panic!("{}", e) }
+
+        let mut bad = bytes.clone();
+        bad[8] = 99;
+        match load(&bad) { SnapshotError::UnsupportedVersion(99) => {}, e => panic!("{}", e) }
+
+        match TeardownSet::<u32>::from_snapshot(&bytes).err().unwrap() {
+            SnapshotError::NodeSizeMismatch { .. } => {},
+            e => panic!("{}", e)
+        }
+
+        match load(&bytes[..bytes.len()-1]) { SnapshotError::Truncated => {}, e => panic!("{}", e) }
+
+        let mut bad = bytes.clone();
+        *bad.last_mut().unwrap() ^= 1;
+        match load(&bad) { SnapshotError::ChecksumMismatch => {}, e => panic!("{}", e) }
+
+        // the header is covered by the checksum
+        let mut bad = bytes.clone();
+        bad[24] -= 1;
+        match load(&bad) { SnapshotError::ChecksumMismatch => {}, e => panic!("{}", e) }
+
+        // a structurally broken mask with a valid checksum
+        let mut bad = bytes.clone();
+        bad[HEADER_LEN] = 0;
+        fix_checksum(&mut bad);
+        match load(&bad) { SnapshotError::Corrupt(_) => {}, e => panic!("{}", e) }
+
+        // a height that does not match the capacity, with a valid checksum
+        for &height in &[0u64, 6, 8, 1 << 40] {
+            let mut bad = bytes.clone();
+            bad[32..40].copy_from_slice(&height.to_le_bytes());
+            fix_checksum(&mut bad);
+            match load(&bad) { SnapshotError::Corrupt(_) => {}, e => panic!("{}", e) }
+        }
+
+        // capacities for which the length of the snapshot overflows, with a valid checksum
+        let bytes8 = snapshot_of(&TeardownSet::new((0..100u8).collect()));
+        for &capacity in &[u64::max_value(), usize::max_value() as u64, usize::max_value() as u64 - 40] {
+            let mut bad = bytes8.clone();
+            bad[16..24].copy_from_slice(&capacity.to_le_bytes());
+            fix_checksum(&mut bad);
+            match TeardownSet::<u8>::from_snapshot(&bad).err().unwrap() { SnapshotError::Corrupt(_) => {}, e => panic!("{}", e) }
+            match TeardownSet::<u8>::new(vec![]).refill_from_reader(&mut &bad[..]).err().unwrap() {
+                SnapshotError::Corrupt(_) => {},
+                e => panic!("{}", e)
+            }
+        }
+
+        // a huge capacity is not allocated before the input turns out to be too short
+        let mut bad = bytes.clone();
+        bad[16..24].copy_from_slice(&(1u64 << 40).to_le_bytes());
+        bad[32..40].copy_from_slice(&41u64.to_le_bytes());
+        match TeardownSet::<u64>::new(vec![]).refill_from_reader(&mut &bad[..]).err().unwrap() {
+            SnapshotError::Truncated | SnapshotError::Corrupt(_) => {},
+            e => panic!("{}", e)
+        }
+
+        // a failed in-memory refill leaves the set unchanged, a failed streaming refill leaves it empty
+        let mut bad = bytes.clone();
+        *bad.last_mut().unwrap() ^= 1;
+        let mut copy = TeardownSet::new(vec![1u64, 2, 3]);
+        assert!(copy.refill_from_snapshot(&bad).is_err());
+        assert_eq!(copy.iter().cloned().collect::<Vec<_>>(), vec![1, 2, 3]);
+        assert!(copy.refill_from_reader(&mut &bad[..]).is_err());
+        assert_eq!(copy.size(), 0);
+        assert!(copy.iter().next().is_none());
+    }
+
+    #[test]
+    fn refill_incremental_after_snapshot() {
+        let master = TeardownSet::new((0..1000u64).collect());
+        let other = TeardownSet::new((1000..2000u64).collect());
+
+        // a tree loaded from a snapshot has never been refilled from `master`
+        let mut copy = TeardownSet::<u64>::from_snapshot(&snapshot_of(&other)).unwrap();
+        copy.refill_incremental(&master);
+        assert!(copy.iter().eq(master.iter()));
+        assert_eq!(copy.size(), master.size());
+
+        let mut copy = master.clone();
+        copy.delete_range(0..10, Vec::new());
+        copy.refill_from_snapshot(&snapshot_of(&other)).unwrap();
+        copy.refill_incremental(&master);
+        assert!(copy.iter().eq(master.iter()));
+
+        let mut copy = master.clone();
+        copy.refill_from_reader(&mut &snapshot_of(&other)[..]).unwrap();
+        copy.refill_incremental(&master);
+        assert!(copy.iter().eq(master.iter()));
+    }
+
+    fn fix_checksum(bytes: &mut [u8]) {
+        let mut checksum = Checksum::new();
+        checksum.update(&bytes[..CHECKSUM_OFFSET]);
+        checksum.update(&bytes[HEADER_LEN..]);
+        bytes[CHECKSUM_OFFSET..HEADER_LEN].copy_from_slice(&checksum.value().to_le_bytes());
+    }
+
+    #[test]
+    fn zeroed_padding() {
+        let mut map = TeardownMap::new((0..200u64).map(|x| (x as u8, x * 0x0101_0101_0101)).collect());
+        for x in (0..200).filter(|x| x % 3 == 0) {
+            map.delete(&(x as u8));
+        }
+        let mut bytes = vec![];
+        map.write_snapshot(&mut bytes).unwrap();
+
+        // the node is a (u8, u64) tuple, so 7 of its 16 bytes are padding
+        let item = (0u8, 0u64);
+        let base = &item as *const _ as usize;
+        let (key_offset, val_offset) = (&item.0 as *const _ as usize - base, &item.1 as *const _ as usize - base);
+        let header = SnapshotHeader::parse(&bytes, 16).unwrap();
+        let data = &bytes[HEADER_LEN + header.mask_len()..];
+        assert_eq!(data.len(), header.capacity() * 16);
+        for node in data.chunks(16) {
+            for (i, &b) in node.iter().enumerate() {
+                if i != key_offset && !(val_offset <= i && i < val_offset + 8) {
+                    assert_eq!(b, 0);
+                }
+            }
+        }
+
+        let restored = TeardownMap::<u8, u64>::from_snapshot(&bytes).unwrap();
+        assert!(restored.iter().eq(map.iter()));
+    }
+}
+
+
+#[cfg(all(test, feature = "mmap"))]
+mod test_mapped {
+    use external_api::{TeardownMap, TeardownSet, MappedTeardownMap, MappedTeardownSet, Refill};
+
+    use std::env;
+    use std::fs::{self, File};
+    use std::path::PathBuf;
+
+
+    fn temp_path(name: &str) -> PathBuf {
+        env::temp_dir().join(format!("teardown_tree_{}_{}", name, ::std::process::id()))
+    }
+
+    #[test]
+    fn mapped_map_queries() {
+        let mut master = TeardownMap::new((0..2000).map(|x| (x, x * 3)).collect());
+        for x in (0..2000).filter(|x| x % 5 == 0) {
+            master.delete(&x);
+        }
+        let path = temp_path("map");
+        master.write_snapshot(&mut File::create(&path).unwrap()).unwrap();
+        // This is safe: the file is not modified while it is mapped.
+        let mapped = unsafe { MappedTeardownMap::<usize, usize>::open(&path) }.unwrap();
+
+        assert_eq!(mapped.size(), master.size());
+        assert!(mapped.iter().eq(master.iter()));
+        for x in 0..2010 {
+            assert_eq!(mapped.find(&x), master.find(&x));
+            assert_eq!(mapped.contains_key(&x), master.contains_key(&x));
+        }
+        for &(from, to) in [(0, 0), (0, 10), (3, 97), (1500, 2500), (1999, 2000)].iter() {
+            let (mut expected, mut output) = (vec![], vec![]);
+            master.query_range(from..to, &mut expected);
+            mapped.query_range(from..to, &mut output);
+            assert_eq!(output, expected);
+        }
+
+        // the mapped tree as the master for refills
+        let mut copy = TeardownMap::new(vec![(1, 1)]);
+        copy.refill(&mapped);
+        assert!(copy.iter().eq(master.iter()));
+        for round in 0..3 {
+            let mut output = vec![];
+            copy.delete_range(round*300..round*300+500, &mut output);
+            copy.insert(5000 + round, 0);
+            copy.refill_incremental(&mapped);
+            assert!(copy.iter().eq(master.iter()));
+        }
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn mapped_set() {
+        let master = TeardownSet::new((0..100u32).map(|x| x * 2).collect());
+        let path = temp_path("set");
+        master.write_snapshot(&mut File::create(&path).unwrap()).unwrap();
+        // This is safe: the file is not modified while it is mapped.
+        let mapped = unsafe { MappedTeardownSet::<u32>::open(&path) }.unwrap();
+
+        assert!(mapped.iter().eq(master.iter()));
+        assert!(mapped.contains(&10) && !mapped.contains(&11));
+        let mut output = vec![];
+        mapped.query_range(10..=20, &mut output);
+        assert_eq!(output, vec![&10, &12, &14, &16, &18, &20]);
+
+        let mut copy = TeardownSet::new(vec![]);
+        copy.refill(&mapped);
+        assert!(copy.iter().eq(master.iter()));
+
+        assert!(unsafe { MappedTeardownSet::<u64>::open(&path) }.is_err());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn refill_incremental_from_other_snapshot() {
+        let masters = [TeardownMap::new((0..1000).map(|x| (x, x)).collect()),
+                       TeardownMap::new((0..1000).map(|x| (x, x + 1)).collect())];
+        let paths = [temp_path("incremental_a"), temp_path("incremental_b")];
+        for (master, path) in masters.iter().zip(paths.iter()) {
+            master.write_snapshot(&mut File::create(path).unwrap()).unwrap();
+        }
+        // This is safe: the files are not modified while they are mapped.
+        let mapped: Vec<_> = paths.iter()
+            .map(|path| unsafe { MappedTeardownMap::<usize, usize>::open(path) }.unwrap())
+            .collect();
+
+        // neither a clone nor a copy refilled from another snapshot has the dirty flags for `mapped[1]`
+        let mut copy = masters[0].clone();
+        copy.refill_incremental(&mapped[1]);
+        assert!(copy.iter().eq(masters[1].iter()));
+        copy.refill(&mapped[0]);
+        copy.delete(&0);
+        copy.refill_incremental(&mapped[1]);
+        assert!(copy.iter().eq(masters[1].iter()));
+
+        // nor does a copy refilled from the same snapshot have them for a tree
+        copy.refill_incremental(&masters[0]);
+        assert!(copy.iter().eq(masters[0].iter()));
+
+        drop(mapped);
+        for path in paths.iter() {
+            fs::remove_file(path).unwrap();
+        }
+    }
+}
+
+
+#[cfg(all(test, feature = "serde"))]
+mod test_serde {
+    use serde_json;
+
+    use applied::plain_tree::PlTree;
+    use applied::interval::{Interval, KeyInterval};
+    use external_api::{TeardownMap, TeardownSet, IntervalTeardownMap, IntervalTeardownSet, TreeWrapperAccess, Refill};
+    use super::test_delete_plain::test_exhaustive_n;
+
+    type Tree = PlTree<usize, ()>;
+    type Iv = KeyInterval<usize>;
+
+
+    #[test]
+    fn roundtrip_exhaustive() {
+        for n in 1..7 {
+            test_exhaustive_n(n, &|tree: Tree| {
+                let set = TeardownSet::from_internal(tree);
+                let json = serde_json::to_string(&set).unwrap();
+                let restored: TeardownSet<usize> = serde_json::from_str(&json).unwrap();
+
+                // the layout is preserved, not just the items
+                assert_eq!(format!("{:?}", restored), format!("{:?}", set));
+                assert_eq!(serde_json::to_string(&restored).unwrap(), json);
+            });
+        }
+    }
+
+    #[test]
+    fn roundtrip_map() {
+        let mut map = TeardownMap::new((0..100).map(|x| (x, x.to_string())).collect());
+        for x in (0..100).filter(|x| x % 3 == 0) {
+            map.delete(&x);
+        }
+
+        let json = serde_json::to_string(&map).unwrap();
+        let mut restored: TeardownMap<usize, String> = serde_json::from_str(&json).unwrap();
+        assert_eq!(restored.size(), map.size());
+        assert!(restored.iter().eq(map.iter()));
+
+        let mut output = vec![];
+        restored.delete_range(10..20, &mut output);
+        assert_eq!(output, (10..20).filter(|x| x % 3 != 0).map(|x| (x, x.to_string())).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn roundtrip_interval() {
+        let items: Vec<_> = (0..200).map(|x| (Iv::new(x % 41, x % 41 + x*7 % 20), x)).collect();
+        let mut map = IntervalTeardownMap::new(items);
+        for x in 0..30 {
+            map.delete(&Iv::new(x*3 % 41, x*3 % 41 + x*21 % 20));
+        }
+
+        let json = serde_json::to_string(&map).unwrap();
+        let restored: IntervalTeardownMap<Iv, usize> = serde_json::from_str(&json).unwrap();
+        assert!(restored.iter().eq(map.iter()));
+
+        for a in 0..70 {
+            let query = Iv::new(a, a + 5);
+            let expected: Vec<_> = map.iter().filter(|&&(iv, _)| iv.overlaps(&query)).collect();
+            assert_eq!(restored.overlapping(&query).collect::<Vec<_>>(), expected);
+        }
+
+        let set = IntervalTeardownSet::new(vec![Iv::new(1, 3), Iv::new(2, 7), Iv::new(5, 6)]);
+        let restored: IntervalTeardownSet<Iv> = serde_json::from_str(&serde_json::to_string(&set).unwrap()).unwrap();
+        assert!(restored.iter().eq(set.iter()));
+    }
+
+    #[test]
+    fn refill_incremental_after_deserialize() {
+        let master = TeardownSet::new((0..1000).collect());
+        let other = TeardownSet::new((1000..2000).collect());
+
+        // a deserialized tree has never been refilled from `master`
+        let mut copy: TeardownSet<usize> = serde_json::from_str(&serde_json::to_string(&other).unwrap()).unwrap();
+        copy.refill_incremental(&master);
+        assert!(copy.iter().eq(master.iter()));
+        assert_eq!(copy.size(), master.size());
+    }
+
+    #[test]
+    fn invalid_input() {
+        let parse = |json: &str| serde_json::from_str::<TeardownSet<usize>>(json);
+
+        assert!(parse(r#"{"mask":[true,true,true],"nodes":[[2,null],[1,null],[3,null]]}"#).is_ok());
+        // node count does not match the mask
+        assert!(parse(r#"{"mask":[true,true,true],"nodes":[[2,null],[1,null]]}"#).is_err());
+        // a child without a parent
+        assert!(parse(r#"{"mask":[false,true,false],"nodes":[[1,null]]}"#).is_err());
+        // not a search tree
+        assert!(parse(r#"{"mask":[true,true,true],"nodes":[[1,null],[2,null],[3,null]]}"#).is_err());
+    }
+
+    #[test]
+    fn untrusted_maxb() {
+        // the maxb values are all wrong: they are recomputed, not taken as is
+        let json = r#"{"mask":[true,true,true],"nodes":[[[2,7],null,0],[[1,3],null,0],[[5,6],null,0]]}"#;
+        let set: IntervalTeardownSet<Iv> = serde_json::from_str(json).unwrap();
+        let expected = vec![Iv::new(2, 7), Iv::new(5, 6)];
+        assert_eq!(set.overlapping(&Iv::new(5, 6)).cloned().collect::<Vec<_>>(), expected);
+    }
+}
+
+
+#[cfg(all(test, feature = "rayon"))]
+mod test_parallel {
+    use base::{TreeRepr, Node};
+    use applied::plain_tree::PlTree;
+    use applied::interval::KeyInterval;
+    use external_api::{TeardownMap, TeardownSet, IntervalTeardownMap, IntervalTeardownSet, TreeWrapperAccess};
+    use super::test_delete_plain::test_exhaustive_n;
+    use super::common::mk_bounds;
+
+    type Tree = PlTree<usize, ()>;
+    type Iv = KeyInterval<usize>;
+
+
+    /// Checks that both trees have the same layout, slot by slot.
+    fn assert_same_layout<N: Node, F>(par: &TreeRepr<N>, seq: &TreeRepr<N>, eq: F)
+        where F: Fn(&N, &N) -> bool
+    {
+        assert_eq!(par.size(), seq.size());
+        assert_eq!(par.capacity(), seq.capacity());
+        for i in 0..seq.capacity() {
+            assert_eq!(par.mask(i), seq.mask(i), "slot {}", i);
+            if seq.mask(i) {
+                assert!(eq(par.node(i), seq.node(i)), "slot {}", i);
+            }
+        }
+    }
+
+    /// Pseudo-random items with duplicates.
+    fn items(n: usize) -> Vec<usize> {
+        (0..n).map(|i| i.wrapping_mul(2654435761) % (n / 2 + 1)).collect()
+    }
+
+    #[test]
+    fn par_new_map() {
+        for &n in [0, 1, 2, 1000, (1 << 14) - 1, 1 << 14, 100_000].iter() {
+            let items: Vec<(usize, usize)> = items(n).into_iter().enumerate().map(|(i, k)| (k, i)).collect();
+            let par = TeardownMap::par_new(items.clone());
+            let seq = TeardownMap::new(items);
+            assert_same_layout(par.internal(), seq.internal(), |a, b| a.key() == b.key());
+            assert!(par.iter().map(|(k, _)| k).eq(seq.iter().map(|(k, _)| k)));
+        }
+    }
+
+    #[test]
+    fn par_with_sorted_set() {
+        let sorted: Vec<usize> = (0..70_000).collect();
+        let mut par = TeardownSet::par_with_sorted(sorted.clone());
+        let seq = TeardownSet::with_sorted(sorted);
+        assert_same_layout(par.internal(), seq.internal(), |a, b| a.key() == b.key());
+
+        let mut output = vec![];
+        par.delete_range(100..60_000, &mut output);
+        assert_eq!(output, (100..60_000).collect::<Vec<_>>());
+        assert_eq!(par.size(), 10_100);
+    }
+
+    #[test]
+    fn par_new_interval() {
+        for &n in [0, 1, 1000, 100_000].iter() {
+            let ivs: Vec<Iv> = items(n).into_iter().enumerate()
+                .map(|(i, a)| KeyInterval::new(a, a + i % 100))
+                .collect();
+            let par = IntervalTeardownSet::par_new(ivs.clone());
+            let seq = IntervalTeardownSet::new(ivs.clone());
+            assert_same_layout(par.internal(), seq.internal(),
+                               |a, b| a.key() == b.key() && a.maxb == b.maxb);
+
+            for q in [KeyInterval::new(0, 0), KeyInterval::new(10, 20), KeyInterval::new(n / 3, n / 3 + 50)].iter() {
+                let (mut expected, mut output) = (vec![], vec![]);
+                seq.query_overlap(q, &mut expected);
+                par.query_overlap(q, &mut output);
+                assert_eq!(output, expected);
+            }
+        }
+
+        let sorted: Vec<_> = (0..50_000).map(|x| (KeyInterval::new(x, x + 10), x)).collect();
+        let par = IntervalTeardownMap::par_with_sorted(sorted.clone());
+        let seq = IntervalTeardownMap::with_sorted(sorted);
+        assert_same_layout(par.internal(), seq.internal(), |a, b| a.key() == b.key() && a.maxb == b.maxb);
+        assert_eq!(par.internal().node(0).maxb, 50_009);
+        assert!(par.overlapping(&KeyInterval::new(100, 105)).eq(seq.overlapping(&KeyInterval::new(100, 105))));
+    }
+
+    #[test]
+    fn par_delete_range_exhaustive() {
+        for n in 1..7 {
+            test_exhaustive_n(n, &|tree: Tree| {
+                let mut counted = tree.clone();
+                counted.enable_counts();
+
+                for orig in [tree, counted].iter() {
+                    for bounds in mk_bounds(n + 2) {
+                        let mut seq = orig.clone();
+                        let mut expected = vec![];
+                        seq.delete_range(bounds, &mut expected);
+
+                        // small thresholds, so that even the tiny trees are split
+                        for &min_subtree in [1, 2].iter() {
+                            let mut par = orig.clone();
+                            let output: Vec<_> = par.par_delete_range_with(bounds, min_subtree, Vec::new)
+                                .into_iter().flat_map(|chunk| chunk).collect();
+                            assert_eq!(output, expected, "bounds={:?}", bounds);
+                            assert_eq!(format!("{:?}", par), format!("{:?}", seq), "bounds={:?}", bounds);
+                            assert_eq!(par.size(), seq.size());
+                            for k in 0..par.size() {
+                                assert_eq!(par.select(k), seq.select(k));
+                            }
+                        }
+                    }
+                }
+            });
+        }
+    }
+
+    #[test]
+    fn par_query_range_exhaustive() {
+        for n in 1..7 {
+            test_exhaustive_n(n, &|tree: Tree| {
+                for bounds in mk_bounds(n + 2) {
+                    let mut expected = vec![];
+                    tree.query_range(bounds, &mut expected);
+                    for &min_subtree in [1, 2].iter() {
+                        let output: Vec<_> = tree.par_query_range_with(bounds, min_subtree, Vec::new)
+                            .into_iter().flat_map(|chunk| chunk).collect();
+                        assert_eq!(output, expected, "bounds={:?}", bounds);
+                    }
+                }
+            });
+        }
+    }
+
+    #[test]
+    fn par_delete_range_large() {
+        let items: Vec<(usize, usize)> = items(200_000).into_iter().enumerate().map(|(i, k)| (k, i)).collect();
+        let mut seq = TeardownMap::new(items);
+        let mut par = seq.clone();
+
+        let mut expected = vec![];
+        seq.delete_range(10..99_990, &mut expected);
+        let sinks = par.par_delete_range(10..99_990, Vec::new);
+        assert!(sinks.len() > 1);
+        let output: Vec<_> = sinks.into_iter().flat_map(|chunk| chunk).collect();
+        assert_eq!(output.len(), expected.len());
+        assert!(output.iter().map(|e| e.0).eq(expected.iter().map(|e| e.0)));
+        assert_same_layout(par.internal(), seq.internal(), |a, b| a.key() == b.key());
+
+        let set = TeardownSet::new((0..100_000).collect());
+        let output: Vec<_> = set.par_query_range(.., Vec::new).into_iter().flat_map(|chunk| chunk).collect();
+        assert!(output.into_iter().cloned().eq(0..100_000));
+
+        let mut set = set;
+        let output: Vec<_> = set.par_delete_range(1.., Vec::new).into_iter().flat_map(|chunk| chunk).collect();
+        assert!(output.into_iter().eq(1..100_000));
+        assert_eq!(set.size(), 1);
+        assert!(set.contains(&0));
+    }
+
+    #[test]
+    fn par_empty_half_open_range() {
+        let mut tree = Tree::new((0..10).map(|k| (k, ())).collect());
+        let output: Vec<_> = tree.par_delete_range_with(5..5, 1, Vec::new).into_iter().flat_map(|chunk| chunk).collect();
+        assert!(output.is_empty());
+        assert_eq!(tree.size(), 10);
+
+        // like `query_range()`, `par_query_range()` treats `a..a` as the point `a`
+        let output: Vec<_> = tree.par_query_range_with(5..5, 1, Vec::new).into_iter().flat_map(|chunk| chunk).collect();
+        assert_eq!(output, vec![&(5, ())]);
+    }
+}
+
+#[cfg(test)]
+mod test_send_sync {
+    use external_api::{TeardownMap, TeardownSet, IntervalTeardownMap, IntervalTeardownSet, KeyInterval, Refill};
+
+    use std::sync::Arc;
+    use std::thread;
+
+    fn assert_send_sync<T: Send+Sync>() {}
+
+    #[test]
+    fn collections_are_send_and_sync() {
+        assert_send_sync::<TeardownMap<usize, String>>();
+        assert_send_sync::<TeardownSet<String>>();
+        assert_send_sync::<IntervalTeardownMap<KeyInterval<usize>, String>>();
+        assert_send_sync::<IntervalTeardownSet<KeyInterval<usize>>>();
+    }
+
+    #[test]
+    fn shared_master() {
+        let master = Arc::new(TeardownMap::new((0..1000).map(|x| (x, x * 2)).collect()));
+        let ivs = Arc::new(IntervalTeardownSet::new((0..1000).map(|x| KeyInterval::new(x, x + 5)).collect()));
+
+        let workers: Vec<_> = (0..4).map(|i| {
+            let (master, ivs) = (master.clone(), ivs.clone());
+            thread::spawn(move || {
+                let mut copy = (*master).clone();
+                for round in 0..10 {
+                    let from = i * 200 + round * 10;
+                    let mut output = vec![];
+                    copy.delete_range(from..from + 100, &mut output);
+                    assert_eq!(output.len(), 100);
+                    copy.refill(&*master);
+                }
+
+                let mut output = vec![];
+                ivs.query_overlap(&KeyInterval::new(i * 100, i * 100 + 1), &mut output);
+                assert_eq!(output.len(), (0..1000).filter(|&x| x <= i * 100 && x + 5 > i * 100).count());
+                copy
+            })
+        }).collect();
+
+        for worker in workers {
+            let copy = worker.join().unwrap();
+            assert!(copy.iter().eq(master.iter()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_pool {
+    use external_api::{TeardownSet, TeardownMap, Refill};
+    use pool::TeardownPool;
+
+    use std::sync::Arc;
+    use std::thread;
+
+
+    #[test]
+    fn lazy_refill() {
+        let master = Arc::new(TeardownSet::new((0..1000).collect()));
+        let pool = TeardownPool::new(master.clone());
+        assert_eq!(pool.idle(), 0);
+
+        for round in 0..5 {
+            let mut a = pool.get();
+            let mut b = pool.get();
+            assert!(a.iter().eq(master.iter()) && b.iter().eq(master.iter()));
+
+            let mut output = vec![];
+            a.delete_range(round * 100..round * 100 + 500, &mut output);
+            assert_eq!(output.len(), 500);
+            b.insert(5000);
+            assert_eq!(b.size(), 1001);
+            drop(a);
+            drop(b);
+            // the two copies are recycled rather than cloned again
+            assert_eq!(pool.idle(), 2);
+        }
+
+        let copy = pool.get().into_inner();
+        assert!(copy.iter().eq(master.iter()));
+        assert_eq!(pool.idle(), 1);
+
+        pool.reserve(4);
+        assert_eq!(pool.idle(), 4);
+    }
+
+    #[test]
+    fn background_refill() {
+        let master = Arc::new(TeardownMap::new((0..10_000).map(|x| (x, x)).collect()));
+        let pool = Arc::new(TeardownPool::with_background_refill(master.clone()));
+        pool.reserve(2);
+
+        let workers: Vec<_> = (0..4).map(|i| {
+            let pool = pool.clone();
+            thread::spawn(move || {
+                for round in 0..20 {
+                    let mut copy = pool.get();
+                    assert_eq!(copy.size(), 10_000);
+                    let from = (i * 20 + round) * 100;
+                    let mut output = vec![];
+                    copy.delete_range(from..from + 1000, &mut output);
+                    assert_eq!(output.len(), 1000);
+                }
+            })
+        }).collect();
+        for worker in workers {
+            worker.join().unwrap();
+        }
+
+        assert!(pool.idle() <= 4);
+        let copy = pool.get();
+        assert!(copy.iter().eq(master.iter()));
+    }
+
+    /// A copy whose refill panics if it has been marked as poisoned.
+    #[derive(Clone)]
+    struct Flaky {
+        poisoned: bool
+    }
+
+    impl Refill for Flaky {
+        fn refill(&mut self, _: &Self) {
+            if self.poisoned {
+                panic!("refill failed");
+            }
+        }
+    }
+
+    #[test]
+    fn panicking_background_refill() {
+        let pool = TeardownPool::with_background_refill(Arc::new(Flaky { poisoned: false }));
+        pool.reserve(3);
+        let mut copies: Vec<_> = (0..3).map(|_| pool.get()).collect();
+        copies[0].poisoned = true;
+        drop(copies);
+
+        // the pool neither waits for the lost copy nor loses the other ones
+        for _ in 0..10 {
+            let copies: Vec<_> = (0..3).map(|_| pool.get()).collect();
+            assert!(copies.iter().all(|copy| !copy.poisoned));
+        }
+        assert!(pool.idle() >= 2 && pool.idle() <= 3);
+    }
+
+    #[test]
+    fn concurrent_reserve() {
+        let master = Arc::new(TeardownSet::new((0..1000).collect()));
+        let pool = Arc::new(TeardownPool::new(master));
+        let threads: Vec<_> = (0..4).map(|_| {
+            let pool = pool.clone();
+            thread::spawn(move || pool.reserve(5))
+        }).collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(pool.idle(), 5);
+    }
+}
+
+#[cfg(test)]
+mod test_compact {
+    use external_api::{TeardownSet, TeardownMap, IntervalTeardownSet, KeyInterval, Refill};
+    use applied::plain_tree::PlNode;
+    use base::TreeRepr;
+    use base::layout::Veb;
+
+
+    #[test]
+    fn compact_plain() {
+        let master = TeardownMap::new((0..1000).map(|x| (x, x * 2)).collect());
+        let mut map = master.clone();
+        map.enable_counts();
+        let mut output = vec![];
+        map.delete_range(100..950, &mut output);
+        assert_eq!(map.capacity(), 1000);
+
+        map.compact();
+        assert_eq!(map.capacity(), 150);
+        assert!(map.iter().cloned().eq((0..100).chain(950..1000).map(|x| (x, x * 2))));
+        assert_eq!(map.nth(120), Some(&(970, 1940)));
+        assert_eq!(map.rank(&960), 110);
+
+        // the tree is fully usable afterwards
+        map.insert(500, 1000);
+        output.clear();
+        map.delete_range(0..960, &mut output);
+        assert_eq!(output.len(), 111);
+        assert_eq!(map.nth(0), Some(&(960, 1920)));
+
+        map.refill(&master);
+        assert!(map.iter().eq(master.iter()));
+
+        let mut empty = TeardownSet::new(vec![1, 2, 3]);
+        empty.delete_range::<i32, _, _>(.., &mut vec![]);
+        empty.compact();
+        assert_eq!((empty.size(), empty.capacity()), (0, 0));
+        empty.insert(5);
+        assert!(empty.contains(&5));
+    }
+
+    #[test]
+    fn compact_interval() {
+        let mut set = IntervalTeardownSet::new((0..1000).map(|x| KeyInterval::new(x, x + 50)).collect());
+        let mut output = vec![];
+        set.delete_overlap(&KeyInterval::new(200, 900), &mut output);
+        set.compact();
+        assert_eq!(set.capacity(), set.size());
+
+        // `maxb` has been recomputed: the results match those of a tree built from scratch
+        let fresh = IntervalTeardownSet::new(set.iter().cloned().collect());
+        for q in 0..1100 {
+            let (mut expected, mut actual) = (vec![], vec![]);
+            fresh.query_overlap(&KeyInterval::new(q, q + 3), &mut expected);
+            set.query_overlap(&KeyInterval::new(q, q + 3), &mut actual);
+            assert_eq!(actual, expected);
+        }
+    }
+
+    #[test]
+    fn auto_compact() {
+        let mut set = TeardownSet::new((0..1024).collect());
+        assert_eq!(set.auto_compact(), None);
+        set.set_auto_compact(Some(0.25));
+
+        let mut output = vec![];
+        set.delete_range(0..700, &mut output);
+        assert_eq!(set.capacity(), 1024);
+        set.delete_range(700..800, &mut output);
+        assert_eq!(set.capacity(), 224);
+
+        for x in 800..1000 {
+            assert!(set.delete(&x));
+        }
+        // compacted once the size fell below 224 / 4, and not again since
+        assert_eq!(set.capacity(), 55);
+        assert_eq!(set.auto_compact(), Some(0.25));
+        assert!(set.iter().cloned().eq(1000..1024));
+
+        let mut ivs = IntervalTeardownSet::new((0..100).map(|x| KeyInterval::new(x, x + 10)).collect());
+        ivs.set_auto_compact(Some(0.5));
+        while ivs.size() > 10 {
+            ivs.pop_first();
+        }
+        assert!(ivs.capacity() < 20);
+        let fresh = IntervalTeardownSet::new((90..100).map(|x| KeyInterval::new(x, x + 10)).collect());
+        let (mut expected, mut actual) = (vec![], vec![]);
+        fresh.query_overlap(&KeyInterval::new(95, 96), &mut expected);
+        ivs.query_overlap(&KeyInterval::new(95, 96), &mut actual);
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn auto_compact_rounded_capacity() {
+        // Veb rounds the capacity up to 2^h - 1, so compacting 100 items out of 127 slots would
+        // yield the same capacity again
+        let mut repr: TreeRepr<PlNode<usize, ()>, Veb> = TreeRepr::with_sorted((0..100).map(|x| (x, ())).collect());
+        repr.set_auto_compact(Some(0.9));
+        assert_eq!(repr.capacity(), 127);
+        assert!(!repr.should_compact());
+
+        let mut set: TeardownSet<usize, Veb> = TeardownSet::with_layout((0..110).collect());
+        set.set_auto_compact(Some(0.9));
+        for x in 0..60 {
+            set.delete(&x);
+        }
+        assert_eq!(set.capacity(), 63);
+        assert!(set.iter().cloned().eq(60..110));
+    }
+
+    #[test]
+    #[should_panic]
+    fn auto_compact_bad_ratio() {
+        TeardownSet::new(vec![1, 2, 3]).set_auto_compact(Some(1.0));
+    }
+}
+
+#[cfg(test)]
+mod test_bitset {
+    use base::bitset::BitSet;
+    use external_api::{TeardownSet, TeardownMap, IntervalTeardownSet, KeyInterval};
+
+
+    #[test]
+    fn bitset_ops() {
+        let mut bits = BitSet::new(200);
+        bits.set_range(60, 130);
+        bits.set(3, true);
+        bits.set(199, true);
+        bits.set(64, false);
+        let expected: Vec<usize> = vec![3].into_iter().chain(60..64).chain(65..130).chain(vec![199]).collect();
+        assert_eq!(bits.ones().collect::<Vec<_>>(), expected);
+        assert_eq!(bits.last_one(), Some(199));
+
+        let mut copy = BitSet::new(200);
+        copy.copy_range_from(&bits, 64, 64);
+        assert_eq!(copy.ones().collect::<Vec<_>>(), (65..128).collect::<Vec<_>>());
+
+        bits.resize(100);
+        assert_eq!(bits.last_one(), Some(99));
+        bits.resize(300);
+        assert_eq!(bits.last_one(), Some(99));
+        assert!(!bits.get(130) && !bits.get(199));
+
+        let mut drained = vec![];
+        bits.drain_ones(|idx| drained.push(idx));
+        assert_eq!(drained.len(), 1 + 4 + 35);
+        assert_eq!(bits.last_one(), None);
+        assert_eq!(BitSet::filled(65).ones().count(), 65);
+    }
+
+    #[test]
+    fn iter_unordered() {
+        let mut map = TeardownMap::new((0..1000).map(|x| (x, x + 1)).collect());
+        let mut output = vec![];
+        map.delete_range(100..900, &mut output);
+        map.insert(500, 0);
+
+        let mut items: Vec<_> = map.iter_unordered().cloned().collect();
+        assert_eq!(map.iter_unordered().len(), map.size());
+        items.sort();
+        assert!(items.iter().eq(map.iter()));
+
+        let set = TeardownSet::new(vec![5, 1, 3]);
+        let mut items: Vec<_> = set.iter_unordered().cloned().collect();
+        items.sort();
+        assert_eq!(items, vec![1, 3, 5]);
+
+        let ivs = IntervalTeardownSet::new((0..100).map(|x| KeyInterval::new(x, x + 2)).collect());
+        assert_eq!(ivs.iter_unordered().count(), 100);
+    }
+}
+
+#[cfg(test)]
+mod test_layout {
+    use base::layout::{Layout, Veb};
+    use base::full_capacity;
+    use external_api::{TeardownSet, TeardownMap, Refill, RefillClone};
+
+    use rand::{Rng, XorShiftRng, SeedableRng};
+
+
+    #[test]
+    fn veb_positions() {
+        let veb = Veb::with_capacity(15);
+        // the top tree {0, 1, 2}, followed by the bottom trees rooted at 3, 4, 5 and 6
+        let expected = vec![0, 1, 2, 3, 6, 9, 12, 4, 5, 7, 8, 10, 11, 13, 14];
+        assert_eq!((0..15).map(|idx| veb.pos(idx)).collect::<Vec<_>>(), expected);
+
+        for height in 0..16 {
+            let capacity = full_capacity(height);
+            assert_eq!(Veb::capacity_for(capacity), capacity);
+            let veb = Veb::with_capacity(capacity);
+            let mut seen = vec![false; capacity];
+            for idx in 0..capacity {
+                let pos = veb.pos(idx);
+                assert!(!seen[pos], "height={}, idx={}", height, idx);
+                seen[pos] = true;
+            }
+        }
+        assert_eq!(Veb::capacity_for(1000), 1023);
+    }
+
+    #[test]
+    fn veb_matches_bfs() {
+        let n = 3000;
+        let items: Vec<_> = (0..n).map(|x| (x, x * 3)).collect();
+        let master = TeardownMap::new(items.clone());
+        let veb_master: TeardownMap<usize, usize, Veb> = TeardownMap::with_layout(items);
+        assert!(veb_master.iter().eq(master.iter()));
+        assert_eq!(veb_master.capacity(), 4095);
+
+        let mut bfs = master.clone();
+        let mut veb = veb_master.clone();
+        bfs.enable_counts();
+        veb.enable_counts();
+
+        let mut rng = XorShiftRng::from_seed([5, 71, 3, 12]);
+        for round in 0..300 {
+            let from = rng.gen_range(0, n);
+            let to = from + rng.gen_range(0, 200);
+            let (mut expected, mut actual) = (vec![], vec![]);
+            match rng.gen_range(0, 6) {
+                0 => {
+                    bfs.delete_range(from..to, &mut expected);
+                    veb.delete_range(from..to, &mut actual);
+                },
+                1 => {
+                    let (mut expected, mut actual) = (vec![], vec![]);
+                    bfs.query_range(from..to, &mut expected);
+                    veb.query_range(from..to, &mut actual);
+                    assert_eq!(actual, expected);
+                },
+                2 => {
+                    for x in from..from + 20 {
+                        bfs.insert(x + n, x);
+                        veb.insert(x + n, x);
+                    }
+                },
+                3 => {
+                    expected.extend(bfs.delete(&from).map(|v| (from, v)));
+                    actual.extend(veb.delete(&from).map(|v| (from, v)));
+                    expected.extend(bfs.pop_first());
+                    actual.extend(veb.pop_first());
+                },
+                4 => {
+                    expected.extend(bfs.drain_range(from..to).take(10));
+                    actual.extend(veb.drain_range(from..to).take(10));
+                },
+                _ => {
+                    if round % 2 == 0 {
+                        bfs.refill_incremental(&master);
+                        veb.refill_incremental(&veb_master);
+                    } else {
+                        bfs.refill_clone(&master);
+                        veb.refill_clone(&veb_master);
+                    }
+                }
+            }
+            assert_eq!(actual, expected);
+            assert!(veb.iter().eq(bfs.iter()), "round={}", round);
+            assert_eq!(veb.size(), bfs.size());
+            let k = rng.gen_range(0, n);
+            assert_eq!(veb.nth(k), bfs.nth(k));
+            assert_eq!(veb.rank(&k), bfs.rank(&k));
+        }
+
+        let mut items: Vec<_> = veb.iter_unordered().cloned().collect();
+        items.sort();
+        assert!(items.iter().eq(bfs.iter()));
+
+        veb.compact();
+        assert_eq!(veb.capacity(), Veb::capacity_for(bfs.size()));
+        assert!(veb.iter().eq(bfs.iter()));
+
+        veb.refill(&veb_master);
+        assert!(veb.into_iter().eq(master.into_iter()));
+    }
+
+    #[test]
+    fn veb_set() {
+        let mut set: TeardownSet<u32, Veb> = TeardownSet::with_sorted_layout((0..100).collect());
+        let mut output = vec![];
+        set.delete_range(10..90, &mut output);
+        assert!(output.into_iter().eq(10..90));
+        assert!(set.iter().cloned().eq((0..10).chain(90..100)));
+
+        let mut empty: TeardownSet<u32, Veb> = TeardownSet::with_layout(vec![]);
+        for x in (0..50).rev() {
+            empty.insert(x);
+        }
+        assert!(empty.iter().cloned().eq(0..50));
+        assert_eq!(empty.capacity(), 127);
+    }
+}
+
+#[cfg(test)]
+mod test_block_set {
+    use block_set::{BlockTeardownSet, BlockKey};
+    use external_api::{TeardownSet, Refill};
+
+    use rand::{Rng, XorShiftRng, SeedableRng};
+    use std::{u32, u64, i64};
+    use std::ops::Bound::{Included, Excluded, Unbounded};
+
+
+    fn check_rank<K: BlockKey>(rng: &mut XorShiftRng, pool: &[K]) {
+        for _ in 0..1000 {
+            let mut keys: Vec<_> = (0..K::LEN).map(|_| pool[rng.gen_range(0, pool.len())]).collect();
+            keys.sort();
+            let mut block = K::filled(K::MAX);
+            K::keys_mut(&mut block).copy_from_slice(&keys);
+
+            for &query in pool.iter() {
+                let expected = keys.iter().filter(|&&key| key < query).count();
+                assert_eq!(K::rank(&block, query), expected, "keys={:?}, query={:?}", keys, query);
+            }
+        }
+    }
+
+    #[test]
+    fn rank() {
+        let mut rng = XorShiftRng::from_seed([3, 1, 4, 1]);
+        let mut pool: Vec<u32> = vec![0, 1, 0x7fff_ffff, 0x8000_0000, 0x8000_0001, u32::MAX - 1, u32::MAX];
+        pool.extend((0..10).map(|_| rng.gen::<u32>()));
+        check_rank(&mut rng, &pool);
+
+        let mut pool: Vec<u64> = vec![0, 1, 0x7fff_ffff_ffff_ffff, 0x8000_0000_0000_0000, u64::MAX - 1, u64::MAX];
+        pool.extend((0..10).map(|_| rng.gen::<u64>()));
+        check_rank(&mut rng, &pool);
+
+        let mut pool: Vec<i64> = vec![i64::MIN, i64::MIN + 1, -1, 0, 1, i64::MAX - 1, i64::MAX];
+        pool.extend((0..10).map(|_| rng.gen::<i64>()));
+        check_rank(&mut rng, &pool);
+    }
+
+    #[test]
+    fn range_bounds() {
+        // duplicates spanning several blocks, and keys equal to the padding
+        let items: Vec<u32> = (0..200).map(|x| x / 20 * 3).chain(vec![u32::MAX; 5]).collect();
+        let set = TeardownSet::new(items.clone());
+        let block_set = BlockTeardownSet::new(items);
+
+        let bounds = |x: u32| vec![Included(x), Excluded(x), Unbounded];
+        let keys: Vec<u32> = (0..32).chain(vec![u32::MAX - 1, u32::MAX]).collect();
+        for &a in keys.iter() {
+            for &b in keys.iter() {
+                for start in bounds(a) {
+                    for end in bounds(b) {
+                        let (mut expected, mut actual) = (vec![], vec![]);
+                        set.query_range((start, end), &mut expected);
+                        block_set.query_range((start, end), &mut actual);
+                        assert_eq!(actual, expected, "{:?}", (start, end));
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn matches_teardown_set() {
+        let n = 5000;
+        let mut rng = XorShiftRng::from_seed([8, 6, 7, 5]);
+        let mut items: Vec<u64> = (0..n).map(|_| rng.gen_range(0, n / 2)).collect();
+        items.extend(vec![u64::MAX, u64::MAX]);
+        let master = TeardownSet::new(items.clone());
+        let block_master = BlockTeardownSet::new(items);
+        assert!(block_master.iter().eq(master.iter()));
+        assert_eq!(block_master.size(), master.size());
+
+        let mut set = master.clone();
+        let mut block = block_master.clone();
+        for round in 0..300 {
+            let from = rng.gen_range(0, n / 2);
+            let to = from + rng.gen_range(0, 100);
+            let (mut expected, mut actual) = (vec![], vec![]);
+            match rng.gen_range(0, 5) {
+                0 => {
+                    set.delete_range(from..to, &mut expected);
+                    block.delete_range(from..to, &mut actual);
+                },
+                1 => {
+                    let (mut expected, mut actual) = (vec![], vec![]);
+                    set.query_range(from..=to, &mut expected);
+                    block.query_range(from..=to, &mut actual);
+                    assert_eq!(actual, expected);
+                },
+                2 => {
+                    for x in from..to {
+                        assert_eq!(block.delete(&x), set.delete(&x), "round={}, x={}", round, x);
+                    }
+                    assert_eq!(block.delete(&u64::MAX), set.delete(&u64::MAX));
+                },
+                3 => {
+                    set.delete_range::<u64, _, _>(.., &mut expected);
+                    block.delete_range(.., &mut actual);
+                },
+                _ => {
+                    set.refill(&master);
+                    block.refill(&block_master);
+                }
+            }
+            assert_eq!(actual, expected);
+            assert!(block.iter().eq(set.iter()), "round={}", round);
+            assert_eq!(block.size(), set.size());
+            for x in from..to {
+                assert_eq!(block.contains(&x), set.contains(&x), "round={}, x={}", round, x);
+            }
+            assert_eq!(block.contains(&u64::MAX), set.contains(&u64::MAX));
+        }
+
+        let mut other = BlockTeardownSet::new(vec![1, 2, 3]);
+        other.refill(&block_master);
+        assert!(other.iter().eq(master.iter()));
+    }
+
+    #[test]
+    fn signed_and_u32_keys() {
+        let mut set: BlockTeardownSet<i64> = BlockTeardownSet::new((-500..500).rev().chain(vec![i64::MIN, i64::MAX]).collect());
+        let mut output = vec![];
+        set.delete_range(-10..10, &mut output);
+        assert!(output.into_iter().eq(-10..10));
+        assert!(set.contains(&-11) && set.contains(&10) && !set.contains(&0));
+        assert!(set.contains(&i64::MIN) && set.contains(&i64::MAX));
+        assert_eq!(set.size(), 982);
+        assert_eq!(format!("{:?}", set.iter().take(3).collect::<Vec<_>>()), "[-9223372036854775808, -500, -499]");
+
+        let mut set: BlockTeardownSet<u32> = BlockTeardownSet::with_sorted(vec![0, 5, u32::MAX - 1, u32::MAX]);
+        assert_eq!(set.capacity(), 16);
+        assert!(set.contains(&u32::MAX) && !set.contains(&6));
+        assert!(set.delete(&u32::MAX) && !set.delete(&u32::MAX));
+        assert_eq!(format!("{:?}", set), "{0, 5, 4294967294}");
+        set.clear();
+        assert!(set.is_empty() && !set.contains(&0) && set.iter().next().is_none());
+
+        let mut empty: BlockTeardownSet<u32> = BlockTeardownSet::new(vec![]);
+        empty.delete_range(.., &mut vec![]);
+        assert!(empty.is_empty() && !empty.contains(&0) && !empty.delete(&0));
+    }
+}
+
+#[cfg(test)]
+mod test_split_map {
+    use split_map::SplitTeardownMap;
+    use external_api::{TeardownMap, Refill, RefillClone};
+
+    use rand::{Rng, XorShiftRng, SeedableRng};
+    use std::rc::Rc;
+
+
+    type Val = [usize; 8];
+
+    fn val(x: usize) -> Val {
+        [x; 8]
+    }
+
+    #[test]
+    fn matches_teardown_map() {
+        let n = 2000;
+        let mut rng = XorShiftRng::from_seed([2, 7, 1, 8]);
+        let items: Vec<_> = (0..n).map(|_| { let x = rng.gen_range(0, n); (x, val(x)) }).collect();
+        let master = TeardownMap::new(items.clone());
+        let split_master = SplitTeardownMap::new(items);
+        assert!(split_master.iter().eq(master.iter().map(|&(ref k, ref v)| (k, v))));
+
+        let mut map = master.clone();
+        let mut split = split_master.clone();
+        for round in 0..300 {
+            let from = rng.gen_range(0, n);
+            let to = from + rng.gen_range(0, 100);
+            let (mut expected, mut actual) = (vec![], vec![]);
+            match rng.gen_range(0, 7) {
+                0 => {
+                    map.delete_range(from..to, &mut expected);
+                    split.delete_range(from..to, &mut actual);
+                },
+                1 => {
+                    let (mut expected, mut actual) = (vec![], vec![]);
+                    map.query_range(from..to, &mut expected);
+                    split.query_range(from..to, &mut actual);
+                    assert!(actual.into_iter().eq(expected.into_iter().map(|&(ref k, ref v)| (k, v))));
+                },
+                2 => {
+                    for x in from..from + 10 {
+                        map.insert(x, val(x + 1));
+                        split.insert(x, val(x + 1));
+                    }
+                },
+                3 => {
+                    expected.extend(map.delete(&from).map(|v| (from, v)));
+                    actual.extend(split.delete(&from).map(|v| (from, v)));
+                    expected.extend(map.pop_first().into_iter().chain(map.pop_last()));
+                    actual.extend(split.pop_first().into_iter().chain(split.pop_last()));
+                },
+                4 => {
+                    if let Some(v) = map.get_mut(&from) { v[0] = round; }
+                    if let Some(v) = split.get_mut(&from) { v[0] = round; }
+                },
+                5 => {
+                    map.refill(&master);
+                    split.refill_incremental(&split_master);
+                },
+                _ => {
+                    map.refill(&master);
+                    split.refill(&split_master);
+                }
+            }
+            assert_eq!(actual, expected);
+            assert!(split.iter().eq(map.iter().map(|&(ref k, ref v)| (k, v))), "round={}", round);
+            assert!(split.iter().rev().eq(map.iter().rev().map(|&(ref k, ref v)| (k, v))));
+            assert_eq!(split.size(), map.size());
+            assert_eq!(split.find(&from), map.find(&from));
+            assert_eq!(split.contains_key(&to), map.contains_key(&to));
+        }
+    }
+
+    #[test]
+    fn drops_values() {
+        let rc = Rc::new(());
+        {
+            let items: Vec<_> = (0..100).map(|x| (x, rc.clone())).collect();
+            let master = SplitTeardownMap::new(items);
+            let mut split = master.clone();
+            assert_eq!(Rc::strong_count(&rc), 201);
+
+            let mut output = vec![];
+            split.delete_range(10..60, &mut output);
+            assert_eq!(output.len(), 50);
+            drop(output);
+            assert!(split.delete(&5).is_some() && split.delete(&5).is_none());
+            split.insert(200, rc.clone());
+            split.insert(201, rc.clone());
+            assert_eq!(Rc::strong_count(&rc), 152);
+
+            split.refill_clone(&master);
+            assert_eq!(Rc::strong_count(&rc), 201);
+            assert_eq!(split.size(), 100);
+
+            split.delete_range(..50, &mut vec![]);
+            split.clear();
+            assert!(split.is_empty() && split.iter().next().is_none());
+            assert_eq!(Rc::strong_count(&rc), 101);
+            split.insert(1, rc.clone());
+            assert_eq!(format!("{:?}", split), "{1: ()}");
+        }
+        assert_eq!(Rc::strong_count(&rc), 1);
+    }
+
+    #[test]
+    fn refill_from_master_with_free_slots() {
+        // the deleted items leave slots without values in the master
+        let mut master = SplitTeardownMap::new((0..100).map(|x| (x, val(x))).collect());
+        master.delete_range(20..70, &mut vec![]);
+        master.insert(500, val(500));
+
+        let mut split = SplitTeardownMap::new(vec![(1, val(1))]);
+        for _ in 0..3 {
+            split.refill(&master);
+            assert!(split.iter().eq(master.iter()));
+            split.delete_range(..90, &mut vec![]);
+            split.insert(5, val(6));
+            split.refill_incremental(&master);
+            assert!(split.iter().eq(master.iter()));
+            assert_eq!(split.delete(&500), Some(val(500)));
+        }
+
+        master.clear();
+        master.insert(3, val(3));
+        split.refill(&master);
+        assert!(split.iter().eq(master.iter()));
+    }
+
+    #[test]
+    fn refill_incremental_from_other_master() {
+        let master = SplitTeardownMap::new((0..100).map(|x| (x, val(x))).collect());
+        let other = SplitTeardownMap::new((0..100).map(|x| (x, val(x + 1))).collect());
+
+        // the values of the copy have not been written, but they differ from those of `other`
+        let mut split = master.clone();
+        split.refill_incremental(&other);
+        assert!(split.iter().eq(other.iter()));
+    }
+}
+
+#[cfg(test)]
+mod common {
+    use base::validation::{check_bst_del_range, check_integrity_del_range};
+    use base::{Node, TreeRepr, TreeDeref, Traverse, ItemFilter, lefti, righti};
+    use applied::AppliedTree;
+    use applied::interval::{Interval, KeyInterval};
+    use applied::plain_tree::{PlNode};
+
+    use std::fmt::{Debug, Display};
+    use std::ops::{Range, Bound};
+    use std::ops::Bound::{Included, Excluded, Unbounded};
+    use std::cmp;
+
+    //---- exhaustive testing ----------------------------------------------------------------------
+    #[derive(Debug)]
+    struct TreeRangeInfo {
+        range: Range<usize>,
+        root_idx: usize
+    }
+
+    pub fn test_exhaustive_items<N: Node, Tree: AppliedTree<N>, F>(items: &mut Vec<Option<(N::K, N::V)>>, check: &F)
+        where N::K: Clone, N::V: Clone,
+              F: Fn(Tree) -> ()
+    {
+        let n = items.len();
+        let mut stack = vec![TreeRangeInfo { range: (0..n), root_idx: 0 }];
+        let mut shape = vec![None; 1 << n];
+        test_exhaustive_rec::<_, Tree, _>(items, &mut shape, &mut stack, check)
+    }
+
+    fn test_exhaustive_rec<N: Node, Tree: AppliedTree<N>, F>(items: &mut Vec<Option<(N::K, N::V)>>, shape: &mut Vec<Option<(N::K, N::V)>>,
+                                                             stack: &mut Vec<TreeRangeInfo>, check: &F)
+        where N::K: Clone, N::V: Clone,
+              F: Fn(Tree) -> ()
+    {
         if stack.is_empty() {
             let tree = unsafe { Tree::with_shape(shape.clone()) };
             check(tree);
@@ -1004,6 +3422,41 @@ mod common {
         nodes
     }
 
+    /// Returns all combinations of bounds over `0..n` (except the `a..a` ranges, which are treated
+    /// as points and are covered by other tests).
+    pub fn mk_bounds(n: usize) -> Vec<(Bound<usize>, Bound<usize>)> {
+        let mut bounds = vec![Unbounded];
+        for i in 0..n {
+            bounds.push(Included(i));
+            bounds.push(Excluded(i));
+        }
+
+        let mut result = vec![];
+        for &start in bounds.iter() {
+            for &end in bounds.iter() {
+                match (start, end) {
+                    (Included(a), Excluded(b)) if a == b => {},
+                    _ => result.push((start, end))
+                }
+            }
+        }
+        result
+    }
+
+    pub fn bounds_contain(bounds: &(Bound<usize>, Bound<usize>), x: &usize) -> bool {
+        let above_start = match bounds.0 {
+            Included(a) => a <= *x,
+            Excluded(a) => a < *x,
+            Unbounded => true
+        };
+        let below_end = match bounds.1 {
+            Included(b) => *x <= b,
+            Excluded(b) => *x < b,
+            Unbounded => true
+        };
+        above_start && below_end
+    }
+
     pub fn conv_from_tuple_vec<K>(items: &mut Vec<(K, ())>) -> &mut Vec<K> {
         use std::mem;
         unsafe { mem::transmute(items) }
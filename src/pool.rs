@@ -0,0 +1,223 @@
+//! A pool of copies of a shared master tree, for the clone → teardown → refill pattern.
+//!
+//! `TeardownPool::get()` hands out a guard holding a copy that has the same contents as the master.
+//! When the guard is dropped, the copy goes back to the pool and is refilled from the master with
+//! `Refill::refill()`, either lazily (by the next `get()` that picks it up) or on a background
+//! thread. The storage of the copies, including their `DeleteRangeCache`s, is reused across
+//! rounds, so after warm-up the pool does not allocate.
+
+use base::Refill;
+
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Weak, Mutex, Condvar};
+use std::sync::mpsc::{self, Sender, Receiver};
+use std::thread;
+
+
+pub struct TeardownPool<T> {
+    shared: Arc<Shared<T>>,
+}
+
+struct Shared<T> {
+    master: Arc<T>,
+    idle: Mutex<Idle<T>>,
+    refilled: Condvar,
+    /// The channel to the background thread, if there is one.
+    refiller: Option<Mutex<Sender<T>>>,
+}
+
+struct Idle<T> {
+    /// Copies that have the same contents as the master.
+    ready: Vec<T>,
+    /// Copies that have been returned, but not refilled yet.
+    stale: Vec<T>,
+    /// The number of copies being refilled on the background thread.
+    pending: usize,
+    /// Set when the background thread has exited because a refill panicked.
+    refiller_gone: bool,
+}
+
+impl<T: Refill+Clone> TeardownPool<T> {
+    /// Creates a pool whose copies are refilled lazily, on the thread that calls `get()`.
+    pub fn new(master: Arc<T>) -> TeardownPool<T> {
+        TeardownPool { shared: Arc::new(Shared::new(master, None)) }
+    }
+
+    /// Returns a guard holding a copy with the same contents as the master. The copy is taken
+    /// from the idle copies if there are any (waiting for a background refill in progress if
+    /// needed); otherwise, a new one is cloned from the master.
+    pub fn get(&self) -> PoolGuard<T> {
+        let shared = &self.shared;
+        let mut idle = shared.idle.lock().unwrap();
+        let copy = loop {
+            if let Some(copy) = idle.ready.pop() {
+                break copy;
+            }
+            if let Some(mut copy) = idle.stale.pop() {
+                drop(idle);
+                copy.refill(&shared.master);
+                break copy;
+            }
+            if idle.pending == 0 {
+                drop(idle);
+                break (*shared.master).clone();
+            }
+            idle = shared.refilled.wait(idle).unwrap();
+        };
+        PoolGuard { shared: shared.clone(), copy: Some(copy) }
+    }
+
+    /// Clones copies from the master until there are at least `n` idle copies. The count is
+    /// checked again under the lock before each copy is added, so concurrent calls do not
+    /// overshoot `n`.
+    pub fn reserve(&self, n: usize) {
+        while self.idle() < n {
+            // the master is cloned without holding the lock, so `get()` is not blocked meanwhile
+            let copy = (*self.shared.master).clone();
+            let mut idle = self.shared.idle.lock().unwrap();
+            if idle.count() >= n {
+                return;
+            }
+            idle.ready.push(copy);
+        }
+    }
+}
+
+impl<T: Refill+Clone+Send+Sync+'static> TeardownPool<T> {
+    /// Creates a pool whose copies are refilled on a background thread as soon as they are
+    /// returned. The thread exits when the pool and all its guards have been dropped.
+    pub fn with_background_refill(master: Arc<T>) -> TeardownPool<T> {
+        let (sender, receiver) = mpsc::channel();
+        let shared = Arc::new(Shared::new(master, Some(Mutex::new(sender))));
+        let weak = Arc::downgrade(&shared);
+        thread::spawn(move || refill_loop(receiver, weak));
+        TeardownPool { shared: shared }
+    }
+}
+
+impl<T> TeardownPool<T> {
+    #[inline]
+    pub fn master(&self) -> &Arc<T> {
+        &self.shared.master
+    }
+
+    /// Returns the number of copies currently held by the pool, including the ones waiting to be
+    /// refilled.
+    pub fn idle(&self) -> usize {
+        self.shared.idle.lock().unwrap().count()
+    }
+}
+
+impl<T> Idle<T> {
+    #[inline]
+    fn count(&self) -> usize {
+        self.ready.len() + self.stale.len() + self.pending
+    }
+}
+
+impl<T> Shared<T> {
+    fn new(master: Arc<T>, refiller: Option<Mutex<Sender<T>>>) -> Shared<T> {
+        Shared {
+            master: master,
+            idle: Mutex::new(Idle { ready: Vec::new(), stale: Vec::new(), pending: 0, refiller_gone: false }),
+            refilled: Condvar::new(),
+            refiller: refiller,
+        }
+    }
+
+    fn put_back(&self, copy: T) {
+        let mut idle = self.idle.lock().unwrap();
+        match self.refiller {
+            // `pending` is incremented under the lock, so the background thread cannot decrement
+            // it first. If the thread is gone (a refill has panicked), the copy is refilled lazily.
+            Some(ref sender) if !idle.refiller_gone => match sender.lock().unwrap().send(copy) {
+                Ok(()) => idle.pending += 1,
+                Err(mpsc::SendError(copy)) => idle.stale.push(copy),
+            },
+            _ => idle.stale.push(copy),
+        }
+    }
+}
+
+fn refill_loop<T: Refill>(receiver: Receiver<T>, shared: Weak<Shared<T>>) {
+    // The loop ends when the sender, owned by `Shared`, is dropped.
+    while let Ok(mut copy) = receiver.recv() {
+        if let Some(shared) = shared.upgrade() {
+            let mut pending = PendingRefill { shared: &shared, receiver: &receiver, refilled: None };
+            copy.refill(&shared.master);
+            pending.refilled = Some(copy);
+        }
+    }
+}
+
+/// Accounts for a copy being refilled on the background thread. When dropped, it puts the
+/// refilled copy into the pool and decrements `pending`. If the refill panics, the copy is lost,
+/// but `pending` is still decremented, so `get()` does not wait for it forever; since the thread
+/// is about to exit, the copies still in the channel are moved to `stale` to be refilled lazily.
+struct PendingRefill<'a, T: 'a> {
+    shared: &'a Shared<T>,
+    receiver: &'a Receiver<T>,
+    refilled: Option<T>,
+}
+
+impl<'a, T> Drop for PendingRefill<'a, T> {
+    fn drop(&mut self) {
+        // A poisoned lock only means that another thread panicked while holding it; the counts
+        // are still consistent, and panicking here while unwinding would abort.
+        let mut idle = self.shared.idle.lock().unwrap_or_else(|e| e.into_inner());
+        idle.pending -= 1;
+        match self.refilled.take() {
+            Some(copy) => {
+                idle.ready.push(copy);
+                self.shared.refilled.notify_one();
+            },
+            None => {
+                // `put_back()` sends under the lock, so no copy can be sent after this
+                idle.refiller_gone = true;
+                while let Ok(copy) = self.receiver.try_recv() {
+                    idle.pending -= 1;
+                    idle.stale.push(copy);
+                }
+                // every waiting `get()` has to check whether it can proceed now
+                self.shared.refilled.notify_all();
+            },
+        }
+    }
+}
+
+
+/// A copy handed out by `TeardownPool::get()`. It dereferences to the copy and returns it to the
+/// pool when dropped.
+pub struct PoolGuard<T> {
+    shared: Arc<Shared<T>>,
+    copy: Option<T>,
+}
+
+impl<T> PoolGuard<T> {
+    /// Takes the copy out of the pool for good.
+    pub fn into_inner(mut self) -> T {
+        self.copy.take().unwrap()
+    }
+}
+
+impl<T> Deref for PoolGuard<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.copy.as_ref().unwrap()
+    }
+}
+
+impl<T> DerefMut for PoolGuard<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.copy.as_mut().unwrap()
+    }
+}
+
+impl<T> Drop for PoolGuard<T> {
+    fn drop(&mut self) {
+        if let Some(copy) = self.copy.take() {
+            self.shared.put_back(copy);
+        }
+    }
+}
@@ -0,0 +1,174 @@
+//! Serde support (enabled by the `serde` feature).
+//!
+//! The collections are serialized in their internal layout: the slot mask followed by the nodes
+//! occupying the slots, in slot order. `IvNode`s carry their `maxb` along. Deserialization places
+//! the nodes back into their slots, so no sorting or rebuilding takes place; the order of the keys
+//! is checked, and the interval trees recompute `maxb` (in O(n) time). The public collections
+//! delegate to the impls below (see `external_api`).
+
+use serde::{Serialize, Serializer, Deserialize, Deserializer};
+use serde::ser::{SerializeSeq, SerializeStruct, SerializeTuple};
+use serde::de::{self, Visitor, SeqAccess, MapAccess};
+
+use base::{TreeRepr, Node, Entry};
+use applied::plain_tree::PlNode;
+use applied::interval::{Interval, KeyInterval, IvNode};
+
+use std::fmt;
+use std::marker::PhantomData;
+
+
+//---- intervals -----------------------------------------------------------------------------------
+impl<K: Ord+Clone+Serialize> Serialize for KeyInterval<K> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        (self.a(), self.b()).serialize(serializer)
+    }
+}
+
+impl<'de, K: Ord+Clone+Deserialize<'de>> Deserialize<'de> for KeyInterval<K> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let (a, b) = <(K, K)>::deserialize(deserializer)?;
+        Ok(KeyInterval::new(a, b))
+    }
+}
+
+
+//---- nodes ---------------------------------------------------------------------------------------
+impl<K: Ord+Clone+Serialize, V: Serialize> Serialize for PlNode<K, V> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut tuple = serializer.serialize_tuple(2)?;
+        tuple.serialize_element(self.key())?;
+        tuple.serialize_element(self.val())?;
+        tuple.end()
+    }
+}
+
+impl<'de, K: Ord+Clone+Deserialize<'de>, V: Deserialize<'de>> Deserialize<'de> for PlNode<K, V> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let (key, val) = <(K, V)>::deserialize(deserializer)?;
+        Ok(PlNode { entry: Entry::new(key, val) })
+    }
+}
+
+impl<Iv, V> Serialize for IvNode<Iv, V>
+    where Iv: Interval+Serialize, Iv::K: Serialize, V: Serialize
+{
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut tuple = serializer.serialize_tuple(3)?;
+        tuple.serialize_element(self.key())?;
+        tuple.serialize_element(self.val())?;
+        tuple.serialize_element(&self.maxb)?;
+        tuple.end()
+    }
+}
+
+impl<'de, Iv, V> Deserialize<'de> for IvNode<Iv, V>
+    where Iv: Interval+Deserialize<'de>, Iv::K: Deserialize<'de>, V: Deserialize<'de>
+{
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let (key, val, maxb) = <(Iv, V, Iv::K)>::deserialize(deserializer)?;
+        Ok(IvNode { entry: Entry::new(key, val), maxb: maxb })
+    }
+}
+
+
+//---- TreeRepr ------------------------------------------------------------------------------------
+const FIELDS: &'static [&'static str] = &["mask", "nodes"];
+
+struct MaskSer<'a, N: Node+'a>(&'a TreeRepr<N>);
+
+impl<'a, N: Node> Serialize for MaskSer<'a, N> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let repr = self.0;
+        let mut seq = serializer.serialize_seq(Some(repr.capacity()))?;
+        for i in 0..repr.capacity() {
+            seq.serialize_element(&repr.mask(i))?;
+        }
+        seq.end()
+    }
+}
+
+struct NodesSer<'a, N: Node+'a>(&'a TreeRepr<N>);
+
+impl<'a, N: Node+Serialize> Serialize for NodesSer<'a, N> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let repr = self.0;
+        let mut seq = serializer.serialize_seq(Some(repr.size()))?;
+        for i in 0..repr.capacity() {
+            if repr.mask(i) {
+                seq.serialize_element(repr.node(i))?;
+            }
+        }
+        seq.end()
+    }
+}
+
+impl<N: Node+Serialize> Serialize for TreeRepr<N> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut state = serializer.serialize_struct("TreeRepr", 2)?;
+        state.serialize_field("mask", &MaskSer(self))?;
+        state.serialize_field("nodes", &NodesSer(self))?;
+        state.end()
+    }
+}
+
+impl<'de, N: Node+Deserialize<'de>> Deserialize<'de> for TreeRepr<N> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_struct("TreeRepr", FIELDS, ReprVisitor { _ph: PhantomData })
+    }
+}
+
+struct ReprVisitor<N> {
+    _ph: PhantomData<N>
+}
+
+impl<'de, N: Node+Deserialize<'de>> Visitor<'de> for ReprVisitor<N> {
+    type Value = TreeRepr<N>;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a teardown tree")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+        let mask = seq.next_element()?.ok_or_else(|| de::Error::invalid_length(0, &self))?;
+        let nodes = seq.next_element()?.ok_or_else(|| de::Error::invalid_length(1, &self))?;
+        build_repr(mask, nodes)
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+        let mut mask = None;
+        let mut nodes = None;
+        while let Some(field) = map.next_key::<String>()? {
+            match field.as_str() {
+                "mask" if mask.is_none()   => mask = Some(map.next_value()?),
+                "nodes" if nodes.is_none() => nodes = Some(map.next_value()?),
+                "mask" | "nodes"           => return Err(de::Error::custom(format!("duplicate field `{}`", field))),
+                _                          => return Err(de::Error::unknown_field(&field, FIELDS)),
+            }
+        }
+        let mask = mask.ok_or_else(|| de::Error::missing_field("mask"))?;
+        let nodes = nodes.ok_or_else(|| de::Error::missing_field("nodes"))?;
+        build_repr(mask, nodes)
+    }
+}
+
+fn build_repr<N: Node, E: de::Error>(mask: Vec<bool>, nodes: Vec<N>) -> Result<TreeRepr<N>, E> {
+    let repr = TreeRepr::with_raw_parts(mask, nodes).map_err(E::custom)?;
+
+    // the slots are taken as is, but checking the order is cheap compared to sorting
+    let sorted = {
+        let mut iter = repr.iter();
+        let mut prev = iter.next();
+        iter.all(|entry| {
+            let ok = prev.map_or(true, |p| p.key() <= entry.key());
+            prev = Some(entry);
+            ok
+        })
+    };
+    if !sorted {
+        return Err(E::custom("the nodes are not in order"));
+    }
+
+    Ok(repr)
+}
+
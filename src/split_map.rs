@@ -0,0 +1,339 @@
+//! A map that keeps the values apart from the keys.
+//!
+//! `SplitTeardownMap` is a `TeardownMap` whose tree holds only the keys, each with the handle of its
+//! value. The values live in a separate array, indexed by handle. The searches and the
+//! rebalancing moves of the tree only touch the keys and the handles, which matters when the values
+//! are much larger than the keys: a value is not moved when its key is relocated, only when it is
+//! deleted from the map (and handed to the caller). The handles are assigned in key order when the
+//! map is built, so the values of the items deleted by a `delete_range` are read sequentially.
+
+use base::{Refill, RefillClone, Sink};
+use base::bitset::BitSet;
+use external_api::TeardownMap;
+use external_api::iter::MapIter;
+
+use std::fmt;
+use std::fmt::Debug;
+use std::mem::MaybeUninit;
+use std::ops::RangeBounds;
+
+
+pub struct SplitTeardownMap<K: Ord+Clone, V> {
+    /// The keys, each with the handle of its value.
+    keys: TeardownMap<K, usize>,
+    /// The values, indexed by handle. Only the slots whose handles are in `keys` hold values; the
+    /// others are uninitialized.
+    vals: Vec<MaybeUninit<V>>,
+    /// The handles of the uninitialized slots of `vals`, reused by `insert()`.
+    free: Vec<usize>,
+    /// The handles whose values have been overwritten since the last refill (see
+    /// `refill_incremental()`). Grows on demand.
+    written: BitSet,
+}
+
+impl<K: Ord+Clone, V> SplitTeardownMap<K, V> {
+    /// Creates a new `SplitTeardownMap` with the given set of items. The items can be given in
+    /// any order. Duplicate keys are supported.
+    pub fn new(mut items: Vec<(K, V)>) -> SplitTeardownMap<K, V> {
+        items.sort_by(|a, b| a.0.cmp(&b.0));
+        Self::with_sorted(items)
+    }
+
+    /// Creates a new `SplitTeardownMap` with the given set of items. Duplicate keys are supported.
+    /// **Note**: the items are assumed to be sorted!
+    pub fn with_sorted(sorted: Vec<(K, V)>) -> SplitTeardownMap<K, V> {
+        let mut keys = Vec::with_capacity(sorted.len());
+        let mut vals = Vec::with_capacity(sorted.len());
+        for (handle, (key, val)) in sorted.into_iter().enumerate() {
+            keys.push((key, handle));
+            vals.push(MaybeUninit::new(val));
+        }
+
+        SplitTeardownMap { keys: TeardownMap::with_sorted(keys), vals: vals, free: Vec::new(), written: BitSet::new(0) }
+    }
+
+    /// Finds the item with the given key and returns it (or None).
+    #[inline]
+    pub fn find<'a, Q>(&'a self, query: &'a Q) -> Option<&'a V>
+        where Q: PartialOrd<K>
+    {
+        // This is safe: the handle is in `keys`, so its slot holds a value.
+        self.keys.find(query).map(|&handle| unsafe { self.vals[handle].assume_init_ref() })
+    }
+
+    /// Finds the item with the given key and returns a mutable reference to its value (or None).
+    #[inline]
+    pub fn get_mut<'a, Q>(&'a mut self, query: &Q) -> Option<&'a mut V>
+        where Q: PartialOrd<K>
+    {
+        match self.keys.get_mut(query) {
+            Some(&mut handle) => {
+                self.mark_written(handle);
+                // This is safe: see find().
+                Some(unsafe { self.vals[handle].assume_init_mut() })
+            },
+            None => None
+        }
+    }
+
+    /// Returns true if the map contains the given key.
+    #[inline]
+    pub fn contains_key<Q>(&self, query: &Q) -> bool
+        where Q: PartialOrd<K>
+    {
+        self.keys.contains_key(query)
+    }
+
+    /// Executes a range query and feeds references to the matching keys and values into `sink`.
+    /// The items are returned in order.
+    #[inline]
+    pub fn query_range<'a, Q, R, S>(&'a self, range: R, sink: S)
+        where Q: PartialOrd<K>+Clone,
+              R: RangeBounds<Q>,
+              S: Sink<(&'a K, &'a V)>
+    {
+        self.keys.query_range(range, RefVals { vals: &self.vals, sink: sink })
+    }
+
+    /// Inserts the item into the map. Duplicate keys are supported. The value is placed into an
+    /// unused slot of the value array if there is one (see `TeardownMap::insert()` for the keys).
+    pub fn insert(&mut self, key: K, val: V) {
+        let handle = match self.free.pop() {
+            Some(handle) => {
+                self.vals[handle] = MaybeUninit::new(val);
+                self.mark_written(handle);
+                handle
+            },
+            None => {
+                self.vals.push(MaybeUninit::new(val));
+                self.vals.len() - 1
+            }
+        };
+        self.keys.insert(key, handle);
+    }
+
+    /// Deletes the item with the given key from the map and returns its value (or None).
+    #[inline]
+    pub fn delete<Q>(&mut self, query: &Q) -> Option<V>
+        where Q: PartialOrd<K>
+    {
+        self.keys.delete(query).map(|handle| self.take_val(handle))
+    }
+
+    /// Deletes the smallest item from the map and returns it (or None if the map is empty).
+    #[inline]
+    pub fn pop_first(&mut self) -> Option<(K, V)> {
+        self.keys.pop_first().map(|(key, handle)| (key, self.take_val(handle)))
+    }
+
+    /// Deletes the largest item from the map and returns it (or None if the map is empty).
+    #[inline]
+    pub fn pop_last(&mut self) -> Option<(K, V)> {
+        self.keys.pop_last().map(|(key, handle)| (key, self.take_val(handle)))
+    }
+
+    /// Deletes all items inside `range` from the map and feeds them into `sink`.
+    /// The items are returned in order.
+    #[inline]
+    pub fn delete_range<Q, R, S>(&mut self, range: R, sink: S)
+        where Q: PartialOrd<K>+Clone, R: RangeBounds<Q>, S: Sink<(K, V)>
+    {
+        let take_vals = TakeVals { vals: &self.vals, free: &mut self.free, sink: sink };
+        self.keys.delete_range(range, take_vals)
+    }
+
+    /// Returns the number of items in this map.
+    #[inline] pub fn size(&self) -> usize { self.keys.size() }
+
+    #[inline] pub fn is_empty(&self) -> bool { self.size() == 0 }
+
+    /// Removes all items from the map (the items are dropped, but the internal storage is not).
+    pub fn clear(&mut self) {
+        self.drop_vals();
+        self.keys.clear();
+        self.free.clear();
+        self.written.clear();
+    }
+
+    /// Creates an iterator into the map.
+    #[inline]
+    pub fn iter<'a>(&'a self) -> SplitMapIter<'a, K, V> {
+        SplitMapIter { keys: self.keys.iter(), vals: &self.vals }
+    }
+
+
+    #[inline]
+    fn mark_written(&mut self, handle: usize) {
+        if handle >= self.written.len() {
+            self.written.resize(self.vals.len());
+        }
+        self.written.set(handle, true);
+    }
+
+    /// Moves the value out of the slot of `handle`, which has just been deleted from `keys`.
+    #[inline]
+    fn take_val(&mut self, handle: usize) -> V {
+        self.free.push(handle);
+        // This is safe: the slot held the value of the handle, which is no longer in `keys`, so the
+        // slot is considered uninitialized from now on and the value is not read again.
+        unsafe { self.vals[handle].assume_init_read() }
+    }
+
+    /// Drops the values of the items in the map and empties the value array, keeping the storage.
+    fn drop_vals(&mut self) {
+        for &(_, handle) in self.keys.iter_unordered() {
+            // This is safe: the handle is in `keys`, and `vals` is cleared right below.
+            unsafe { self.vals[handle].assume_init_drop(); }
+        }
+        self.vals.clear();
+    }
+
+    /// Fills the value array, which must be empty, with clones of the values of `master`, keeping
+    /// their handles. `keys` must be empty as well and is only filled by the caller afterwards, so
+    /// that a panicking `clone()` does not leave handles to uninitialized slots in it.
+    fn clone_vals_from(&mut self, master: &Self) where V: Clone {
+        debug_assert!(self.vals.is_empty() && self.keys.is_empty());
+        self.copy_live_vals_from(master, |val| val.clone());
+    }
+
+    /// Resizes the value array to the size of `master`'s and writes `f(val)` for every value of
+    /// `master` into the slot with the same handle. The old contents of `vals` are discarded
+    /// without being dropped. The slots of the handles not in `master.keys` are left uninitialized,
+    /// so they are never read.
+    fn copy_live_vals_from<F: Fn(&V) -> V>(&mut self, master: &Self, f: F) {
+        self.vals.clear();
+        self.vals.resize_with(master.vals.len(), MaybeUninit::uninit);
+        for &(_, handle) in master.keys.iter_unordered() {
+            // This is safe: the handle is in `master.keys`, so its slot holds a value.
+            self.vals[handle] = MaybeUninit::new(f(unsafe { master.vals[handle].assume_init_ref() }));
+        }
+        self.free.clone_from(&master.free);
+        self.written.clear();
+    }
+}
+
+
+impl<K: Ord+Clone+Copy, V: Copy> Refill for SplitTeardownMap<K, V> {
+    /// Only the values of the items of `master` are copied: the other slots are uninitialized.
+    fn refill(&mut self, master: &Self) {
+        self.keys.refill(&master.keys);
+        self.copy_live_vals_from(master, |&val| val);
+    }
+
+    /// Deleting an item does not modify its value (it is copied out), so only the values written by
+    /// `insert()` and `get_mut()` since the last refill are restored. The slots are copied as
+    /// `MaybeUninit`, so copying the uninitialized ones is fine.
+    fn refill_incremental(&mut self, master: &Self) {
+        if self.vals.len() < master.vals.len() || !self.keys.is_copy_of(&master.keys) {
+            return self.refill(master);
+        }
+
+        self.keys.refill_incremental(&master.keys);
+        self.vals.truncate(master.vals.len());
+        for handle in self.written.ones() {
+            if handle < master.vals.len() {
+                self.vals[handle] = master.vals[handle];
+            }
+        }
+        self.free.clone_from(&master.free);
+        self.written.clear();
+    }
+}
+
+impl<K: Ord+Clone, V: Clone> RefillClone for SplitTeardownMap<K, V> {
+    fn refill_clone(&mut self, master: &Self) {
+        self.drop_vals();
+        self.keys.clear();
+        self.clone_vals_from(master);
+        self.keys.refill_clone(&master.keys);
+    }
+}
+
+impl<K: Ord+Clone, V: Clone> Clone for SplitTeardownMap<K, V> {
+    fn clone(&self) -> Self {
+        let mut map = SplitTeardownMap { keys: TeardownMap::with_sorted(Vec::new()), vals: Vec::new(),
+                                         free: Vec::new(), written: BitSet::new(0) };
+        map.clone_vals_from(self);
+        map.keys = self.keys.clone();
+        map
+    }
+}
+
+impl<K: Ord+Clone, V> Drop for SplitTeardownMap<K, V> {
+    fn drop(&mut self) {
+        self.drop_vals();
+    }
+}
+
+impl<K: Ord+Clone+Debug, V: Debug> Debug for SplitTeardownMap<K, V> {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_map().entries(self.iter()).finish()
+    }
+}
+
+
+/// Takes the values of the items deleted from the key tree and feeds the items into `sink`.
+struct TakeVals<'a, V: 'a, S> {
+    vals: &'a [MaybeUninit<V>],
+    free: &'a mut Vec<usize>,
+    sink: S,
+}
+
+impl<'a, K, V: 'a, S: Sink<(K, V)>> Sink<(K, usize)> for TakeVals<'a, V, S> {
+    #[inline]
+    fn consume(&mut self, (key, handle): (K, usize)) {
+        self.free.push(handle);
+        // See `SplitTeardownMap::take_val()`.
+        let val = unsafe { self.vals[handle].assume_init_read() };
+        self.sink.consume((key, val))
+    }
+}
+
+/// Looks up the values of the items found in the key tree and feeds references to the items into
+/// `sink`.
+struct RefVals<'a, V: 'a, S> {
+    vals: &'a [MaybeUninit<V>],
+    sink: S,
+}
+
+impl<'a, K: 'a, V: 'a, S: Sink<(&'a K, &'a V)>> Sink<&'a (K, usize)> for RefVals<'a, V, S> {
+    #[inline]
+    fn consume(&mut self, item: &'a (K, usize)) {
+        // This is safe: the handle is in the key tree, so its slot holds a value.
+        self.sink.consume((&item.0, unsafe { self.vals[item.1].assume_init_ref() }))
+    }
+}
+
+
+/// An in-order iterator over a `SplitTeardownMap`.
+pub struct SplitMapIter<'a, K: Ord+Clone+'a, V: 'a> {
+    keys: MapIter<'a, K, usize>,
+    vals: &'a [MaybeUninit<V>],
+}
+
+impl<'a, K: Ord+Clone+'a, V: 'a> Iterator for SplitMapIter<'a, K, V> {
+    type Item = (&'a K, &'a V);
+
+    #[inline]
+    fn next(&mut self) -> Option<Self::Item> {
+        let vals = self.vals;
+        // This is safe: the handle is in the key tree, so its slot holds a value.
+        self.keys.next().map(|&(ref key, handle)| (key, unsafe { vals[handle].assume_init_ref() }))
+    }
+
+    #[inline]
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.keys.size_hint()
+    }
+}
+
+impl<'a, K: Ord+Clone+'a, V: 'a> DoubleEndedIterator for SplitMapIter<'a, K, V> {
+    #[inline]
+    fn next_back(&mut self) -> Option<Self::Item> {
+        let vals = self.vals;
+        // This is safe: see next().
+        self.keys.next_back().map(|&(ref key, handle)| (key, unsafe { vals[handle].assume_init_ref() }))
+    }
+}
+
+impl<'a, K: Ord+Clone+'a, V: 'a> ExactSizeIterator for SplitMapIter<'a, K, V> {}